@@ -0,0 +1,26 @@
+//! Arbitrary client bytes through the RESP command parser.
+//!
+//! Run with `cargo +nightly fuzz run resp` from the repository root. Inputs that crash it
+//! belong in the parser's unit tests in `src/resp.rs`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The proxy is a binary crate, so the parser module is compiled in directly
+#[allow(dead_code)]
+#[path = "../../src/resp.rs"]
+mod resp;
+
+fuzz_target!(|buf: &[u8]| {
+    let (names, consumed) = resp::parse_commands(buf);
+    assert!(consumed <= buf.len());
+
+    let (commands, consumed_full) = resp::parse_commands_full(buf);
+    assert_eq!(consumed_full, consumed);
+    assert_eq!(commands.len(), names.len());
+    assert!(commands.iter().all(|tokens| !tokens.is_empty()));
+
+    // The consumed bytes are complete commands on their own, however the input ends
+    assert_eq!(resp::parse_commands_full(&buf[..consumed]), (commands, consumed));
+});
@@ -0,0 +1,485 @@
+//! Accepting client connections without dying on accept errors.
+//!
+//! `accept` fails when the process runs out of file descriptors, but the connection stays
+//! queued in the kernel, so retrying just spins. A spare descriptor is held in reserve
+//! for that case: it is closed to make room, the waiting connection is accepted, told why
+//! and closed, and the spare is taken again.
+//!
+//! If the listening socket itself fails, say it was closed from outside or its network
+//! went down, accepting again cannot succeed: the server stops, or with
+//! `--rebind-on-listen-error` binds the address again, backing off while that fails.
+//! Binding is given up on only if the address is taken by another process or not ours to
+//! use. Other accept errors are logged and retried after a short pause.
+//!
+//! With `--listen unix:PATH` clients connect over a Unix domain socket instead of TCP. A
+//! socket file left behind by a process that exited without removing it is replaced on
+//! bind; one another process is still listening on is not. Unix socket clients have no
+//! address, so they are reported as [`UNIX_PEER`].
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, warn};
+
+use crate::error::Result;
+use crate::upstream::backoff;
+
+/// `errno` for the per-process and system-wide descriptor limits (the same on Linux,
+/// macOS and the BSDs).
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+/// `errno` for accepting on something that is not, or no longer, a listening socket.
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+#[cfg(target_os = "linux")]
+const ENOTSOCK: i32 = 88;
+#[cfg(not(target_os = "linux"))]
+const ENOTSOCK: i32 = 38;
+
+/// Pause after an accept error that shedding a connection could not clear.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before the first retry of binding the listening address again; later ones back
+/// off exponentially.
+const REBIND_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Prefix of a `--listen` address naming a Unix domain socket path.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Stands in for the peer address of Unix socket clients, which have none.
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// A listening socket: TCP, or a Unix domain socket for `unix:PATH` addresses.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Listener {
+    /// Bind `addr`, a TCP `host:port` or `unix:PATH`.
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => bind_unix(Path::new(path)).map(Listener::Unix),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            )),
+            None => TcpListener::bind(addr).await.map(Listener::Tcp),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(ClientStream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((ClientStream::Tcp(stream), peer))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), UNIX_PEER))
+            }
+        }
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket file left there.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale_socket(path) => {
+            warn!("Removing stale socket file {}", path.display());
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Whether `path` is a socket file nothing is listening on any more. Anything else, such
+/// as a regular file or a socket still in use, is left alone.
+#[cfg(unix)]
+fn is_stale_socket(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+        && std::os::unix::net::UnixStream::connect(path)
+            .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+/// An accepted client connection.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// Write `reply` straight to the socket and close it, without waiting for tokio to
+    /// see it become writable.
+    fn reject(self, reply: &[u8]) {
+        let _ = match self {
+            ClientStream::Tcp(stream) => stream.into_std().and_then(|mut s| s.write(reply)),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.into_std().and_then(|mut s| s.write(reply)),
+        };
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Accepts from a listener, shedding connections while out of file descriptors.
+pub struct Acceptor {
+    /// Only missing while the address is being bound again
+    listener: Option<Listener>,
+    /// Closed to make room for accepting one connection when descriptors run out
+    spare: Option<File>,
+    /// Written to shed connections, for clients that can read it
+    reply: Option<&'static [u8]>,
+    /// Address bound again if the listening socket fails, with `--rebind-on-listen-error`
+    rebind: Option<String>,
+}
+
+impl Acceptor {
+    pub fn new(listener: impl Into<Listener>, reply: Option<&'static [u8]>) -> Self {
+        Self {
+            listener: Some(listener.into()),
+            spare: reserve(),
+            reply,
+            rebind: None,
+        }
+    }
+
+    /// Bind `addr` again instead of failing when the listening socket fails.
+    pub fn with_rebind(mut self, addr: impl Into<String>) -> Self {
+        self.rebind = Some(addr.into());
+        self
+    }
+
+    fn listener(&self) -> &Listener {
+        self.listener.as_ref().expect("listener bound outside of rebind")
+    }
+
+    /// Wait for the next client connection. Fails only if the listening socket failed
+    /// and could not be replaced.
+    pub async fn accept(&mut self) -> Result<(ClientStream, SocketAddr)> {
+        loop {
+            match self.listener().accept().await {
+                Ok(accepted) => return Ok(accepted),
+                Err(e) => self.recover(e).await?,
+            }
+        }
+    }
+
+    /// Get past a failed accept.
+    async fn recover(&mut self, e: io::Error) -> Result<()> {
+        if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+        ) {
+            // The connection went away before it could be accepted; nothing to fix
+            warn!("Failed to accept a connection: {}", e);
+            return Ok(());
+        }
+        if is_out_of_descriptors(&e) && self.spare.take().is_some() {
+            self.shed().await;
+            self.spare = reserve();
+            return Ok(());
+        }
+        if is_listener_failure(&e) {
+            let Some(addr) = self.rebind.clone() else {
+                error!("Listening socket failed: {}; see --rebind-on-listen-error", e);
+                return Err(e.into());
+            };
+            error!("Listening socket failed, binding {} again: {}", addr, e);
+            // Closed first, as the old socket may still hold the address
+            self.listener = None;
+            self.listener = Some(bind_again(&addr).await?);
+            info!("Listening on {} again", addr);
+            return Ok(());
+        }
+        error!("Failed to accept a connection, retrying shortly: {}", e);
+        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+        if self.spare.is_none() {
+            self.spare = reserve();
+        }
+        Ok(())
+    }
+
+    /// Accept and close one waiting connection, using the descriptor the spare freed.
+    async fn shed(&mut self) {
+        match self.listener().accept().await {
+            Ok((stream, peer_addr)) => {
+                error!(
+                    "Out of file descriptors, rejected connection from {}; raise the \
+                     descriptor limit (ulimit -n) or lower --max-connections",
+                    peer_addr
+                );
+                // Written straight to the socket: tokio has yet to see it become writable,
+                // and waiting for that would hold the descriptor
+                if let Some(reply) = self.reply {
+                    stream.reject(reply);
+                }
+            }
+            Err(e) => error!("Out of file descriptors, failed to shed a connection: {}", e),
+        }
+    }
+}
+
+fn is_out_of_descriptors(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(EMFILE | ENFILE))
+}
+
+/// Whether `e` means the listening socket is gone or unusable, rather than one
+/// connection failing.
+fn is_listener_failure(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(EBADF | EINVAL | ENOTSOCK))
+        || e.kind() == io::ErrorKind::NetworkDown
+}
+
+/// Bind `addr` until it succeeds or fails in a way waiting cannot fix.
+async fn bind_again(addr: &str) -> Result<Listener> {
+    let mut failures = 0;
+    loop {
+        match Listener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if is_fatal_bind_error(&e) => {
+                error!("Cannot bind {} again, giving up: {}", addr, e);
+                return Err(e.into());
+            }
+            Err(e) => {
+                let delay = backoff(REBIND_BASE_DELAY, failures);
+                warn!("Failed to bind {} again, retrying in {:?}: {}", addr, delay, e);
+                tokio::time::sleep(delay).await;
+                failures += 1;
+            }
+        }
+    }
+}
+
+/// Bind errors that persist: another process holds the address, or it is not ours to use.
+/// Anything else, such as the address missing while an interface is down, may clear up.
+fn is_fatal_bind_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Take a descriptor to hold in reserve; none if the limit is already reached.
+fn reserve() -> Option<File> {
+    File::open(if cfg!(windows) { "NUL" } else { "/dev/null" }).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const REPLY: &[u8] = b"-ERR out of file descriptors\r\n";
+
+    async fn acceptor() -> (Acceptor, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Acceptor::new(listener, Some(REPLY)), addr)
+    }
+
+    #[tokio::test]
+    async fn test_out_of_descriptors_sheds_waiting_connection() {
+        let (mut acceptor, addr) = acceptor().await;
+        assert!(acceptor.spare.is_some());
+        let mut overflow = TcpStream::connect(addr).await.unwrap();
+
+        // As if accept had just hit the descriptor limit
+        acceptor.recover(io::Error::from_raw_os_error(EMFILE)).await.unwrap();
+
+        let mut reply = Vec::new();
+        overflow.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, REPLY);
+        assert!(acceptor.spare.is_some(), "spare descriptor not taken back");
+
+        // Later connections are accepted as usual
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = acceptor.accept().await.unwrap();
+        assert_eq!(peer.ip(), addr.ip());
+    }
+
+    #[tokio::test]
+    async fn test_aborted_connections_skipped() {
+        let (mut acceptor, addr) = acceptor().await;
+        let client = TcpStream::connect(addr).await.unwrap();
+        acceptor.recover(io::ErrorKind::ConnectionAborted.into()).await.unwrap();
+
+        // The waiting connection was left for the next accept
+        let (_, peer) = acceptor.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert!(acceptor.spare.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_other_errors_retried_after_pause() {
+        let (mut acceptor, _) = acceptor().await;
+        // Without a spare left there is nothing to shed with, so wait it out and take
+        // one again once descriptors are free
+        acceptor.spare = None;
+        let started = tokio::time::Instant::now();
+        acceptor.recover(io::Error::from_raw_os_error(ENFILE)).await.unwrap();
+        assert_eq!(started.elapsed(), ACCEPT_ERROR_DELAY);
+        assert!(acceptor.spare.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_listen_failure_rebinds_when_enabled() {
+        let (acceptor, addr) = acceptor().await;
+        let mut acceptor = acceptor.with_rebind(addr.to_string());
+
+        // As if the listening socket had been closed from outside
+        acceptor.recover(io::Error::from_raw_os_error(EBADF)).await.unwrap();
+        let Listener::Tcp(listener) = acceptor.listener() else {
+            panic!("rebound as a Unix socket");
+        };
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = acceptor.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_listen_failure_fatal_without_rebind() {
+        let (mut acceptor, _) = acceptor().await;
+        assert!(acceptor.recover(io::Error::from_raw_os_error(EINVAL)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rebind_gives_up_when_address_taken() {
+        let (acceptor, _) = acceptor().await;
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut acceptor = acceptor.with_rebind(other.local_addr().unwrap().to_string());
+        let failed = acceptor.recover(io::ErrorKind::NetworkDown.into()).await;
+        assert!(failed.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rebind_retried_while_address_unavailable() {
+        let (acceptor, _) = acceptor().await;
+        // A documentation-only address, not assigned to any local interface
+        let mut acceptor = acceptor.with_rebind("192.0.2.1:0");
+        let rebinding = acceptor.recover(io::Error::from_raw_os_error(EBADF));
+        let waited = tokio::time::timeout(Duration::from_secs(60), rebinding).await;
+        assert!(waited.is_err(), "gave up instead of retrying");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = std::env::temp_dir().join(format!("accept-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.sock");
+        let addr = format!("unix:{}", path.display());
+
+        // A socket file left by an earlier process that exited is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut acceptor = Acceptor::new(Listener::bind(&addr).await.unwrap(), Some(REPLY));
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, peer) = acceptor.accept().await.unwrap();
+        assert_eq!(peer, UNIX_PEER);
+        stream.write_all(b"+OK\r\n").await.unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        // One still in use is not
+        let err = Listener::bind(&addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(is_fatal_bind_error(&err));
+
+        // Neither is a file that is not a socket
+        let regular = dir.join("regular");
+        std::fs::write(&regular, b"data").unwrap();
+        let taken = Listener::bind(&format!("unix:{}", regular.display())).await;
+        assert!(taken.is_err());
+        assert_eq!(std::fs::read(&regular).unwrap(), b"data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_descriptor_errors_recognized() {
+        assert!(is_out_of_descriptors(&io::Error::from_raw_os_error(EMFILE)));
+        assert!(is_out_of_descriptors(&io::Error::from_raw_os_error(ENFILE)));
+        assert!(!is_out_of_descriptors(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(is_listener_failure(&io::Error::from_raw_os_error(ENOTSOCK)));
+        assert!(!is_listener_failure(&io::Error::from_raw_os_error(EMFILE)));
+        assert!(is_fatal_bind_error(&io::ErrorKind::AddrInUse.into()));
+        assert!(!is_fatal_bind_error(&io::ErrorKind::AddrNotAvailable.into()));
+    }
+}
@@ -0,0 +1,345 @@
+//! Minimal admin HTTP endpoint for health checks and operational control.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::drain::{self, Drain};
+use crate::error::Result;
+use crate::memory::MemoryGuard;
+use crate::samples::CommandSamples;
+use crate::stats::Stats;
+
+/// Maximum size of an HTTP request head we are willing to buffer.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Shared state the admin endpoint reports on.
+pub struct AdminState {
+    pub stats: Arc<Stats>,
+    pub memory: Option<Arc<MemoryGuard>>,
+    pub drain: Arc<Drain>,
+    /// Effective configuration, already redacted, served at `/config`
+    pub config_json: String,
+    /// Commands sampled with `--sample-rate`, served at `/samples`
+    pub samples: Option<Arc<CommandSamples>>,
+    /// Latency percentiles reported at `/stats.json`
+    pub percentiles: Vec<f64>,
+}
+
+/// An HTTP response produced by the admin router.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into(),
+        }
+    }
+
+    /// A 200 response with `value` encoded as JSON.
+    fn json_of(value: &impl Serialize) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self::json(200, format!("{}\n", body)),
+            Err(e) => Self::text(500, format!("failed to encode response: {}\n", e)),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+}
+
+impl AdminState {
+    /// Route a request to its handler.
+    pub fn handle(&self, method: &str, path: &str) -> Response {
+        match (method, path) {
+            ("GET", "/health") => self.health(),
+            ("POST", "/drain") => self.start_drain(),
+            ("GET", "/config") => Response::json(200, format!("{}\n", self.config_json)),
+            ("GET", "/slowest") => Response::json_of(&self.stats.slowest_commands()),
+            ("GET", "/samples") => self.samples(),
+            ("GET", "/slowlog") => match self.stats.slowlog() {
+                Some(entries) => Response::json_of(&entries),
+                None => Response::text(404, "slowlog is off, see --slowlog-threshold-ms\n"),
+            },
+            ("GET", "/stats.json") => Response::json_of(&self.stats.to_json(&self.percentiles)),
+            ("POST", "/stats/reset") => {
+                self.stats.reset();
+                info!("Statistics reset via admin endpoint");
+                Response::text(200, "statistics reset\n")
+            }
+            (
+                _,
+                "/health" | "/drain" | "/config" | "/slowest" | "/slowlog" | "/samples"
+                | "/stats.json" | "/stats/reset",
+            ) => Response::text(405, "method not allowed\n"),
+            _ => Response::text(404, "not found\n"),
+        }
+    }
+
+    fn health(&self) -> Response {
+        if self.drain.is_draining() {
+            return Response::text(503, "draining\n");
+        }
+        if let Some(memory) = &self.memory
+            && !memory.accepts_connections()
+        {
+            return Response::text(503, "memory pressure\n");
+        }
+        Response::text(200, "OK\n")
+    }
+
+    fn samples(&self) -> Response {
+        match &self.samples {
+            Some(samples) => Response::json_of(&samples.snapshot()),
+            None => Response::text(404, "command sampling is off, see --sample-rate\n"),
+        }
+    }
+
+    /// Stop accepting connections and log progress until the active ones have closed.
+    fn start_drain(&self) -> Response {
+        let remaining = self.stats.active_connections();
+        if self.drain.start() {
+            info!("Drain requested via admin endpoint");
+            tokio::spawn(drain::report_progress(
+                self.stats.clone(),
+                drain::REPORT_INTERVAL,
+            ));
+        }
+        Response::text(
+            202,
+            format!("draining, {} active connections\n", remaining),
+        )
+    }
+}
+
+/// Run the admin HTTP server until the task is dropped.
+pub async fn run_admin_server(addr: &str, state: Arc<AdminState>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Admin endpoint listening on {}", addr);
+    serve_admin(listener, state).await
+}
+
+/// Answer admin requests accepted on `listener`, each on its own task.
+pub async fn serve_admin(listener: TcpListener, state: Arc<AdminState>) -> Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, |method, path| state.handle(method, path)).await {
+                debug!("Admin request from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+/// Serve a single request on `stream` with `handle`, given its method and path, and
+/// close it.
+pub async fn serve(mut stream: TcpStream, handle: impl Fn(&str, &str) -> Response) -> Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_HEAD {
+            break;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let response = handle(method, path);
+    let out = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    stream.write_all(out.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::time::Duration;
+    use crate::samples::Sample;
+
+    fn state(memory: Option<Arc<MemoryGuard>>) -> AdminState {
+        AdminState {
+            stats: Stats::new(),
+            memory,
+            drain: Drain::new(),
+            config_json: r#"{"listen":"0.0.0.0:16379"}"#.to_string(),
+            samples: None,
+            percentiles: vec![50.0, 99.0],
+        }
+    }
+
+    #[test]
+    fn test_health_reports_memory_pressure() {
+        let memory = MemoryGuard::new(1000);
+        let state = state(Some(memory.clone()));
+
+        assert_eq!(state.handle("GET", "/health").status, 200);
+        memory.update(5000);
+        assert_eq!(state.handle("GET", "/health").status, 503);
+        memory.update(10);
+        assert_eq!(state.handle("GET", "/health").status, 200);
+    }
+
+    #[test]
+    fn test_unknown_path() {
+        let state = state(None);
+        assert_eq!(state.handle("GET", "/nope").status, 404);
+    }
+
+    #[test]
+    fn test_config_endpoint() {
+        let state = state(None);
+        let response = state.handle("GET", "/config");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "application/json");
+        assert_eq!(response.body, "{\"listen\":\"0.0.0.0:16379\"}\n");
+        assert_eq!(state.handle("POST", "/config").status, 405);
+    }
+
+    #[test]
+    fn test_slowest_endpoint() {
+        let state = state(None);
+        assert_eq!(state.handle("GET", "/slowest").body, "[]\n");
+
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        state
+            .stats
+            .record_command_latency("GET", std::time::Duration::from_micros(250), peer);
+        let response = state.handle("GET", "/slowest");
+        assert_eq!(response.status, 200);
+        assert!(
+            response.body.starts_with(r#"[{"command":"GET","duration_us":250,"#),
+            "{}",
+            response.body
+        );
+    }
+
+    #[test]
+    fn test_samples_endpoint() {
+        let mut state = state(None);
+        assert_eq!(state.handle("GET", "/samples").status, 404);
+
+        let samples = Arc::new(CommandSamples::new(10));
+        state.samples = Some(samples.clone());
+        assert_eq!(state.handle("GET", "/samples").body, "[]\n");
+        samples.record(Sample {
+            command: "GET k".to_string(),
+            reply: Some("$-1\\r\\n".to_string()),
+            duration: Some(std::time::Duration::from_micros(250)),
+            timestamp_ms: 1700000000000,
+            peer: "10.0.0.1:50000".parse().unwrap(),
+        });
+        let response = state.handle("GET", "/samples");
+        assert_eq!(response.status, 200);
+        assert!(
+            response.body.starts_with(r#"[{"command":"GET k","reply":"$-1\\r\\n","#),
+            "{}",
+            response.body
+        );
+        assert_eq!(state.handle("POST", "/samples").status, 405);
+    }
+
+    #[test]
+    fn test_stats_endpoint() {
+        let state = state(None);
+        state.stats.record_command("GET");
+        let response = state.handle("GET", "/stats.json");
+        assert_eq!((response.status, response.content_type), (200, "application/json"));
+        assert!(response.body.contains(r#""total_commands":1,"#), "{}", response.body);
+        assert!(response.body.contains(r#""commands":[{"name":"GET","count":1}]"#));
+        assert_eq!(state.handle("POST", "/stats.json").status, 405);
+    }
+
+    #[test]
+    fn test_slowlog_endpoint() {
+        let mut state = state(None);
+        assert_eq!(state.handle("GET", "/slowlog").status, 404);
+
+        let config = crate::config::Config::parse_from([
+            "redis-tls-proxy", "--no-tls", "--slowlog-threshold-ms", "5", "--parse-replies", "on",
+        ]);
+        state.stats = Stats::from_config(&config);
+        assert_eq!(state.handle("GET", "/slowlog").body, "[]\n");
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        state.stats.record_command_latency("keys", Duration::from_millis(20), peer);
+        state.stats.record_command_latency("GET", Duration::from_millis(1), peer);
+
+        let response = state.handle("GET", "/slowlog");
+        assert_eq!(response.status, 200);
+        assert!(response.body.starts_with(r#"[{"id":0,"timestamp_ms":"#), "{}", response.body);
+        assert!(response.body.contains(r#""command":"KEYS","duration_us":20000,"#));
+        assert!(!response.body.contains("GET"));
+        assert_eq!(state.handle("POST", "/slowlog").status, 405);
+    }
+
+    #[test]
+    fn test_stats_reset_endpoint() {
+        let state = state(None);
+        state.stats.record_command("GET");
+        assert_eq!(state.handle("GET", "/stats/reset").status, 405);
+        assert_eq!(state.stats.total(), 1);
+
+        let response = state.handle("POST", "/stats/reset");
+        assert_eq!((response.status, response.body.as_str()), (200, "statistics reset\n"));
+        assert_eq!(state.stats.total(), 0);
+        assert!(state.stats.command_counts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain_endpoint() {
+        let state = state(None);
+        let _conn = state.stats.track_connection();
+
+        assert_eq!(state.handle("GET", "/drain").status, 405);
+        let response = state.handle("POST", "/drain");
+        assert_eq!(response.status, 202);
+        assert_eq!(response.body, "draining, 1 active connections\n");
+        assert!(state.drain.is_draining());
+        assert_eq!(state.handle("GET", "/health").status, 503);
+    }
+}
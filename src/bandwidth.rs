@@ -0,0 +1,120 @@
+//! Aggregate bandwidth limiting across all connections.
+//!
+//! `--max-total-bps` caps the bytes per second the proxy reads from clients and upstreams
+//! combined, protecting a shared upstream link. Every connection draws from one token
+//! bucket; a connection that finds it empty waits in line, and the connections behind it
+//! are served in arrival order, so a busy connection cannot starve the others.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+use crate::clock::Clock;
+
+/// Shared token bucket holding up to one second's worth of bytes.
+pub struct BandwidthLimiter {
+    bytes_per_sec: u64,
+    clock: Arc<dyn Clock>,
+    /// Waiters queue on this lock in FIFO order, which is what shares the budget fairly
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may pass without waiting; negative while paying off a large read
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64, clock: Arc<dyn Clock>) -> Arc<Self> {
+        let refilled_at = clock.now();
+        Arc::new(Self {
+            bytes_per_sec,
+            clock,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled_at,
+            }),
+        })
+    }
+
+    /// Account for `bytes` just read, waiting until the budget allows them.
+    pub async fn consume(&self, bytes: usize) {
+        // Held while sleeping: later connections wait their turn rather than jumping in
+        // whenever a few tokens come back
+        let mut bucket = self.bucket.lock().await;
+        self.refill(&mut bucket);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens < 0.0 {
+            let wait = -bucket.tokens / self.bytes_per_sec as f64;
+            self.clock.sleep(Duration::from_secs_f64(wait)).await;
+            self.refill(&mut bucket);
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+        let capacity = self.bytes_per_sec as f64;
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_burst_then_paced() {
+        let clock = MockClock::start();
+        let limiter = BandwidthLimiter::new(1000, clock.clone());
+        let start = clock.now();
+
+        // A full second's budget is available straight away
+        limiter.consume(1000).await;
+        assert_eq!(clock.now(), start);
+
+        limiter.consume(500).await;
+        let waited = (clock.now() - start).as_millis();
+        assert!((500..=502).contains(&waited), "waited {waited}ms");
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_throughput_capped_and_shared() {
+        let clock = MockClock::start();
+        let limiter = BandwidthLimiter::new(10_000, clock.clone());
+        // Spend the initial burst, so every byte below has to wait for its share
+        limiter.consume(10_000).await;
+        let start = clock.now();
+
+        // Three connections reading as fast as they can for five seconds
+        let deadline = start + Duration::from_secs(5);
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let limiter = limiter.clone();
+            let clock = clock.clone();
+            connections.push(tokio::spawn(async move {
+                let mut read = 0;
+                while clock.now() < deadline {
+                    limiter.consume(1000).await;
+                    read += 1000;
+                }
+                read
+            }));
+        }
+        let mut totals = Vec::new();
+        for connection in connections {
+            totals.push(connection.await.unwrap());
+        }
+
+        // Five seconds at the cap, give or take a read each
+        let total: u64 = totals.iter().sum();
+        assert!(total <= 50_000 + 3000, "read {total} bytes");
+        assert!(total >= 50_000 - 3000, "read {total} bytes");
+        let (min, max) = (totals.iter().min().unwrap(), totals.iter().max().unwrap());
+        assert!(max - min <= 1000, "unfair split: {totals:?}");
+    }
+}
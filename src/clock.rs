@@ -0,0 +1,106 @@
+//! Source of time for timing-dependent code.
+//!
+//! Uptime, command latencies, draining and upstream outages all read the time and sleep
+//! through a [`Clock`], so tests can run them on a [`MockClock`] that only moves when
+//! told to instead of waiting on real time.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A monotonic clock that can also be slept on.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The real time, used outside of tests.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// The clock used by default.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Virtual time on top of tokio's paused clock.
+///
+/// Creating one pauses time for the current (single-threaded) runtime: it then moves
+/// only through [`MockClock::advance`], or jumps straight to the next pending sleep
+/// whenever every task is idle, so timing tests run instantly and deterministically.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock;
+
+#[cfg(test)]
+impl MockClock {
+    pub fn start() -> Arc<Self> {
+        tokio::time::pause();
+        Arc::new(MockClock)
+    }
+
+    /// Move time forward, running every task woken by a sleep that falls due on the way.
+    /// Timers fire on millisecond ticks, so time can end up a millisecond further.
+    pub async fn advance(&self, duration: Duration) {
+        // Sleeping on paused time skips straight through each earlier deadline in order
+        tokio::time::sleep(duration).await;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_clock_moves_only_when_advanced() {
+        let clock = MockClock::start();
+        let start = clock.now();
+        tokio::task::yield_now().await;
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(3600)).await;
+        assert_eq!((clock.now() - start).as_secs(), 3600);
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_sleep_completes_without_waiting() {
+        let clock = MockClock::start();
+        let start = clock.now();
+        let real_start = std::time::Instant::now();
+
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(59)).await;
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+
+        // With nothing else to do, time skips ahead to the sleep's deadline
+        sleep.await.unwrap();
+        assert_eq!((clock.now() - start).as_secs(), 60);
+        assert!(real_start.elapsed() < Duration::from_secs(1));
+    }
+}
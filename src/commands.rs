@@ -0,0 +1,111 @@
+//! Which commands write to the dataset, for `--read-only`.
+//!
+//! The list follows the `write` flag Redis itself gives its commands, so a client sees the
+//! same commands refused by the proxy as by a read-only replica. Commands such as `SORT`
+//! and `GEORADIUS` that only write with an option are writes here too, as in Redis; their
+//! `_RO` variants are not. Unknown commands are let through, so module commands are not
+//! covered.
+
+use crate::resp::Command;
+
+/// Commands whose first argument is a subcommand that decides whether they write.
+const CONTAINERS: &[&str] = &["OBJECT", "XGROUP", "XINFO", "FUNCTION"];
+
+/// Commands, and `CONTAINER SUBCOMMAND` forms, that modify data.
+const WRITE_COMMANDS: &[&str] = &[
+    // Keys
+    "DEL", "UNLINK", "EXPIRE", "PEXPIRE", "EXPIREAT", "PEXPIREAT", "PERSIST", "RENAME",
+    "RENAMENX", "MOVE", "COPY", "RESTORE", "RESTORE-ASKING", "MIGRATE", "SORT", "FLUSHDB",
+    "FLUSHALL", "SWAPDB",
+    // Strings and bitmaps
+    "SET", "SETNX", "SETEX", "PSETEX", "MSET", "MSETNX", "APPEND", "INCR", "DECR", "INCRBY",
+    "DECRBY", "INCRBYFLOAT", "GETSET", "GETDEL", "GETEX", "SETRANGE", "SETBIT", "BITOP",
+    "BITFIELD",
+    // Hashes
+    "HSET", "HSETNX", "HMSET", "HDEL", "HINCRBY", "HINCRBYFLOAT", "HGETDEL", "HGETEX",
+    "HSETEX", "HEXPIRE", "HPEXPIRE", "HEXPIREAT", "HPEXPIREAT", "HPERSIST",
+    // Lists
+    "LPUSH", "RPUSH", "LPUSHX", "RPUSHX", "LINSERT", "LSET", "LREM", "LTRIM", "LPOP", "RPOP",
+    "RPOPLPUSH", "LMOVE", "LMPOP", "BLPOP", "BRPOP", "BRPOPLPUSH", "BLMOVE", "BLMPOP",
+    // Sets
+    "SADD", "SREM", "SPOP", "SMOVE", "SINTERSTORE", "SUNIONSTORE", "SDIFFSTORE",
+    // Sorted sets
+    "ZADD", "ZINCRBY", "ZREM", "ZREMRANGEBYSCORE", "ZREMRANGEBYRANK", "ZREMRANGEBYLEX",
+    "ZPOPMIN", "ZPOPMAX", "ZMPOP", "BZPOPMIN", "BZPOPMAX", "BZMPOP", "ZUNIONSTORE",
+    "ZINTERSTORE", "ZDIFFSTORE", "ZRANGESTORE",
+    // Streams
+    "XADD", "XDEL", "XTRIM", "XACK", "XCLAIM", "XAUTOCLAIM", "XSETID", "XREADGROUP",
+    "XGROUP CREATE", "XGROUP SETID", "XGROUP DESTROY", "XGROUP CREATECONSUMER",
+    "XGROUP DELCONSUMER",
+    // Geo and HyperLogLog
+    "GEOADD", "GEORADIUS", "GEORADIUSBYMEMBER", "GEOSEARCHSTORE", "PFADD", "PFMERGE",
+    // Scripting
+    "EVAL", "EVALSHA", "FCALL", "FUNCTION LOAD", "FUNCTION DELETE", "FUNCTION FLUSH",
+    "FUNCTION RESTORE",
+];
+
+/// Whether `name` modifies data. `name` is a command name, or a command and its
+/// subcommand separated by a space (`XGROUP CREATE`, `OBJECT ENCODING`); case is ignored.
+pub fn is_write_command(name: &str) -> bool {
+    WRITE_COMMANDS.iter().any(|known| same_words(name, known))
+}
+
+/// Whether `cmd`, with its subcommand if it has one, modifies data.
+pub fn writes(cmd: &Command) -> bool {
+    match cmd.args.first() {
+        Some(sub) if CONTAINERS.iter().any(|c| cmd.name.eq_ignore_ascii_case(c)) => {
+            is_write_command(&format!("{} {}", cmd.name, sub))
+        }
+        _ => is_write_command(&cmd.name),
+    }
+}
+
+/// Whether `name` has the same words as `known`, ignoring case and extra whitespace.
+fn same_words(name: &str, known: &str) -> bool {
+    let mut words = name.split_ascii_whitespace();
+    known
+        .split(' ')
+        .all(|part| words.next().is_some_and(|word| word.eq_ignore_ascii_case(part)))
+        && words.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(words: &[&str]) -> Command {
+        Command {
+            name: words[0].to_string(),
+            args: words[1..].iter().map(|w| w.to_string()).collect(),
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_writes_recognized_in_any_case() {
+        for name in ["SET", "set", "Del", "LPUSH", "zadd", "XADD", "EVAL", "FLUSHALL"] {
+            assert!(is_write_command(name), "{name}");
+        }
+        for name in ["GET", "mget", "SORT_RO", "EVAL_RO", "SUBSCRIBE", "MULTI", "UNKNOWN"] {
+            assert!(!is_write_command(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_subcommands_classified_with_their_command() {
+        assert!(!is_write_command("OBJECT ENCODING"));
+        assert!(!is_write_command("object  freq"));
+        assert!(is_write_command("xgroup create"));
+        assert!(!is_write_command("XGROUP"));
+        assert!(!is_write_command("SET EXTRA"));
+
+        assert!(!writes(&command(&["OBJECT", "ENCODING", "k"])));
+        assert!(writes(&command(&["xgroup", "CREATE", "s", "g", "$"])));
+        assert!(!writes(&command(&["XINFO", "STREAM", "s"])));
+        assert!(writes(&command(&["FUNCTION", "flush"])));
+        assert!(!writes(&command(&["FUNCTION", "LIST"])));
+        // A key named like a subcommand does not change how a plain command is classified
+        assert!(!writes(&command(&["GET", "create"])));
+        assert!(writes(&command(&["SET", "encoding", "v"])));
+    }
+}
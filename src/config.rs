@@ -1,17 +1,142 @@
-use clap::Parser;
+use clap::builder::BoolishValueParser;
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use rustls::crypto::aws_lc_rs;
+use serde_json::json;
+use std::ffi::OsString;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::accept::UNIX_PREFIX;
+use crate::chain::ChainKey;
+use crate::config_file::file_args;
+use crate::upstream::UpstreamConfig;
+
+/// Prefix of the environment variables options can be set with, e.g. `REDIS_PROXY_LISTEN`.
+const ENV_PREFIX: &str = "REDIS_PROXY_";
+
+/// Environment variable for the option with clap id `id`.
+fn env_var(id: &str) -> String {
+    format!("{}{}", ENV_PREFIX, id.to_ascii_uppercase())
+}
+
+/// Replaces secret values in the configuration dump.
+const REDACTED: &str = "<redacted>";
+
+/// What the RESP traffic being proxied is.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Redis: command names are case-insensitive and Redis commands get special handling
+    Redis,
+    /// Another RESP-speaking service: the first element of each command is an opaque
+    /// token, counted verbatim with no Redis-specific handling
+    Generic,
+}
+
+impl Protocol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Redis => "redis",
+            Protocol::Generic => "generic",
+        }
+    }
+}
+
+/// What a SIGUSR1 stats dump reports.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpMode {
+    /// Totals since startup, like the shutdown summary
+    Cumulative,
+    /// Commands since the previous dump, with the rate over that interval
+    Delta,
+}
+
+impl DumpMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DumpMode::Cumulative => "cumulative",
+            DumpMode::Delta => "delta",
+        }
+    }
+}
+
+/// What happens to a command over `--max-commands-per-sec`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Answer it with an error instead of forwarding it
+    Reject,
+    /// Hold it back until the connection is within its rate again
+    Delay,
+}
+
+impl RateLimitAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RateLimitAction::Reject => "reject",
+            RateLimitAction::Delay => "delay",
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with a structured event for each command
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Oldest TLS version clients may negotiate.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+}
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "redis-tls-proxy")]
 #[command(about = "A TLS proxy for Redis connections")]
 pub struct Config {
-    /// Address to listen on (e.g., 0.0.0.0:16379)
+    /// Read options from this TOML file; flags given on the command line override it
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on (e.g., 0.0.0.0:16379), or unix:PATH for a Unix domain socket
     #[arg(short, long, default_value = "0.0.0.0:16379")]
     pub listen: String,
 
-    /// Upstream Redis server address (e.g., 127.0.0.1:6379)
-    #[arg(short, long, default_value = "127.0.0.1:6379")]
-    pub upstream: String,
+    /// If the listening socket fails, e.g. as a network interface goes away, bind the
+    /// address again (backing off while that fails) instead of shutting down
+    #[arg(long, default_value = "false")]
+    pub rebind_on_listen_error: bool,
+
+    /// Upstream Redis server address (e.g., 127.0.0.1:6379). Repeat to balance across
+    /// several upstreams. Per-upstream options follow the address, comma-separated:
+    /// tls, plain, hostname=NAME, ca=PATH, client-cert=PATH, client-key=PATH, bind=IP
+    #[arg(short, long, default_value = "127.0.0.1:6379", value_parser = UpstreamConfig::parse)]
+    pub upstream: Vec<UpstreamConfig>,
 
     /// Path to TLS certificate file (PEM format). Required unless --no-tls is set.
     #[arg(short, long)]
@@ -21,10 +146,42 @@ pub struct Config {
     #[arg(short, long)]
     pub key: Option<PathBuf>,
 
+    /// Require clients to present a certificate issued by a CA in this PEM bundle (mutual TLS)
+    #[arg(long)]
+    pub client_ca: Option<PathBuf>,
+
+    /// Reject client certificates listed in this certificate revocation list (PEM or DER).
+    /// Reloaded along with the certificate on SIGHUP
+    #[arg(long, requires = "client_ca")]
+    pub client_crl: Option<PathBuf>,
+
+    /// Request a client certificate but also accept clients that present none; a
+    /// certificate that is presented must still pass `--client-ca` and `--client-crl`
+    #[arg(long, default_value = "false", requires = "client_ca")]
+    pub client_ca_optional: bool,
+
+    /// Oldest TLS version accepted from clients
+    #[arg(long, value_enum, default_value = "1.2")]
+    pub tls_min_version: TlsVersion,
+
+    /// Cipher suites offered to clients, comma-separated rustls names such as
+    /// TLS13_AES_256_GCM_SHA384 (default: rustls's defaults)
+    #[arg(long, value_name = "SUITE", value_delimiter = ',', value_parser = parse_cipher_suite)]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// Application protocol offered to clients through ALPN, most preferred first; repeat
+    /// for several. Clients that offer none of them are refused
+    #[arg(long, value_name = "PROTOCOL", value_parser = parse_alpn)]
+    pub alpn: Vec<String>,
+
     /// Disable TLS on the listening side (for local development)
     #[arg(long, default_value = "false")]
     pub no_tls: bool,
 
+    /// Plain TCP on both sides: shortcut for --no-tls with plain upstreams
+    #[arg(long, default_value = "false", conflicts_with_all = ["cert", "key", "upstream_tls"])]
+    pub transparent: bool,
+
     /// Enable TLS for upstream connection
     #[arg(long, default_value = "false")]
     pub upstream_tls: bool,
@@ -32,16 +189,448 @@ pub struct Config {
     /// Upstream server hostname for TLS verification (defaults to upstream host)
     #[arg(long)]
     pub upstream_tls_hostname: Option<String>,
+
+    /// PEM CA bundle to verify TLS upstreams against instead of the webpki roots
+    #[arg(long, value_name = "PATH")]
+    pub upstream_ca: Option<PathBuf>,
+
+    /// Trust the webpki roots as well as the custom CA bundle of each TLS upstream
+    #[arg(long, default_value = "false")]
+    pub upstream_ca_add: bool,
+
+    /// Client certificate (PEM) presented to TLS upstreams that require mutual TLS
+    #[arg(long, value_name = "PATH", requires = "upstream_client_key")]
+    pub upstream_client_cert: Option<PathBuf>,
+
+    /// Private key (PEM: PKCS#8, PKCS#1 or SEC1) for --upstream-client-cert
+    #[arg(long, value_name = "PATH", requires = "upstream_client_cert")]
+    pub upstream_client_key: Option<PathBuf>,
+
+    /// Allow `tls` on `unix:PATH` upstreams. Off by default, as a local socket rarely
+    /// needs encrypting and `--upstream-tls` is then most likely meant for other upstreams
+    #[arg(long, default_value = "false")]
+    pub allow_upstream_unix_tls: bool,
+
+    /// Accept any certificate from TLS upstreams, without verifying it. For testing
+    /// against self-signed servers only: anyone on the path can impersonate the upstream
+    #[arg(long, default_value = "false")]
+    pub upstream_insecure_skip_verify: bool,
+
+    /// Local IP address upstream connections originate from (for multi-homed hosts)
+    #[arg(long)]
+    pub upstream_bind: Option<IpAddr>,
+
+    /// Application protocol requested from TLS upstreams through ALPN, most preferred
+    /// first; repeat for several
+    #[arg(long, value_name = "PROTOCOL", value_parser = parse_alpn)]
+    pub upstream_alpn: Vec<String>,
+
+    /// Shared secret authenticating traffic between chained instances of this proxy
+    #[arg(long, value_name = "KEY")]
+    pub chain_secret: Option<String>,
+
+    /// Expect --chain-secret framed traffic from another instance of this proxy on the
+    /// listening side
+    #[arg(long, requires = "chain_secret")]
+    pub chain_listen: bool,
+
+    /// Frame traffic to upstreams with --chain-secret; every upstream must be another
+    /// instance of this proxy started with --chain-listen
+    #[arg(long, requires = "chain_secret")]
+    pub chain_upstream: bool,
+
+    /// Protocol spoken by clients and upstreams: `redis`, or `generic` for other
+    /// RESP-speaking services whose command names are counted as opaque tokens
+    #[arg(long, value_enum, default_value = "redis")]
+    pub protocol: Protocol,
+
+    /// Address for the admin HTTP endpoint (e.g., 127.0.0.1:9090). Disabled when unset.
+    #[arg(long)]
+    pub admin_listen: Option<String>,
+
+    /// Address to serve Prometheus metrics on at /metrics (e.g., 127.0.0.1:9100). Disabled
+    /// when unset.
+    #[arg(long)]
+    pub metrics_listen: Option<String>,
+
+    /// Stop accepting new connections while the proxy's resident memory exceeds this size (e.g., 512M, 2G)
+    #[arg(long, value_parser = parse_byte_size)]
+    pub max_rss: Option<u64>,
+
+    /// Select this database on every upstream connection before proxying
+    #[arg(long)]
+    pub force_db: Option<u32>,
+
+    /// Reject client SELECT commands with an error instead of forwarding them
+    #[arg(long, default_value = "false")]
+    pub block_select: bool,
+
+    /// Reject this command with an error instead of forwarding it (repeatable)
+    #[arg(long, value_name = "CMD", conflicts_with = "allow_command")]
+    pub deny_command: Vec<String>,
+
+    /// Forward only these commands and reject all others with an error (repeatable)
+    #[arg(long, value_name = "CMD")]
+    pub allow_command: Vec<String>,
+
+    /// Reject commands that modify data with a READONLY error, e.g. to expose a replica
+    #[arg(long, default_value = "false")]
+    pub read_only: bool,
+
+    /// Reject client SELECT commands for database indexes above this
+    #[arg(long, value_name = "N")]
+    pub max_select_db: Option<u32>,
+
+    /// Authenticate each upstream connection with AUTH as this user (Redis 6 ACLs); needs
+    /// --upstream-auth-password
+    #[arg(long, requires = "upstream_auth_password")]
+    pub upstream_auth_user: Option<String>,
+
+    /// Authenticate each upstream connection with this password before proxying, for
+    /// clients that connect without credentials
+    #[arg(long)]
+    pub upstream_auth_password: Option<String>,
+
+    /// Forward client AUTH commands to upstream (on), or acknowledge them locally with +OK
+    /// for clients that always authenticate against a password-less upstream (off)
+    #[arg(long, default_value = "on", value_name = "on|off", action = ArgAction::Set,
+          value_parser = BoolishValueParser::new())]
+    pub auth_passthrough: bool,
+
+    /// When the upstream connection drops mid-session, hold client commands for up to this
+    /// long (e.g., 500ms, 2s) while reconnecting, then send the queued commands. Of those
+    /// upstream may already have run, only reads are replayed; the rest are answered with
+    /// an error. Requires --parse-replies on
+    #[arg(long, value_parser = parse_duration)]
+    pub upstream_unavailable_queue_time: Option<Duration>,
+
+    /// Commands held per connection during an upstream outage before giving up on it
+    #[arg(long, default_value = "1000", value_name = "N")]
+    pub upstream_unavailable_queue_size: usize,
+
+    /// Retry connecting to an unreachable upstream this many times before giving up on the
+    /// client connection
+    #[arg(long, default_value = "0")]
+    pub upstream_connect_retries: u32,
+
+    /// Wait before the first connection retry, doubled for each next one (with jitter)
+    #[arg(long, default_value = "100")]
+    pub upstream_connect_backoff_ms: u64,
+
+    /// Parse upstream replies to match them to commands (on), or pass them through
+    /// untouched (off). Command latency, the timing log, the slowest-command list and
+    /// invalidation counting all need this on. Options that make the proxy answer commands
+    /// itself, such as --deny-command or --read-only, turn it on regardless
+    #[arg(long, default_value = "off", value_name = "on|off", action = ArgAction::Set,
+          value_parser = BoolishValueParser::new())]
+    pub parse_replies: bool,
+
+    /// Answer `PROXY INFO [section]` and `INFO proxy` with proxy statistics in Redis INFO format
+    #[arg(long, default_value = "false")]
+    pub proxy_info: bool,
+
+    /// Write logs as text, or as JSON with an access event for each command (peer,
+    /// command, argument count, size), sampled by --log-sample-rate and redacted like the
+    /// verbose log
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Log every proxied command at info level
+    #[arg(short, long, default_value = "false")]
+    pub verbose: bool,
+
+    /// Fraction of commands logged in verbose mode (0.0-1.0)
+    #[arg(long, default_value = "1.0", value_parser = parse_fraction)]
+    pub log_sample_rate: f64,
+
+    /// Leave all command arguments out of the verbose log
+    #[arg(long, default_value = "false")]
+    pub redact_args: bool,
+
+    /// Leave these commands' arguments out of the verbose log (comma-separated); AUTH and
+    /// HELLO are always redacted
+    #[arg(long, value_name = "CMD", value_delimiter = ',')]
+    pub redact_command: Vec<String>,
+
+    /// Cut each argument in the verbose log to this many bytes
+    #[arg(long, default_value = "64")]
+    pub log_arg_max_len: usize,
+
+    /// Fraction of forwarded commands captured with their arguments and reply for the
+    /// admin `/samples` endpoint (0.0-1.0); redacted like the verbose log
+    #[arg(long, default_value = "0.0", value_parser = parse_fraction)]
+    pub sample_rate: f64,
+
+    /// Most recent command samples kept for `/samples`
+    #[arg(long, default_value = "100", value_name = "N")]
+    pub sample_capacity: usize,
+
+    /// List a connection's N most used commands in the log line written when it closes
+    #[arg(long, value_name = "N")]
+    pub connection_top_commands: Option<usize>,
+
+    /// Close connections that buffer an inline command longer than this without a line ending
+    #[arg(long, default_value = "64K", value_parser = parse_byte_size)]
+    pub max_inline_length: u64,
+
+    /// Treat bulk strings from clients declaring more than this many bytes as malformed,
+    /// instead of buffering until that much arrives
+    #[arg(long, default_value = "512M", value_parser = parse_byte_size)]
+    pub max_bulk_len: u64,
+
+    /// Close a connection once it buffers more than this many bytes of partial commands
+    /// and replies (e.g., 16M), so one connection cannot take a large share of memory
+    #[arg(long, value_parser = parse_byte_size)]
+    pub max_connection_buffer_bytes: Option<u64>,
+
+    /// Reject inline commands with more than this many words (command name included)
+    #[arg(long)]
+    pub max_inline_args: Option<usize>,
+
+    /// Reject multi-key commands such as MGET or DEL that name more keys than this
+    #[arg(long)]
+    pub max_keys_per_command: Option<usize>,
+
+    /// Most commands each client connection may send upstream per second, with bursts of
+    /// up to a second's worth
+    #[arg(long, value_name = "N")]
+    pub max_commands_per_sec: Option<u64>,
+
+    /// What happens to commands over --max-commands-per-sec: `reject` answers them with
+    /// an error, `delay` holds them back until the connection is within its rate
+    #[arg(long, value_enum, default_value = "reject", requires = "max_commands_per_sec")]
+    pub rate_limit_action: RateLimitAction,
+
+    /// Most commands all client connections together may send upstream per second, with
+    /// bursts of up to a second's worth
+    #[arg(long, value_name = "N")]
+    pub global_max_commands_per_sec: Option<u64>,
+
+    /// How long commands over --global-max-commands-per-sec may wait for a slot before
+    /// being refused
+    #[arg(long, default_value = "100", value_name = "MS",
+          requires = "global_max_commands_per_sec")]
+    pub global_rate_limit_queue_ms: u64,
+
+    /// Process at most this many commands from a client before letting other connections
+    /// run, so one huge pipeline cannot monopolize a worker thread
+    #[arg(long)]
+    pub max_commands_per_batch: Option<usize>,
+
+    /// Close TLS clients that send more than this many bytes before the handshake completes
+    #[arg(long, default_value = "64K", value_parser = parse_byte_size)]
+    pub max_handshake_bytes: u64,
+
+    /// Rewrite a command name before forwarding, for upstreams using rename-command (FROM=TO, repeatable)
+    #[arg(long, value_parser = parse_rename)]
+    pub rename: Vec<(String, String)>,
+
+    /// Latency percentiles reported in the summary, INFO output and `/stats.json`
+    /// (comma-separated)
+    #[arg(long, default_value = "50,99", value_delimiter = ',', value_parser = parse_percentile)]
+    pub percentiles: Vec<f64>,
+
+    /// Leave this command out of all command statistics; it is still proxied (repeatable)
+    #[arg(long, value_name = "CMD")]
+    pub no_count: Vec<String>,
+
+    /// POST a JSON event to this http:// URL whenever a client connects or disconnects
+    #[arg(long)]
+    pub event_webhook: Option<String>,
+
+    /// What SIGUSR1 prints: cumulative totals, or the delta since the previous SIGUSR1
+    /// with commands/sec over that interval
+    #[arg(long, value_enum, default_value = "cumulative")]
+    pub sigusr1_stats: DumpMode,
+
+    /// Keep the newest commands that took longer than this many milliseconds to answer
+    /// for the admin `/slowlog` endpoint; needs --parse-replies on
+    #[arg(long, value_name = "MS")]
+    pub slowlog_threshold_ms: Option<u64>,
+
+    /// Most entries kept in the slowlog
+    #[arg(long, default_value = "128", value_name = "N", requires = "slowlog_threshold_ms")]
+    pub slowlog_max_len: usize,
+
+    /// Rewrite this file with the statistics every --stats-interval-secs: as JSON like
+    /// `/stats.json` if it ends in `.json`, as the shutdown summary otherwise
+    #[arg(long, value_name = "PATH")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Seconds between --stats-file dumps
+    #[arg(long, default_value = "60", requires = "stats_file")]
+    pub stats_interval_secs: u64,
+
+    /// Write per-command start/duration events to this file in Chrome trace format
+    #[arg(long)]
+    pub timing_log: Option<PathBuf>,
+
+    /// Close client connections after this many seconds without traffic in either
+    /// direction; 0 keeps them open. Clients waiting on a reply or subscribed count as
+    /// busy when replies are parsed
+    #[arg(long, default_value = "0", value_name = "SECS")]
+    pub idle_timeout_secs: u64,
+
+    /// Drain and shut down once the proxy has been running this long (e.g., 1440m), so a
+    /// supervisor can restart it
+    #[arg(long, value_parser = parse_duration)]
+    pub max_uptime: Option<Duration>,
+
+    /// How long a --max-uptime shutdown waits for connections to finish after draining
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    pub shutdown_grace: Duration,
+
+    /// Maximum number of client connections proxied at once; further connections wait
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// Maximum connections waiting for --max-connections; beyond this they are rejected
+    /// immediately (unbounded when unset)
+    #[arg(long, requires = "max_connections")]
+    pub max_queued_connections: Option<usize>,
+
+    /// Maximum simultaneous connections from any single client IP; further connections
+    /// from it are closed
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Cap on the bytes per second read from all clients and upstreams combined (e.g.,
+    /// 10M), shared fairly between connections
+    #[arg(long, value_parser = parse_byte_size)]
+    pub max_total_bps: Option<u64>,
 }
 
 impl Config {
     pub fn parse_args() -> Result<Self, String> {
-        let config = Config::parse();
+        Self::from_args(std::env::args_os())
+    }
+
+    /// Parse `args`, program name first. Each option not on the command line is taken
+    /// from its `REDIS_PROXY_*` environment variable if set, then from the `--config`
+    /// file.
+    pub fn from_args<T>(args: impl IntoIterator<Item = T>) -> Result<Self, String>
+    where
+        T: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let command = Self::command().mut_args(|arg| {
+            let var = env_var(arg.get_id().as_str());
+            arg.env(var)
+        });
+        // Leniently: what the command line lacks may be in the file
+        let given = command.clone().ignore_errors(true).get_matches_from(&args);
+        let matches = match (given.get_one::<PathBuf>("config"), args.split_first()) {
+            (Some(path), Some((program, cli))) => {
+                let mut merged = vec![program.clone()];
+                merged.extend(file_args(path, &command, &given)?);
+                merged.extend_from_slice(cli);
+                command.try_get_matches_from(merged).map_err(|e| match e.kind() {
+                    ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => e.exit(),
+                    _ => format!(
+                        "{}note: options from {} are applied first; the command line and \
+                         environment override them",
+                        e,
+                        path.display()
+                    ),
+                })?
+            }
+            _ => command.get_matches_from(&args),
+        };
+        let mut config = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        config.expand_shortcuts();
         config.validate()?;
         Ok(config)
     }
 
+    /// Apply the settings implied by shortcut flags.
+    fn expand_shortcuts(&mut self) {
+        if self.transparent {
+            self.no_tls = true;
+        }
+    }
+
     fn validate(&self) -> Result<(), String> {
+        if self.listen.strip_prefix(UNIX_PREFIX) == Some("") {
+            return Err("--listen unix: needs a socket path".to_string());
+        }
+        if !self.allow_upstream_unix_tls
+            && let Some(upstream) =
+                self.upstream.iter().find(|u| u.unix_path().is_some() && u.tls == Some(true))
+        {
+            return Err(format!(
+                "upstream {} uses TLS over a Unix socket; pass --allow-upstream-unix-tls if \
+                 that is intended",
+                upstream.addr
+            ));
+        }
+        if self.transparent && self.upstream.iter().any(|u| u.tls == Some(true)) {
+            return Err("--transparent cannot be combined with TLS upstreams".to_string());
+        }
+        if self.chain_secret.is_some() && !self.chain_listen && !self.chain_upstream {
+            return Err("--chain-secret needs --chain-listen and/or --chain-upstream".to_string());
+        }
+        if self.protocol == Protocol::Generic {
+            let redis_only = [
+                ("--force-db", self.force_db.is_some()),
+                ("--block-select", self.block_select),
+                ("--read-only", self.read_only),
+                ("--max-select-db", self.max_select_db.is_some()),
+                ("--max-keys-per-command", self.max_keys_per_command.is_some()),
+                ("--auth-passthrough off", !self.auth_passthrough),
+                ("--upstream-auth-password", self.upstream_auth_password.is_some()),
+                ("--proxy-info", self.proxy_info),
+            ];
+            if let Some((flag, _)) = redis_only.iter().find(|(_, set)| *set) {
+                return Err(format!("{} relies on Redis commands and needs --protocol redis", flag));
+            }
+        }
+        if self.max_connections_per_ip == Some(0) {
+            return Err("--max-connections-per-ip must be at least 1".to_string());
+        }
+        if self.max_total_bps == Some(0) {
+            return Err("--max-total-bps must be at least 1".to_string());
+        }
+        if self.connection_top_commands == Some(0) {
+            return Err("--connection-top-commands must be at least 1".to_string());
+        }
+        if self.log_arg_max_len == 0 {
+            return Err("--log-arg-max-len must be at least 1".to_string());
+        }
+        if self.sample_capacity == 0 {
+            return Err("--sample-capacity must be at least 1".to_string());
+        }
+        if self.max_bulk_len == 0 {
+            return Err("--max-bulk-len must be at least 1".to_string());
+        }
+        if self.max_connection_buffer_bytes == Some(0) {
+            return Err("--max-connection-buffer-bytes must be at least 1".to_string());
+        }
+        if self.max_commands_per_sec == Some(0) {
+            return Err("--max-commands-per-sec must be at least 1".to_string());
+        }
+        if self.global_max_commands_per_sec == Some(0) {
+            return Err("--global-max-commands-per-sec must be at least 1".to_string());
+        }
+        if self.max_commands_per_batch == Some(0) {
+            return Err("--max-commands-per-batch must be at least 1".to_string());
+        }
+        if self.slowlog_max_len == 0 {
+            return Err("--slowlog-max-len must be at least 1".to_string());
+        }
+        if self.stats_interval_secs == 0 {
+            return Err("--stats-interval-secs must be at least 1".to_string());
+        }
+        if self.timing_log.is_some() && !self.parse_replies {
+            return Err("--timing-log requires --parse-replies on".to_string());
+        }
+        if self.slowlog_threshold_ms.is_some() && !self.parse_replies {
+            return Err("--slowlog-threshold-ms requires --parse-replies on".to_string());
+        }
+        if self.upstream_unavailable_queue_time.is_some() && !self.parse_replies {
+            return Err(
+                "--upstream-unavailable-queue-time requires --parse-replies on".to_string(),
+            );
+        }
         if !self.no_tls {
             if self.cert.is_none() {
                 return Err("--cert is required when TLS is enabled (use --no-tls to disable)".to_string());
@@ -49,19 +638,860 @@ impl Config {
             if self.key.is_none() {
                 return Err("--key is required when TLS is enabled (use --no-tls to disable)".to_string());
             }
+        } else if self.client_ca.is_some() {
+            return Err("--client-ca needs TLS on the listening side".to_string());
+        }
+        if self.tls_min_version == TlsVersion::Tls13
+            && !self.tls_cipher_suites.is_empty()
+            && !self.tls_cipher_suites.iter().any(|s| s.starts_with("TLS13_"))
+        {
+            return Err("--tls-cipher-suites has no TLS 1.3 suite but --tls-min-version is 1.3"
+                .to_string());
         }
         Ok(())
     }
 
-    pub fn upstream_hostname(&self) -> String {
-        self.upstream_tls_hostname
-            .clone()
-            .unwrap_or_else(|| {
-                self.upstream
-                    .split(':')
-                    .next()
-                    .unwrap_or("localhost")
-                    .to_string()
+    /// Upstreams with the global `--upstream-tls` and `--upstream-bind` settings applied
+    /// to entries that don't override them.
+    pub fn upstreams(&self) -> Vec<UpstreamConfig> {
+        self.upstream
+            .iter()
+            .cloned()
+            .map(|mut upstream| {
+                // Unix socket upstreams only use TLS when their entry asks for it
+                let unix = upstream.unix_path().is_some();
+                upstream.tls = Some(upstream.tls.unwrap_or(self.upstream_tls && !unix));
+                if upstream.hostname.is_none() {
+                    upstream.hostname = self.upstream_tls_hostname.clone();
+                }
+                if upstream.ca.is_none() {
+                    upstream.ca = self.upstream_ca.clone();
+                }
+                upstream.webpki_roots = self.upstream_ca_add;
+                upstream.insecure_skip_verify = self.upstream_insecure_skip_verify;
+                if upstream.client_cert.is_none() {
+                    upstream.client_cert = self.upstream_client_cert.clone();
+                    upstream.client_key = self.upstream_client_key.clone();
+                }
+                if !unix {
+                    upstream.bind = upstream.bind.or(self.upstream_bind);
+                }
+                upstream.alpn = self.upstream_alpn.clone();
+                if self.chain_upstream {
+                    upstream.chain = self.chain_secret.as_deref().map(ChainKey::new);
+                }
+                upstream
             })
+            .collect()
+    }
+
+    /// Whether upstream replies are matched to commands: with `--parse-replies on`, and
+    /// whenever the proxy may answer commands itself, as those answers have to wait for
+    /// the replies to the commands pipelined ahead of them.
+    pub fn tracks_replies(&self) -> bool {
+        self.parse_replies || self.answers_locally()
+    }
+
+    /// Whether any option makes the proxy answer some commands instead of upstream.
+    fn answers_locally(&self) -> bool {
+        !self.deny_command.is_empty()
+            || !self.allow_command.is_empty()
+            || self.read_only
+            || self.block_select
+            || self.max_select_db.is_some()
+            || self.max_keys_per_command.is_some()
+            || self.max_inline_args.is_some()
+            || !self.auth_passthrough
+            || self.proxy_info
+            || (self.max_commands_per_sec.is_some()
+                && self.rate_limit_action == RateLimitAction::Reject)
+            || self.global_max_commands_per_sec.is_some()
+    }
+
+    /// Key for authenticating framed traffic from clients, with `--chain-listen`.
+    pub fn chain_listen_key(&self) -> Option<ChainKey> {
+        self.chain_secret
+            .as_deref()
+            .filter(|_| self.chain_listen)
+            .map(ChainKey::new)
+    }
+
+    /// The effective configuration as a JSON object, with upstream defaults resolved
+    /// and secrets redacted. Renamed command names act as passwords, so only the
+    /// original names are shown.
+    pub fn to_redacted_json(&self) -> serde_json::Value {
+        let renames: Vec<String> = self
+            .rename
+            .iter()
+            .map(|(from, _)| format!("{}={}", from, REDACTED))
+            .collect();
+        json!({
+            "config": self.config,
+            "listen": self.listen,
+            "rebind_on_listen_error": self.rebind_on_listen_error,
+            "upstreams": self.upstreams(),
+            "allow_upstream_unix_tls": self.allow_upstream_unix_tls,
+            "cert": self.cert,
+            "key": self.key,
+            "client_ca": self.client_ca,
+            "client_crl": self.client_crl,
+            "client_ca_optional": self.client_ca_optional,
+            "tls_min_version": self.tls_min_version.as_str(),
+            "tls_cipher_suites": self.tls_cipher_suites,
+            "alpn": self.alpn,
+            "no_tls": self.no_tls,
+            "transparent": self.transparent,
+            "chain_secret": self.chain_secret.as_ref().map(|_| REDACTED),
+            "chain_listen": self.chain_listen,
+            "chain_upstream": self.chain_upstream,
+            "protocol": self.protocol.as_str(),
+            "admin_listen": self.admin_listen,
+            "metrics_listen": self.metrics_listen,
+            "max_rss": self.max_rss,
+            "force_db": self.force_db,
+            "block_select": self.block_select,
+            "deny_command": self.deny_command,
+            "allow_command": self.allow_command,
+            "read_only": self.read_only,
+            "max_select_db": self.max_select_db,
+            "upstream_auth_user": self.upstream_auth_user,
+            "upstream_auth_password": self.upstream_auth_password.as_ref().map(|_| REDACTED),
+            "auth_passthrough": self.auth_passthrough,
+            "upstream_unavailable_queue_time_ms":
+                self.upstream_unavailable_queue_time.map(|t| t.as_millis() as u64),
+            "upstream_unavailable_queue_size": self.upstream_unavailable_queue_size,
+            "upstream_connect_retries": self.upstream_connect_retries,
+            "upstream_connect_backoff_ms": self.upstream_connect_backoff_ms,
+            "parse_replies": self.parse_replies,
+            "proxy_info": self.proxy_info,
+            "log_format": self.log_format.as_str(),
+            "verbose": self.verbose,
+            "log_sample_rate": self.log_sample_rate,
+            "redact_args": self.redact_args,
+            "redact_command": self.redact_command,
+            "log_arg_max_len": self.log_arg_max_len,
+            "sample_rate": self.sample_rate,
+            "sample_capacity": self.sample_capacity,
+            "connection_top_commands": self.connection_top_commands,
+            "max_inline_length": self.max_inline_length,
+            "max_bulk_len": self.max_bulk_len,
+            "max_connection_buffer_bytes": self.max_connection_buffer_bytes,
+            "max_inline_args": self.max_inline_args,
+            "max_keys_per_command": self.max_keys_per_command,
+            "max_commands_per_sec": self.max_commands_per_sec,
+            "rate_limit_action": self.rate_limit_action.as_str(),
+            "global_max_commands_per_sec": self.global_max_commands_per_sec,
+            "global_rate_limit_queue_ms": self.global_rate_limit_queue_ms,
+            "max_commands_per_batch": self.max_commands_per_batch,
+            "max_handshake_bytes": self.max_handshake_bytes,
+            "rename": renames,
+            "percentiles": self.percentiles,
+            "no_count": self.no_count,
+            "event_webhook": self.event_webhook,
+            "sigusr1_stats": self.sigusr1_stats.as_str(),
+            "slowlog_threshold_ms": self.slowlog_threshold_ms,
+            "slowlog_max_len": self.slowlog_max_len,
+            "stats_file": self.stats_file,
+            "stats_interval_secs": self.stats_interval_secs,
+            "timing_log": self.timing_log,
+            "idle_timeout_secs": self.idle_timeout_secs,
+            "max_uptime_ms": self.max_uptime.map(|t| t.as_millis() as u64),
+            "shutdown_grace_ms": self.shutdown_grace.as_millis() as u64,
+            "max_connections": self.max_connections,
+            "max_queued_connections": self.max_queued_connections,
+            "max_connections_per_ip": self.max_connections_per_ip,
+            "max_total_bps": self.max_total_bps,
+        })
+    }
+}
+
+/// Parse a `FROM=TO` command rename.
+pub fn parse_rename(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() && !to.contains([' ', '\r', '\n']) => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("expected FROM=TO, got '{}'", s)),
+    }
+}
+
+/// Parse a cipher suite name, ignoring case, into the name rustls gives it.
+pub fn parse_cipher_suite(s: &str) -> Result<String, String> {
+    let names = || {
+        aws_lc_rs::ALL_CIPHER_SUITES
+            .iter()
+            .filter_map(|suite| suite.suite().as_str())
+    };
+    names()
+        .find(|name| name.eq_ignore_ascii_case(s.trim()))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            let supported: Vec<&str> = names().collect();
+            format!("unknown cipher suite '{}' (supported: {})", s, supported.join(", "))
+        })
+}
+
+/// Parse an ALPN protocol name, which must be 1 to 255 bytes.
+pub fn parse_alpn(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > 255 {
+        return Err(format!("ALPN protocol must be 1 to 255 bytes, got {}", s.len()));
+    }
+    Ok(s.to_string())
+}
+
+/// Parse a percentile strictly between 0 and 100.
+pub fn parse_percentile(s: &str) -> Result<f64, String> {
+    let value: f64 = s.trim().parse().map_err(|_| format!("invalid number: {}", s))?;
+    if !(value > 0.0 && value < 100.0) {
+        return Err(format!("percentile must be between 0 and 100, got {}", s));
+    }
+    Ok(value)
+}
+
+/// Parse a fraction in the range 0.0-1.0.
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("invalid number: {}", s))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("must be between 0.0 and 1.0, got {}", value));
+    }
+    Ok(value)
+}
+
+/// Parse a duration with an optional ms/s/m suffix; a bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (digits, unit): (&str, fn(u64) -> Duration) = if let Some(d) = s.strip_suffix("ms") {
+        (d, Duration::from_millis)
+    } else if let Some(d) = s.strip_suffix('s') {
+        (d, Duration::from_secs)
+    } else if let Some(d) = s.strip_suffix('m') {
+        (d, |n| Duration::from_secs(n * 60))
+    } else {
+        (s, Duration::from_secs)
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    if value > u64::MAX / 60 {
+        return Err(format!("duration too large: {}", s));
+    }
+    Ok(unit(value))
+}
+
+/// Parse a byte size with an optional K/M/G suffix (powers of 1024).
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid byte size: {}", s))?;
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("byte size too large: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_upstreams_apply_global_tls_settings() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-tls",
+            "--upstream-tls-hostname",
+            "redis.internal",
+            "-u",
+            "10.0.0.1:6379",
+            "-u",
+            "10.0.0.2:6379,plain",
+            "-u",
+            "10.0.0.3:6380,hostname=replica.internal,bind=10.0.0.9",
+            "--upstream-bind",
+            "10.0.0.8",
+        ]);
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams.len(), 3);
+        assert!(upstreams[0].use_tls());
+        assert_eq!(upstreams[0].hostname(), "redis.internal");
+        assert!(!upstreams[1].use_tls());
+        assert!(upstreams[2].use_tls());
+        assert_eq!(upstreams[2].hostname(), "replica.internal");
+        assert_eq!(upstreams[0].bind, Some("10.0.0.8".parse().unwrap()));
+        assert_eq!(upstreams[2].bind, Some("10.0.0.9".parse().unwrap()));
+        assert!(upstreams.iter().all(|u| u.alpn.is_empty()));
+    }
+
+    #[test]
+    fn test_upstream_client_certificate_defaults() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-client-cert",
+            "proxy.pem",
+            "--upstream-client-key",
+            "proxy.key",
+            "-u",
+            "10.0.0.1:6380,tls",
+            "-u",
+            "10.0.0.2:6380,tls,client-cert=other.pem,client-key=other.key",
+        ]);
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams[0].client_cert, Some(PathBuf::from("proxy.pem")));
+        assert_eq!(upstreams[0].client_key, Some(PathBuf::from("proxy.key")));
+        assert_eq!(upstreams[1].client_cert, Some(PathBuf::from("other.pem")));
+        assert_eq!(upstreams[1].client_key, Some(PathBuf::from("other.key")));
+
+        let cert_only = ["redis-tls-proxy", "--no-tls", "--upstream-client-cert", "proxy.pem"];
+        assert!(Config::try_parse_from(cert_only).is_err());
+        let key_only = ["redis-tls-proxy", "--no-tls", "--upstream-client-key", "proxy.key"];
+        assert!(Config::try_parse_from(key_only).is_err());
+    }
+
+    #[test]
+    fn test_upstream_ca_defaults() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-tls",
+            "--upstream-ca",
+            "/etc/private-ca.pem",
+            "--upstream-ca-add",
+            "-u",
+            "10.0.0.1:6379",
+            "-u",
+            "10.0.0.2:6379,ca=/etc/other-ca.pem",
+        ]);
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams[0].ca, Some(PathBuf::from("/etc/private-ca.pem")));
+        assert_eq!(upstreams[1].ca, Some(PathBuf::from("/etc/other-ca.pem")));
+        assert!(upstreams.iter().all(|u| u.webpki_roots));
+
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--upstream-tls"]);
+        assert_eq!(config.upstreams()[0].ca, None);
+        assert!(!config.upstreams()[0].webpki_roots);
+        assert!(!config.upstreams()[0].insecure_skip_verify);
+
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-tls",
+            "--upstream-insecure-skip-verify",
+        ]);
+        assert!(config.upstreams()[0].insecure_skip_verify);
+    }
+
+    #[test]
+    fn test_alpn_protocols() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--alpn",
+            "redis",
+            "--alpn",
+            "resp3",
+            "--upstream-alpn",
+            "redis",
+        ]);
+        assert_eq!(config.alpn, ["redis", "resp3"]);
+        assert_eq!(config.upstreams()[0].alpn, ["redis"]);
+        assert_eq!(config.to_redacted_json()["alpn"], json!(["redis", "resp3"]));
+
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--alpn", ""]).is_err());
+        let long = "p".repeat(256);
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--upstream-alpn", &long]).is_err());
+    }
+
+    #[test]
+    fn test_single_default_upstream() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls"]);
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams.len(), 1);
+        assert_eq!(upstreams[0].addr, "127.0.0.1:6379");
+        assert!(!upstreams[0].use_tls());
+        assert_eq!(upstreams[0].hostname(), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_transparent_mode() {
+        let mut config = Config::parse_from(["redis-tls-proxy", "--transparent"]);
+        config.expand_shortcuts();
+        assert!(config.no_tls);
+        assert!(config.validate().is_ok());
+        assert!(!config.upstreams()[0].use_tls());
+
+        let mut config =
+            Config::parse_from(["redis-tls-proxy", "--transparent", "-u", "10.0.0.1:6380,tls"]);
+        config.expand_shortcuts();
+        assert!(config.validate().is_err());
+
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--transparent", "--upstream-tls"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_local_answers_track_replies() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls"]);
+        assert!(!config.tracks_replies());
+        let on = ["redis-tls-proxy", "--no-tls", "--parse-replies", "on"];
+        assert!(Config::parse_from(on).tracks_replies());
+        for args in [
+            &["--deny-command", "FLUSHALL"][..],
+            &["--allow-command", "GET"],
+            &["--read-only"],
+            &["--block-select"],
+            &["--auth-passthrough", "off"],
+            &["--max-commands-per-sec", "100"],
+            &["--global-max-commands-per-sec", "100"],
+        ] {
+            let mut argv = vec!["redis-tls-proxy", "--no-tls"];
+            argv.extend_from_slice(args);
+            let config = Config::parse_from(argv);
+            assert!(!config.parse_replies && config.tracks_replies(), "{args:?}");
+        }
+        // Delayed commands are all forwarded, so their replies can pass through untouched
+        let delay = ["--max-commands-per-sec", "100", "--rate-limit-action", "delay"];
+        let config = Config::parse_from([&["redis-tls-proxy", "--no-tls"][..], &delay].concat());
+        assert!(!config.tracks_replies());
+    }
+
+    #[test]
+    fn test_auth_passthrough_flag() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls"]);
+        assert!(config.auth_passthrough);
+        let config = Config::parse_from(["redis-tls-proxy", "--auth-passthrough", "off"]);
+        assert!(!config.auth_passthrough);
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--auth-passthrough", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn test_timing_log_requires_reply_parsing() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--timing-log", "t.json"]);
+        assert!(!config.parse_replies);
+        assert!(config.validate().is_err());
+
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--timing-log",
+            "t.json",
+            "--parse-replies",
+            "on",
+        ]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_max_bulk_len() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls"]);
+        assert_eq!(config.max_bulk_len, 512 * 1024 * 1024);
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--max-bulk-len", "1K"]);
+        assert_eq!(config.max_bulk_len, 1024);
+        let zero = Config::parse_from(["redis-tls-proxy", "--no-tls", "--max-bulk-len", "0"]);
+        assert_eq!(zero.validate(), Err("--max-bulk-len must be at least 1".to_string()));
+    }
+
+    #[test]
+    fn test_rate_limit_options() {
+        let action = ["redis-tls-proxy", "--no-tls", "--rate-limit-action", "delay"];
+        assert!(Config::try_parse_from(action).is_err());
+
+        let config = Config::parse_from(["redis-tls-proxy", "--max-commands-per-sec", "100"]);
+        assert_eq!(config.rate_limit_action, RateLimitAction::Reject);
+        let zero = ["redis-tls-proxy", "--no-tls", "--max-commands-per-sec", "0"];
+        let config = Config::parse_from(zero);
+        assert_eq!(
+            config.validate(),
+            Err("--max-commands-per-sec must be at least 1".to_string())
+        );
+
+        let queue = ["redis-tls-proxy", "--no-tls", "--global-rate-limit-queue-ms", "5"];
+        assert!(Config::try_parse_from(queue).is_err());
+        let global = ["redis-tls-proxy", "--no-tls", "--global-max-commands-per-sec", "5000"];
+        assert_eq!(Config::parse_from(global).global_rate_limit_queue_ms, 100);
+    }
+
+    #[test]
+    fn test_slowlog_requires_reply_parsing() {
+        let args = ["redis-tls-proxy", "--no-tls", "--slowlog-threshold-ms", "10"];
+        let config = Config::parse_from(args);
+        assert_eq!(config.slowlog_max_len, 128);
+        assert_eq!(
+            config.validate(),
+            Err("--slowlog-threshold-ms requires --parse-replies on".to_string())
+        );
+        let config = Config::parse_from(args.iter().chain(&["--parse-replies", "on"]));
+        assert!(config.validate().is_ok());
+
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--slowlog-max-len", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_stats_interval_needs_stats_file() {
+        let interval = ["redis-tls-proxy", "--no-tls", "--stats-interval-secs", "10"];
+        assert!(Config::try_parse_from(interval).is_err());
+
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--stats-file", "s.json"]);
+        assert_eq!(config.stats_interval_secs, 60);
+        assert!(config.validate().is_ok());
+
+        let config = Config::parse_from([
+            "redis-tls-proxy", "--no-tls", "--stats-file", "s.json", "--stats-interval-secs", "0",
+        ]);
+        assert_eq!(
+            config.validate(),
+            Err("--stats-interval-secs must be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_generic_protocol_rejects_redis_only_flags() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--protocol", "generic"]);
+        assert_eq!(config.protocol, Protocol::Generic);
+        assert!(config.validate().is_ok());
+
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--protocol",
+            "generic",
+            "--block-select",
+        ]);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "--block-select relies on Redis commands and needs --protocol redis"
+        );
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--protocol", "memcache"]).is_err());
+    }
+
+    #[test]
+    fn test_deny_and_allow_commands_exclusive() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--deny-command",
+            "FLUSHALL",
+            "--deny-command",
+            "config",
+        ]);
+        assert_eq!(config.deny_command, ["FLUSHALL", "config"]);
+        assert!(Config::try_parse_from([
+            "redis-tls-proxy",
+            "--deny-command",
+            "FLUSHALL",
+            "--allow-command",
+            "GET",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_client_crl_needs_client_ca_and_tls() {
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--client-crl", "crl.pem"]).is_err());
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--client-ca", "ca.pem"]);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "--client-ca needs TLS on the listening side"
+        );
+    }
+
+    #[test]
+    fn test_config_file_overridden_by_command_line() {
+        let path = std::env::temp_dir().join(format!("proxy-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "no_tls = true\nlisten = \"127.0.0.1:7000\"\nupstream = [\"10.0.0.1:6379\"]\n\
+             max_uptime = \"90m\"\nclient_ca = \"ca.pem\"\n",
+        )
+        .unwrap();
+        let path_arg = path.to_str().unwrap();
+
+        let config = Config::from_args(["redis-tls-proxy", "--config", path_arg, "-l", ":7001"]);
+        let err = config.unwrap_err();
+        // The file's client_ca conflicts with its own no_tls
+        assert_eq!(err, "--client-ca needs TLS on the listening side");
+
+        std::fs::write(&path, "no_tls = true\nlisten = \"127.0.0.1:7000\"\nmax_uptime = \"90m\"\n")
+            .unwrap();
+        let config =
+            Config::from_args(["redis-tls-proxy", "--config", path_arg, "-l", "0.0.0.0:7001"])
+                .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:7001");
+        assert!(config.no_tls);
+        assert_eq!(config.max_uptime, Some(Duration::from_secs(5400)));
+        assert_eq!(config.config.as_deref(), Some(path.as_path()));
+
+        // Conflicts between the file and the command line explain which wins
+        std::fs::write(&path, "transparent = true\n").unwrap();
+        let err =
+            Config::from_args(["redis-tls-proxy", "--config", path_arg, "--cert", "c.pem"])
+                .unwrap_err();
+        assert!(err.contains("the command line and environment override them"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_environment_variables() {
+        let vars = [
+            ("REDIS_PROXY_UPSTREAM", "10.0.0.5:6380,tls"),
+            ("REDIS_PROXY_SAMPLE_RATE", "0.25"),
+            ("REDIS_PROXY_READ_ONLY", "true"),
+            ("REDIS_PROXY_IDLE_TIMEOUT_SECS", "30"),
+            ("REDIS_PROXY_DENY_COMMAND", "FLUSHALL"),
+        ];
+        // SAFETY: set only here; other tests parsing with from_args do not depend on them
+        unsafe {
+            for (name, value) in vars {
+                std::env::set_var(name, value);
+            }
+        }
+        let path = std::env::temp_dir().join(format!("env-{}.toml", std::process::id()));
+        std::fs::write(&path, "sample_rate = 0.5\nmax_connections = 7\n").unwrap();
+
+        // Command line over environment over file over defaults
+        let config = Config::from_args([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--idle-timeout-secs",
+            "60",
+            "--config",
+            path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(config.upstreams()[0].addr, "10.0.0.5:6380");
+        assert!(config.upstreams()[0].use_tls());
+        assert_eq!(config.sample_rate, 0.25);
+        assert!(config.read_only);
+        assert_eq!(config.idle_timeout_secs, 60);
+        assert_eq!(config.deny_command, ["FLUSHALL"]);
+        assert_eq!(config.max_connections, Some(7));
+        assert_eq!(config.listen, "0.0.0.0:16379");
+        std::fs::remove_file(&path).unwrap();
+
+        // Settings from the environment are validated like any other
+        let err = Config::from_args(["redis-tls-proxy", "--no-tls", "--client-ca", "ca.pem"]);
+        assert_eq!(err.unwrap_err(), "--client-ca needs TLS on the listening side");
+        unsafe {
+            for (name, _) in vars {
+                std::env::remove_var(name);
+            }
+        }
+
+        // Without the variables, the defaults apply again
+        let config = Config::from_args(["redis-tls-proxy", "--no-tls"]).unwrap();
+        assert_eq!(config.upstreams()[0].addr, "127.0.0.1:6379");
+        assert!(!config.read_only);
+        assert_eq!(env_var("upstream_tls_hostname"), "REDIS_PROXY_UPSTREAM_TLS_HOSTNAME");
+    }
+
+    #[test]
+    fn test_unix_listen_address() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "-l", "unix:/run/p.sock"]);
+        assert!(config.validate().is_ok());
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "-l", "unix:"]);
+        assert_eq!(config.validate().unwrap_err(), "--listen unix: needs a socket path");
+    }
+
+    #[test]
+    fn test_unix_socket_upstreams() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-tls",
+            "--upstream-bind",
+            "10.0.0.8",
+            "-u",
+            "unix:/run/redis.sock",
+            "-u",
+            "10.0.0.1:6380",
+        ]);
+        assert!(config.validate().is_ok());
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams[0].unix_path(), Some(Path::new("/run/redis.sock")));
+        assert!(!upstreams[0].use_tls());
+        assert_eq!(upstreams[0].bind, None);
+        assert!(upstreams[1].use_tls());
+        assert_eq!(upstreams[1].bind, Some("10.0.0.8".parse().unwrap()));
+
+        let tls = ["redis-tls-proxy", "--no-tls", "-u", "unix:/run/redis.sock,tls"];
+        let err = Config::parse_from(tls).validate().unwrap_err();
+        assert!(err.contains("--allow-upstream-unix-tls"), "{}", err);
+        let config = Config::parse_from(tls.iter().chain(&["--allow-upstream-unix-tls"]));
+        assert!(config.validate().is_ok());
+        assert!(config.upstreams()[0].use_tls());
+        assert_eq!(config.upstreams()[0].hostname(), "localhost");
+    }
+
+    #[test]
+    fn test_client_ca_optional_needs_client_ca() {
+        let args = ["redis-tls-proxy", "--cert", "c.pem", "--key", "k.pem", "--client-ca-optional"];
+        assert!(Config::try_parse_from(args).is_err());
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--cert",
+            "c.pem",
+            "--key",
+            "k.pem",
+            "--client-ca",
+            "ca.pem",
+            "--client-ca-optional",
+        ]);
+        assert!(config.client_ca_optional);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_tls_version_and_cipher_suites() {
+        let tls = ["redis-tls-proxy", "--cert", "c.pem", "--key", "k.pem"];
+        let config = Config::parse_from(tls);
+        assert_eq!(config.tls_min_version, TlsVersion::Tls12);
+        assert!(config.tls_cipher_suites.is_empty());
+
+        let config = Config::parse_from(tls.iter().chain(&[
+            "--tls-min-version",
+            "1.3",
+            "--tls-cipher-suites",
+            "tls13_aes_256_gcm_sha384, TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        ]));
+        assert_eq!(config.tls_min_version, TlsVersion::Tls13);
+        assert_eq!(
+            config.tls_cipher_suites,
+            ["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"]
+        );
+        assert!(config.validate().is_ok());
+
+        let err = Config::try_parse_from(tls.iter().chain(&["--tls-cipher-suites", "RC4_MD5"]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown cipher suite 'RC4_MD5'"), "{}", err);
+        assert!(err.contains("TLS13_AES_128_GCM_SHA256"), "{}", err);
+        assert!(Config::try_parse_from(tls.iter().chain(&["--tls-min-version", "1.1"])).is_err());
+
+        let config = Config::parse_from(tls.iter().chain(&[
+            "--tls-min-version",
+            "1.3",
+            "--tls-cipher-suites",
+            "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        ]));
+        assert!(config.validate().unwrap_err().contains("no TLS 1.3 suite"));
+    }
+
+    #[test]
+    fn test_upstream_unavailable_queue_settings() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--upstream-unavailable-queue-time",
+            "500ms",
+        ]);
+        assert_eq!(config.upstream_unavailable_queue_time, Some(Duration::from_millis(500)));
+        assert_eq!(config.upstream_unavailable_queue_size, 1000);
+        assert!(config.validate().is_err());
+
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("3"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn test_chain_settings() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--chain-secret",
+            "k",
+            "--chain-upstream",
+        ]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.upstreams()[0].chain, Some(ChainKey::new("k")));
+        assert_eq!(config.chain_listen_key(), None);
+
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "--chain-secret", "k"]);
+        assert!(config.validate().is_err());
+        assert!(Config::try_parse_from(["redis-tls-proxy", "--chain-listen"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_percentiles() {
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls"]);
+        assert_eq!(config.percentiles, vec![50.0, 99.0]);
+
+        let config = Config::parse_from(["redis-tls-proxy", "--percentiles", "50,90,99,99.9"]);
+        assert_eq!(config.percentiles, vec![50.0, 90.0, 99.0, 99.9]);
+
+        assert!(parse_percentile("0").is_err());
+        assert!(parse_percentile("100").is_err());
+        assert!(parse_percentile("p99").is_err());
+        assert!(parse_percentile("NaN").is_err());
+    }
+
+    #[test]
+    fn test_redacted_json() {
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "-u",
+            "10.0.0.1:6380,tls,hostname=redis.internal",
+            "--rename",
+            "CONFIG=s3cr3t-config",
+            "--max-connections",
+            "100",
+            "--chain-secret",
+            "chain-key-123",
+            "--chain-upstream",
+            "--upstream-auth-user",
+            "proxy",
+            "--upstream-auth-password",
+            "hunter2",
+        ]);
+        let json = config.to_redacted_json().to_string();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert!(json.contains(r#""upstream_auth_user":"proxy""#), "{}", json);
+        assert!(!json.contains("s3cr3t-config"), "{}", json);
+        assert!(!json.contains("chain-key-123"), "{}", json);
+        assert!(json.contains(r#""chain_secret":"<redacted>""#), "{}", json);
+        assert!(json.contains(r#""rename":["CONFIG=<redacted>"]"#), "{}", json);
+        assert!(json.contains(r#""listen":"0.0.0.0:16379""#), "{}", json);
+        assert!(json.contains(r#""max_connections":100"#), "{}", json);
+        assert!(
+            json.contains(r#""upstreams":[{"addr":"10.0.0.1:6380","tls":true,"hostname":"redis.internal""#),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        assert_eq!(
+            parse_rename("CONFIG=abc123"),
+            Ok(("CONFIG".to_string(), "abc123".to_string()))
+        );
+        assert!(parse_rename("CONFIG").is_err());
+        assert!(parse_rename("=x").is_err());
+        assert!(parse_rename("CONFIG=a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        assert_eq!(parse_fraction("0.25"), Ok(0.25));
+        assert!(parse_fraction("1.5").is_err());
+        assert!(parse_fraction("-0.1").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("4K"), Ok(4096));
+        assert_eq!(parse_byte_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_byte_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_byte_size("lots").is_err());
     }
 }
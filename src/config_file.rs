@@ -0,0 +1,185 @@
+//! Options read from a TOML file with `--config`.
+//!
+//! Each key is an option's long name, words separated by `-` or `_`, and takes the value it
+//! would on the command line: `listen = "0.0.0.0:6380"`, `max_uptime = "12h"`,
+//! `no_tls = true`, `upstream = ["10.0.0.1:6379", "10.0.0.2:6379"]`. The file is turned
+//! into flags placed before the command line's and parsed by clap, so defaults and
+//! validation are the same however an option is given. An option on the command line or
+//! in the environment replaces the file's value entirely, lists included.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+/// Flags for the options set in the TOML file at `path`, leaving out those `given`
+/// already sets on the command line or through the environment.
+pub fn file_args(
+    path: &Path,
+    command: &Command,
+    given: &ArgMatches,
+) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+    let table: Table = text
+        .parse()
+        .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+
+    let mut args = Vec::new();
+    for (key, value) in &table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()) && long != "config")
+            .ok_or_else(|| format!("unknown option '{}' in {}", key, path.display()))?;
+        if matches!(
+            given.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        push_args(arg, &long, value, &mut args)
+            .map_err(|e| format!("option '{}' in {}: {}", key, path.display(), e))?;
+    }
+    Ok(args)
+}
+
+/// Append the flags setting `arg` to `value`.
+fn push_args(
+    arg: &Arg,
+    long: &str,
+    value: &Value,
+    args: &mut Vec<OsString>,
+) -> Result<(), String> {
+    let flag = format!("--{}", long);
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set)) => {
+            if *set {
+                args.push(flag.into());
+            }
+        }
+        (ArgAction::SetTrue, _) => return Err("expected true or false".to_string()),
+        (ArgAction::Append, Value::Array(items)) => {
+            for item in items {
+                args.push(format!("{}={}", flag, scalar(item)?).into());
+            }
+        }
+        (_, Value::Array(_)) => return Err("takes a single value, not a list".to_string()),
+        (_, value) => args.push(format!("{}={}", flag, scalar(value)?).into()),
+    }
+    Ok(())
+}
+
+/// `value` as it would be written on the command line.
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Datetime(_) | Value::Array(_) | Value::Table(_) => {
+            Err(format!("unsupported {} value", value.type_str()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::CommandFactory;
+    use std::path::PathBuf;
+
+    /// Write `contents` to a fresh file, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn args(file: &TempFile, cli: &[&str]) -> Vec<String> {
+        let command = Config::command();
+        let given = command.clone().get_matches_from(cli);
+        file_args(&file.0, &command, &given)
+            .unwrap()
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_values_become_flags() {
+        let file = TempFile::new(
+            "config-flags",
+            r#"
+            listen = "127.0.0.1:6380"
+            no-tls = true
+            upstream_tls = false
+            upstream = ["10.0.0.1:6379", "10.0.0.2:6379,tls"]
+            max_connections = 50
+            sample_rate = 0.5
+            parse_replies = true
+            verbose = true
+            "#,
+        );
+        // In key order, which makes no difference to clap
+        assert_eq!(
+            args(&file, &["redis-tls-proxy"]),
+            [
+                "--listen=127.0.0.1:6380",
+                "--max-connections=50",
+                "--no-tls",
+                "--parse-replies=true",
+                "--sample-rate=0.5",
+                "--upstream=10.0.0.1:6379",
+                "--upstream=10.0.0.2:6379,tls",
+                "--verbose",
+            ]
+        );
+
+        // Options on the command line are left out, lists included
+        let cli = ["redis-tls-proxy", "-l", "0.0.0.0:7000", "-u", "10.0.0.9:6379"];
+        assert_eq!(
+            args(&file, &cli),
+            [
+                "--max-connections=50",
+                "--no-tls",
+                "--parse-replies=true",
+                "--sample-rate=0.5",
+                "--verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_errors_name_the_option() {
+        let command = Config::command();
+        let given = command.clone().get_matches_from(["redis-tls-proxy"]);
+        let error = |contents: &str| {
+            let file = TempFile::new("config-errors", contents);
+            file_args(&file.0, &command, &given).unwrap_err()
+        };
+
+        assert!(error("listen = ").starts_with("invalid config file"));
+        assert!(error("bogus = 1").starts_with("unknown option 'bogus'"));
+        assert!(error(r#"config = "other.toml""#).starts_with("unknown option 'config'"));
+        assert!(error(r#"no_tls = "yes""#).contains("expected true or false"));
+        assert!(error(r#"listen = ["a", "b"]"#).contains("takes a single value"));
+        assert!(error("listen = { host = 1 }").contains("unsupported table value"));
+
+        let missing = file_args(Path::new("/nonexistent/proxy.toml"), &command, &given);
+        assert!(missing.unwrap_err().starts_with("cannot read config file"));
+    }
+}
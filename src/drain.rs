@@ -0,0 +1,153 @@
+//! Drain mode: stop taking new connections and report progress until existing ones finish.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::stats::Stats;
+
+/// How often the remaining connection count is logged while draining.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Process-wide drain flag.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+}
+
+impl Drain {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Enter drain mode. Returns false if draining had already started.
+    pub fn start(&self) -> bool {
+        !self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// Log the number of active connections every `interval` until it reaches zero.
+pub async fn report_progress(stats: Arc<Stats>, interval: Duration) {
+    loop {
+        let remaining = stats.active_connections();
+        if remaining == 0 {
+            info!("Drain complete: no active connections remain");
+            return;
+        }
+        info!("Draining: {} active connections remaining", remaining);
+        stats.clock().sleep(interval).await;
+    }
+}
+
+/// Once the proxy has been up for `max_uptime`, start draining and wait up to `grace`
+/// for connections to finish, logging progress every `interval`. Returns when the
+/// proxy should shut down.
+pub async fn drain_at_uptime(
+    max_uptime: Duration,
+    grace: Duration,
+    drain: Arc<Drain>,
+    stats: Arc<Stats>,
+    interval: Duration,
+) {
+    let clock = stats.clock().clone();
+    clock.sleep(max_uptime.saturating_sub(stats.uptime())).await;
+    warn!(
+        "Maximum uptime of {:?} reached, draining for up to {:?} before shutting down",
+        max_uptime, grace
+    );
+    drain.start();
+    tokio::select! {
+        _ = report_progress(stats.clone(), interval) => {}
+        _ = clock.sleep(grace) => {
+            warn!(
+                "Shutdown grace period over with {} connections still active",
+                stats.active_connections()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_start_is_idempotent() {
+        let drain = Drain::new();
+        assert!(!drain.is_draining());
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert!(drain.is_draining());
+    }
+
+    #[tokio::test]
+    async fn test_report_completes_as_connections_close() {
+        let stats = Stats::new();
+        let mut connections: Vec<_> = (0..3).map(|_| stats.track_connection()).collect();
+
+        let reporter = tokio::spawn(report_progress(stats.clone(), Duration::from_millis(5)));
+
+        while let Some(conn) = connections.pop() {
+            let before = stats.active_connections();
+            drop(conn);
+            assert_eq!(stats.active_connections(), before - 1);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert!(connections.is_empty() || !reporter.is_finished());
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), reporter)
+            .await
+            .expect("drain report should finish once all connections closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drains_at_max_uptime() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let drain = Drain::new();
+        let connection = stats.track_connection();
+        let shutdown = tokio::spawn(drain_at_uptime(
+            Duration::from_secs(24 * 3600),
+            Duration::from_secs(30),
+            drain.clone(),
+            stats.clone(),
+            REPORT_INTERVAL,
+        ));
+
+        clock.advance(Duration::from_secs(24 * 3600 - 1)).await;
+        assert!(!drain.is_draining());
+        clock.advance(Duration::from_secs(1)).await;
+        assert!(drain.is_draining());
+        clock.advance(Duration::from_secs(12)).await;
+        assert!(!shutdown.is_finished());
+
+        // Shutdown follows at the next progress report once the last connection closes
+        drop(connection);
+        clock.advance(REPORT_INTERVAL).await;
+        assert!(shutdown.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_grace_period_bounds_drain() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let _connection = stats.track_connection();
+        drain_at_uptime(
+            Duration::from_secs(60),
+            Duration::from_secs(30),
+            Drain::new(),
+            stats.clone(),
+            REPORT_INTERVAL,
+        )
+        .await;
+        assert_eq!(stats.uptime().as_secs(), 90);
+    }
+}
@@ -15,8 +15,17 @@ pub enum ProxyError {
     #[error("Failed to load private key: {0}")]
     PrivateKeyLoad(String),
 
+    #[error("Failed to load certificate revocation list: {0}")]
+    CrlLoad(String),
+
     #[error("Connection error: {0}")]
     Connection(String),
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
 }
 
 pub type Result<T> = std::result::Result<T, ProxyError>;
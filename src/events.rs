@@ -0,0 +1,285 @@
+//! Connection lifecycle events delivered to an HTTP webhook.
+//!
+//! Events are queued on a bounded channel and POSTed one at a time by a background
+//! task, so a slow or unreachable webhook never holds up proxying. When the queue is
+//! full new events are dropped and counted in the stats.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::error::{ProxyError, Result};
+use crate::stats::Stats;
+
+/// Events waiting to be delivered before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Time allowed for a single webhook delivery.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection lifecycle event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    Connect {
+        peer: SocketAddr,
+        upstream: String,
+    },
+    Disconnect {
+        peer: SocketAddr,
+        upstream: String,
+        duration: Duration,
+        commands: u64,
+        outcome: &'static str,
+    },
+}
+
+impl ConnectionEvent {
+    /// Encode the event as a JSON object, stamped with `timestamp_ms` since the Unix epoch.
+    pub fn to_json(&self, timestamp_ms: u128) -> serde_json::Value {
+        let timestamp_ms = timestamp_ms as u64;
+        match self {
+            ConnectionEvent::Connect { peer, upstream } => json!({
+                "event": "connect",
+                "timestamp_ms": timestamp_ms,
+                "peer": peer,
+                "upstream": upstream,
+            }),
+            ConnectionEvent::Disconnect {
+                peer,
+                upstream,
+                duration,
+                commands,
+                outcome,
+            } => json!({
+                "event": "disconnect",
+                "timestamp_ms": timestamp_ms,
+                "peer": peer,
+                "upstream": upstream,
+                "duration_ms": duration.as_millis() as u64,
+                "commands": commands,
+                "outcome": outcome,
+            }),
+        }
+    }
+}
+
+/// Handle for emitting events from connection tasks.
+pub struct EventSink {
+    tx: mpsc::Sender<String>,
+    stats: Arc<Stats>,
+}
+
+impl EventSink {
+    /// Start delivering events to `url` (`http://host[:port]/path`) in the background.
+    pub fn start(url: &str, stats: Arc<Stats>) -> Result<Arc<Self>> {
+        let webhook = Webhook::parse(url)?;
+        let (sink, rx) = Self::new(QUEUE_CAPACITY, stats);
+        tokio::spawn(deliver(webhook, rx));
+        Ok(sink)
+    }
+
+    fn new(capacity: usize, stats: Arc<Stats>) -> (Arc<Self>, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Arc::new(Self { tx, stats }), rx)
+    }
+
+    /// Queue an event for delivery, dropping it if the queue is full.
+    pub fn emit(&self, event: ConnectionEvent) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        match self.tx.try_send(event.to_json(timestamp_ms).to_string()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.stats.record_dropped_event(),
+            Err(TrySendError::Closed(_)) => debug!("Event webhook sender has stopped"),
+        }
+    }
+}
+
+/// Parsed `http://` webhook URL.
+#[derive(Debug, PartialEq, Eq)]
+struct Webhook {
+    /// host:port to connect to
+    addr: String,
+    /// Value for the Host header
+    host: String,
+    path: String,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Result<Self> {
+        let invalid =
+            |reason: &str| ProxyError::Config(format!("--event-webhook {}: {}", url, reason));
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("only http:// URLs are supported"))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let addr = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+        {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Deliver queued events until every sink has been dropped.
+async fn deliver(webhook: Webhook, mut rx: mpsc::Receiver<String>) {
+    while let Some(body) = rx.recv().await {
+        match timeout(DELIVERY_TIMEOUT, post(&webhook, &body)).await {
+            Ok(Ok(status)) if (200..300).contains(&status) => {}
+            Ok(Ok(status)) => warn!("Event webhook returned HTTP {}", status),
+            Ok(Err(e)) => warn!("Failed to deliver event to webhook: {}", e),
+            Err(_) => warn!("Event webhook timed out"),
+        }
+    }
+}
+
+/// POST a JSON body and return the response status code.
+async fn post(webhook: &Webhook, body: &str) -> Result<u16> {
+    let mut stream = TcpStream::connect(&webhook.addr).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        webhook.path,
+        webhook.host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Only the status line matters: "HTTP/1.1 200 OK"
+    let mut head = [0u8; 64];
+    let mut len = 0;
+    while len < head.len() && !head[..len].contains(&b'\n') {
+        let n = stream.read(&mut head[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let line = String::from_utf8_lossy(&head[..len]);
+    line.split(' ')
+        .nth(1)
+        .and_then(|code| code.trim().parse().ok())
+        .ok_or_else(|| ProxyError::Connection("invalid HTTP response from webhook".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn peer() -> SocketAddr {
+        "10.1.2.3:50000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_event_payload_format() {
+        let connect = ConnectionEvent::Connect {
+            peer: peer(),
+            upstream: "redis:6379".to_string(),
+        };
+        assert_eq!(
+            connect.to_json(1700000000000).to_string(),
+            r#"{"event":"connect","timestamp_ms":1700000000000,"peer":"10.1.2.3:50000","upstream":"redis:6379"}"#
+        );
+
+        let disconnect = ConnectionEvent::Disconnect {
+            peer: peer(),
+            upstream: "redis:6379".to_string(),
+            duration: Duration::from_millis(1234),
+            commands: 42,
+            outcome: "client_closed",
+        };
+        assert_eq!(
+            disconnect.to_json(1700000001234).to_string(),
+            r#"{"event":"disconnect","timestamp_ms":1700000001234,"peer":"10.1.2.3:50000","upstream":"redis:6379","duration_ms":1234,"commands":42,"outcome":"client_closed"}"#
+        );
+    }
+
+    #[test]
+    fn test_full_queue_drops_events() {
+        let stats = Stats::new();
+        let (sink, mut rx) = EventSink::new(2, stats.clone());
+        for _ in 0..5 {
+            sink.emit(ConnectionEvent::Connect {
+                peer: peer(),
+                upstream: "redis:6379".to_string(),
+            });
+        }
+        assert_eq!(stats.dropped_events(), 3);
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_parse_webhook_url() {
+        assert_eq!(
+            Webhook::parse("http://hooks.internal:8080/redis/events").unwrap(),
+            Webhook {
+                addr: "hooks.internal:8080".to_string(),
+                host: "hooks.internal:8080".to_string(),
+                path: "/redis/events".to_string(),
+            }
+        );
+        assert_eq!(Webhook::parse("http://hooks").unwrap().addr, "hooks:80");
+        assert!(Webhook::parse("https://hooks/x").is_err());
+        assert!(Webhook::parse("http:///x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events_posted_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let sink = EventSink::start(&url, Stats::new()).unwrap();
+        sink.emit(ConnectionEvent::Connect {
+            peer: peer(),
+            upstream: "redis:6379".to_string(),
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid-request");
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(
+            request.starts_with("POST /events HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.contains(r#""event":"connect""#), "{}", request);
+    }
+}
@@ -0,0 +1,185 @@
+//! Lock-free log-linear histogram for latency and size distributions.
+//!
+//! Values below 16 get exact buckets; above that each power of two is split into 16
+//! linear sub-buckets, bounding the relative error of reported percentiles to ~6%.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const NUM_BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// A fixed-size histogram of `u64` values safe for concurrent recording.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..NUM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a single value.
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Forget all recorded values. Values recorded concurrently may be partly kept, but
+    /// every later read still sees a valid histogram.
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Largest recorded value.
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Mean of recorded values, or 0 if empty.
+    pub fn mean(&self) -> u64 {
+        self.sum
+            .load(Ordering::Relaxed)
+            .checked_div(self.count())
+            .unwrap_or(0)
+    }
+
+    /// Value at percentile `p` (0-100), reported as the upper bound of its bucket.
+    /// Returns 0 if nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+        let rank = ((p / 100.0) * count as f64).ceil().max(1.0) as u64;
+
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return bucket_upper_bound(index).min(self.max());
+            }
+        }
+        self.max()
+    }
+}
+
+/// Index of the bucket holding `value`.
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let shift = exp - SUB_BITS;
+    let mantissa = (value >> shift) as usize; // in [SUB_BUCKETS, 2 * SUB_BUCKETS)
+    (shift as usize + 1) * SUB_BUCKETS + (mantissa - SUB_BUCKETS)
+}
+
+/// Largest value that maps to bucket `index`.
+fn bucket_upper_bound(index: usize) -> u64 {
+    let group = index / SUB_BUCKETS;
+    if group == 0 {
+        return index as u64;
+    }
+    let mantissa = (SUB_BUCKETS + index % SUB_BUCKETS) as u128;
+    let shift = group as u32 - 1;
+    (((mantissa + 1) << shift) - 1).min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_forgets_values() {
+        let hist = Histogram::new();
+        for value in [5, 500, 50_000] {
+            hist.record(value);
+        }
+        hist.reset();
+        assert_eq!((hist.count(), hist.mean(), hist.max(), hist.percentile(99.0)), (0, 0, 0, 0));
+        hist.record(7);
+        assert_eq!((hist.count(), hist.percentile(50.0)), (1, 7));
+    }
+
+    #[test]
+    fn test_bucket_bounds_are_contiguous() {
+        for value in [0, 1, 15, 16, 17, 31, 32, 1000, 123_456, u64::MAX / 3, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(value <= bucket_upper_bound(index), "value {}", value);
+            if index > 0 {
+                assert!(value > bucket_upper_bound(index - 1), "value {}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles_of_uniform_distribution() {
+        let hist = Histogram::new();
+        for v in 1..=1000 {
+            hist.record(v);
+        }
+        assert_eq!(hist.count(), 1000);
+        assert_eq!(hist.max(), 1000);
+        assert_eq!(hist.mean(), 500);
+
+        let p50 = hist.percentile(50.0);
+        let p99 = hist.percentile(99.0);
+        assert!((500..=532).contains(&p50), "p50 = {}", p50);
+        assert!((990..=1000).contains(&p99), "p99 = {}", p99);
+        assert_eq!(hist.percentile(100.0), 1000);
+    }
+
+    #[test]
+    fn test_fractional_percentiles() {
+        let hist = Histogram::new();
+        for v in 1..=10_000 {
+            hist.record(v);
+        }
+        // Reported values are bucket upper bounds, at most ~6% above the exact value
+        for (p, exact) in [(90.0, 9000), (99.0, 9900), (99.9, 9990), (99.99, 9999)] {
+            let value = hist.percentile(p);
+            assert!(
+                value >= exact && value <= exact + exact / 16,
+                "p{} = {}, expected about {}",
+                p,
+                value,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_histogram() {
+        let hist = Histogram::new();
+        assert_eq!(hist.percentile(99.0), 0);
+        assert_eq!(hist.mean(), 0);
+    }
+}
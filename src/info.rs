@@ -0,0 +1,201 @@
+//! Redis INFO-style reporting of proxy statistics.
+//!
+//! Lets existing Redis monitoring tools scrape the proxy over RESP, via
+//! `PROXY INFO [section]` or `INFO proxy`.
+
+use crate::resp::Command;
+use crate::stats::{format_close_counts, Stats};
+
+/// Returns the INFO section requested by `cmd`, or `None` if the command is not an
+/// INFO request addressed to the proxy.
+///
+/// Recognized forms:
+/// - `PROXY INFO` / `INFO proxy` - the `# Proxy` section
+/// - `PROXY INFO commandstats` - the `# Commandstats` section
+/// - `PROXY INFO all` - both sections
+pub fn info_request(cmd: &Command) -> Option<String> {
+    let section = if cmd.name.eq_ignore_ascii_case("PROXY") {
+        let sub = cmd.args.first()?;
+        if !sub.eq_ignore_ascii_case("INFO") {
+            return None;
+        }
+        cmd.args
+            .get(1)
+            .map(|s| s.to_lowercase())
+            .unwrap_or_else(|| "proxy".to_string())
+    } else if cmd.name.eq_ignore_ascii_case("INFO") {
+        let section = cmd.args.first()?;
+        if !section.eq_ignore_ascii_case("proxy") {
+            return None;
+        }
+        "proxy".to_string()
+    } else {
+        return None;
+    };
+    Some(section)
+}
+
+/// Format the requested section(s) of proxy statistics in Redis INFO format, reporting
+/// latencies at `percentiles`.
+pub fn format_info(stats: &Stats, section: &str, percentiles: &[f64]) -> String {
+    let mut out = String::new();
+    let all = section == "all" || section == "everything";
+
+    if all || section == "proxy" {
+        out.push_str("# Proxy\r\n");
+        out.push_str(&format!("proxy_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+        out.push_str(&format!(
+            "uptime_in_seconds:{}\r\n",
+            stats.uptime().as_secs()
+        ));
+        out.push_str(&format!(
+            "total_connections_received:{}\r\n",
+            stats.total_connections()
+        ));
+        out.push_str(&format!("total_commands_processed:{}\r\n", stats.total()));
+        out.push_str(&format!(
+            "connections_closed:{}\r\n",
+            format_close_counts(&stats.close_counts(), ",")
+        ));
+        out.push_str(&format!(
+            "max_pipeline_depth:{}\r\n",
+            stats.max_pipeline_depth()
+        ));
+        out.push_str(&format!(
+            "invalidation_messages:{}\r\n",
+            stats.invalidations()
+        ));
+        out.push_str(&format!(
+            "webhook_events_dropped:{}\r\n",
+            stats.dropped_events()
+        ));
+        let ttls = stats.key_ttls();
+        out.push_str(&format!("key_ttl_set:count={}", ttls.count()));
+        for &p in percentiles {
+            out.push_str(&format!(",p{}_ms={}", p, ttls.percentile(p)));
+        }
+        out.push_str(&format!(",max_ms={}\r\n", ttls.max()));
+        out.push_str(&format!(
+            "keys_set_without_ttl:{}\r\n",
+            stats.sets_without_ttl()
+        ));
+        for (label, tls) in [("plain", false), ("tls", true)] {
+            let hist = stats.upstream_connect_latency(tls);
+            out.push_str(&format!("upstream_connect_{}:count={}", label, hist.count()));
+            for &p in percentiles {
+                out.push_str(&format!(",p{}_us={}", p, hist.percentile(p)));
+            }
+            out.push_str(&format!(",max_us={}\r\n", hist.max()));
+        }
+    }
+
+    if all || section == "commandstats" {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        out.push_str("# Commandstats\r\n");
+        let mut counts: Vec<_> = stats.command_counts().into_iter().collect();
+        counts.sort();
+        for (cmd, calls) in counts {
+            out.push_str(&format!(
+                "cmdstat_{}:calls={}\r\n",
+                cmd.to_lowercase(),
+                calls
+            ));
+        }
+    }
+
+    out
+}
+
+/// Encode an INFO payload as a RESP bulk string reply.
+pub fn info_reply(payload: &str) -> String {
+    format!("${}\r\n{}\r\n", payload.len(), payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn command(name: &str, args: &[&str]) -> Command {
+        Command {
+            name: name.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            span: 0..0,
+        }
+    }
+
+    /// Parse INFO output into section name -> key/value pairs, as Redis clients do.
+    fn parse_info(payload: &str) -> HashMap<String, HashMap<String, String>> {
+        let mut sections = HashMap::new();
+        let mut current = String::new();
+        for line in payload.split("\r\n") {
+            if let Some(name) = line.strip_prefix("# ") {
+                current = name.to_string();
+                sections.insert(current.clone(), HashMap::new());
+            } else if let Some((key, value)) = line.split_once(':') {
+                sections
+                    .get_mut(&current)
+                    .expect("field outside of a section")
+                    .insert(key.to_string(), value.to_string());
+            } else {
+                assert!(line.is_empty(), "unexpected INFO line: {line:?}");
+            }
+        }
+        sections
+    }
+
+    #[test]
+    fn test_info_request_forms() {
+        assert_eq!(
+            info_request(&command("PROXY", &["INFO"])),
+            Some("proxy".into())
+        );
+        assert_eq!(
+            info_request(&command("proxy", &["info", "ALL"])),
+            Some("all".into())
+        );
+        assert_eq!(
+            info_request(&command("INFO", &["proxy"])),
+            Some("proxy".into())
+        );
+        assert_eq!(info_request(&command("INFO", &[])), None);
+        assert_eq!(info_request(&command("INFO", &["memory"])), None);
+        assert_eq!(info_request(&command("GET", &["k"])), None);
+    }
+
+    #[test]
+    fn test_format_info_parses_as_sections() {
+        let stats = Stats::new();
+        stats.record_connection();
+        stats.record_command("GET");
+        stats.record_command("GET");
+        stats.record_command("set");
+
+        stats.record_upstream_connect(false, std::time::Duration::from_micros(120));
+
+        let sections = parse_info(&format_info(&stats, "all", &[50.0, 99.9]));
+        let proxy = &sections["Proxy"];
+        assert_eq!(proxy["total_connections_received"], "1");
+        assert_eq!(proxy["total_commands_processed"], "3");
+        assert!(proxy.contains_key("uptime_in_seconds"));
+        assert_eq!(
+            proxy["upstream_connect_plain"],
+            "count=1,p50_us=120,p99.9_us=120,max_us=120"
+        );
+
+        let commands = &sections["Commandstats"];
+        assert_eq!(commands["cmdstat_get"], "calls=2");
+        assert_eq!(commands["cmdstat_set"], "calls=1");
+    }
+
+    #[test]
+    fn test_info_reply_is_bulk_string() {
+        let payload = "# Proxy\r\nuptime_in_seconds:1\r\n";
+        assert_eq!(
+            info_reply(payload),
+            format!("${}\r\n{}\r\n", payload.len(), payload)
+        );
+    }
+}
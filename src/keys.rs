@@ -0,0 +1,77 @@
+//! Number of keys touched by multi-key commands.
+//!
+//! Commands like `DEL`, `MGET` or `SINTERSTORE` take any number of keys, and a single
+//! huge one can block Redis for a long time. The key positions of each such command are
+//! known here, so the number of keys can be reported and limited.
+
+use crate::resp::Command;
+
+/// The number of keys `cmd` operates on, if it is a multi-key command.
+///
+/// Returns `None` for single-key and keyless commands, and for `numkeys` arguments
+/// Redis would reject.
+pub fn key_count(cmd: &Command) -> Option<usize> {
+    let name = cmd.name.to_ascii_uppercase();
+    let args = cmd.args.len();
+    let numkeys = |i: usize| cmd.args.get(i)?.parse::<usize>().ok();
+    match name.as_str() {
+        // Every argument is a key, including the destination of the *STORE variants
+        "DEL" | "UNLINK" | "EXISTS" | "TOUCH" | "MGET" | "WATCH" | "SINTER" | "SUNION"
+        | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" | "PFCOUNT" | "PFMERGE" => {
+            Some(args)
+        }
+        // Key/value pairs
+        "MSET" | "MSETNX" => Some(args / 2),
+        // Keys followed by a timeout
+        "BLPOP" | "BRPOP" | "BZPOPMIN" | "BZPOPMAX" => Some(args.saturating_sub(1)),
+        // numkeys first, then the keys
+        "ZUNION" | "ZINTER" | "ZDIFF" | "ZINTERCARD" | "SINTERCARD" | "LMPOP" | "ZMPOP" => {
+            numkeys(0)
+        }
+        // A destination key, then numkeys
+        "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => numkeys(1).map(|n| n + 1),
+        // Something else (a script, function or timeout), then numkeys
+        "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO" | "BLMPOP"
+        | "BZMPOP" => numkeys(1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        let mut words = line.split(' ').map(str::to_string);
+        Command {
+            name: words.next().unwrap(),
+            args: words.collect(),
+            span: 0..0,
+        }
+    }
+
+    fn keys(line: &str) -> Option<usize> {
+        key_count(&command(line))
+    }
+
+    #[test]
+    fn test_key_counts() {
+        assert_eq!(keys("MGET a b c"), Some(3));
+        assert_eq!(keys("del a"), Some(1));
+        assert_eq!(keys("SINTERSTORE dest a b"), Some(3));
+        assert_eq!(keys("MSET a 1 b 2"), Some(2));
+        assert_eq!(keys("BLPOP a b 0"), Some(2));
+        assert_eq!(keys("ZUNIONSTORE dest 2 a b WEIGHTS 1 2"), Some(3));
+        assert_eq!(keys("ZINTER 2 a b"), Some(2));
+        assert_eq!(keys("EVAL script 2 a b arg"), Some(2));
+        assert_eq!(keys("BLMPOP 0 2 a b LEFT"), Some(2));
+    }
+
+    #[test]
+    fn test_not_multi_key() {
+        assert_eq!(keys("GET a"), None);
+        assert_eq!(keys("PING"), None);
+        assert_eq!(keys("EVAL script many"), None);
+        assert_eq!(keys("ZUNIONSTORE dest"), None);
+    }
+}
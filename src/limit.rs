@@ -0,0 +1,239 @@
+//! Concurrent connection limiting with a bounded waiting queue.
+//!
+//! Connections beyond `--max-connections` wait for a slot to free up, but only up to
+//! `--max-queued-connections` of them; any more are rejected straight away so overload
+//! degrades predictably instead of piling up sockets. Separately, `--max-connections-per-ip`
+//! keeps a single client address from taking all of them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+
+/// Gate for accepted connections, shared by the accept loops.
+pub struct ConnectionLimiter {
+    permits: Arc<Semaphore>,
+    /// Maximum connections waiting for a permit, `None` for unbounded
+    max_queued: Option<usize>,
+    queued: AtomicUsize,
+}
+
+/// A connection's place at the gate: admitted, or waiting in the queue.
+pub enum Entry {
+    Admitted(OwnedSemaphorePermit),
+    Queued(QueueSlot),
+}
+
+/// A spot in the waiting queue, released when dropped.
+pub struct QueueSlot {
+    limiter: Arc<ConnectionLimiter>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_queued: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            permits: Arc::new(Semaphore::new(max_connections)),
+            max_queued,
+            queued: AtomicUsize::new(0),
+        })
+    }
+
+    /// Limiter for `--max-connections` / `--max-queued-connections`. Without a
+    /// connection limit every connection is admitted immediately.
+    pub fn from_config(config: &Config) -> Arc<Self> {
+        let max_connections = config.max_connections.unwrap_or(Semaphore::MAX_PERMITS);
+        Self::new(max_connections, config.max_queued_connections)
+    }
+
+    /// Claim a place for a newly accepted connection: a permit if one is free,
+    /// otherwise a spot in the queue. Returns `None` if the queue is full.
+    pub fn try_enter(self: &Arc<Self>) -> Option<Entry> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(Entry::Admitted(permit));
+        }
+        let max_queued = self.max_queued.unwrap_or(usize::MAX);
+        self.queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max_queued).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Entry::Queued(QueueSlot {
+            limiter: self.clone(),
+        }))
+    }
+
+    /// Number of connections currently waiting for a permit.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+impl Entry {
+    /// Wait until the connection may proceed. The permit is held for its lifetime.
+    pub async fn permit(self) -> OwnedSemaphorePermit {
+        match self {
+            Entry::Admitted(permit) => permit,
+            Entry::Queued(slot) => slot
+                .limiter
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("connection semaphore is never closed"),
+        }
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.limiter.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Per-client-IP connection counts, shared by the accept loops.
+pub struct IpLimiter {
+    max_per_ip: usize,
+    /// Open connections by client IP; addresses without any are removed
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// One connection's share of its IP's limit, released when dropped.
+pub struct IpSlot {
+    limiter: Arc<IpLimiter>,
+    /// None for connections that count against no IP
+    ip: Option<IpAddr>,
+}
+
+impl IpLimiter {
+    pub fn new(max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_per_ip,
+            connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Limiter for `--max-connections-per-ip`, admitting any number when unset.
+    pub fn from_config(config: &Config) -> Arc<Self> {
+        Self::new(config.max_connections_per_ip.unwrap_or(usize::MAX))
+    }
+
+    /// Count a new connection from `ip`, or `None` if it already has the maximum open.
+    pub fn try_enter(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.get(&ip).copied().unwrap_or(0);
+        if count >= self.max_per_ip {
+            return None;
+        }
+        connections.insert(ip, count + 1);
+        Some(IpSlot {
+            limiter: self.clone(),
+            ip: Some(ip),
+        })
+    }
+
+    /// A slot for a connection without a client IP, which is never limited.
+    pub fn unlimited(self: &Arc<Self>) -> IpSlot {
+        IpSlot {
+            limiter: self.clone(),
+            ip: None,
+        }
+    }
+
+    /// Number of connections currently open from `ip`.
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        self.connections.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+
+    /// Number of client IPs with connections open.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn tracked_ips(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut connections = self.limiter.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_bounds_waiting_connections() {
+        let limiter = ConnectionLimiter::new(1, Some(1));
+
+        let first = limiter.try_enter().unwrap();
+        assert!(matches!(first, Entry::Admitted(_)));
+        let second = limiter.try_enter().unwrap();
+        assert!(matches!(second, Entry::Queued(_)));
+        assert_eq!(limiter.queued(), 1);
+        assert!(limiter.try_enter().is_none());
+
+        // Closing the first connection lets the queued one through
+        let waiting = tokio::spawn(second.permit());
+        drop(first.permit().await);
+        let _permit = waiting.await.unwrap();
+        assert_eq!(limiter.queued(), 0);
+        assert!(matches!(limiter.try_enter(), Some(Entry::Queued(_))));
+    }
+
+    #[test]
+    fn test_unbounded_queue_without_limit() {
+        let limiter = ConnectionLimiter::new(0, None);
+        let entries: Vec<_> = (0..100).map(|_| limiter.try_enter().unwrap()).collect();
+        assert_eq!(limiter.queued(), 100);
+        drop(entries);
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[test]
+    fn test_connections_limited_per_ip() {
+        let limiter = IpLimiter::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_enter(a).unwrap();
+        let _second = limiter.try_enter(a).unwrap();
+        assert!(limiter.try_enter(a).is_none());
+        let _other = limiter.try_enter(b).unwrap();
+        assert_eq!(limiter.connections_from(a), 2);
+
+        // A closed connection frees its slot
+        drop(first);
+        let _third = limiter.try_enter(a).unwrap();
+        assert!(limiter.try_enter(a).is_none());
+
+        // Connections without an IP are not counted
+        let unlimited: Vec<_> = (0..5).map(|_| limiter.unlimited()).collect();
+        assert_eq!(limiter.tracked_ips(), 2);
+        drop(unlimited);
+        assert_eq!(limiter.connections_from(a), 2);
+    }
+
+    #[test]
+    fn test_closed_ips_are_forgotten() {
+        let limiter = IpLimiter::new(1);
+        let slots: Vec<_> = (1..=50)
+            .map(|i| limiter.try_enter(IpAddr::from([10, 0, 0, i])).unwrap())
+            .collect();
+        assert_eq!(limiter.tracked_ips(), 50);
+        drop(slots);
+        assert_eq!(limiter.tracked_ips(), 0);
+    }
+}
@@ -1,40 +1,206 @@
+// The redacted configuration is a single `json!` object with a field per option
+#![recursion_limit = "256"]
+
+mod accept;
+mod admin;
+mod bandwidth;
+mod chain;
+mod clock;
+mod commands;
 mod config;
+mod config_file;
+mod drain;
 mod error;
+mod events;
+mod histogram;
+mod info;
+mod keys;
+mod limit;
+mod memory;
+mod metrics;
+mod pool;
 mod proxy;
+mod pubsub;
+mod ratelimit;
+mod redact;
+mod replay;
 mod resp;
+mod samples;
+mod sampling;
 mod server;
+mod slowest;
+mod slowlog;
 mod stats;
+mod stats_file;
+#[cfg(test)]
+mod testing;
+mod timing;
 mod tls;
+mod ttl;
 mod upstream;
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
 use tokio::signal;
 use tracing::info;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::config::Config;
-use crate::server::run_server;
+use crate::admin::{run_admin_server, AdminState};
+use crate::bandwidth::BandwidthLimiter;
+use crate::config::{Config, LogFormat};
+use crate::drain::Drain;
+use crate::events::EventSink;
+use crate::limit::{ConnectionLimiter, IpLimiter};
+use crate::metrics::run_metrics_server;
+use crate::memory::{MemoryGuard, ProcRss};
+use crate::pool::BufferPool;
+use crate::ratelimit::GlobalRate;
+use crate::server::{run_server, ServerState};
 use crate::stats::Stats;
+use crate::stats_file::StatsFile;
+use crate::samples::CommandSamples;
+use crate::timing::TimingLog;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let config = Config::parse_args().map_err(|e| anyhow::anyhow!(e))?;
+
     // Initialize logging
+    let format = match config.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(format)
         .with(EnvFilter::from_default_env().add_directive("redis_tls_proxy=info".parse()?))
         .init();
 
-    let config = Config::parse_args().map_err(|e| anyhow::anyhow!(e))?;
-
     info!("Starting Redis TLS Proxy");
+    info!("Effective configuration: {}", config.to_redacted_json());
 
     // Create shared stats
-    let stats = Stats::new();
+    let stats = Stats::from_config(&config);
     let stats_for_shutdown = stats.clone();
+    let percentiles = config.percentiles.clone();
+
+    // Start memory monitoring if a limit is configured
+    let memory = config.max_rss.map(|max_rss| {
+        info!("Rejecting new connections above {} bytes RSS", max_rss);
+        let guard = MemoryGuard::new(max_rss);
+        tokio::spawn(memory::monitor(guard.clone(), ProcRss));
+        guard
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(dump_stats_on_sigusr1(
+        stats.clone(),
+        config.sigusr1_stats,
+        percentiles.clone(),
+    ));
+
+    let drain = Drain::new();
+    let max_uptime = config.max_uptime.map(|max_uptime| {
+        info!("Shutting down after {:?} of uptime", max_uptime);
+        drain::drain_at_uptime(
+            max_uptime,
+            config.shutdown_grace,
+            drain.clone(),
+            stats.clone(),
+            drain::REPORT_INTERVAL,
+        )
+    });
+
+    let timing = match &config.timing_log {
+        Some(path) => {
+            info!("Writing command timings to {}", path.display());
+            Some(TimingLog::create(path)?)
+        }
+        None => None,
+    };
+    let timing_for_shutdown = timing.clone();
+
+    let stats_file = config.stats_file.clone().map(|path| {
+        let interval = Duration::from_secs(config.stats_interval_secs);
+        info!("Writing stats to {} every {:?}", path.display(), interval);
+        let file = StatsFile::new(path, percentiles.clone());
+        let writer = tokio::spawn(stats_file::write_periodically(
+            file.clone(),
+            stats.clone(),
+            interval,
+        ));
+        (file, writer)
+    });
+
+    let samples = (config.sample_rate > 0.0).then(|| {
+        info!("Sampling {} of commands for /samples", config.sample_rate);
+        Arc::new(CommandSamples::new(config.sample_capacity))
+    });
+
+    let events = match &config.event_webhook {
+        Some(url) => {
+            info!("Sending connection events to {}", url);
+            Some(EventSink::start(url, stats.clone())?)
+        }
+        None => None,
+    };
+
+    // Start admin endpoint if configured
+    if let Some(addr) = config.admin_listen.clone() {
+        let state = Arc::new(AdminState {
+            stats: stats.clone(),
+            memory: memory.clone(),
+            drain: drain.clone(),
+            config_json: config.to_redacted_json().to_string(),
+            samples: samples.clone(),
+            percentiles: percentiles.clone(),
+        });
+        tokio::spawn(async move {
+            if let Err(e) = run_admin_server(&addr, state).await {
+                tracing::error!("Admin endpoint error: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = config.metrics_listen.clone() {
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_metrics_server(&addr, stats).await {
+                tracing::error!("Metrics endpoint error: {}", e);
+            }
+        });
+    }
+
+    let bandwidth = config.max_total_bps.map(|bps| {
+        info!("Limiting total bandwidth to {} bytes/sec", bps);
+        BandwidthLimiter::new(bps, stats.clock().clone())
+    });
+
+    let global_rate = config.global_max_commands_per_sec.map(|per_sec| {
+        info!("Limiting commands from all clients to {}/sec", per_sec);
+        let max_queue = Duration::from_millis(config.global_rate_limit_queue_ms);
+        GlobalRate::new(per_sec, max_queue, stats.clock().now())
+    });
+
+    let buffers = BufferPool::new();
+    let state = ServerState {
+        stats,
+        memory,
+        drain,
+        timing,
+        samples,
+        events,
+        limiter: ConnectionLimiter::from_config(&config),
+        ip_limiter: IpLimiter::from_config(&config),
+        bandwidth,
+        global_rate,
+        buffers: buffers.clone(),
+    };
 
     // Run server with graceful shutdown
     tokio::select! {
-        result = run_server(config, stats) => {
+        result = run_server(config, state) => {
             if let Err(e) = result {
                 tracing::error!("Server error: {}", e);
                 return Err(e.into());
@@ -43,11 +209,55 @@ async fn main() -> Result<()> {
         _ = signal::ctrl_c() => {
             info!("Received shutdown signal, stopping...");
         }
+        _ = async {
+            match max_uptime {
+                Some(shutdown) => shutdown.await,
+                None => std::future::pending().await,
+            }
+        } => {
+            info!("Maximum uptime reached, stopping...");
+        }
     }
 
-    // Print stats on shutdown
-    stats_for_shutdown.print_summary();
+    // Print stats on shutdown, counting connections still open as closed by it
+    let open = stats_for_shutdown.active_connections();
+    stats_for_shutdown.record_closes(proxy::CloseCode::Shutdown, open);
+    stats_for_shutdown.print_summary(&percentiles);
+    info!(
+        "Connection buffers: {} allocated, {} reused",
+        buffers.allocated(),
+        buffers.reused()
+    );
+    if let Some(timing) = timing_for_shutdown {
+        timing.flush();
+    }
+    if let Some((file, writer)) = stats_file {
+        writer.abort();
+        if let Err(e) = file.write(&stats_for_shutdown) {
+            tracing::warn!("Cannot write stats to {}: {}", file.path().display(), e);
+        }
+    }
 
     info!("Server stopped");
     Ok(())
 }
+
+/// Print statistics every time the process receives SIGUSR1.
+#[cfg(unix)]
+async fn dump_stats_on_sigusr1(stats: Arc<Stats>, mode: config::DumpMode, percentiles: Vec<f64>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::warn!("Cannot listen for SIGUSR1, stats dumps disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        match mode {
+            config::DumpMode::Cumulative => stats.print_summary(&percentiles),
+            config::DumpMode::Delta => eprint!("{}", stats.delta_since_last_dump().summary()),
+        }
+    }
+}
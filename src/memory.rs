@@ -0,0 +1,132 @@
+//! Process memory monitoring used to shed load before the OOM killer steps in.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+/// How often the resident set size is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Source of the process's resident set size in bytes.
+pub trait RssSource: Send + Sync + 'static {
+    fn rss_bytes(&self) -> Option<u64>;
+}
+
+/// Reads the resident set size from `/proc/self/status` (Linux only).
+pub struct ProcRss;
+
+impl RssSource for ProcRss {
+    fn rss_bytes(&self) -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb * 1024)
+    }
+}
+
+/// Tracks whether the proxy is above its configured memory threshold.
+#[derive(Debug)]
+pub struct MemoryGuard {
+    max_rss: u64,
+    last_rss: AtomicU64,
+    over_limit: AtomicBool,
+}
+
+impl MemoryGuard {
+    pub fn new(max_rss: u64) -> Arc<Self> {
+        Arc::new(Self {
+            max_rss,
+            last_rss: AtomicU64::new(0),
+            over_limit: AtomicBool::new(false),
+        })
+    }
+
+    /// Record a new RSS reading, logging transitions in and out of memory pressure.
+    pub fn update(&self, rss: u64) {
+        self.last_rss.store(rss, Ordering::Relaxed);
+        let over = rss > self.max_rss;
+        let was_over = self.over_limit.swap(over, Ordering::Relaxed);
+
+        if over && !was_over {
+            warn!(
+                "Memory pressure: RSS {} bytes exceeds limit of {} bytes, rejecting new connections",
+                rss, self.max_rss
+            );
+        } else if !over && was_over {
+            info!(
+                "Memory pressure cleared: RSS {} bytes is below limit of {} bytes, accepting connections",
+                rss, self.max_rss
+            );
+        }
+    }
+
+    /// Take a reading from `source` and update the guard. Unreadable sources are ignored.
+    pub fn sample(&self, source: &dyn RssSource) {
+        if let Some(rss) = source.rss_bytes() {
+            self.update(rss);
+        }
+    }
+
+    /// Whether new client connections should be accepted.
+    pub fn accepts_connections(&self) -> bool {
+        !self.over_limit.load(Ordering::Relaxed)
+    }
+
+    /// The most recent RSS reading in bytes.
+    pub fn last_rss(&self) -> u64 {
+        self.last_rss.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically sample `source` and update `guard` until the task is dropped.
+pub async fn monitor(guard: Arc<MemoryGuard>, source: impl RssSource) {
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        guard.sample(&source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MockRss(Mutex<Vec<u64>>);
+
+    impl RssSource for MockRss {
+        fn rss_bytes(&self) -> Option<u64> {
+            self.0.lock().unwrap().pop()
+        }
+    }
+
+    #[test]
+    fn test_accept_gating_follows_rss() {
+        let guard = MemoryGuard::new(1000);
+        // Readings are popped from the back.
+        let source = MockRss(Mutex::new(vec![900, 1500, 1200, 500]));
+
+        guard.sample(&source);
+        assert!(guard.accepts_connections());
+
+        guard.sample(&source);
+        assert!(!guard.accepts_connections());
+
+        guard.sample(&source);
+        assert!(!guard.accepts_connections());
+        assert_eq!(guard.last_rss(), 1500);
+
+        guard.sample(&source);
+        assert!(guard.accepts_connections());
+    }
+
+    #[test]
+    fn test_unreadable_source_keeps_state() {
+        let guard = MemoryGuard::new(1000);
+        guard.update(2000);
+        guard.sample(&MockRss(Mutex::new(vec![])));
+        assert!(!guard.accepts_connections());
+    }
+}
@@ -0,0 +1,184 @@
+//! Prometheus metrics endpoint.
+//!
+//! With `--metrics-listen`, live counters are served at `/metrics` in the Prometheus text
+//! exposition format, so they can be scraped instead of waiting for the shutdown summary.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tracing::{debug, info};
+
+use crate::admin::{self, Response};
+use crate::error::Result;
+use crate::stats::Stats;
+
+/// Content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Render the current stats in the Prometheus text format.
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP redis_proxy_commands_total Commands forwarded to upstream.");
+    let _ = writeln!(out, "# TYPE redis_proxy_commands_total counter");
+    let _ = writeln!(out, "redis_proxy_commands_total {}", stats.total());
+
+    let mut counts: Vec<_> = stats.command_counts().into_iter().collect();
+    counts.sort();
+    let _ = writeln!(
+        out,
+        "# HELP redis_proxy_command_total Commands forwarded to upstream, by command."
+    );
+    let _ = writeln!(out, "# TYPE redis_proxy_command_total counter");
+    for (command, count) in counts {
+        let _ = writeln!(
+            out,
+            "redis_proxy_command_total{{command=\"{}\"}} {}",
+            escape_label(&command),
+            count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP redis_proxy_active_connections Client connections currently open."
+    );
+    let _ = writeln!(out, "# TYPE redis_proxy_active_connections gauge");
+    let _ = writeln!(out, "redis_proxy_active_connections {}", stats.active_connections());
+
+    let parser = stats.parser();
+    for (name, help, value) in [
+        ("parser_bytes_total", "Bytes of complete client commands parsed.", parser.bytes),
+        ("parser_commands_total", "Client commands parsed.", parser.commands),
+        (
+            "parser_incomplete_total",
+            "Client reads that ended in a partial command.",
+            parser.incomplete,
+        ),
+        (
+            "parser_malformed_total",
+            "Times client data could not be parsed and was passed through.",
+            parser.malformed,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP redis_proxy_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE redis_proxy_{} counter", name);
+        let _ = writeln!(out, "redis_proxy_{} {}", name, value);
+    }
+    out
+}
+
+/// Escape a label value: backslashes, double quotes and newlines are the only special
+/// characters.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Route a metrics request.
+pub fn handle(stats: &Stats, method: &str, path: &str) -> Response {
+    match (method, path) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: CONTENT_TYPE,
+            body: render(stats),
+        },
+        (_, "/metrics") => Response::text(405, "method not allowed\n"),
+        _ => Response::text(404, "not found\n"),
+    }
+}
+
+/// Run the metrics HTTP server until the task is dropped.
+pub async fn run_metrics_server(addr: &str, stats: Arc<Stats>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics endpoint listening on {}", addr);
+    serve_metrics(listener, stats).await
+}
+
+/// Answer metrics requests on `listener`.
+async fn serve_metrics(listener: TcpListener, stats: Arc<Stats>) -> Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(stream, |method, path| handle(&stats, method, path)).await
+            {
+                debug!("Metrics request from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_render_counters_and_gauge() {
+        let stats = Stats::new();
+        stats.record_command("GET");
+        stats.record_command("GET");
+        stats.record_command("SET");
+        let _conn = stats.track_connection();
+
+        let body = render(&stats);
+        assert!(body.contains("\n# TYPE redis_proxy_commands_total counter\n"));
+        assert!(body.contains("\nredis_proxy_commands_total 3\n"));
+        assert!(body.contains(
+            "\nredis_proxy_command_total{command=\"GET\"} 2\n\
+             redis_proxy_command_total{command=\"SET\"} 1\n"
+        ));
+        assert!(body.contains("\n# TYPE redis_proxy_active_connections gauge\n"));
+        assert!(body.contains("\nredis_proxy_active_connections 1\n"));
+    }
+
+    #[test]
+    fn test_render_parser_counters() {
+        let stats = Stats::new();
+        stats.record_parse(40, 3, true);
+        stats.record_malformed_parse();
+
+        let body = render(&stats);
+        assert!(body.contains("\n# TYPE redis_proxy_parser_bytes_total counter\n"));
+        assert!(body.contains("\nredis_proxy_parser_bytes_total 40\n"));
+        assert!(body.contains("\nredis_proxy_parser_commands_total 3\n"));
+        assert!(body.contains("\nredis_proxy_parser_incomplete_total 1\n"));
+        assert!(body.ends_with("\nredis_proxy_parser_malformed_total 1\n"));
+    }
+
+    #[test]
+    fn test_label_values_escaped() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn test_routes() {
+        let stats = Stats::new();
+        let response = handle(&stats, "GET", "/metrics");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, CONTENT_TYPE);
+        assert_eq!(handle(&stats, "POST", "/metrics").status, 405);
+        assert_eq!(handle(&stats, "GET", "/health").status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Stats::new();
+        stats.record_command("PING");
+        tokio::spawn(serve_metrics(listener, stats));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("redis_proxy_commands_total 1\n"), "{response}");
+    }
+}
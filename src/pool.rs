@@ -0,0 +1,142 @@
+//! Pool of connection buffers.
+//!
+//! Every proxied connection needs a read buffer per direction. Under a flood of
+//! short-lived connections, allocating and freeing them per connection adds up, so
+//! closed connections hand their buffers back here for the next connection to reuse.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+/// Initial capacity of a connection buffer.
+pub const BUFFER_SIZE: usize = 8192;
+
+/// Idle buffers kept for reuse; more than this are freed.
+const MAX_IDLE: usize = 1024;
+
+/// Buffers that grew beyond this (a client sent a huge command) are freed rather
+/// than pinning the memory in the pool.
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// Shared pool of `BytesMut` buffers.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+    /// Buffers allocated because the pool was empty
+    allocated: AtomicU64,
+    /// Buffers served from the pool
+    reused: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Take an empty buffer, returned to the pool when the guard is dropped.
+    pub fn get(self: &Arc<Self>) -> PooledBuf {
+        let buf = match self.idle.lock().unwrap().pop() {
+            Some(buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        };
+        PooledBuf {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > MAX_RETAINED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        // Reclaim space consumed by `advance` so the next user gets the full buffer
+        buf.reserve(BUFFER_SIZE);
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(buf);
+        }
+    }
+
+    /// Buffers currently waiting in the pool.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    pub fn reused(&self) -> u64 {
+        self.reused.load(Ordering::Relaxed)
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`].
+pub struct PooledBuf {
+    buf: BytesMut,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new();
+        let mut a = pool.get();
+        let b = pool.get();
+        a.extend_from_slice(b"leftover");
+        assert_eq!(pool.allocated(), 2);
+        assert_eq!(pool.idle(), 0);
+
+        drop(a);
+        drop(b);
+        assert_eq!(pool.idle(), 2);
+
+        let c = pool.get();
+        assert!(c.is_empty());
+        assert!(c.capacity() >= BUFFER_SIZE);
+        assert_eq!(pool.allocated(), 2);
+        assert_eq!(pool.reused(), 1);
+    }
+
+    #[test]
+    fn test_oversized_buffers_are_freed() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get();
+        buf.reserve(MAX_RETAINED_CAPACITY * 2);
+        drop(buf);
+        assert_eq!(pool.idle(), 0);
+    }
+}
@@ -1,84 +1,2900 @@
-/// Bidirectional proxy between client and upstream Redis connections.
+//! Bidirectional proxy between client and upstream Redis connections.
 
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
-use bytes::BytesMut;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::{debug, error};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, error, info, warn};
 
-use crate::resp::parse_commands;
+use crate::bandwidth::BandwidthLimiter;
+use crate::commands;
+use crate::config::{Config, LogFormat, Protocol, RateLimitAction};
+use crate::error::{ProxyError, Result};
+use crate::info::{format_info, info_reply, info_request};
+use crate::keys::key_count;
+use crate::pool::BufferPool;
+use crate::pubsub::{self, Event, Expected, Family, Subscriptions};
+use crate::ratelimit::{
+    CommandRate, GlobalRate, GLOBAL_RATE_LIMITED_REPLY, RATE_LIMITED_REPLY,
+};
+use crate::redact::Redactor;
+use crate::replay;
+use crate::resp::{
+    error_class, hello_version, parse_command_frames_limited, parse_reply_frames, push_type,
+    redirect, rename_command, Command, RespVersion,
+};
+use crate::samples::{unix_millis, CommandSamples, Sample, MAX_SAMPLE_TEXT};
+use crate::sampling::Sampler;
 use crate::stats::Stats;
+use crate::timing::TimingLog;
+use crate::ttl;
+
+/// Free space ensured in each read buffer before reading from a stream.
+const READ_CHUNK: usize = 4096;
+
+/// Delay between attempts to reconnect to an unavailable upstream.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Distinct command names counted per connection for `--connection-top-commands`; the
+/// rest are counted together under [`OTHER_COMMANDS`], so a client sending ever new names
+/// cannot grow the map.
+const MAX_CONNECTION_COMMANDS: usize = 64;
+
+/// Name the commands beyond [`MAX_CONNECTION_COMMANDS`] are counted under.
+const OTHER_COMMANDS: &str = "(other)";
+
+/// Slots kept in an emptied reply queue, so one deep pipeline does not pin its memory
+/// for the rest of a long-lived connection.
+const RETAINED_PENDING: usize = 1024;
+
+/// Answer to a command refused by `--deny-command` / `--allow-command`.
+const BLOCKED_REPLY: &str = "-ERR command blocked by proxy\r\n";
+
+/// Answer to a command that modifies data, with `--read-only`.
+const READ_ONLY_REPLY: &str = "-READONLY proxy is in read-only mode\r\n";
+
+/// Answer to a command sent to an upstream that went away, and not safe to replay.
+const NOT_REPLAYED_REPLY: &str =
+    "-ERR upstream connection lost, command may or may not have run\r\n";
+
+/// Answer to a command queued while upstream is unavailable that will never be sent.
+const UNAVAILABLE_REPLY: &str = "-ERR upstream unavailable\r\n";
+
+/// A forwarded command still waiting for its reply from upstream.
+struct PendingCommand {
+    name: String,
+    sent_at: Instant,
+    /// Replies still owed, for pub/sub commands answered with several
+    expected: Expected,
+    /// The bytes sent upstream, kept to replay after reconnecting
+    frame: Option<Bytes>,
+    /// The protocol version a `HELLO` switches to once it succeeds
+    hello: Option<RespVersion>,
+    /// Answered by the proxy in its turn rather than by upstream: a local reply queued
+    /// behind earlier commands, or a command sent to an upstream that went away and not
+    /// safe to replay
+    answer: Option<Bytes>,
+    /// The command as captured for `/samples`, if it was sampled
+    sample: Option<String>,
+}
+
+/// Where sampled commands go and which ones are taken, with `--sample-rate`.
+struct Sampling {
+    samples: Arc<CommandSamples>,
+    sampler: Sampler,
+    /// The verbose-log policy, without its argument length limit
+    redactor: Redactor,
+}
+
+/// Per-connection command handling: counts commands, applies proxy policy and
+/// matches upstream replies to the commands that caused them.
+struct Session {
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    log_sampler: Sampler,
+    redactor: Redactor,
+    /// Client address, for attributing slow commands
+    peer: SocketAddr,
+    /// Forwarded commands in the order their replies are expected
+    pending: VecDeque<PendingCommand>,
+    /// False unless [`Config::tracks_replies`], or once either stream stopped parsing
+    /// since replies can no longer be matched
+    track_replies: bool,
+    /// Timing log and this connection's track in it
+    timing: Option<(Arc<TimingLog>, u64)>,
+    /// Keep the frames of commands awaiting replies so they can be replayed
+    keep_frames: bool,
+    /// Commands forwarded on this connection
+    commands: u64,
+    /// Forwarded commands by name, kept only with `--connection-top-commands`
+    command_counts: Option<HashMap<String, u64>>,
+    /// Most commands read from the client at once on this connection
+    max_pipeline_depth: u64,
+    /// Channels and patterns this connection is subscribed to
+    subscriptions: Subscriptions,
+    /// Protocol upstream replies are encoded in, as last negotiated with `HELLO`
+    resp_version: RespVersion,
+    /// Between a forwarded `MULTI` and its `EXEC` or `DISCARD`
+    in_transaction: bool,
+    /// Forwarded a command such as `SELECT` or `HELLO` whose effect a new upstream
+    /// connection would not have
+    connection_state_changed: bool,
+    /// Upstream is lost and commands are queued until it reconnects
+    upstream_lost: bool,
+    /// Budget shared by all connections with `--max-total-bps`
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// This connection's budget with `--max-commands-per-sec`
+    rate: Option<CommandRate>,
+    /// Budget shared by all connections with `--global-max-commands-per-sec`
+    global_rate: Option<Arc<GlobalRate>>,
+    /// When the last global slot reserved for the commands being processed comes up
+    global_slot: Option<Instant>,
+    sampling: Option<Sampling>,
+}
+
+impl Session {
+    fn new(config: Arc<Config>, stats: Arc<Stats>, peer: SocketAddr) -> Self {
+        let log_sampler = Sampler::new(config.log_sample_rate);
+        let redactor = Redactor::new(&config);
+        let track_replies = config.tracks_replies();
+        let keep_frames = config.upstream_unavailable_queue_time.is_some();
+        let top_commands = config.connection_top_commands;
+        let rate = config
+            .max_commands_per_sec
+            .map(|per_sec| CommandRate::new(per_sec, stats.clock().now()));
+        Self {
+            config,
+            stats,
+            log_sampler,
+            redactor,
+            peer,
+            pending: VecDeque::new(),
+            track_replies,
+            timing: None,
+            keep_frames,
+            commands: 0,
+            command_counts: top_commands.map(|_| HashMap::new()),
+            max_pipeline_depth: 0,
+            subscriptions: Subscriptions::default(),
+            resp_version: RespVersion::default(),
+            in_transaction: false,
+            connection_state_changed: false,
+            upstream_lost: false,
+            bandwidth: None,
+            rate,
+            global_rate: None,
+            global_slot: None,
+            sampling: None,
+        }
+    }
+
+    /// Record command timings for this connection to `log`.
+    fn with_timing(mut self, log: Arc<TimingLog>) -> Self {
+        let track = log.register_connection();
+        self.timing = Some((log, track));
+        self
+    }
+
+    /// Capture `--sample-rate` of forwarded commands into `samples`.
+    fn with_samples(mut self, samples: Arc<CommandSamples>) -> Self {
+        self.sampling = Some(Sampling {
+            samples,
+            sampler: Sampler::new(self.config.sample_rate),
+            redactor: self.redactor.clone().with_max_len(MAX_SAMPLE_TEXT),
+        });
+        self
+    }
+
+    /// The command as captured if it is sampled; recorded right away if no reply will
+    /// be matched to it.
+    fn sample_command(&mut self, cmd: &Command) -> Option<String> {
+        let sampling = self.sampling.as_mut()?;
+        if !sampling.sampler.sample() {
+            return None;
+        }
+        let command = sampling.redactor.describe(cmd);
+        if self.track_replies {
+            return Some(command);
+        }
+        sampling.samples.record(Sample {
+            command,
+            reply: None,
+            duration: None,
+            timestamp_ms: unix_millis(),
+            peer: self.peer,
+        });
+        None
+    }
+
+    /// Follow `MULTI` ... `EXEC`/`DISCARD` on this connection, counting each `EXEC` that
+    /// completes a transaction.
+    fn track_transaction(&mut self, cmd: &Command) {
+        let name = cmd.name.as_str();
+        if name.eq_ignore_ascii_case("MULTI") {
+            self.in_transaction = true;
+        } else if name.eq_ignore_ascii_case("EXEC") {
+            if self.in_transaction {
+                self.stats.record_transaction();
+            }
+            self.in_transaction = false;
+        } else if name.eq_ignore_ascii_case("DISCARD") {
+            self.in_transaction = false;
+        }
+    }
+
+    /// This connection's most forwarded commands with their counts, most frequent first,
+    /// up to `--connection-top-commands` of them.
+    fn top_commands(&self) -> Vec<(String, u64)> {
+        let Some(counts) = &self.command_counts else {
+            return Vec::new();
+        };
+        let mut top: Vec<_> = counts.iter().map(|(name, &count)| (name.clone(), count)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(self.config.connection_top_commands.unwrap_or_default());
+        top
+    }
+
+    /// The reply refusing a command about to be forwarded, if it goes over
+    /// `--max-commands-per-sec` or the global limit's queue is full. Commands let through
+    /// may still have to wait; see [`Session::rate_delay`].
+    fn rate_limit_reply(&mut self, now: Instant) -> Option<&'static str> {
+        if let Some(rate) = &mut self.rate {
+            match self.config.rate_limit_action {
+                RateLimitAction::Reject => {
+                    if !rate.try_take(now) {
+                        self.stats.record_rate_limited_command();
+                        return Some(RATE_LIMITED_REPLY);
+                    }
+                }
+                RateLimitAction::Delay => rate.take(now),
+            }
+        }
+        if let Some(global) = &self.global_rate {
+            match global.reserve(now) {
+                Some(wait) => {
+                    let until = now + wait;
+                    self.global_slot = Some(self.global_slot.map_or(until, |s| s.max(until)));
+                }
+                None => {
+                    self.stats.record_rate_limited_command();
+                    return Some(GLOBAL_RATE_LIMITED_REPLY);
+                }
+            }
+        }
+        None
+    }
+
+    /// How long to hold back the commands just processed so they stay within
+    /// `--max-commands-per-sec` with `--rate-limit-action delay`, and wait for their
+    /// slots under `--global-max-commands-per-sec`.
+    fn rate_delay(&mut self) -> Duration {
+        let now = self.stats.clock().now();
+        let local = match &mut self.rate {
+            Some(rate) if self.config.rate_limit_action == RateLimitAction::Delay => {
+                rate.wait(now)
+            }
+            _ => Duration::ZERO,
+        };
+        let global = self
+            .global_slot
+            .take()
+            .map_or(Duration::ZERO, |slot| slot.saturating_duration_since(now));
+        local.max(global)
+    }
+
+    /// Wait until the shared bandwidth budget covers what was just read from either side.
+    async fn pace(&self, read: &io::Result<usize>) {
+        if let (Some(bandwidth), Ok(bytes)) = (&self.bandwidth, read) {
+            bandwidth.consume(*bytes).await;
+        }
+    }
+
+    /// Process buffered client bytes. Complete commands are removed from `buf`
+    /// and either appended to `to_upstream` or answered locally via `to_client`.
+    /// A trailing partial command stays in `buf` until more data arrives.
+    ///
+    /// At most `--max-commands-per-batch` commands are processed per call; returns
+    /// whether complete commands were left in `buf` for the next one. Fails if the
+    /// buffered partial command can never complete within limits.
+    fn process_client_data(
+        &mut self,
+        buf: &mut BytesMut,
+        to_upstream: &mut Vec<u8>,
+        to_client: &mut Vec<u8>,
+    ) -> Result<bool> {
+        let parsed = parse_command_frames_limited(buf, self.config.max_bulk_len);
+        let mut forwarded = 0;
+        let now = self.stats.clock().now();
+
+        let depth = parsed.commands.len() as u64;
+        if depth > self.max_pipeline_depth {
+            self.max_pipeline_depth = depth;
+            self.stats.record_pipeline_depth(depth);
+        }
+
+        let limit = self.config.max_commands_per_batch.unwrap_or(usize::MAX).max(1);
+        let batch = &parsed.commands[..parsed.commands.len().min(limit)];
+        let more = batch.len() < parsed.commands.len();
+
+        for cmd in batch {
+            self.log_command(cmd);
+
+            let inline = buf[cmd.span.start] != b'*';
+            let reply = if inline && self.too_many_inline_args(cmd) {
+                Some("-ERR Protocol error: too many arguments in inline request\r\n".to_string())
+            } else {
+                self.local_reply(cmd)
+            };
+            // Only commands bound for upstream count against the rate
+            let reply = reply.or_else(|| self.rate_limit_reply(now).map(String::from));
+            if let Some(reply) = reply {
+                debug!("Answered locally: {}", cmd.name);
+                to_upstream.extend_from_slice(&buf[forwarded..cmd.span.start]);
+                self.answer_locally(cmd, reply, now, to_client);
+                forwarded = cmd.span.end;
+                continue;
+            }
+
+            self.stats.record_command(&cmd.name);
+            if self.config.protocol == Protocol::Redis
+                && let Some(expiry) = ttl::expiry(cmd, SystemTime::now())
+            {
+                self.stats.record_expiry(&cmd.name, expiry);
+            }
+            if self.config.protocol == Protocol::Redis
+                && let Some(keys) = key_count(cmd)
+            {
+                self.stats.record_key_count(&cmd.name, keys);
+            }
+            if self.config.protocol == Protocol::Redis {
+                self.track_transaction(cmd);
+                if self.keep_frames && replay::changes_connection_state(cmd) {
+                    self.connection_state_changed = true;
+                }
+            }
+            self.commands += 1;
+            if let Some(counts) = &mut self.command_counts {
+                let mut name = self.stats.command_key(&cmd.name);
+                if counts.len() >= MAX_CONNECTION_COMMANDS && !counts.contains_key(&name) {
+                    name = OTHER_COMMANDS.to_string();
+                }
+                *counts.entry(name).or_default() += 1;
+            }
+
+            let sample = self.sample_command(cmd);
+            let renamed = self.renamed(cmd).map(|to| {
+                debug!("Renaming command {} to {}", cmd.name, to);
+                rename_command(&buf[cmd.span.clone()], to.as_bytes())
+            });
+            if self.track_replies {
+                let frame = self.keep_frames.then(|| match &renamed {
+                    Some(frame) => Bytes::copy_from_slice(frame),
+                    None => Bytes::copy_from_slice(&buf[cmd.span.clone()]),
+                });
+                self.pending.push_back(PendingCommand {
+                    name: cmd.name.clone(),
+                    sent_at: now,
+                    expected: pubsub::expected(cmd),
+                    frame,
+                    hello: hello_version(cmd),
+                    answer: None,
+                    sample,
+                });
+            }
+            if let Some(frame) = renamed {
+                to_upstream.extend_from_slice(&buf[forwarded..cmd.span.start]);
+                to_upstream.extend_from_slice(&frame);
+                forwarded = cmd.span.end;
+            }
+        }
+
+        // Bytes that will never parse are passed through untouched and left to upstream to
+        // reject, except a header that never ends, which would be buffered without bound
+        let consumed = if more {
+            batch[batch.len() - 1].span.end
+        } else if parsed.header_too_long {
+            self.stats.record_malformed_parse();
+            parsed.consumed
+        } else if parsed.malformed && self.upstream_lost {
+            // Nothing reaches upstream while it is lost, so the queued commands would never
+            // be answered once tracking stopped
+            self.stats.record_malformed_parse();
+            self.fail_pending(UNAVAILABLE_REPLY, to_client);
+            let reason = "malformed command while upstream unavailable";
+            return Err(ProxyError::Protocol(reason.to_string()));
+        } else if parsed.malformed {
+            self.stats.record_malformed_parse();
+            self.stop_tracking_replies(to_client);
+            buf.len()
+        } else {
+            parsed.consumed
+        };
+        let parsed_bytes = if more { consumed } else { parsed.consumed };
+        let incomplete = !more && !parsed.malformed && consumed < buf.len();
+        self.stats.record_parse(parsed_bytes, batch.len(), incomplete);
+        to_upstream.extend_from_slice(&buf[forwarded..consumed]);
+        buf.advance(consumed);
+
+        // An inline command is only complete at CRLF, so without a limit a client could
+        // grow the buffer forever
+        if !more
+            && buf.first().is_some_and(|&b| b != b'*')
+            && buf.len() as u64 > self.config.max_inline_length
+        {
+            return Err(ProxyError::Protocol("too big inline request".to_string()));
+        }
+        if !more && parsed.header_too_long {
+            return Err(ProxyError::Protocol("too big count string".to_string()));
+        }
+        Ok(more)
+    }
+
+    /// Process buffered upstream bytes. Complete replies are moved from `buf` to
+    /// `to_client` and matched, in order, to the commands awaiting them. A trailing
+    /// partial reply stays in `buf` until more data arrives.
+    fn process_upstream_data(&mut self, buf: &mut BytesMut, to_client: &mut Vec<u8>) {
+        if !self.track_replies {
+            to_client.extend_from_slice(buf);
+            buf.clear();
+            return;
+        }
+
+        // A HELLO's reply is already in the version it asks for, and RESP3 parsing accepts
+        // RESP2 too
+        let awaiting_resp3 = self
+            .pending
+            .iter()
+            .any(|cmd| cmd.hello == Some(RespVersion::Resp3));
+        let version = if awaiting_resp3 {
+            RespVersion::Resp3
+        } else {
+            self.resp_version
+        };
+        let parsed = parse_reply_frames(buf, version);
+        let now = self.stats.clock().now();
+        let mut forwarded = 0;
+        for reply in &parsed.replies {
+            // Commands the proxy answers itself go once the replies before them are through
+            if self.pending.front().is_some_and(|cmd| cmd.answer.is_some()) {
+                to_client.extend_from_slice(&buf[forwarded..reply.span.start]);
+                forwarded = reply.span.start;
+                self.answer_in_turn(to_client);
+            }
+            let frame = &buf[reply.span.clone()];
+            let event = pubsub::event(frame);
+            if let Some(Event::Confirmation {
+                family,
+                subscribe,
+                channel,
+            }) = &event
+            {
+                let awaited = self.awaits_confirmation(*family);
+                // RESP3 confirmations are pushes and may also arrive unprompted
+                if awaited || reply.kind == b'>' {
+                    self.subscriptions.apply(*family, *subscribe, channel);
+                    debug!("Active subscriptions: {}", self.subscriptions.total());
+                    if awaited {
+                        self.confirm(*family, frame, now);
+                    }
+                    continue;
+                }
+            }
+            // Pushes arrive out of band and don't answer any command
+            if reply.kind == b'>' {
+                self.handle_push(frame);
+                continue;
+            }
+            // As do messages delivered to a RESP2 subscriber
+            if event == Some(Event::Message) && self.subscriptions.total() > 0 {
+                continue;
+            }
+            if let Some(class) = error_class(frame) {
+                self.stats.record_error_reply(&class);
+                if let Some(redirect) = redirect(frame) {
+                    self.stats.record_redirect(&redirect);
+                }
+            }
+            if let Some(cmd) = self.pending.pop_front() {
+                if let Some(version) = cmd.hello
+                    && !matches!(reply.kind, b'-' | b'!')
+                {
+                    debug!("Upstream replies switched to {:?}", version);
+                    self.resp_version = version;
+                }
+                self.finish_command(cmd, frame, now);
+            }
+        }
+
+        let consumed = if parsed.malformed {
+            warn!("Unparseable reply from upstream, no longer matching replies to commands");
+            to_client.extend_from_slice(&buf[forwarded..]);
+            forwarded = buf.len();
+            self.stop_tracking_replies(to_client);
+            buf.len()
+        } else {
+            parsed.consumed
+        };
+        to_client.extend_from_slice(&buf[forwarded..consumed]);
+        buf.advance(consumed);
+        self.answer_in_turn(to_client);
+        if self.pending.is_empty() {
+            self.pending.shrink_to(RETAINED_PENDING);
+        }
+    }
+
+    /// Whether the oldest command awaiting a reply is waiting for pub/sub
+    /// confirmations of `family`.
+    fn awaits_confirmation(&self, family: Family) -> bool {
+        self.pending.front().is_some_and(|cmd| match cmd.expected {
+            Expected::Confirmations(f, _) | Expected::UntilUnsubscribed(f) => f == family,
+            Expected::One => false,
+        })
+    }
+
+    /// Count a confirmation towards the oldest command, finishing it once the last one
+    /// it is owed has arrived.
+    fn confirm(&mut self, family: Family, frame: &[u8], now: Instant) {
+        let Some(cmd) = self.pending.front_mut() else {
+            return;
+        };
+        let done = match &mut cmd.expected {
+            Expected::Confirmations(_, remaining) => {
+                *remaining -= 1;
+                *remaining == 0
+            }
+            Expected::UntilUnsubscribed(_) => self.subscriptions.count(family) == 0,
+            Expected::One => true,
+        };
+        if done && let Some(cmd) = self.pending.pop_front() {
+            self.finish_command(cmd, frame, now);
+        }
+    }
+
+    /// Account for a RESP3 push frame from upstream.
+    fn handle_push(&self, frame: &[u8]) {
+        let kind = push_type(frame);
+        debug!("Push from upstream: {}", kind.as_deref().unwrap_or("?"));
+        if self.config.protocol == Protocol::Redis
+            && kind.is_some_and(|kind| kind.eq_ignore_ascii_case("invalidate"))
+        {
+            self.stats.record_invalidation();
+        }
+    }
+
+    /// Account for a command whose reply, ending with `frame`, has arrived.
+    fn finish_command(&self, cmd: PendingCommand, frame: &[u8], replied_at: Instant) {
+        let duration = replied_at.saturating_duration_since(cmd.sent_at);
+        self.stats.record_command_latency(&cmd.name, duration, self.peer);
+        if let (Some(sampling), Some(command)) = (&self.sampling, cmd.sample) {
+            sampling.samples.record(Sample {
+                command,
+                reply: Some(sampling.redactor.describe_reply(&cmd.name, frame)),
+                duration: Some(duration),
+                timestamp_ms: unix_millis(),
+                peer: self.peer,
+            });
+        }
+        if let Some((log, track)) = &self.timing {
+            log.record(*track, &cmd.name, cmd.sent_at, replied_at);
+        }
+    }
+
+    /// Whether `buffered` bytes held for this connection exceed
+    /// `--max-connection-buffer-bytes`, logging it if so.
+    fn over_buffer_limit(&self, buffered: usize) -> bool {
+        match self.config.max_connection_buffer_bytes {
+            Some(max) if buffered as u64 > max => {
+                warn!(
+                    "Closing connection from {}: {} bytes buffered, more than \
+                     --max-connection-buffer-bytes ({})",
+                    self.peer, buffered, max
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the client is waiting on upstream, for a reply to a command such as
+    /// `BLPOP` or for messages on its subscriptions, and so not idle however quiet.
+    fn waiting_on_upstream(&self) -> bool {
+        !self.pending.is_empty() || self.subscriptions.total() > 0
+    }
+
+    /// Whether every command awaiting a reply can be sent again to a new upstream.
+    fn can_replay(&self) -> bool {
+        self.track_replies && self.keep_frames
+    }
+
+    /// Whether the client relies on state of its upstream connection: set up by commands
+    /// such as `SELECT`, or an open transaction or subscription.
+    fn needs_same_upstream(&self) -> bool {
+        self.connection_state_changed || self.in_transaction || self.subscriptions.total() > 0
+    }
+
+    /// Give up on the commands that may have run on a lost upstream and are not safe to
+    /// run again, returning how many. Called as the upstream is lost, so every command
+    /// pending was sent to it.
+    fn abandon_unsafe_replays(&mut self) -> usize {
+        let mut abandoned = 0;
+        for cmd in &mut self.pending {
+            if cmd.frame.is_some() && !replay::is_idempotent(&cmd.name) {
+                cmd.frame = None;
+                cmd.answer = Some(Bytes::from_static(NOT_REPLAYED_REPLY.as_bytes()));
+                abandoned += 1;
+            }
+        }
+        abandoned
+    }
+
+    /// Answer a command without forwarding it. While replies are matched to commands it
+    /// waits its turn behind the commands before it, so a pipelining client gets every
+    /// reply in order.
+    fn answer_locally(
+        &mut self,
+        cmd: &Command,
+        reply: String,
+        now: Instant,
+        to_client: &mut Vec<u8>,
+    ) {
+        if !self.track_replies || self.pending.is_empty() {
+            to_client.extend_from_slice(reply.as_bytes());
+            return;
+        }
+        self.pending.push_back(PendingCommand {
+            name: cmd.name.clone(),
+            sent_at: now,
+            expected: Expected::One,
+            frame: None,
+            hello: None,
+            answer: Some(Bytes::from(reply)),
+            sample: None,
+        });
+    }
+
+    /// Answer the commands at the front of the queue that the proxy answers itself,
+    /// whose turn has come.
+    fn answer_in_turn(&mut self, to_client: &mut Vec<u8>) {
+        while let Some(answer) = self.pending.front().and_then(|cmd| cmd.answer.clone()) {
+            self.pending.pop_front();
+            to_client.extend_from_slice(&answer);
+        }
+    }
+
+    /// The commands awaiting replies, in order, as they were sent upstream.
+    fn replay_frames(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for cmd in &self.pending {
+            if let Some(frame) = &cmd.frame {
+                out.extend_from_slice(frame);
+            }
+        }
+        out
+    }
+
+    /// Answer every command still awaiting a reply with `reply`, and those the proxy
+    /// answers itself with their own answer.
+    fn fail_pending(&mut self, reply: &str, to_client: &mut Vec<u8>) {
+        for cmd in self.pending.drain(..) {
+            to_client.extend_from_slice(cmd.answer.as_deref().unwrap_or(reply.as_bytes()));
+        }
+    }
+
+    /// Give up on matching replies for the rest of the connection. Answers the proxy still
+    /// owes are sent right away, as their turn can no longer be told.
+    fn stop_tracking_replies(&mut self, to_client: &mut Vec<u8>) {
+        self.track_replies = false;
+        for cmd in self.pending.drain(..) {
+            if let Some(answer) = cmd.answer {
+                to_client.extend_from_slice(&answer);
+            }
+        }
+    }
+
+    /// Log a command, at info level for the sampled fraction in verbose mode. With
+    /// `--log-format json` the sampled fraction is logged as an access event instead,
+    /// verbose or not.
+    fn log_command(&mut self, cmd: &Command) {
+        if self.config.log_format == LogFormat::Json {
+            if self.log_sampler.sample() {
+                info!(
+                    peer = %self.peer,
+                    command = %cmd.name,
+                    args = cmd.args.len(),
+                    bytes = cmd.span.len(),
+                    line = %self.redactor.describe(cmd),
+                    "command"
+                );
+            }
+        } else if self.config.verbose && self.log_sampler.sample() {
+            info!("Command: {}", self.redactor.describe(cmd));
+        } else {
+            debug!("Command: {}", cmd.name);
+        }
+    }
+
+    /// Whether an inline command has more words than `--max-inline-args` allows.
+    fn too_many_inline_args(&self, cmd: &Command) -> bool {
+        self.config
+            .max_inline_args
+            .is_some_and(|max| cmd.args.len() + 1 > max)
+    }
+
+    /// Whether `--deny-command` names the command, or `--allow-command` doesn't.
+    fn blocked(&self, cmd: &Command) -> bool {
+        let listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(&cmd.name));
+        listed(&self.config.deny_command)
+            || (!self.config.allow_command.is_empty() && !listed(&self.config.allow_command))
+    }
+
+    /// Returns the upstream name for a command renamed with `--rename`.
+    fn renamed(&self, cmd: &Command) -> Option<&str> {
+        self.config
+            .rename
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(&cmd.name))
+            .map(|(_, to)| to.as_str())
+    }
+
+    /// Whether a SELECT asks for a database above `--max-select-db`. Indexes that
+    /// don't parse are left for upstream to reject.
+    fn select_out_of_range(&self, cmd: &Command) -> bool {
+        let Some(max) = self.config.max_select_db else {
+            return false;
+        };
+        cmd.name.eq_ignore_ascii_case("SELECT")
+            && cmd
+                .args
+                .first()
+                .and_then(|db| db.parse::<u64>().ok())
+                .is_some_and(|db| db > u64::from(max))
+    }
+
+    /// Returns the reply to send if the command is answered by the proxy instead of
+    /// being forwarded (policy rejections and proxy-local commands).
+    fn local_reply(&self, cmd: &Command) -> Option<String> {
+        if self.blocked(cmd) {
+            self.stats.record_blocked_command();
+            return Some(BLOCKED_REPLY.to_string());
+        }
+        if self.config.read_only && commands::writes(cmd) {
+            return Some(READ_ONLY_REPLY.to_string());
+        }
+        if self.config.block_select && cmd.name.eq_ignore_ascii_case("SELECT") {
+            return Some("-ERR SELECT is not allowed by proxy policy\r\n".to_string());
+        }
+        if self.select_out_of_range(cmd) {
+            return Some("-ERR DB index is out of range\r\n".to_string());
+        }
+        if let Some(max) = self.config.max_keys_per_command
+            && let Some(keys) = key_count(cmd).filter(|&keys| keys > max)
+        {
+            return Some(format!(
+                "-ERR {} touches {} keys, more than the proxy allows ({})\r\n",
+                cmd.name, keys, max
+            ));
+        }
+        if !self.config.auth_passthrough && cmd.name.eq_ignore_ascii_case("AUTH") {
+            return Some("+OK\r\n".to_string());
+        }
+        if self.config.proxy_info
+            && let Some(section) = info_request(cmd)
+        {
+            let info = format_info(&self.stats, &section, &self.config.percentiles);
+            return Some(info_reply(&info));
+        }
+        None
+    }
+}
+
+/// Why a proxied connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    ClientClosed,
+    UpstreamClosed,
+    /// The client reset the connection or went away mid-write
+    ClientReset,
+    /// Upstream reset the connection or went away mid-write
+    UpstreamReset,
+    ClientError,
+    UpstreamError,
+    ProtocolError,
+    /// Nothing read from either side for `--idle-timeout-secs`
+    IdleTimeout,
+    /// Buffered more than `--max-connection-buffer-bytes`
+    BufferOverflow,
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::UpstreamClosed => "upstream_closed",
+            CloseReason::ClientReset => "client_reset",
+            CloseReason::UpstreamReset => "upstream_reset",
+            CloseReason::ClientError => "client_error",
+            CloseReason::UpstreamError => "upstream_error",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::BufferOverflow => "buffer_overflow",
+        }
+    }
+
+    /// The coarse reason code logged and counted for this close.
+    pub fn code(self) -> CloseCode {
+        match self {
+            CloseReason::ClientClosed => CloseCode::ClientClosed,
+            CloseReason::UpstreamClosed => CloseCode::UpstreamClosed,
+            CloseReason::ClientReset | CloseReason::UpstreamReset => CloseCode::Reset,
+            CloseReason::ClientError
+            | CloseReason::UpstreamError
+            | CloseReason::ProtocolError
+            | CloseReason::BufferOverflow => CloseCode::Error,
+            CloseReason::IdleTimeout => CloseCode::IdleTimeout,
+        }
+    }
+}
+
+/// Reason code for a closed client connection, aggregated in stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    ClientClosed,
+    UpstreamClosed,
+    /// Either side reset the connection instead of closing it cleanly
+    Reset,
+    /// Closed by the proxy after `--idle-timeout-secs` without traffic
+    IdleTimeout,
+    /// Still open when the proxy shut down
+    Shutdown,
+    /// Any failure, including upstream connection setup
+    Error,
+}
+
+impl CloseCode {
+    pub const ALL: [CloseCode; 6] = [
+        CloseCode::ClientClosed,
+        CloseCode::UpstreamClosed,
+        CloseCode::Reset,
+        CloseCode::IdleTimeout,
+        CloseCode::Shutdown,
+        CloseCode::Error,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseCode::ClientClosed => "client_closed",
+            CloseCode::UpstreamClosed => "upstream_closed",
+            CloseCode::Reset => "reset",
+            CloseCode::IdleTimeout => "idle_timeout",
+            CloseCode::Shutdown => "shutdown",
+            CloseCode::Error => "error",
+        }
+    }
+}
+
+/// Whether `e` means the peer dropped the connection rather than closing it cleanly.
+fn is_reset(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Log a failed read from or write to the client and classify it.
+fn client_failure(action: &str, e: &io::Error) -> CloseReason {
+    if is_reset(e) {
+        info!("Client reset the connection: {}", e);
+        CloseReason::ClientReset
+    } else {
+        error!("Failed to {} client: {}", action, e);
+        CloseReason::ClientError
+    }
+}
+
+/// Log a failed read from or write to upstream and classify it.
+fn upstream_failure(action: &str, e: &io::Error) -> CloseReason {
+    if is_reset(e) {
+        warn!("Upstream reset the connection: {}", e);
+        CloseReason::UpstreamReset
+    } else {
+        error!("Failed to {} upstream: {}", action, e);
+        CloseReason::UpstreamError
+    }
+}
+
+/// What happened over the lifetime of a proxied connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionSummary {
+    /// Commands forwarded to upstream
+    pub commands: u64,
+    /// Most commands the client sent at once
+    pub max_pipeline_depth: u64,
+    /// Bytes read from the client
+    pub bytes_in: u64,
+    /// Bytes written to the client
+    pub bytes_out: u64,
+    /// Most forwarded commands, with `--connection-top-commands`
+    pub top_commands: Vec<(String, u64)>,
+    pub reason: CloseReason,
+}
+
+/// Client stream that counts the bytes passing through it.
+struct Counted<S> {
+    inner: S,
+    read: u64,
+    written: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.read += (buf.filled().len() - before) as u64;
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.written += written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Opens a replacement upstream connection, ready to carry client commands.
+pub type Reconnect<U> = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = Result<U>> + Send>> + Send>;
+
+/// Everything a proxied connection needs besides its two streams.
+pub struct ProxyContext<U> {
+    pub peer: SocketAddr,
+    pub stats: Arc<Stats>,
+    pub config: Arc<Config>,
+    pub timing: Option<Arc<TimingLog>>,
+    pub samples: Option<Arc<CommandSamples>>,
+    pub buffers: Arc<BufferPool>,
+    pub bandwidth: Option<Arc<BandwidthLimiter>>,
+    pub global_rate: Option<Arc<GlobalRate>>,
+    /// Used with `--upstream-unavailable-queue-time` to replace a failed upstream
+    pub reconnect: Option<Reconnect<U>>,
+}
+
+impl<U> ProxyContext<U> {
+    pub fn new(peer: SocketAddr, stats: Arc<Stats>, config: Arc<Config>) -> Self {
+        Self {
+            peer,
+            stats,
+            config,
+            timing: None,
+            samples: None,
+            buffers: BufferPool::new(),
+            bandwidth: None,
+            global_rate: None,
+            reconnect: None,
+        }
+    }
+}
+
+/// How an upstream outage ended.
+enum Outage<U> {
+    /// A new upstream is connected and every command awaiting a reply was resent to it
+    Reconnected(U),
+    Closed(CloseReason),
+}
 
 /// Proxy data bidirectionally between client and upstream connections,
 /// counting Redis commands in the client->upstream direction.
-pub async fn proxy_connection<C, U>(mut client: C, mut upstream: U, stats: Arc<Stats>)
+pub async fn proxy_connection<C, U>(
+    client: C,
+    mut upstream: U,
+    ctx: ProxyContext<U>,
+) -> ConnectionSummary
 where
     C: AsyncRead + AsyncWrite + Unpin,
     U: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut client_buf = BytesMut::with_capacity(8192);
-    let mut upstream_buf = BytesMut::with_capacity(8192);
-    let mut client_temp = [0u8; 8192];
-    let mut upstream_temp = [0u8; 8192];
+    let ProxyContext {
+        peer,
+        stats,
+        config,
+        timing,
+        samples,
+        buffers,
+        bandwidth,
+        global_rate,
+        mut reconnect,
+    } = ctx;
+    let mut client = Counted::new(client);
+    let queue_time = config.upstream_unavailable_queue_time;
+    let queue_size = config.upstream_unavailable_queue_size;
+    let idle_timeout = (config.idle_timeout_secs > 0)
+        .then(|| Duration::from_secs(config.idle_timeout_secs));
+    let clock = stats.clock().clone();
+    let mut session = Session::new(config, stats, peer);
+    if let Some(log) = timing {
+        session = session.with_timing(log);
+    }
+    if let Some(samples) = samples {
+        session = session.with_samples(samples);
+    }
+    session.bandwidth = bandwidth;
+    session.global_rate = global_rate;
+    let mut client_buf = buffers.get();
+    let mut upstream_buf = buffers.get();
+    let mut to_upstream = Vec::with_capacity(8192);
+    let mut to_client = Vec::new();
 
-    loop {
-        tokio::select! {
-            // Client -> Upstream (parse commands)
-            result = client.read(&mut client_temp) => {
-                match result {
-                    Ok(0) => {
-                        debug!("Client disconnected");
-                        break;
-                    }
-                    Ok(n) => {
-                        client_buf.extend_from_slice(&client_temp[..n]);
-
-                        // Parse and count commands
-                        let (commands, _consumed) = parse_commands(&client_buf);
-                        for cmd in &commands {
-                            debug!("Command: {}", cmd);
-                            stats.record_command(cmd);
+    let reason = 'session: loop {
+        let lost = 'connected: loop {
+            // Partial commands and replies wait here for the rest of their bytes
+            if session.over_buffer_limit(client_buf.len() + upstream_buf.len()) {
+                break 'session CloseReason::BufferOverflow;
+            }
+            client_buf.reserve(READ_CHUNK);
+            upstream_buf.reserve(READ_CHUNK);
+            // Restarted with every read from either side
+            let idle = async {
+                match idle_timeout {
+                    Some(timeout) => clock.sleep(timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                // Client -> Upstream (parse commands)
+                result = client.read_buf(&mut *client_buf) => {
+                    session.pace(&result).await;
+                    match result {
+                        Ok(0) => {
+                            debug!("Client disconnected");
+                            break 'session CloseReason::ClientClosed;
                         }
+                        // Parse and count commands in batches, keeping any partial command
+                        // buffered
+                        Ok(_) => loop {
+                            let result = session.process_client_data(
+                                &mut client_buf,
+                                &mut to_upstream,
+                                &mut to_client,
+                            );
+                            if let Err(ProxyError::Protocol(reason)) = &result {
+                                let reply = format!("-ERR Protocol error: {}\r\n", reason);
+                                to_client.extend_from_slice(reply.as_bytes());
+                            }
 
-                        // Forward all data to upstream
-                        if let Err(e) = upstream.write_all(&client_buf).await {
-                            error!("Failed to write to upstream: {}", e);
-                            break;
+                            if !to_client.is_empty() {
+                                if let Err(e) = client.write_all(&to_client).await {
+                                    break 'session client_failure("write to", &e);
+                                }
+                                to_client.clear();
+                            }
+
+                            let delay = session.rate_delay();
+                            if !delay.is_zero() {
+                                clock.sleep(delay).await;
+                            }
+                            let written = upstream.write_all(&to_upstream).await;
+                            to_upstream.clear();
+                            let more = match result {
+                                Ok(more) => more,
+                                Err(e) => {
+                                    warn!("Closing malformed client connection: {}", e);
+                                    break 'session CloseReason::ProtocolError;
+                                }
+                            };
+                            if let Err(e) = written {
+                                break 'connected upstream_failure("write to", &e);
+                            }
+                            if !more {
+                                break;
+                            }
+                            // Let other connections run before the next batch
+                            tokio::task::yield_now().await;
+                        },
+                        Err(e) => break 'session client_failure("read from", &e),
+                    }
+                }
+
+                // Upstream -> Client (match replies to commands)
+                result = upstream.read_buf(&mut *upstream_buf) => {
+                    session.pace(&result).await;
+                    match result {
+                        Ok(0) => {
+                            debug!("Upstream disconnected");
+                            break CloseReason::UpstreamClosed;
                         }
-                        client_buf.clear();
+                        Ok(_) => {
+                            session.process_upstream_data(&mut upstream_buf, &mut to_client);
+                            if let Err(e) = client.write_all(&to_client).await {
+                                break 'session client_failure("write to", &e);
+                            }
+                            to_client.clear();
+                        }
+                        Err(e) => break upstream_failure("read from", &e),
                     }
-                    Err(e) => {
-                        error!("Failed to read from client: {}", e);
-                        break;
+                }
+
+                () = idle => {
+                    if session.waiting_on_upstream() {
+                        continue;
                     }
+                    info!(
+                        "Closing connection from {}: idle for {}s",
+                        peer,
+                        idle_timeout.unwrap_or_default().as_secs()
+                    );
+                    break 'session CloseReason::IdleTimeout;
+                }
+            }
+        };
+
+        let (Some(window), Some(reconnect)) = (queue_time, reconnect.as_mut()) else {
+            break lost;
+        };
+        if !session.can_replay() {
+            break lost;
+        }
+        if session.needs_same_upstream() {
+            warn!(
+                "Lost upstream for {}, not replaying commands as the client set up state on it",
+                peer
+            );
+            session.fail_pending(NOT_REPLAYED_REPLY, &mut to_client);
+            let _ = client.write_all(&to_client).await;
+            break lost;
+        }
+        warn!("Lost upstream for {}, queueing commands for up to {:?}", peer, window);
+        let outage = ride_out_outage(
+            &mut client,
+            &mut client_buf,
+            &mut session,
+            reconnect,
+            window,
+            queue_size,
+            lost,
+        );
+        match outage.await {
+            Outage::Reconnected(replacement) => {
+                upstream = replacement;
+                // A partial reply from the old connection belongs to a replayed command
+                upstream_buf.clear();
+            }
+            Outage::Closed(reason) => break reason,
+        }
+    };
+
+    // Flush any remaining data, and end chained links with their end-of-stream frame
+    let _ = client.shutdown().await;
+    let _ = upstream.shutdown().await;
+
+    ConnectionSummary {
+        commands: session.commands,
+        max_pipeline_depth: session.max_pipeline_depth,
+        bytes_in: client.read,
+        bytes_out: client.written,
+        top_commands: session.top_commands(),
+        reason,
+    }
+}
+
+/// Keep a client connected while its upstream is unavailable: queue its commands,
+/// retry `reconnect` and resend everything awaiting a reply once it succeeds. If
+/// `window` passes or more than `max_queued` commands are waiting first, each of them
+/// is answered with an error and the connection closes with `lost`.
+async fn ride_out_outage<C, U>(
+    client: &mut C,
+    client_buf: &mut BytesMut,
+    session: &mut Session,
+    reconnect: &mut Reconnect<U>,
+    window: Duration,
+    max_queued: usize,
+    lost: CloseReason,
+) -> Outage<U>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let clock = session.stats.clock().clone();
+    let abandoned = session.abandon_unsafe_replays();
+    session.upstream_lost = true;
+    let mut expired = clock.sleep(window);
+    let mut attempt = reconnect();
+    // Queued commands are resent from their pending frames
+    let mut queued = Vec::new();
+    let mut to_client = Vec::new();
+
+    'queueing: loop {
+        if session.over_buffer_limit(client_buf.len()) {
+            return Outage::Closed(CloseReason::BufferOverflow);
+        }
+        client_buf.reserve(READ_CHUNK);
+        tokio::select! {
+            connected = &mut attempt => {
+                match connected {
+                    Ok(mut upstream) => match upstream.write_all(&session.replay_frames()).await {
+                        Ok(()) => {
+                            info!(
+                                "Upstream for {} reconnected, replayed {} commands ({} not safe \
+                                 to replay)",
+                                session.peer,
+                                session.pending.iter().filter(|c| c.frame.is_some()).count(),
+                                abandoned
+                            );
+                            session.upstream_lost = false;
+                            session.answer_in_turn(&mut to_client);
+                            if let Err(e) = client.write_all(&to_client).await {
+                                return Outage::Closed(client_failure("write to", &e));
+                            }
+                            return Outage::Reconnected(upstream);
+                        }
+                        Err(e) => debug!("Failed to replay commands to upstream: {}", e),
+                    },
+                    Err(e) => debug!("Failed to reconnect to upstream: {}", e),
                 }
+                let next = reconnect();
+                let interval = clock.sleep(RECONNECT_INTERVAL);
+                attempt = Box::pin(async move {
+                    interval.await;
+                    next.await
+                });
+            }
+
+            _ = &mut expired => {
+                warn!("Upstream for {} still unavailable after {:?}", session.peer, window);
+                break;
             }
 
-            // Upstream -> Client (pass through)
-            result = upstream.read(&mut upstream_temp) => {
+            result = client.read_buf(&mut *client_buf) => {
+                session.pace(&result).await;
                 match result {
-                    Ok(0) => {
-                        debug!("Upstream disconnected");
-                        break;
-                    }
-                    Ok(n) => {
-                        upstream_buf.extend_from_slice(&upstream_temp[..n]);
-                        if let Err(e) = client.write_all(&upstream_buf).await {
-                            error!("Failed to write to client: {}", e);
+                    Ok(0) => return Outage::Closed(CloseReason::ClientClosed),
+                    Ok(_) => loop {
+                        let result = session.process_client_data(
+                            client_buf,
+                            &mut queued,
+                            &mut to_client,
+                        );
+                        queued.clear();
+                        if let Err(ProxyError::Protocol(reason)) = &result {
+                            let reply = format!("-ERR Protocol error: {}\r\n", reason);
+                            to_client.extend_from_slice(reply.as_bytes());
+                        }
+                        if let Err(e) = client.write_all(&to_client).await {
+                            return Outage::Closed(client_failure("write to", &e));
+                        }
+                        to_client.clear();
+                        let more = match result {
+                            Ok(more) => more,
+                            Err(e) => {
+                                warn!("Closing malformed client connection: {}", e);
+                                return Outage::Closed(CloseReason::ProtocolError);
+                            }
+                        };
+                        if session.pending.len() > max_queued {
+                            warn!("More than {} commands queued for {}", max_queued, session.peer);
+                            break 'queueing;
+                        }
+                        if !more {
                             break;
                         }
-                        upstream_buf.clear();
-                    }
-                    Err(e) => {
-                        error!("Failed to read from upstream: {}", e);
-                        break;
-                    }
+                        tokio::task::yield_now().await;
+                    },
+                    Err(e) => return Outage::Closed(client_failure("read from", &e)),
                 }
             }
         }
     }
 
-    // Flush any remaining data
-    let _ = client.flush().await;
-    let _ = upstream.flush().await;
+    session.fail_pending(UNAVAILABLE_REPLY, &mut to_client);
+    let _ = client.write_all(&to_client).await;
+    Outage::Closed(lost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::sync::Notify;
+
+    use crate::clock::{Clock, MockClock};
+    use crate::resp::encode_command;
+    use crate::testing::{reply_ok, MockUpstream};
+
+    fn peer() -> SocketAddr {
+        "10.0.0.1:50000".parse().unwrap()
+    }
+
+    fn config(args: &[&str]) -> Arc<Config> {
+        let mut argv = vec!["redis-tls-proxy", "--no-tls"];
+        argv.extend_from_slice(args);
+        Arc::new(Config::parse_from(argv))
+    }
+
+    fn process(session: &mut Session, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut buf = BytesMut::from(data);
+        let mut to_upstream = Vec::new();
+        let mut to_client = Vec::new();
+        session
+            .process_client_data(&mut buf, &mut to_upstream, &mut to_client)
+            .unwrap();
+        (to_upstream, to_client)
+    }
+
+    #[test]
+    fn test_block_select_rejects_client_select() {
+        let stats = Stats::new();
+        let cfg = config(&["--force-db", "2", "--block-select"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        let (to_upstream, to_client) = process(
+            &mut session,
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n5\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        );
+
+        assert_eq!(to_upstream, b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        assert_eq!(to_client, b"-ERR SELECT is not allowed by proxy policy\r\n");
+        assert_eq!(stats.command_counts().get("SELECT"), None);
+        assert_eq!(stats.command_counts().get("GET"), Some(&1));
+    }
+
+    #[test]
+    fn test_denied_command_mid_pipeline_answered_in_turn() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on", "--deny-command", "flushall"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        let (to_upstream, to_client) = process(&mut session, b"GET a\r\nFLUSHALL\r\nGET b\r\n");
+        assert_eq!(to_upstream, b"GET a\r\nGET b\r\n");
+        // Held back until the reply to the GET before it
+        assert!(to_client.is_empty());
+
+        let mut to_client = Vec::new();
+        let mut replies = BytesMut::from(&b"$1\r\nx\r\n$1\r\ny\r\n"[..]);
+        session.process_upstream_data(&mut replies, &mut to_client);
+        let expected = [&b"$1\r\nx\r\n"[..], BLOCKED_REPLY.as_bytes(), b"$1\r\ny\r\n"].concat();
+        assert_eq!(to_client, expected);
+        assert!(session.pending.is_empty());
+        assert_eq!(stats.blocked_commands(), 1);
+        assert_eq!(stats.command_counts().get("FLUSHALL"), None);
+    }
+
+    #[test]
+    fn test_denied_command_answered_in_turn_without_parse_replies() {
+        let cfg = config(&["--deny-command", "flushall"]);
+        assert!(!cfg.parse_replies);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+
+        let (to_upstream, to_client) = process(&mut session, b"GET a\r\nFLUSHALL\r\nGET b\r\n");
+        assert_eq!(to_upstream, b"GET a\r\nGET b\r\n");
+        assert!(to_client.is_empty());
+        let to_client = reply(&mut session, b"$1\r\nx\r\n");
+        assert_eq!(to_client, [&b"$1\r\nx\r\n"[..], BLOCKED_REPLY.as_bytes()].concat());
+
+        // The same goes for every other answer the proxy gives itself
+        let cfg = config(&["--read-only", "--block-select"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let (_, to_client) = process(&mut session, b"GET a\r\nSET a 1\r\nSELECT 3\r\n");
+        assert!(to_client.is_empty());
+        let to_client = reply(&mut session, b"$1\r\nx\r\n");
+        let refusals = [READ_ONLY_REPLY, "-ERR SELECT is not allowed by proxy policy\r\n"];
+        assert_eq!(to_client, [&b"$1\r\nx\r\n"[..], refusals.concat().as_bytes()].concat());
+    }
+
+    #[test]
+    fn test_parser_stats_follow_client_data() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        let get = b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n";
+
+        // Two complete commands and the start of a third, kept for the next read
+        let mut buf = BytesMut::from(&[&b"PING\r\n"[..], get, b"*2\r\n$3\r\nGE"].concat()[..]);
+        session.process_client_data(&mut buf, &mut Vec::new(), &mut Vec::new()).unwrap();
+        let parser = stats.parser();
+        assert_eq!((parser.bytes, parser.commands, parser.incomplete), (6 + 20, 2, 1));
+
+        buf.extend_from_slice(b"T\r\n$1\r\nb\r\n");
+        session.process_client_data(&mut buf, &mut Vec::new(), &mut Vec::new()).unwrap();
+        let parser = stats.parser();
+        assert_eq!((parser.bytes, parser.commands, parser.incomplete), (6 + 40, 3, 1));
+        assert!((parser.average_command_size() - 46.0 / 3.0).abs() < 0.001);
+
+        process(&mut session, b"*abc\r\n");
+        assert_eq!((stats.parser().bytes, stats.parser().malformed), (46, 1));
+    }
+
+    #[test]
+    fn test_bulk_length_over_limit_passed_through_unparsed() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&["--max-bulk-len", "1K"]), stats.clone(), peer());
+        let data = b"PING\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4096\r\nvalue";
+        let (to_upstream, to_client) = process(&mut session, data);
+        // Nothing is held back waiting for the rest of the value; upstream rejects it
+        assert_eq!((&to_upstream[..], &to_client[..]), (&data[..], &b""[..]));
+        assert_eq!(stats.command_counts().get("PING"), Some(&1));
+        assert_eq!(stats.command_counts().get("SET"), None);
+        assert_eq!(stats.parser().malformed, 1);
+    }
+
+    #[test]
+    fn test_allow_command_rejects_others() {
+        let stats = Stats::new();
+        let cfg = config(&["--allow-command", "GET", "--allow-command", "PING"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        let (to_upstream, to_client) = process(&mut session, b"SET a 1\r\nget a\r\n");
+        assert_eq!(to_upstream, b"get a\r\n");
+        assert_eq!(to_client, BLOCKED_REPLY.as_bytes());
+        assert_eq!(stats.blocked_commands(), 1);
+    }
+
+    #[test]
+    fn test_read_only_rejects_writes() {
+        let mut session = Session::new(config(&["--read-only"]), Stats::new(), peer());
+        let data = b"GET a\r\nset a 1\r\n*3\r\n$6\r\nOBJECT\r\n$8\r\nENCODING\r\n$1\r\na\r\n";
+        let (to_upstream, to_client) = process(&mut session, data);
+        assert_eq!(to_upstream, [&b"GET a\r\n"[..], &data[16..]].concat());
+        assert!(to_client.is_empty());
+        let to_client = reply(&mut session, b"$1\r\nx\r\n+embstr\r\n");
+        let expected = [&b"$1\r\nx\r\n"[..], READ_ONLY_REPLY.as_bytes(), b"+embstr\r\n"];
+        assert_eq!(to_client, expected.concat());
+
+        let (to_upstream, to_client) = process(&mut session, b"XGROUP CREATE s g $\r\n");
+        assert!(to_upstream.is_empty());
+        assert_eq!(to_client, READ_ONLY_REPLY.as_bytes());
+
+        // Writes go through as usual without the flag
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        let (to_upstream, to_client) = process(&mut session, b"SET a 1\r\n");
+        assert_eq!((to_upstream, to_client), (b"SET a 1\r\n".to_vec(), Vec::new()));
+    }
+
+    #[test]
+    fn test_read_only_write_mid_pipeline_answered_in_turn() {
+        let cfg = config(&["--read-only", "--parse-replies", "on"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let (to_upstream, to_client) = process(&mut session, b"GET a\r\nDEL a\r\nGET b\r\n");
+        assert_eq!(to_upstream, b"GET a\r\nGET b\r\n");
+        assert!(to_client.is_empty());
+
+        let to_client = reply(&mut session, b"$1\r\n1\r\n$1\r\n2\r\n");
+        let expected = [&b"$1\r\n1\r\n"[..], READ_ONLY_REPLY.as_bytes(), b"$1\r\n2\r\n"].concat();
+        assert_eq!(to_client, expected);
+    }
+
+    #[test]
+    fn test_select_forwarded_without_block() {
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        let data = b"*2\r\n$6\r\nSELECT\r\n$1\r\n5\r\n";
+        let (to_upstream, to_client) = process(&mut session, data);
+        assert_eq!(to_upstream, data);
+        assert!(to_client.is_empty());
+    }
+
+    #[test]
+    fn test_max_select_db_rejects_higher_indexes() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&["--max-select-db", "4"]), stats.clone(), peer());
+
+        let (to_upstream, to_client) = process(
+            &mut session,
+            b"*2\r\n$6\r\nSELECT\r\n$2\r\n16\r\nSELECT 4\r\nSELECT abc\r\n",
+        );
+        assert_eq!(to_upstream, b"SELECT 4\r\nSELECT abc\r\n");
+        assert_eq!(to_client, b"-ERR DB index is out of range\r\n");
+        assert_eq!(stats.command_counts().get("SELECT"), Some(&2));
+    }
+
+    #[test]
+    fn test_keys_per_command_counted_and_limited() {
+        let stats = Stats::new();
+        let cfg = config(&["--max-keys-per-command", "100"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        let keys = |n: usize| (0..n).map(|i| format!(" k{i}")).collect::<String>();
+        let allowed = format!("MGET{}\r\nMGET{}\r\nGET k\r\n", keys(3), keys(100));
+        let data = format!("{}mget{}\r\n", allowed, keys(101));
+        let (to_upstream, to_client) = process(&mut session, data.as_bytes());
+        assert_eq!(to_upstream, allowed.as_bytes());
+        assert!(to_client.is_empty());
+        let to_client = reply(&mut session, b"*0\r\n*0\r\n$-1\r\n");
+        assert_eq!(
+            String::from_utf8(to_client).unwrap(),
+            "*0\r\n*0\r\n$-1\r\n-ERR mget touches 101 keys, more than the proxy allows (100)\r\n"
+        );
+
+        // Only forwarded multi-key commands are counted
+        let counted = stats.keys_per_command();
+        assert_eq!(counted.len(), 1);
+        let (command, hist) = &counted[0];
+        assert_eq!(command, "MGET");
+        assert_eq!((hist.count(), hist.max()), (2, 100));
+        assert_eq!(stats.command_counts().get("MGET"), Some(&2));
+    }
+
+    #[test]
+    fn test_unterminated_inline_command_is_rejected() {
+        let cfg = config(&["--max-inline-length", "1K"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let mut buf = BytesMut::new();
+        let mut to_upstream = Vec::new();
+        let mut to_client = Vec::new();
+
+        // A partial inline command below the limit is retained
+        buf.extend_from_slice(&[b'A'; 1000]);
+        assert!(session
+            .process_client_data(&mut buf, &mut to_upstream, &mut to_client)
+            .is_ok());
+        assert_eq!(buf.len(), 1000);
+
+        // Growing past the limit without a CRLF is a protocol error
+        buf.extend_from_slice(&[b'A'; 100]);
+        let err = session
+            .process_client_data(&mut buf, &mut to_upstream, &mut to_client)
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::Protocol(_)));
+        assert!(to_upstream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_large_no_crlf_payload_closes_connection() {
+        let (client, mut client_remote) = duplex(1 << 20);
+        let (upstream, _redis) = duplex(1 << 20);
+        let cfg = config(&[]);
+        let ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        let payload = vec![b'x'; 256 * 1024];
+        client_remote.write_all(&payload).await.unwrap();
+        let mut reply = Vec::new();
+        client_remote.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR Protocol error: too big inline request\r\n");
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_endless_count_closes_connection() {
+        let (client, mut client_remote) = duplex(1 << 20);
+        let (upstream, mut redis) = duplex(1 << 20);
+        let stats = Stats::new();
+        let ctx = ProxyContext::new(peer(), stats.clone(), config(&[]));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // Closed at the header, not after buffering the rest up to some larger limit
+        let payload = [&b"PING\r\n*"[..], &vec![b'1'; 256 * 1024]].concat();
+        client_remote.write_all(&payload).await.unwrap();
+        let mut reply = Vec::new();
+        client_remote.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR Protocol error: too big count string\r\n");
+        proxy.await.unwrap();
+
+        let mut forwarded = Vec::new();
+        redis.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, b"PING\r\n");
+        assert_eq!(stats.parser().malformed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_command_split_across_reads_counted_once() {
+        let (client, mut client_remote) = duplex(4096);
+        let (upstream, mut redis) = duplex(4096);
+        let stats = Stats::new();
+        let ctx = ProxyContext::new(peer(), stats.clone(), config(&[]));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        client_remote.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nk").await.unwrap();
+        // Let the proxy read the first half on its own
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(stats.total(), 0);
+        client_remote.write_all(b"ey\r\n$1\r\nv\r\n").await.unwrap();
+
+        let mut forwarded = [0u8; 29];
+        redis.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(&forwarded, b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n");
+        assert_eq!(stats.total(), 1);
+        assert_eq!(stats.command_counts().get("SET"), Some(&1));
+
+        drop(client_remote);
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_split_across_reads_counts_every_command() {
+        let mut pipeline = Vec::new();
+        for i in 0..50 {
+            let key = format!("key{i}");
+            match i % 3 {
+                0 => pipeline.extend(encode_command(&[b"GET", key.as_bytes()])),
+                1 => pipeline.extend(encode_command(&[b"SET", key.as_bytes(), b"value"])),
+                _ => pipeline.extend(format!("INCR {key}\r\n").into_bytes()),
+            }
+        }
+
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut redis) = duplex(1 << 16);
+        let stats = Stats::new();
+        let ctx = ProxyContext::new(peer(), stats.clone(), config(&[]));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // Uneven chunks, so commands and their headers are cut at many different points
+        let mut rest = &pipeline[..];
+        for size in [1, 7, 3, 20, 2, 45, 11, 5].iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+            client_remote.write_all(chunk).await.unwrap();
+            tokio::task::yield_now().await;
+            rest = tail;
+        }
+
+        let mut forwarded = vec![0; pipeline.len()];
+        redis.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, pipeline);
+        assert_eq!(stats.total(), 50);
+        let counts = stats.command_counts();
+        assert_eq!((counts["GET"], counts["SET"], counts["INCR"]), (17, 17, 16));
+
+        drop(client_remote);
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_buffers_returned_to_pool_on_close() {
+        let buffers = BufferPool::new();
+        for _ in 0..3 {
+            let (client, mut client_remote) = duplex(4096);
+            let (upstream, mut redis) = duplex(4096);
+            let mut ctx = ProxyContext::new(peer(), Stats::new(), config(&[]));
+            ctx.buffers = buffers.clone();
+            let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+            client_remote.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            let mut request = [0u8; 14];
+            redis.read_exact(&mut request).await.unwrap();
+            redis.write_all(b"+PONG\r\n").await.unwrap();
+            let mut reply = [0u8; 7];
+            client_remote.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"+PONG\r\n");
+
+            drop(client_remote);
+            proxy.await.unwrap();
+            assert_eq!(buffers.idle(), 2);
+        }
+        // The first connection allocated both buffers; later ones reused them
+        assert_eq!(buffers.allocated(), 2);
+        assert_eq!(buffers.reused(), 4);
+    }
+
+    #[test]
+    fn test_rename_rewrites_command_token() {
+        let stats = Stats::new();
+        let cfg = config(&["--rename", "CONFIG=s3cr3t-config", "--rename", "flushall=FA"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        let (to_upstream, _) = process(
+            &mut session,
+            b"*3\r\n$6\r\nconfig\r\n$3\r\nGET\r\n$1\r\n*\r\n*1\r\n$4\r\nPING\r\nFLUSHALL ASYNC\r\n",
+        );
+        assert_eq!(
+            to_upstream,
+            b"*3\r\n$13\r\ns3cr3t-config\r\n$3\r\nGET\r\n$1\r\n*\r\n*1\r\n$4\r\nPING\r\nFA ASYNC\r\n"
+        );
+        // Stats use the name the client sent
+        assert_eq!(stats.command_counts().get("CONFIG"), Some(&1));
+    }
+
+    #[test]
+    fn test_replies_matched_to_commands_in_timing_log() {
+        let path = std::env::temp_dir().join(format!("timing-log-{}.json", std::process::id()));
+        let log = TimingLog::create(&path).unwrap();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, Stats::new(), peer()).with_timing(log.clone());
+
+        process(&mut session, b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nPING\r\n");
+        assert_eq!(session.pending.len(), 2);
+
+        // A reply split across reads is held back until complete
+        let mut buf = BytesMut::from(&b"$1\r\nv\r\n+PO"[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+        assert_eq!(to_client, b"$1\r\nv\r\n");
+        assert_eq!(&buf[..], b"+PO");
+        assert_eq!(session.pending.len(), 1);
+
+        buf.extend_from_slice(b"NG\r\n");
+        session.process_upstream_data(&mut buf, &mut to_client);
+        assert_eq!(to_client, b"$1\r\nv\r\n+PONG\r\n");
+        assert!(buf.is_empty());
+        assert!(session.pending.is_empty());
+
+        log.flush();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<&str> = text.lines().skip(1).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].starts_with("{\"name\":\"GET\""));
+        assert!(events[1].starts_with("{\"name\":\"PING\""));
+    }
+
+    /// Feed `data` from upstream to the session, returning what the client receives.
+    fn reply(session: &mut Session, data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::from(data);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+        to_client
+    }
+
+    fn confirmation(kind: &str, channel: &str, count: usize) -> Vec<u8> {
+        let channel = format!("${}\r\n{}\r\n", channel.len(), channel);
+        format!("*3\r\n${}\r\n{}\r\n{}:{}\r\n", kind.len(), kind, channel, count).into_bytes()
+    }
+
+    #[test]
+    fn test_subscribe_confirmations_matched_to_one_command() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        process(&mut session, b"SUBSCRIBE a b c\r\nPING\r\n");
+        let confirmations = [
+            confirmation("subscribe", "a", 1),
+            confirmation("subscribe", "b", 2),
+            confirmation("subscribe", "c", 3),
+        ]
+        .concat();
+        // Confirmations split across reads
+        let mut buf = BytesMut::from(&confirmations[..40]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+        assert_eq!(to_client, &confirmations[..30]);
+        assert_eq!(session.pending.len(), 2);
+        buf.extend_from_slice(&confirmations[40..]);
+        session.process_upstream_data(&mut buf, &mut to_client);
+        assert_eq!(to_client, confirmations);
+        // All three confirmations answer SUBSCRIBE alone
+        assert_eq!(session.subscriptions.total(), 3);
+        assert_eq!(session.pending.len(), 1);
+        assert_eq!(session.pending[0].name, "PING");
+        assert_eq!(stats.slowest_commands().len(), 1);
+
+        // Messages published meanwhile answer nothing
+        reply(&mut session, b"*3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n");
+        assert_eq!(session.pending.len(), 1);
+        reply(&mut session, b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+        assert!(session.pending.is_empty());
+
+        // UNSUBSCRIBE without arguments is confirmed once per remaining channel
+        process(&mut session, b"UNSUBSCRIBE b\r\nUNSUBSCRIBE\r\nGET k\r\n");
+        reply(&mut session, &confirmation("unsubscribe", "b", 2));
+        reply(&mut session, &confirmation("unsubscribe", "a", 1));
+        assert_eq!(session.pending.len(), 2);
+        reply(&mut session, &confirmation("unsubscribe", "c", 0));
+        assert_eq!(session.subscriptions.total(), 0);
+        assert_eq!(session.pending.len(), 1);
+        reply(&mut session, b"$1\r\nv\r\n");
+        assert!(session.pending.is_empty());
+        assert_eq!(stats.slowest_commands().len(), 5);
+    }
+
+    #[test]
+    fn test_subscription_lookalike_reply_answers_its_command() {
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        process(&mut session, b"LRANGE l 0 -1\r\nGET k\r\n");
+        reply(&mut session, &confirmation("subscribe", "a", 1));
+        assert_eq!(session.subscriptions.total(), 0);
+        assert_eq!(session.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_invalidation_push_counted_without_consuming_reply() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+
+        // The push arrives ahead of the GET reply and passes through unchanged
+        let data = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let mut buf = BytesMut::from(&data[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+
+        assert_eq!(to_client, data);
+        assert!(buf.is_empty());
+        assert_eq!(stats.invalidations(), 1);
+        assert!(session.pending.is_empty());
+    }
+
+    #[test]
+    fn test_error_replies_counted_by_class() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"LPUSH s x\r\nSET k v\r\nGET k\r\nGET k\r\n");
+
+        let data = b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+                     -OOM command not allowed\r\n$5\r\n-OOM \r\n-ERR oops\r\n";
+        let mut buf = BytesMut::from(&data[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+
+        assert_eq!(to_client, data);
+        assert!(session.pending.is_empty());
+        assert_eq!(
+            stats.error_classes(),
+            [
+                ("ERR".to_string(), 1),
+                ("OOM".to_string(), 1),
+                ("WRONGTYPE".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_reply_split_across_reads_counted_once() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"LPUSH s x\r\nGET k\r\n");
+
+        let mut forwarded = Vec::new();
+        let mut buf = BytesMut::new();
+        for part in [&b"-WRONG"[..], b"TYPE wrong kind\r", b"\n-E", b"RR oops\r\n"] {
+            buf.extend_from_slice(part);
+            session.process_upstream_data(&mut buf, &mut forwarded);
+        }
+
+        assert_eq!(forwarded, b"-WRONGTYPE wrong kind\r\n-ERR oops\r\n");
+        assert_eq!(
+            stats.error_classes(),
+            [("ERR".to_string(), 1), ("WRONGTYPE".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_cluster_redirects_counted() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"GET a\r\nGET b\r\nGET c\r\n");
+
+        let data = b"-MOVED 3999 127.0.0.1:7001\r\n-ASK 3999 127.0.0.1:7001\r\n$1\r\nv\r\n";
+        let mut buf = BytesMut::from(&data[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+
+        assert_eq!(to_client, data);
+        let redirects = stats.redirects();
+        assert_eq!(redirects.len(), 1);
+        assert_eq!((redirects[0].slot, redirects[0].node.as_str()), (3999, "127.0.0.1:7001"));
+        assert_eq!((redirects[0].moved, redirects[0].ask), (1, 1));
+        assert_eq!(
+            stats.error_classes(),
+            [("ASK".to_string(), 1), ("MOVED".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_latency_measured_on_clock() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"GET k\r\n");
+
+        clock.advance(Duration::from_millis(250)).await;
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut BytesMut::from(&b"$1\r\nv\r\n"[..]), &mut to_client);
+
+        let slowest = stats.slowest_commands();
+        assert_eq!(slowest[0].command, "GET");
+        assert!((250..=251).contains(&slowest[0].duration.as_millis()), "{slowest:?}");
+    }
+
+    #[tokio::test]
+    async fn test_summary_after_session() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, upstream_remote) = duplex(1 << 16);
+        let _upstream = MockUpstream::spawn(upstream_remote, reply_ok());
+        let cfg = config(&["--connection-top-commands", "2"]);
+        let ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        let sent = b"SET a 1\r\nget a\r\nGET b\r\nDEL a\r\nGET c\r\nSET b 2\r\n";
+        client_remote.write_all(sent).await.unwrap();
+        let mut replies = [0u8; 30];
+        client_remote.read_exact(&mut replies).await.unwrap();
+        drop(client_remote);
+
+        let summary = proxy.await.unwrap();
+        assert_eq!(summary.commands, 6);
+        assert_eq!(summary.bytes_in, sent.len() as u64);
+        assert_eq!(summary.bytes_out, 30);
+        let top = [("GET".to_string(), 3), ("SET".to_string(), 2)];
+        assert_eq!(summary.top_commands, top);
+        assert_eq!(summary.reason, CloseReason::ClientClosed);
+    }
+
+    #[test]
+    fn test_long_session_state_stays_bounded() {
+        let cfg = config(&["--parse-replies", "on", "--connection-top-commands", "3"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let mut to_client = Vec::new();
+        // Days of traffic: a deep pipeline, then a steady stream of ever new command names
+        let mut pipeline = Vec::new();
+        for _ in 0..10_000 {
+            pipeline.extend_from_slice(b"GET k\r\n");
+        }
+        process(&mut session, &pipeline);
+        let replies = b"$-1\r\n".repeat(10_000);
+        session.process_upstream_data(&mut BytesMut::from(&replies[..]), &mut to_client);
+        for i in 0..20_000 {
+            process(&mut session, format!("CMD{i} k\r\n").as_bytes());
+            session.process_upstream_data(&mut BytesMut::from(&b"+OK\r\n"[..]), &mut to_client);
+        }
+
+        assert_eq!(session.commands, 30_000);
+        assert!(session.pending.capacity() <= 2 * RETAINED_PENDING);
+        let counts = session.command_counts.as_ref().unwrap();
+        assert_eq!(counts.len(), MAX_CONNECTION_COMMANDS + 1);
+        assert_eq!(counts[OTHER_COMMANDS], 20_000 - (MAX_CONNECTION_COMMANDS as u64 - 1));
+        let top = session.top_commands();
+        assert_eq!(top[0].0, OTHER_COMMANDS);
+        assert_eq!(top[1], ("GET".to_string(), 10_000));
+    }
+
+    #[tokio::test]
+    async fn test_top_commands_only_kept_on_request() {
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        process(&mut session, b"GET a\r\n");
+        assert!(session.command_counts.is_none());
+        assert!(session.top_commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_timed_per_command() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        process(&mut session, b"GET k\r\nSET k v\r\n");
+
+        // Replies come back in order, each matched to the oldest command still waiting
+        let mut to_client = Vec::new();
+        clock.advance(Duration::from_millis(100)).await;
+        session.process_upstream_data(&mut BytesMut::from(&b"$1\r\nv\r\n"[..]), &mut to_client);
+        clock.advance(Duration::from_millis(50)).await;
+        session.process_upstream_data(&mut BytesMut::from(&b"+OK\r\n"[..]), &mut to_client);
+
+        let latencies = stats.latency_per_command();
+        let millis: Vec<_> = latencies
+            .iter()
+            .map(|(name, hist)| (name.as_str(), hist.count(), hist.max() / 1000))
+            .collect();
+        assert!(
+            matches!(millis[..], [("GET", 1, 100 | 101), ("SET", 1, 150..=152)]),
+            "{millis:?}"
+        );
+    }
+
+    #[test]
+    fn test_sampled_commands_captured_with_replies() {
+        let samples = Arc::new(CommandSamples::new(2));
+        let cfg = config(&[
+            "--parse-replies", "on", "--sample-rate", "1.0", "--redact-command", "SET",
+        ]);
+        let mut session = Session::new(cfg, Stats::new(), peer()).with_samples(samples.clone());
+
+        process(&mut session, b"SET k v\r\nGET k\r\nPING\r\n");
+        assert!(samples.snapshot().is_empty(), "recorded before the reply");
+        reply(&mut session, b"+OK\r\n$1\r\nv\r\n+PONG\r\n");
+
+        // Only the newest are kept, and redacted commands keep their values out of both
+        let kept = samples.snapshot();
+        let captured: Vec<_> = kept
+            .iter()
+            .map(|s| (s.command.as_str(), s.reply.as_deref().unwrap()))
+            .collect();
+        assert_eq!(captured, [("GET k", "$1\\r\\nv\\r\\n"), ("PING", "+PONG\\r\\n")]);
+        assert_eq!(kept[0].peer, peer());
+        assert!(kept[0].duration.is_some());
+
+        process(&mut session, b"SET k secret\r\n");
+        reply(&mut session, b"+OK\r\n");
+        let newest = samples.snapshot().pop().unwrap();
+        assert_eq!(newest.command, "SET (2 args redacted)");
+        assert_eq!(newest.reply.as_deref(), Some("(5 bytes redacted)"));
+    }
+
+    /// Log lines written while `f` runs with `--log-format json` output.
+    fn json_log(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Buffer {
+            fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(data);
+                Ok(data.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let lines = buffer.0.lock().unwrap().clone();
+        String::from_utf8(lines).unwrap()
+    }
+
+    #[test]
+    fn test_json_access_events() {
+        let cfg = config(&["--log-format", "json"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let log = json_log(|| {
+            process(&mut session, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+            process(&mut session, b"AUTH hunter2\r\n");
+        });
+
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].contains(r#""peer":"10.0.0.1:50000""#), "{log}");
+        assert!(lines[0].contains(r#""command":"SET","args":2,"bytes":27"#), "{log}");
+        assert!(lines[0].contains(r#""line":"SET k v""#), "{log}");
+        // Credentials never reach the log
+        assert!(lines[1].contains(r#""command":"AUTH","args":1,"bytes":14"#), "{log}");
+        assert!(lines[1].contains(r#""line":"AUTH (1 args redacted)""#), "{log}");
+        assert!(!log.contains("hunter2"));
+
+        // Text format keeps commands out of the info log unless verbose
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        assert_eq!(json_log(|| drop(process(&mut session, b"GET k\r\n"))), "");
+    }
+
+    #[test]
+    fn test_samples_without_reply_parsing() {
+        let samples = Arc::new(CommandSamples::new(10));
+        let cfg = config(&["--sample-rate", "1.0"]);
+        let mut session = Session::new(cfg, Stats::new(), peer()).with_samples(samples.clone());
+        process(&mut session, b"GET k\r\n");
+
+        let kept = samples.snapshot();
+        assert_eq!(kept.len(), 1);
+        assert_eq!((kept[0].command.as_str(), &kept[0].reply), ("GET k", &None));
+        assert_eq!(kept[0].duration, None);
+
+        let unsampled = config(&["--sample-rate", "0.0"]);
+        let mut session =
+            Session::new(unsampled, Stats::new(), peer()).with_samples(samples.clone());
+        process(&mut session, b"GET k\r\n");
+        assert_eq!(samples.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn test_replies_parsed_in_protocol_chosen_by_hello() {
+        let stats = Stats::new();
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        let hgetall = b"%1\r\n$1\r\nf\r\n$1\r\nv\r\n";
+
+        // The HELLO reply is the first in RESP3, arriving in one read with what follows
+        process(&mut session, b"GET k\r\nHELLO 3\r\nHGETALL h\r\n");
+        let data = [&b"$1\r\nv\r\n%1\r\n$5\r\nproto\r\n:3\r\n"[..], hgetall].concat();
+        reply(&mut session, &data);
+        assert!(session.track_replies);
+        assert!(session.pending.is_empty());
+        assert_eq!(session.resp_version, RespVersion::Resp3);
+
+        process(&mut session, b"HELLO 2\r\nHGETALL h\r\n");
+        reply(&mut session, b"*2\r\n$5\r\nproto\r\n:2\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        assert!(session.pending.is_empty());
+        assert_eq!(session.resp_version, RespVersion::Resp2);
+
+        // Back in RESP2, a map can only be garbage
+        process(&mut session, b"HGETALL h\r\n");
+        reply(&mut session, hgetall);
+        assert!(!session.track_replies);
+    }
+
+    #[test]
+    fn test_rejected_hello_keeps_protocol() {
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        process(&mut session, b"HELLO 3\r\nGET k\r\n");
+        reply(&mut session, b"-NOPROTO unsupported protocol version\r\n$1\r\nv\r\n");
+        assert!(session.pending.is_empty());
+        assert_eq!(session.resp_version, RespVersion::Resp2);
+    }
+
+    #[test]
+    fn test_unparseable_reply_passed_through() {
+        let cfg = config(&["--parse-replies", "on"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        process(&mut session, b"PING\r\nPING\r\n");
+
+        let mut buf = BytesMut::from(&b"+PONG\r\n?garbage"[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+        assert_eq!(to_client, b"+PONG\r\n?garbage");
+        assert!(!session.track_replies);
+        assert!(session.pending.is_empty());
+    }
+
+    #[test]
+    fn test_replies_untouched_without_reply_parsing() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        process(&mut session, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+        assert!(session.pending.is_empty());
+
+        // Pushes, partial and malformed replies are all forwarded as-is
+        let data = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n$3\r\nbar\r\n?x\r\n$5\r\nab";
+        let mut buf = BytesMut::from(&data[..]);
+        let mut to_client = Vec::new();
+        session.process_upstream_data(&mut buf, &mut to_client);
+
+        assert_eq!(to_client, data);
+        assert!(buf.is_empty());
+        assert_eq!(stats.invalidations(), 0);
+        assert!(stats.slowest_commands().is_empty());
+    }
+
+    #[test]
+    fn test_set_ttl_recorded() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        process(
+            &mut session,
+            b"*5\r\n$3\r\nSET\r\n$3\r\nkey\r\n$3\r\nval\r\n$2\r\nEX\r\n$4\r\n3600\r\nSET k v\r\n",
+        );
+        assert_eq!(stats.key_ttls().count(), 1);
+        assert_eq!(stats.key_ttls().max(), 3_600_000);
+        assert_eq!(stats.sets_without_ttl(), 1);
+    }
+
+    #[test]
+    fn test_generic_protocol_counts_opaque_tokens() {
+        let cfg = config(&["--protocol", "generic"]);
+        let stats = Stats::for_protocol(Protocol::Generic, &cfg.no_count);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        let data = b"*2\r\n$7\r\nlookup!\r\n$1\r\nk\r\nSET k v EX 10\r\nset k v\r\nINFO proxy\r\n";
+
+        let (to_upstream, to_client) = process(&mut session, data);
+        assert_eq!(to_upstream, data);
+        assert!(to_client.is_empty());
+        assert_eq!(stats.total(), 4);
+        let counts = stats.command_counts();
+        assert_eq!(counts.get("lookup!"), Some(&1));
+        assert_eq!(counts.get("SET"), Some(&1));
+        assert_eq!(counts.get("set"), Some(&1));
+        assert_eq!(counts.get("INFO"), Some(&1));
+        // SET options mean nothing to a generic service
+        assert_eq!(stats.key_ttls().count(), 0);
+        assert_eq!(stats.sets_without_ttl(), 0);
+    }
+
+    #[test]
+    fn test_deep_pipeline_updates_max_depth() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        process(&mut session, &b"PING\r\n".repeat(3));
+        process(&mut session, &b"*1\r\n$4\r\nPING\r\n".repeat(250));
+        process(&mut session, b"GET k\r\n");
+        assert_eq!(session.max_pipeline_depth, 250);
+        assert_eq!(stats.max_pipeline_depth(), 250);
+
+        // A shallower connection leaves the global maximum alone
+        let mut other = Session::new(config(&[]), stats.clone(), peer());
+        process(&mut other, &b"PING\r\n".repeat(10));
+        assert_eq!(other.max_pipeline_depth, 10);
+        assert_eq!(stats.max_pipeline_depth(), 250);
+    }
+
+    #[test]
+    fn test_commands_processed_in_batches() {
+        let stats = Stats::new();
+        let cfg = config(&["--max-commands-per-batch", "2"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+        let mut buf = BytesMut::from(&b"GET a\r\nGET b\r\nGET c\r\nGET d\r\nGET e\r\nGE"[..]);
+        let mut to_upstream = Vec::new();
+        let mut to_client = Vec::new();
+
+        let mut batches = Vec::new();
+        loop {
+            let more = session
+                .process_client_data(&mut buf, &mut to_upstream, &mut to_client)
+                .unwrap();
+            batches.push(String::from_utf8(std::mem::take(&mut to_upstream)).unwrap());
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(batches, ["GET a\r\nGET b\r\n", "GET c\r\nGET d\r\n", "GET e\r\n"]);
+        assert_eq!(&buf[..], b"GE");
+        assert_eq!(stats.total(), 5);
+        // The whole read still counts as one pipeline
+        assert_eq!(session.max_pipeline_depth, 5);
+    }
+
+    #[tokio::test]
+    async fn test_yields_between_batches() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, _redis) = duplex(1 << 16);
+        client_remote.write_all(&b"PING\r\n".repeat(10)).await.unwrap();
+
+        let stats = Stats::new();
+        let cfg = config(&["--max-commands-per-batch", "3"]);
+        let ctx = ProxyContext::new(peer(), stats.clone(), cfg);
+        tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // On this single-threaded runtime, progress only shows between the proxy's yields
+        let mut seen = vec![0];
+        while stats.total() < 10 {
+            tokio::task::yield_now().await;
+            seen.push(stats.total());
+        }
+        assert!(seen.windows(2).all(|w| w[1] - w[0] <= 3), "{seen:?}");
+        assert!(seen.contains(&3) && seen.contains(&6) && seen.contains(&9), "{seen:?}");
+    }
+
+    #[tokio::test]
+    async fn test_reads_paced_by_shared_bandwidth() {
+        let clock = MockClock::start();
+        let bandwidth = BandwidthLimiter::new(100, clock.clone());
+        let start = clock.now();
+
+        // Two connections sharing 100 bytes/sec, each forwarding 150 bytes
+        let mut upstreams = Vec::new();
+        for _ in 0..2 {
+            let (client, mut client_remote) = duplex(4096);
+            let (upstream, redis) = duplex(4096);
+            let mut ctx = ProxyContext::new(peer(), Stats::with_clock(clock.clone()), config(&[]));
+            ctx.bandwidth = Some(bandwidth.clone());
+            tokio::spawn(proxy_connection(client, upstream, ctx));
+            tokio::spawn(async move {
+                for _ in 0..25 {
+                    client_remote.write_all(b"PING\r\n").await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+                client_remote
+            });
+            upstreams.push(redis);
+        }
+        for redis in &mut upstreams {
+            let mut forwarded = [0; 150];
+            redis.read_exact(&mut forwarded).await.unwrap();
+        }
+
+        // The first second's burst, then the remaining 200 bytes at 100 bytes/sec
+        assert_eq!((clock.now() - start).as_secs(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_commands_over_rate_rejected() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let cfg = config(&["--max-commands-per-sec", "2", "--deny-command", "KEYS"]);
+        let mut session = Session::new(cfg, stats.clone(), peer());
+
+        // Commands the proxy answers itself don't use up the rate
+        let data = b"KEYS *\r\nGET a\r\nGET b\r\nGET c\r\n";
+        let (to_upstream, to_client) = process(&mut session, data);
+        assert_eq!(to_upstream, b"GET a\r\nGET b\r\n");
+        // The refusal waits for the replies to the commands ahead of it
+        assert_eq!(to_client, BLOCKED_REPLY.as_bytes());
+        let to_client = reply(&mut session, b"$1\r\nx\r\n$1\r\ny\r\n");
+        assert_eq!(to_client, [b"$1\r\nx\r\n$1\r\ny\r\n", RATE_LIMITED_REPLY.as_bytes()].concat());
+        assert_eq!(stats.rate_limited_commands(), 1);
+        assert!(stats.summary(&[]).contains("\nRate-limited commands: 1\n"));
+        assert_eq!(session.rate_delay(), Duration::ZERO);
+
+        clock.advance(Duration::from_millis(500)).await;
+        let (to_upstream, to_client) = process(&mut session, b"GET d\r\nGET e\r\n");
+        assert_eq!((to_upstream, to_client), (b"GET d\r\n".to_vec(), Vec::new()));
+        let to_client = reply(&mut session, b"$-1\r\n");
+        assert_eq!(to_client, [b"$-1\r\n", RATE_LIMITED_REPLY.as_bytes()].concat());
+
+        // Rejecting keeps replies in order under the default --parse-replies off too
+        let cfg = config(&["--max-commands-per-sec", "1"]);
+        assert!(!cfg.parse_replies);
+        let mut session = Session::new(cfg, Stats::with_clock(clock.clone()), peer());
+        let (to_upstream, to_client) = process(&mut session, b"GET a\r\nGET b\r\nGET c\r\n");
+        assert_eq!((to_upstream, to_client), (b"GET a\r\n".to_vec(), Vec::new()));
+        let to_client = reply(&mut session, b"$1\r\nx\r\n");
+        assert_eq!(to_client, [b"$1\r\nx\r\n", RATE_LIMITED_REPLY.repeat(2).as_bytes()].concat());
+    }
+
+    #[tokio::test]
+    async fn test_global_rate_shared_by_connections() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let global = GlobalRate::new(2, Duration::from_millis(500), clock.now());
+        let mut sessions: Vec<_> = (0..2)
+            .map(|_| {
+                let cfg = config(&["--global-max-commands-per-sec", "2"]);
+                let mut session = Session::new(cfg, stats.clone(), peer());
+                session.global_rate = Some(global.clone());
+                session
+            })
+            .collect();
+
+        let (to_upstream, _) = process(&mut sessions[0], b"GET a\r\nGET b\r\n");
+        assert_eq!(to_upstream, b"GET a\r\nGET b\r\n");
+        assert_eq!(sessions[0].rate_delay(), Duration::ZERO);
+
+        // The other connection finds the burst used up: one command queues for its slot,
+        // the rest would wait longer than the queue allows
+        let (to_upstream, to_client) = process(&mut sessions[1], b"GET c\r\nGET d\r\nGET e\r\n");
+        assert_eq!(to_upstream, b"GET c\r\n");
+        // Refused behind the reply to the command forwarded ahead of them
+        assert!(to_client.is_empty());
+        let to_client = reply(&mut sessions[1], b"$1\r\nz\r\n");
+        let refusals = GLOBAL_RATE_LIMITED_REPLY.repeat(2);
+        assert_eq!(to_client, [b"$1\r\nz\r\n", refusals.as_bytes()].concat());
+        assert_eq!(sessions[1].rate_delay(), Duration::from_millis(500));
+        assert_eq!(sessions[1].rate_delay(), Duration::ZERO, "only waited once");
+        assert_eq!(stats.rate_limited_commands(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_commands_over_rate_delayed() {
+        let clock = MockClock::start();
+        let start = clock.now();
+        let (client, mut client_remote) = duplex(4096);
+        let (upstream, mut redis) = duplex(4096);
+        let cfg = config(&["--max-commands-per-sec", "2", "--rate-limit-action", "delay"]);
+        let ctx = ProxyContext::new(peer(), Stats::with_clock(clock.clone()), cfg);
+        tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // A second's burst straight away, then the rest at two per second
+        client_remote.write_all(b"GET a\r\nGET b\r\nGET c\r\nGET d\r\n").await.unwrap();
+        let mut forwarded = [0; 28];
+        redis.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!((clock.now() - start).as_secs(), 1);
+
+        client_remote.write_all(b"GET e\r\n").await.unwrap();
+        redis.read_exact(&mut forwarded[..7]).await.unwrap();
+        // Timers fire on whole milliseconds
+        assert!(matches!((clock.now() - start).as_millis(), 1500..=1502));
+        assert_eq!(&forwarded[..7], b"GET e\r\n");
+    }
+
+    #[test]
+    fn test_non_utf8_command_names_counted_apart() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        let data = b"*1\r\n$2\r\n\xff\xff\r\n*1\r\n$2\r\n\xfe\xfe\r\n\xff\xff\r\n";
+        assert_eq!(process(&mut session, data).0, data);
+
+        // Uppercased like any other name, which keeps the escapes distinct
+        let counts = stats.command_counts();
+        assert_eq!(counts.get("\\XFF\\XFF"), Some(&2));
+        assert_eq!(counts.get("\\XFE\\XFE"), Some(&1));
+    }
+
+    #[test]
+    fn test_transactions_counted_on_exec() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&[]), stats.clone(), peer());
+        process(&mut session, b"MULTI\r\nSET k v\r\nINCR n\r\nEXEC\r\n");
+        assert_eq!(stats.transactions(), 1);
+
+        // Discarded transactions and an EXEC outside MULTI don't count
+        process(&mut session, b"MULTI\r\nSET k v\r\nDISCARD\r\nEXEC\r\n");
+        assert_eq!(stats.transactions(), 1);
+
+        // A transaction can span reads
+        process(&mut session, b"multi\r\nSET k v\r\n");
+        assert_eq!(stats.transactions(), 1);
+        process(&mut session, b"exec\r\n");
+        assert_eq!(stats.transactions(), 2);
+    }
+
+    #[test]
+    fn test_inline_arg_limit() {
+        let stats = Stats::new();
+        let mut session = Session::new(config(&["--max-inline-args", "3"]), stats.clone(), peer());
+
+        let (to_upstream, to_client) = process(
+            &mut session,
+            b"SET k v\r\nMSET a 1 b 2\r\n*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n",
+        );
+        // Only the inline command over the limit is rejected; the connection stays usable
+        assert_eq!(
+            to_upstream,
+            b"SET k v\r\n*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert!(to_client.is_empty());
+        assert_eq!(
+            reply(&mut session, b"+OK\r\n+OK\r\n"),
+            b"+OK\r\n-ERR Protocol error: too many arguments in inline request\r\n+OK\r\n"
+        );
+        assert_eq!(stats.command_counts().get("MSET"), Some(&1));
+    }
+
+    #[test]
+    fn test_auth_passthrough() {
+        let auth = b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n";
+
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        let (to_upstream, to_client) = process(&mut session, auth);
+        assert_eq!(to_upstream, auth);
+        assert!(to_client.is_empty());
+
+        let cfg = config(&["--auth-passthrough", "off"]);
+        let mut session = Session::new(cfg, Stats::new(), peer());
+        let (to_upstream, to_client) = process(&mut session, auth);
+        assert!(to_upstream.is_empty());
+        assert_eq!(to_client, b"+OK\r\n");
+    }
+
+    #[test]
+    fn test_proxy_info_answered_locally() {
+        let stats = Stats::new();
+        stats.record_command("GET");
+        let mut session = Session::new(config(&["--proxy-info"]), stats, peer());
+
+        let data = b"*2\r\n$5\r\nPROXY\r\n$4\r\nINFO\r\n";
+        let (to_upstream, to_client) = process(&mut session, data);
+        assert!(to_upstream.is_empty());
+        let reply = String::from_utf8(to_client).unwrap();
+        assert!(reply.starts_with('$'));
+        assert!(reply.contains("total_commands_processed:1\r\n"));
+
+        // Without the flag, INFO requests go to upstream
+        let mut session = Session::new(config(&[]), Stats::new(), peer());
+        let data = b"*2\r\n$4\r\nINFO\r\n$5\r\nproxy\r\n";
+        assert_eq!(process(&mut session, data).0, data);
+    }
+
+    #[tokio::test]
+    async fn test_forced_db_with_blocked_select() {
+        use crate::upstream::{send_setup_command, setup_reply};
+        let (client, mut client_remote) = duplex(4096);
+        let (mut upstream, mut redis) = duplex(4096);
+
+        // Mock upstream: acknowledge SELECT 2, then record what the client session sends
+        let redis_task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            let n = redis.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+            redis.write_all(&setup_reply("+OK")).await.unwrap();
+            let n = redis.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+            redis.write_all(b"$1\r\nv\r\n").await.unwrap();
+            received
+        });
+
+        send_setup_command(&mut upstream, &[b"SELECT", b"2"])
+            .await
+            .unwrap();
+        let cfg = config(&["--force-db", "2", "--block-select"]);
+        let ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        client_remote
+            .write_all(b"*2\r\n$6\r\nSELECT\r\n$1\r\n5\r\n")
+            .await
+            .unwrap();
+        let mut reply = vec![0u8; 64];
+        let n = client_remote.read(&mut reply).await.unwrap();
+        assert_eq!(
+            &reply[..n],
+            b"-ERR SELECT is not allowed by proxy policy\r\n"
+        );
+
+        client_remote
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+        let n = client_remote.read(&mut reply).await.unwrap();
+        assert_eq!(&reply[..n], b"$1\r\nv\r\n");
+
+        drop(client_remote);
+        proxy.await.unwrap();
+        // The forced SELECT and its setup PING, then only the GET: the client's SELECT was blocked
+        let received = redis_task.await.unwrap();
+        let setup_len = received.len() - b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".len();
+        assert!(received.starts_with(&encode_command(&[b"SELECT", b"2"])));
+        assert!(received[..setup_len].ends_with(b"PING\r\n$21\r\nredis-tls-proxy-setup\r\n"));
+        assert_eq!(&received[setup_len..], b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_close_paths_report_reason_codes() {
+        /// Run a connection, letting `end` act on the client and upstream remotes. Whatever
+        /// it returns stays open until the proxy finishes.
+        async fn run<K>(end: impl FnOnce(DuplexStream, DuplexStream) -> K) -> CloseReason {
+            let (client, client_remote) = duplex(4096);
+            let (upstream, redis) = duplex(4096);
+            let ctx = ProxyContext::new(peer(), Stats::new(), config(&["--max-inline-length", "8"]));
+            let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+            let _open = end(client_remote, redis);
+            proxy.await.unwrap().reason
+        }
+
+        let reason = run(|_, redis| redis).await;
+        assert_eq!((reason, reason.code()), (CloseReason::ClientClosed, CloseCode::ClientClosed));
+
+        let reason = run(|client, _| client).await;
+        assert_eq!(
+            (reason, reason.code()),
+            (CloseReason::UpstreamClosed, CloseCode::UpstreamClosed)
+        );
+
+        let reason = run(|mut client, redis| {
+            tokio::spawn(async move {
+                client.write_all(b"an overlong inline command").await.unwrap();
+                let mut rest = Vec::new();
+                let _ = client.read_to_end(&mut rest).await;
+            });
+            redis
+        })
+        .await;
+        assert_eq!((reason, reason.code()), (CloseReason::ProtocolError, CloseCode::Error));
+        assert_eq!(CloseReason::UpstreamError.code().as_str(), "error");
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closed() {
+        let clock = MockClock::start();
+        let (client, mut client_remote) = duplex(4096);
+        let (upstream, mut redis) = duplex(4096);
+        let cfg = config(&["--idle-timeout-secs", "10"]);
+        let ctx = ProxyContext::new(peer(), Stats::with_clock(clock.clone()), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // Traffic restarts the timer
+        clock.advance(Duration::from_secs(8)).await;
+        client_remote.write_all(b"PING\r\n").await.unwrap();
+        redis.read_exact(&mut [0; 6]).await.unwrap();
+        clock.advance(Duration::from_secs(8)).await;
+        assert!(!proxy.is_finished(), "closed while in use");
+
+        clock.advance(Duration::from_secs(3)).await;
+        let reason = proxy.await.unwrap().reason;
+        assert_eq!((reason, reason.code()), (CloseReason::IdleTimeout, CloseCode::IdleTimeout));
+        assert_eq!(reason.as_str(), "idle_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_spares_clients_waiting_on_replies() {
+        let clock = MockClock::start();
+        let (client, mut client_remote) = duplex(4096);
+        let (upstream, mut redis) = duplex(4096);
+        let cfg = config(&["--idle-timeout-secs", "10", "--parse-replies", "on"]);
+        let ctx = ProxyContext::new(peer(), Stats::with_clock(clock.clone()), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        let blpop = b"BLPOP q 0\r\n";
+        client_remote.write_all(blpop).await.unwrap();
+        redis.read_exact(&mut [0; 11]).await.unwrap();
+        clock.advance(Duration::from_secs(60)).await;
+        assert!(!proxy.is_finished(), "closed while blocked");
+
+        // Idle again once the reply is through
+        redis.write_all(b"*2\r\n$1\r\nq\r\n$1\r\nv\r\n").await.unwrap();
+        client_remote.read_exact(&mut [0; 18]).await.unwrap();
+        clock.advance(Duration::from_secs(11)).await;
+        assert_eq!(proxy.await.unwrap().reason, CloseReason::IdleTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_connection_over_buffer_limit_closed() {
+        let (client, mut client_remote) = duplex(64 * 1024);
+        let (upstream, mut redis) = duplex(64 * 1024);
+        let cfg = config(&["--max-connection-buffer-bytes", "1K"]);
+        let ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // Complete commands are passed on and not held, whatever their size; this one
+        // arrives in a single read
+        let value = "v".repeat(4000);
+        let set = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4000\r\n{}\r\n", value);
+        client_remote.write_all(set.as_bytes()).await.unwrap();
+        redis.read_exact(&mut vec![0; set.len()]).await.unwrap();
+
+        // A partial one waits in the buffer for the rest
+        let partial = &set.as_bytes()[..2000];
+        client_remote.write_all(partial).await.unwrap();
+        let reason = proxy.await.unwrap().reason;
+        assert_eq!(reason, CloseReason::BufferOverflow);
+        assert_eq!((reason.as_str(), reason.code()), ("buffer_overflow", CloseCode::Error));
+    }
+
+    /// A peer that resets the connection as soon as it is read from.
+    struct ResetStream;
+
+    impl AsyncRead for ResetStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for ResetStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resets_reported_apart_from_clean_closes() {
+        let (upstream, _redis) = duplex(4096);
+        let ctx = ProxyContext::new(peer(), Stats::new(), config(&[]));
+        let reason = proxy_connection(ResetStream, upstream, ctx).await.reason;
+        assert_eq!((reason, reason.code()), (CloseReason::ClientReset, CloseCode::Reset));
+
+        let (client, _client_remote) = duplex(4096);
+        let ctx = ProxyContext::new(peer(), Stats::new(), config(&[]));
+        let reason = proxy_connection(client, ResetStream, ctx).await.reason;
+        assert_eq!((reason, reason.code()), (CloseReason::UpstreamReset, CloseCode::Reset));
+
+        // A clean EOF from the same side is still a normal close
+        let (client, client_remote) = duplex(4096);
+        let (upstream, _redis) = duplex(4096);
+        drop(client_remote);
+        let ctx = ProxyContext::new(peer(), Stats::new(), config(&[]));
+        let reason = proxy_connection(client, upstream, ctx).await.reason;
+        assert_eq!((reason, reason.code()), (CloseReason::ClientClosed, CloseCode::ClientClosed));
+    }
+
+    #[test]
+    fn test_reset_error_kinds() {
+        assert!(is_reset(&io::ErrorKind::ConnectionReset.into()));
+        assert!(is_reset(&io::ErrorKind::BrokenPipe.into()));
+        assert!(is_reset(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(!is_reset(&io::ErrorKind::TimedOut.into()));
+        let other = io::ErrorKind::Other.into();
+        assert_eq!(client_failure("read from", &other), CloseReason::ClientError);
+    }
+
+    /// A reconnect that hands out `replacement` once `ready` is notified, and fails if
+    /// called again.
+    /// Reconnect to `replacement` once `ready` is notified; `dialing` is notified as soon as
+    /// the proxy starts trying
+    fn reconnect_to(
+        replacement: DuplexStream,
+        dialing: Arc<Notify>,
+        ready: Arc<Notify>,
+    ) -> Reconnect<DuplexStream> {
+        let mut replacement = Some(replacement);
+        Box::new(move || {
+            dialing.notify_one();
+            let stream = replacement.take();
+            let ready = ready.clone();
+            Box::pin(async move {
+                ready.notified().await;
+                stream.ok_or_else(|| ProxyError::Connection("no replacement left".to_string()))
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_commands_replayed_after_upstream_reconnect() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut first) = duplex(1 << 16);
+        let (replacement, replacement_remote) = duplex(1 << 16);
+        let second = MockUpstream::spawn(replacement_remote, reply_ok());
+        let (dialing, ready) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+
+        let cfg = config(&["--parse-replies", "on", "--upstream-unavailable-queue-time", "5s"]);
+        let mut ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        ctx.reconnect = Some(reconnect_to(replacement, dialing.clone(), ready.clone()));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // The first upstream receives GET but goes away without answering it
+        client_remote.write_all(b"GET a\r\n").await.unwrap();
+        let mut request = [0u8; 7];
+        first.read_exact(&mut request).await.unwrap();
+        drop(first);
+        dialing.notified().await;
+
+        // Sent while upstream is unavailable, then the replacement comes up
+        client_remote.write_all(b"SET a 1\r\n").await.unwrap();
+        ready.notify_one();
+
+        let mut replies = [0u8; 10];
+        client_remote.read_exact(&mut replies).await.unwrap();
+        assert_eq!(&replies, b"+OK\r\n+OK\r\n");
+        let words = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(second.commands(), [words("GET a"), words("SET a 1")]);
+
+        drop(client_remote);
+        let summary = proxy.await.unwrap();
+        assert_eq!(summary.commands, 2);
+        assert_eq!(summary.reason, CloseReason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_only_idempotent_commands_replayed() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut first) = duplex(1 << 16);
+        let (replacement, replacement_remote) = duplex(1 << 16);
+        let second = MockUpstream::spawn(replacement_remote, reply_ok());
+        let ready = Arc::new(Notify::new());
+
+        let cfg = config(&["--parse-replies", "on", "--upstream-unavailable-queue-time", "5s"]);
+        let mut ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        ctx.reconnect = Some(reconnect_to(replacement, Arc::new(Notify::new()), ready.clone()));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        // All three may have run on the first upstream before it went away
+        client_remote.write_all(b"INCR n\r\nGET x\r\nINCR m\r\n").await.unwrap();
+        let mut requests = [0u8; 23];
+        first.read_exact(&mut requests).await.unwrap();
+        drop(first);
+        ready.notify_one();
+
+        // INCR is answered with an error in its turn, around the replayed GET's reply
+        let lost = NOT_REPLAYED_REPLY.as_bytes();
+        let expected = [lost, b"+OK\r\n", lost].concat();
+        let mut replies = vec![0u8; expected.len()];
+        client_remote.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, expected);
+        assert_eq!(second.commands(), [vec!["GET".to_string(), "x".to_string()]]);
+
+        drop(client_remote);
+        assert_eq!(proxy.await.unwrap().reason, CloseReason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_replayed_after_client_selected_db() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut first) = duplex(1 << 16);
+        let (replacement, replacement_remote) = duplex(1 << 16);
+        let second = MockUpstream::spawn(replacement_remote, reply_ok());
+
+        let cfg = config(&["--parse-replies", "on", "--upstream-unavailable-queue-time", "5s"]);
+        let mut ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        ctx.reconnect = Some(reconnect_to(replacement, Arc::new(Notify::new()), Arc::default()));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        client_remote.write_all(b"SELECT 3\r\n").await.unwrap();
+        let mut request = [0u8; 10];
+        first.read_exact(&mut request).await.unwrap();
+        first.write_all(b"+OK\r\n").await.unwrap();
+        let mut reply = [0u8; 5];
+        client_remote.read_exact(&mut reply).await.unwrap();
+
+        // A new connection would read DB 0, so even a read is not replayed
+        client_remote.write_all(b"GET x\r\n").await.unwrap();
+        first.read_exact(&mut request[..7]).await.unwrap();
+        drop(first);
+        let mut replies = Vec::new();
+        client_remote.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, NOT_REPLAYED_REPLY.as_bytes());
+        assert_eq!(proxy.await.unwrap().reason, CloseReason::UpstreamClosed);
+        assert!(second.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_command_during_outage_fails_queued_commands() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut first) = duplex(1 << 16);
+        let (replacement, _) = duplex(1 << 16);
+        let dialing = Arc::new(Notify::new());
+
+        let cfg = config(&["--parse-replies", "on", "--upstream-unavailable-queue-time", "5s"]);
+        let mut ctx = ProxyContext::new(peer(), Stats::new(), cfg);
+        ctx.reconnect = Some(reconnect_to(replacement, dialing.clone(), Arc::default()));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        client_remote.write_all(b"GET x\r\n").await.unwrap();
+        let mut request = [0u8; 7];
+        first.read_exact(&mut request).await.unwrap();
+        drop(first);
+        dialing.notified().await;
+
+        // Nothing after the garbage can be forwarded, so neither GET would ever be answered
+        client_remote.write_all(b"GET y\r\n*1\r\nnot-a-bulk\r\n").await.unwrap();
+        let mut replies = Vec::new();
+        client_remote.read_to_end(&mut replies).await.unwrap();
+        let expected = [
+            UNAVAILABLE_REPLY.as_bytes(),
+            UNAVAILABLE_REPLY.as_bytes(),
+            b"-ERR Protocol error: malformed command while upstream unavailable\r\n",
+        ]
+        .concat();
+        assert_eq!(String::from_utf8_lossy(&replies), String::from_utf8_lossy(&expected));
+        assert_eq!(proxy.await.unwrap().reason, CloseReason::ProtocolError);
+    }
+
+    #[tokio::test]
+    async fn test_queued_commands_fail_when_upstream_stays_down() {
+        let (client, mut client_remote) = duplex(1 << 16);
+        let (upstream, mut first) = duplex(1 << 16);
+        let (replacement, _) = duplex(1 << 16);
+
+        // The queueing window passes on virtual time
+        let stats = Stats::with_clock(MockClock::start());
+        let cfg = config(&["--parse-replies", "on", "--upstream-unavailable-queue-time", "30s"]);
+        let mut ctx = ProxyContext::new(peer(), stats, cfg);
+        let (dialing, never) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        ctx.reconnect = Some(reconnect_to(replacement, dialing, never));
+        let proxy = tokio::spawn(proxy_connection(client, upstream, ctx));
+
+        client_remote.write_all(b"SET a 1\r\n").await.unwrap();
+        let mut request = [0u8; 9];
+        first.read_exact(&mut request).await.unwrap();
+        drop(first);
+        client_remote.write_all(b"GET a\r\n").await.unwrap();
+
+        let mut reply = Vec::new();
+        client_remote.read_to_end(&mut reply).await.unwrap();
+        // SET reached the lost upstream and may have run; GET never ran anywhere
+        let expected = [NOT_REPLAYED_REPLY.as_bytes(), UNAVAILABLE_REPLY.as_bytes()].concat();
+        assert_eq!(reply, expected);
+        // Depending on whether GET was written before the close was seen
+        let reason = proxy.await.unwrap().reason;
+        assert!(matches!(reason, CloseReason::UpstreamClosed | CloseReason::UpstreamReset));
+    }
 }
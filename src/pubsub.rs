@@ -0,0 +1,215 @@
+//! Pub/sub subscriptions and their confirmations.
+//!
+//! `SUBSCRIBE a b c` is answered with three confirmation frames, one per channel, and
+//! a subscribed RESP2 connection receives messages no command asked for. To keep
+//! replies matched to commands, this recognises both kinds of frame and tracks which
+//! channels each connection is subscribed to, which also tells how many confirmations
+//! an `UNSUBSCRIBE` without arguments will produce.
+
+use std::collections::HashSet;
+
+use crate::resp::{flat_array, Command};
+
+/// The kind of subscription a command or confirmation is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    /// `SUBSCRIBE` / `UNSUBSCRIBE`
+    Channel,
+    /// `PSUBSCRIBE` / `PUNSUBSCRIBE`
+    Pattern,
+    /// `SSUBSCRIBE` / `SUNSUBSCRIBE`
+    Shard,
+}
+
+/// How many replies a command is answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// A single reply, like almost every command
+    One,
+    /// This many confirmations of the family
+    Confirmations(Family, usize),
+    /// Confirmations until no subscriptions of the family remain (at least one)
+    UntilUnsubscribed(Family),
+}
+
+/// The replies `cmd` will be answered with.
+pub fn expected(cmd: &Command) -> Expected {
+    let Some((family, subscribe)) = classify(&cmd.name) else {
+        return Expected::One;
+    };
+    match cmd.args.len() {
+        // Subscribing to nothing is an error with a single reply
+        0 if subscribe => Expected::One,
+        0 => Expected::UntilUnsubscribed(family),
+        n => Expected::Confirmations(family, n),
+    }
+}
+
+/// A frame from upstream that belongs to pub/sub rather than to an ordinary command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A channel was subscribed to or unsubscribed from
+    Confirmation {
+        family: Family,
+        subscribe: bool,
+        channel: String,
+    },
+    /// A published message delivered to a subscriber
+    Message,
+}
+
+/// The pub/sub event a reply frame carries, if it looks like one.
+///
+/// Whether the frame really is one depends on what the connection is waiting for: an
+/// ordinary array reply can contain the same strings.
+pub fn event(frame: &[u8]) -> Option<Event> {
+    let elements = flat_array(frame)?;
+    let kind = elements.first()?.to_ascii_lowercase();
+    match kind.as_str() {
+        "message" | "pmessage" | "smessage" => Some(Event::Message),
+        _ => {
+            let (family, subscribe) = classify(&kind)?;
+            let [_, channel, count] = elements.as_slice() else {
+                return None;
+            };
+            count.parse::<i64>().ok()?;
+            Some(Event::Confirmation {
+                family,
+                subscribe,
+                channel: channel.clone(),
+            })
+        }
+    }
+}
+
+/// The family of a subscribe or unsubscribe command name, and whether it subscribes.
+fn classify(name: &str) -> Option<(Family, bool)> {
+    let name = name.to_ascii_lowercase();
+    let (family, subscribe) = match name.as_str() {
+        "subscribe" => (Family::Channel, true),
+        "unsubscribe" => (Family::Channel, false),
+        "psubscribe" => (Family::Pattern, true),
+        "punsubscribe" => (Family::Pattern, false),
+        "ssubscribe" => (Family::Shard, true),
+        "sunsubscribe" => (Family::Shard, false),
+        _ => return None,
+    };
+    Some((family, subscribe))
+}
+
+/// The subscriptions active on one connection.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shard_channels: HashSet<String>,
+}
+
+impl Subscriptions {
+    /// Apply a confirmation from upstream.
+    pub fn apply(&mut self, family: Family, subscribe: bool, channel: &str) {
+        let set = self.family_mut(family);
+        if subscribe {
+            set.insert(channel.to_string());
+        } else {
+            set.remove(channel);
+        }
+    }
+
+    /// Active subscriptions of one family.
+    pub fn count(&self, family: Family) -> usize {
+        match family {
+            Family::Channel => self.channels.len(),
+            Family::Pattern => self.patterns.len(),
+            Family::Shard => self.shard_channels.len(),
+        }
+    }
+
+    /// Active subscriptions of every family.
+    pub fn total(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    fn family_mut(&mut self, family: Family) -> &mut HashSet<String> {
+        match family {
+            Family::Channel => &mut self.channels,
+            Family::Pattern => &mut self.patterns,
+            Family::Shard => &mut self.shard_channels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        let mut words = line.split(' ').map(str::to_string);
+        Command {
+            name: words.next().unwrap(),
+            args: words.collect(),
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_expected_replies() {
+        assert_eq!(expected(&command("GET k")), Expected::One);
+        assert_eq!(
+            expected(&command("SUBSCRIBE a b c")),
+            Expected::Confirmations(Family::Channel, 3)
+        );
+        assert_eq!(
+            expected(&command("psubscribe news.*")),
+            Expected::Confirmations(Family::Pattern, 1)
+        );
+        assert_eq!(
+            expected(&command("SUNSUBSCRIBE")),
+            Expected::UntilUnsubscribed(Family::Shard)
+        );
+        assert_eq!(expected(&command("SUBSCRIBE")), Expected::One);
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(
+            event(b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n"),
+            Some(Event::Confirmation {
+                family: Family::Channel,
+                subscribe: true,
+                channel: "a".to_string(),
+            })
+        );
+        assert_eq!(
+            event(b">3\r\n$12\r\npunsubscribe\r\n$2\r\nn*\r\n:0\r\n"),
+            Some(Event::Confirmation {
+                family: Family::Pattern,
+                subscribe: false,
+                channel: "n*".to_string(),
+            })
+        );
+        assert_eq!(
+            event(b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n"),
+            Some(Event::Message)
+        );
+        assert_eq!(event(b"*2\r\n$9\r\nsubscribe\r\n$1\r\na\r\n"), None);
+        assert_eq!(event(b"*2\r\n$4\r\npong\r\n$0\r\n\r\n"), None);
+        assert_eq!(event(b"+OK\r\n"), None);
+    }
+
+    #[test]
+    fn test_subscriptions_counted() {
+        let mut subs = Subscriptions::default();
+        subs.apply(Family::Channel, true, "a");
+        subs.apply(Family::Channel, true, "a");
+        subs.apply(Family::Channel, true, "b");
+        subs.apply(Family::Pattern, true, "n*");
+        assert_eq!(subs.count(Family::Channel), 2);
+        assert_eq!(subs.total(), 3);
+
+        subs.apply(Family::Channel, false, "a");
+        subs.apply(Family::Shard, false, "never");
+        assert_eq!(subs.count(Family::Channel), 1);
+        assert_eq!(subs.total(), 2);
+    }
+}
@@ -0,0 +1,204 @@
+//! Command rate limiting, per client connection and across all of them.
+//!
+//! `--max-commands-per-sec` gives each connection a token bucket holding up to one second's
+//! worth of commands, refilled continuously from the time elapsed since it was last used
+//! rather than on a fixed tick, so bursts are measured exactly whenever they arrive. Over
+//! the limit, commands are answered with an error or held back until the bucket allows
+//! them, depending on `--rate-limit-action`.
+//!
+//! `--global-max-commands-per-sec` is shared by every connection. Rather than a bucket
+//! behind a lock, it keeps a single atomic: the time at which the next command conforms
+//! to the rate (the generic cell rate algorithm). Each command advances it with one
+//! compare-and-swap, so connections never wait on each other to take a slot. A command
+//! whose slot lies in the future waits for it, up to `--global-rate-limit-queue-ms`;
+//! beyond that the queue is full and it is refused.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Reply to a command refused by `--rate-limit-action reject`.
+pub const RATE_LIMITED_REPLY: &str = "-ERR rate limit exceeded\r\n";
+
+/// Reply to a command refused because the global rate limit's queue is full.
+pub const GLOBAL_RATE_LIMITED_REPLY: &str = "-ERR global rate limit exceeded\r\n";
+
+/// Burst allowed on top of the steady rate: a second's worth of commands.
+const BURST: Duration = Duration::from_secs(1);
+
+/// Token bucket of commands for one connection.
+#[derive(Debug)]
+pub struct CommandRate {
+    per_sec: f64,
+    /// Commands that may pass without waiting; negative while delayed commands are paid off
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl CommandRate {
+    /// A full bucket of `per_sec` commands.
+    pub fn new(per_sec: u64, now: Instant) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            tokens: per_sec as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Take a command's token if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Take a command's token, going into debt if there is none; see [`CommandRate::wait`].
+    pub fn take(&mut self, now: Instant) {
+        self.refill(now);
+        self.tokens -= 1.0;
+    }
+
+    /// How long until the commands taken so far are within the limit.
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.per_sec)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.per_sec);
+        self.refilled_at = now;
+    }
+}
+
+/// Command budget shared by all connections.
+#[derive(Debug)]
+pub struct GlobalRate {
+    /// Nanoseconds between commands at the steady rate
+    interval: u64,
+    /// How far ahead of now a command's slot may be before it is refused, in nanoseconds
+    horizon: u64,
+    epoch: Instant,
+    /// When the next command conforms to the rate, in nanoseconds since `epoch`
+    next: AtomicU64,
+}
+
+impl GlobalRate {
+    /// Allow `per_sec` commands per second with a second's burst, queueing commands over
+    /// that for up to `max_queue`.
+    pub fn new(per_sec: u64, max_queue: Duration, now: Instant) -> Arc<Self> {
+        let interval = (1_000_000_000 / per_sec.max(1)).max(1);
+        Arc::new(Self {
+            interval,
+            horizon: (BURST + max_queue).as_nanos() as u64,
+            epoch: now,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Reserve a slot for one command at `now`: how long it must wait for its slot, or
+    /// `None` if that would take longer than the queue allows.
+    pub fn reserve(&self, now: Instant) -> Option<Duration> {
+        let now = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let reserved = next.max(now) + self.interval;
+            if reserved - now > self.horizon {
+                return None;
+            }
+            match self.next.compare_exchange_weak(
+                next,
+                reserved,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    let ahead = Duration::from_nanos(reserved - now);
+                    return Some(ahead.saturating_sub(BURST));
+                }
+                Err(actual) => next = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill_by_elapsed_time() {
+        let start = Instant::now();
+        let mut rate = CommandRate::new(10, start);
+        assert!((0..10).all(|_| rate.try_take(start)));
+        assert!(!rate.try_take(start));
+
+        // Refilled in proportion to the time passed, not in whole ticks
+        let later = start + Duration::from_millis(250);
+        assert!(rate.try_take(later) && rate.try_take(later));
+        assert!(!rate.try_take(later));
+
+        // Never more than a second's worth, however long it sat idle
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| rate.try_take(much_later)).count(), 10);
+    }
+
+    #[test]
+    fn test_wait_pays_off_debt() {
+        let start = Instant::now();
+        let mut rate = CommandRate::new(100, start);
+        for _ in 0..150 {
+            rate.take(start);
+        }
+        assert_eq!(rate.wait(start), Duration::from_millis(500));
+        let later = start + Duration::from_millis(250);
+        assert_eq!(rate.wait(later), Duration::from_millis(250));
+        // Until the debt is paid, none are available without waiting
+        assert!(!rate.try_take(later));
+        assert_eq!(rate.wait(start + Duration::from_millis(500)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_global_burst_then_queue_then_refuse() {
+        let start = Instant::now();
+        let global = GlobalRate::new(10, Duration::from_millis(200), start);
+        // A second's burst goes straight through
+        for _ in 0..10 {
+            assert_eq!(global.reserve(start), Some(Duration::ZERO));
+        }
+        // The next two queue for their slots, then the queue is full
+        assert_eq!(global.reserve(start), Some(Duration::from_millis(100)));
+        assert_eq!(global.reserve(start), Some(Duration::from_millis(200)));
+        assert_eq!(global.reserve(start), None);
+
+        // Slots free up with time
+        let later = start + Duration::from_millis(150);
+        assert_eq!(global.reserve(later), Some(Duration::from_millis(150)));
+        assert_eq!(global.reserve(later), None);
+        let idle = start + Duration::from_secs(10);
+        assert_eq!(global.reserve(idle), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_global_slots_never_handed_out_twice() {
+        let start = Instant::now();
+        let global = GlobalRate::new(1000, Duration::from_secs(10), start);
+        let reserve = || -> Vec<_> { (0..500).map(|_| global.reserve(start).unwrap()).collect() };
+        let mut waits: Vec<Duration> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8).map(|_| scope.spawn(reserve)).collect();
+            threads.into_iter().flat_map(|t| t.join().unwrap()).collect()
+        });
+
+        // 4000 commands at 1000/sec: a second's burst, then one slot per millisecond
+        waits.sort();
+        assert_eq!(waits.iter().filter(|w| w.is_zero()).count(), 1000);
+        let queued: Vec<_> = (1..=3000).map(Duration::from_millis).collect();
+        assert_eq!(waits[1000..], queued);
+    }
+}
@@ -0,0 +1,272 @@
+//! Redaction of command arguments in the verbose log.
+//!
+//! Arguments carry values and credentials, so they are only logged when policy allows:
+//! `--redact-args` hides all of them, `--redact-command` hides those of chosen commands,
+//! and the rest are cut to `--log-arg-max-len` bytes each. Passwords inside commands that
+//! are otherwise logged, such as `CONFIG SET requirepass`, are always masked. Command
+//! samples kept for `/samples` follow the same policy, replies included.
+
+use crate::config::Config;
+use crate::resp::Command;
+
+/// Commands whose arguments are never logged, whatever the configuration: they carry
+/// passwords (`HELLO` through its `AUTH` option).
+const ALWAYS_REDACTED: &[&str] = &["AUTH", "HELLO"];
+
+/// Stands in for a masked argument.
+const REDACTED: &str = "(redacted)";
+
+/// `CONFIG SET` parameters whose values are passwords.
+const SECRET_PARAMETERS: &[&str] =
+    &["requirepass", "masterauth", "tls-key-file-pass", "tls-client-key-file-pass"];
+
+/// The arguments of command `name` with passwords replaced by `(redacted)`: those of
+/// `AUTH`, `HELLO ... AUTH` and `MIGRATE ... AUTH|AUTH2`, secret `CONFIG SET` values,
+/// and the password rules of `ACL SETUSER`.
+pub fn redact_command(name: &str, args: &[String]) -> Vec<String> {
+    let is = |arg: &String, word: &str| arg.eq_ignore_ascii_case(word);
+    let position = |args: &[String], word: &str| args.iter().position(|a| is(a, word));
+
+    let secrets: Vec<usize> = match name.to_ascii_uppercase().as_str() {
+        // AUTH [username] password
+        "AUTH" => args.len().checked_sub(1).into_iter().collect(),
+        // HELLO [protover [AUTH username password] [SETNAME name]], options walked in turn
+        // so that a client name spelled like the option is not taken for it
+        "HELLO" => {
+            let mut secrets = Vec::new();
+            let mut i = 1;
+            while i < args.len() {
+                if is(&args[i], "AUTH") {
+                    secrets.push(i + 2);
+                    i += 3;
+                } else {
+                    i += 2;
+                }
+            }
+            secrets
+        }
+        // MIGRATE host port key db timeout [COPY] [REPLACE]
+        //     [AUTH password | AUTH2 username password] [KEYS key ...]
+        "MIGRATE" => {
+            const FIXED: usize = 5;
+            let options = &args[FIXED.min(args.len())..];
+            let options = &options[..position(options, "KEYS").unwrap_or(options.len())];
+            let auth = position(options, "AUTH").map(|i| FIXED + i + 1);
+            let auth2 = position(options, "AUTH2").map(|i| FIXED + i + 2);
+            auth.into_iter().chain(auth2).collect()
+        }
+        // CONFIG SET parameter value [parameter value ...]
+        "CONFIG" if args.first().is_some_and(|a| is(a, "SET")) => (1..args.len())
+            .step_by(2)
+            .filter(|&i| SECRET_PARAMETERS.iter().any(|p| is(&args[i], p)))
+            .map(|i| i + 1)
+            .collect(),
+        // ACL SETUSER username [rule ...], where >pass, <pass, #hash and !hash name passwords
+        "ACL" if args.first().is_some_and(|a| is(a, "SETUSER")) => (2..args.len())
+            .filter(|&i| args[i].starts_with(['>', '<', '#', '!']))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut args = args.to_vec();
+    for i in secrets {
+        if let Some(arg) = args.get_mut(i) {
+            *arg = REDACTED.to_string();
+        }
+    }
+    args
+}
+
+/// Decides how much of a command may appear in the log.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    all: bool,
+    commands: Vec<String>,
+    max_len: usize,
+}
+
+impl Redactor {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            all: config.redact_args,
+            commands: config.redact_command.clone(),
+            max_len: config.log_arg_max_len,
+        }
+    }
+
+    /// The same policy with arguments cut to `max_len` bytes instead.
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len, ..self }
+    }
+
+    fn redacts(&self, name: &str) -> bool {
+        self.all
+            || ALWAYS_REDACTED.iter().any(|c| name.eq_ignore_ascii_case(c))
+            || self.commands.iter().any(|c| name.eq_ignore_ascii_case(c))
+    }
+
+    /// The command as it may be logged: its name followed by the arguments, or by a
+    /// count of them if they are redacted.
+    pub fn describe(&self, cmd: &Command) -> String {
+        if cmd.args.is_empty() {
+            return cmd.name.clone();
+        }
+        if self.redacts(&cmd.name) {
+            return format!("{} ({} args redacted)", cmd.name, cmd.args.len());
+        }
+        let mut line = cmd.name.clone();
+        for arg in redact_command(&cmd.name, &cmd.args) {
+            line.push(' ');
+            if arg == REDACTED {
+                line.push_str(REDACTED);
+            } else {
+                line.push_str(&self.truncate(&arg));
+            }
+        }
+        line
+    }
+
+    /// The reply to a command named `name` as it may be shown: control bytes escaped,
+    /// or only its size if the command's arguments are redacted, since replies carry the
+    /// same values.
+    pub fn describe_reply(&self, name: &str, frame: &[u8]) -> String {
+        if self.redacts(name) {
+            return format!("({} bytes redacted)", frame.len());
+        }
+        self.truncate(&frame.escape_ascii().to_string())
+    }
+
+    fn truncate(&self, arg: &str) -> String {
+        if arg.len() <= self.max_len {
+            return arg.to_string();
+        }
+        let mut end = self.max_len;
+        while !arg.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}...(+{} bytes)", &arg[..end], arg.len() - end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn redactor(args: &[&str]) -> Redactor {
+        let argv = ["redis-tls-proxy", "--no-tls"].iter().chain(args);
+        Redactor::new(&Config::parse_from(argv))
+    }
+
+    fn command(words: &[&str]) -> Command {
+        Command {
+            name: words[0].to_string(),
+            args: words[1..].iter().map(|w| w.to_string()).collect(),
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_auth_never_logged() {
+        for args in [&[][..], &["--redact-command", "CONFIG"]] {
+            let redactor = redactor(args);
+            let described = redactor.describe(&command(&["auth", "user", "s3cret"]));
+            assert_eq!(described, "auth (2 args redacted)");
+            let described = redactor.describe(&command(&["HELLO", "3", "AUTH", "u", "s3cret"]));
+            assert!(!described.contains("s3cret"), "{described}");
+        }
+        assert_eq!(redactor(&[]).describe(&command(&["GET", "user:1"])), "GET user:1");
+    }
+
+    fn redacted(words: &[&str]) -> Vec<String> {
+        let args: Vec<_> = words[1..].iter().map(|w| w.to_string()).collect();
+        redact_command(words[0], &args)
+    }
+
+    #[test]
+    fn test_passwords_masked() {
+        assert_eq!(redacted(&["AUTH", "s3cret"]), ["(redacted)"]);
+        assert_eq!(redacted(&["auth", "user", "s3cret"]), ["user", "(redacted)"]);
+        assert_eq!(
+            redacted(&["HELLO", "3", "auth", "user", "s3cret", "SETNAME", "app"]),
+            ["3", "auth", "user", "(redacted)", "SETNAME", "app"]
+        );
+        // A client name spelled like the option is not taken for it
+        assert_eq!(
+            redacted(&["HELLO", "3", "SETNAME", "auth", "AUTH", "u", "pw"]),
+            ["3", "SETNAME", "auth", "AUTH", "u", "(redacted)"]
+        );
+        assert_eq!(
+            redacted(&["config", "set", "maxmemory", "1gb", "REQUIREPASS", "s3cret"]),
+            ["set", "maxmemory", "1gb", "REQUIREPASS", "(redacted)"]
+        );
+        // Only CONFIG SET carries values
+        assert_eq!(redacted(&["CONFIG", "GET", "requirepass"]), ["GET", "requirepass"]);
+        // A key named like the option is not taken for it
+        assert_eq!(
+            redacted(&["MIGRATE", "h", "1", "", "0", "5", "AUTH2", "u", "pw", "KEYS", "auth"]),
+            ["h", "1", "", "0", "5", "AUTH2", "u", "(redacted)", "KEYS", "auth"]
+        );
+        assert_eq!(
+            redacted(&["MIGRATE", "h", "6379", "k", "0", "5000", "AUTH", "s3cret"]),
+            ["h", "6379", "k", "0", "5000", "AUTH", "(redacted)"]
+        );
+        // Nor is a key among the fixed arguments
+        assert_eq!(
+            redacted(&["MIGRATE", "h", "6379", "auth", "0", "5000", "AUTH", "s3cret"]),
+            ["h", "6379", "auth", "0", "5000", "AUTH", "(redacted)"]
+        );
+        assert_eq!(
+            redacted(&["MIGRATE", "h", "6379", "keys", "0", "5000", "AUTH", "s3cret"]),
+            ["h", "6379", "keys", "0", "5000", "AUTH", "(redacted)"]
+        );
+        assert_eq!(
+            redacted(&["ACL", "SETUSER", "alice", "on", ">s3cret", "#ab12", "~cache:*"]),
+            ["SETUSER", "alice", "on", "(redacted)", "(redacted)", "~cache:*"]
+        );
+        assert_eq!(redacted(&["SET", "requirepass", "v"]), ["requirepass", "v"]);
+    }
+
+    #[test]
+    fn test_masked_arguments_not_truncated() {
+        let redactor = redactor(&["--log-arg-max-len", "4"]);
+        let described = redactor.describe(&command(&["CONFIG", "SET", "masterauth", "s3cret"]));
+        assert_eq!(described, "CONFIG SET mast...(+6 bytes) (redacted)");
+    }
+
+    #[test]
+    fn test_redact_chosen_commands_or_all() {
+        let chosen = redactor(&["--redact-command", "CONFIG,set"]);
+        assert_eq!(chosen.describe(&command(&["SET", "k", "v"])), "SET (2 args redacted)");
+        let described = chosen.describe(&command(&["config", "get", "maxmemory"]));
+        assert_eq!(described, "config (2 args redacted)");
+        assert_eq!(chosen.describe(&command(&["GET", "k"])), "GET k");
+
+        let all = redactor(&["--redact-args"]);
+        assert_eq!(all.describe(&command(&["GET", "k"])), "GET (1 args redacted)");
+        assert_eq!(all.describe(&command(&["PING"])), "PING");
+    }
+
+    #[test]
+    fn test_long_values_truncated() {
+        let redactor = redactor(&["--log-arg-max-len", "4"]);
+        let described = redactor.describe(&command(&["SET", "key", "abcdefgh"]));
+        assert_eq!(described, "SET key abcd...(+4 bytes)");
+        // Never cut inside a character
+        let described = redactor.describe(&command(&["SET", "k", "abcdé"]));
+        assert_eq!(described, "SET k abcd...(+2 bytes)");
+        let described = redactor.describe(&command(&["SET", "k", "abcé"]));
+        assert_eq!(described, "SET k abc...(+2 bytes)");
+    }
+
+    #[test]
+    fn test_replies_follow_command_policy() {
+        let chosen = redactor(&["--redact-command", "GET"]);
+        assert_eq!(chosen.describe_reply("SET", b"+OK\r\n"), "+OK\\r\\n");
+        assert_eq!(chosen.describe_reply("get", b"$6\r\ns3cret\r\n"), "(12 bytes redacted)");
+        assert_eq!(chosen.describe_reply("AUTH", b"+OK\r\n"), "(5 bytes redacted)");
+
+        let short = redactor(&[]).with_max_len(4);
+        assert_eq!(short.describe_reply("GET", b"$3\r\nabc\r\n"), "$3\\r...(+9 bytes)");
+    }
+}
@@ -0,0 +1,93 @@
+//! Which commands are safe to send again after an upstream reconnect.
+//!
+//! A command that was sent to an upstream that then went away may or may not have run.
+//! Running a read again is harmless, but running `INCR` or `LPUSH` twice is not, and even
+//! a plain `SET` replayed late can overwrite a newer value written by another client. So
+//! only commands known to have no effect are replayed; everything else is assumed unsafe.
+//!
+//! Nor can anything be replayed once the client has set up its connection with commands
+//! such as `SELECT` or `HELLO`: the new connection only gets the proxy's own setup, so a
+//! replayed read would run against the wrong database or be answered in the wrong protocol.
+//! Keys watched with `WATCH` are likewise lost, and a later `EXEC` would not notice that
+//! they changed.
+
+use crate::resp::Command;
+
+/// Whether running `name` a second time cannot change the data or its own reply.
+pub fn is_idempotent(name: &str) -> bool {
+    const IDEMPOTENT: &[&str] = &[
+        // Keys and strings
+        "GET", "MGET", "EXISTS", "TTL", "PTTL", "EXPIRETIME", "PEXPIRETIME", "TYPE", "STRLEN",
+        "GETRANGE", "SUBSTR", "GETBIT", "BITCOUNT", "BITPOS", "LCS", "DUMP", "RANDOMKEY",
+        // Hashes
+        "HGET", "HMGET", "HGETALL", "HEXISTS", "HLEN", "HKEYS", "HVALS", "HSTRLEN", "HSCAN",
+        // Lists
+        "LLEN", "LRANGE", "LINDEX", "LPOS",
+        // Sets
+        "SCARD", "SISMEMBER", "SMISMEMBER", "SMEMBERS", "SINTER", "SUNION", "SDIFF",
+        "SINTERCARD", "SSCAN",
+        // Sorted sets
+        "ZCARD", "ZSCORE", "ZMSCORE", "ZRANK", "ZREVRANK", "ZCOUNT", "ZLEXCOUNT", "ZRANGE",
+        "ZRANGEBYSCORE", "ZRANGEBYLEX", "ZREVRANGE", "ZREVRANGEBYSCORE", "ZREVRANGEBYLEX",
+        "ZUNION", "ZINTER", "ZDIFF", "ZINTERCARD", "ZSCAN",
+        // Streams, geo and HyperLogLog
+        "XLEN", "XRANGE", "XREVRANGE", "GEOPOS", "GEODIST", "GEOHASH", "GEOSEARCH", "PFCOUNT",
+        // Server
+        "PING", "ECHO", "DBSIZE", "SCAN", "KEYS", "TIME", "INFO",
+    ];
+    IDEMPOTENT.iter().any(|known| name.eq_ignore_ascii_case(known))
+}
+
+/// Whether `cmd` changes state kept by the upstream connection it runs on, which a new
+/// connection would not have.
+pub fn changes_connection_state(cmd: &Command) -> bool {
+    const STATEFUL: &[&str] =
+        &["SELECT", "HELLO", "AUTH", "READONLY", "READWRITE", "WATCH", "UNWATCH"];
+    // CLIENT subcommands that change how this connection is treated
+    const CLIENT: &[&str] = &["SETNAME", "SETINFO", "TRACKING", "REPLY", "NO-EVICT", "NO-TOUCH"];
+    let listed = |names: &[&str], name: &str| names.iter().any(|n| name.eq_ignore_ascii_case(n));
+    listed(STATEFUL, &cmd.name)
+        || (cmd.name.eq_ignore_ascii_case("CLIENT")
+            && cmd.args.first().is_some_and(|sub| listed(CLIENT, sub)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(words: &[&str]) -> Command {
+        Command {
+            name: words[0].to_string(),
+            args: words[1..].iter().map(|w| w.to_string()).collect(),
+            span: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_connection_state_changes() {
+        for words in [&["select", "3"][..], &["HELLO"], &["AUTH", "pw"], &["CLIENT", "reply"]] {
+            assert!(changes_connection_state(&command(words)), "{words:?}");
+        }
+        // A new connection would not watch the keys, and a later EXEC would not see changes
+        for words in [&["WATCH", "k1", "k2"][..], &["unwatch"]] {
+            assert!(changes_connection_state(&command(words)), "{words:?}");
+        }
+        for words in [&["GET", "k"][..], &["CLIENT", "LIST"], &["CLIENT"], &["PING"]] {
+            assert!(!changes_connection_state(&command(words)), "{words:?}");
+        }
+    }
+
+    #[test]
+    fn test_reads_are_idempotent() {
+        for name in ["GET", "get", "EXISTS", "TTL", "HGETALL", "ZRANGE", "PING"] {
+            assert!(is_idempotent(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_writes_are_not_idempotent() {
+        for name in ["INCR", "LPUSH", "SET", "DEL", "EXPIRE", "EVAL", "MULTI", "UNKNOWN"] {
+            assert!(!is_idempotent(name), "{name}");
+        }
+    }
+}
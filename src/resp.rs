@@ -1,122 +1,530 @@
-/// RESP (Redis Serialization Protocol) parser.
-///
-/// This module handles parsing of the Redis protocol to extract command names
-/// from the client->server data stream.
+//! RESP (Redis Serialization Protocol) parser.
+//!
+//! This module handles parsing of the Redis protocol to extract command names
+//! from the client->server data stream, and to delimit replies in the
+//! server->client stream so they can be matched to the commands that caused them.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+/// A complete command parsed from the client stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    /// Command name as sent by the client (case preserved).
+    pub name: String,
+    /// Arguments following the command name.
+    pub args: Vec<String>,
+    /// Byte range the command occupies in the parsed buffer.
+    pub span: Range<usize>,
+}
+
+/// Result of parsing a client buffer into commands.
+#[derive(Debug, Default)]
+pub struct ParsedCommands {
+    pub commands: Vec<Command>,
+    /// Bytes consumed by complete frames. Anything after this is an incomplete command.
+    pub consumed: usize,
+    /// Parsing stopped at bytes that can never form a valid command.
+    pub malformed: bool,
+    /// What stopped parsing was a count or length line running past [`MAX_HEADER_LEN`]
+    /// bytes without its line ending.
+    pub header_too_long: bool,
+}
+
+/// A complete reply parsed from the upstream stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// RESP type marker of the reply (`+`, `-`, `:`, `$`, `*`, or for RESP3 `>` pushes,
+    /// `,` doubles, `#` booleans and `_` nulls).
+    pub kind: u8,
+    /// Byte range the reply occupies in the parsed buffer.
+    pub span: Range<usize>,
+}
+
+/// Result of parsing an upstream buffer into replies.
+#[derive(Debug, Default)]
+pub struct ParsedReplies {
+    pub replies: Vec<Reply>,
+    /// Bytes consumed by complete replies. Anything after this is an incomplete reply.
+    pub consumed: usize,
+    /// Parsing stopped at bytes that can never form a valid reply.
+    pub malformed: bool,
+}
+
+/// Longest count or length line accepted, line ending included. An `i64` takes at most 20.
+pub const MAX_HEADER_LEN: usize = 32;
+
+/// Why input can never become a valid frame, no matter how much more arrives.
+#[derive(Debug, PartialEq, Eq)]
+enum Malformed {
+    Invalid,
+    /// A count or length line without a line ending within [`MAX_HEADER_LEN`] bytes
+    HeaderTooLong,
+}
+
+/// Outcome of a parse step: `Ok(None)` means more data is needed.
+type Step<T> = Result<Option<T>, Malformed>;
+
+/// Longest bulk string accepted from clients by default, as Redis's `proto-max-bulk-len`.
+pub const DEFAULT_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+
+/// A single complete frame: a command, or bytes with no command in them (e.g. `*0\r\n`).
+struct Frame {
+    name: Option<String>,
+    args: Vec<String>,
+    len: usize,
+}
 
 /// Parse RESP protocol to extract command names from the buffer.
 /// Returns the commands found and how many bytes were consumed.
+#[cfg_attr(not(test), allow(dead_code))]
 pub fn parse_commands(buf: &[u8]) -> (Vec<String>, usize) {
-    let mut commands = Vec::new();
+    let (commands, consumed) = parse_commands_full(buf);
+    let names = commands.into_iter().map(|mut tokens| tokens.swap_remove(0)).collect();
+    (names, consumed)
+}
+
+/// Parse RESP protocol into complete commands, each as its name followed by its arguments.
+/// Returns the commands found and how many bytes were consumed; an incomplete command at
+/// the end is left unconsumed.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn parse_commands_full(buf: &[u8]) -> (Vec<Vec<String>>, usize) {
+    let parsed = parse_command_frames(buf);
+    let commands = parsed
+        .commands
+        .into_iter()
+        .map(|c| {
+            let mut tokens = Vec::with_capacity(1 + c.args.len());
+            tokens.push(c.name);
+            tokens.extend(c.args);
+            tokens
+        })
+        .collect();
+    (commands, parsed.consumed)
+}
+
+/// Parse all complete commands in the buffer, recording where each one sits.
+pub fn parse_command_frames(buf: &[u8]) -> ParsedCommands {
+    parse_command_frames_limited(buf, DEFAULT_MAX_BULK_LEN)
+}
+
+/// Like [`parse_command_frames`], but a bulk string declaring more than `max_bulk_len`
+/// bytes is malformed, so a crafted length header cannot make the proxy wait for (and
+/// buffer) data that should never be accepted.
+pub fn parse_command_frames_limited(buf: &[u8], max_bulk_len: u64) -> ParsedCommands {
+    let mut parsed = ParsedCommands::default();
     let mut pos = 0;
 
     while pos < buf.len() {
-        // Commands are RESP arrays starting with '*'
-        if buf[pos] != b'*' {
-            // Inline command (space-separated) - find the command name
-            if let Some(cmd) = parse_inline_command(&buf[pos..]) {
-                commands.push(cmd.0);
-                pos += cmd.1;
-                continue;
+        // Commands are RESP arrays starting with '*', anything else is an inline command
+        let frame = if buf[pos] == b'*' {
+            parse_array_command(&buf[pos..], max_bulk_len)
+        } else {
+            parse_inline_command(&buf[pos..])
+        };
+
+        match frame {
+            Ok(Some(frame)) => {
+                if let Some(name) = frame.name {
+                    parsed.commands.push(Command {
+                        name,
+                        args: frame.args,
+                        span: pos..pos + frame.len,
+                    });
+                }
+                pos += frame.len;
+            }
+            Ok(None) => break, // Incomplete
+            Err(reason) => {
+                parsed.malformed = true;
+                parsed.header_too_long = reason == Malformed::HeaderTooLong;
+                break;
             }
-            break;
         }
+    }
 
-        // Parse array: *<count>\r\n
-        let Some((array_len, consumed)) = parse_integer(&buf[pos + 1..]) else {
-            break; // Incomplete
-        };
-        pos += 1 + consumed;
+    parsed.consumed = pos;
+    parsed
+}
 
-        if array_len <= 0 {
-            continue;
-        }
+/// The RESP version a connection's replies are encoded in, as chosen with `HELLO`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RespVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// The protocol version `HELLO <protover>` asks for. `None` for other commands and a
+/// `HELLO` without a version, which keeps the current one.
+pub fn hello_version(cmd: &Command) -> Option<RespVersion> {
+    if !cmd.name.eq_ignore_ascii_case("HELLO") {
+        return None;
+    }
+    match cmd.args.first()?.as_str() {
+        "2" => Some(RespVersion::Resp2),
+        "3" => Some(RespVersion::Resp3),
+        _ => None,
+    }
+}
+
+/// Parse all complete replies in the buffer, recording where each one sits. The
+/// aggregate and blob types RESP3 adds are only accepted for `RespVersion::Resp3`.
+pub fn parse_reply_frames(buf: &[u8], version: RespVersion) -> ParsedReplies {
+    let mut parsed = ParsedReplies::default();
+    let mut pos = 0;
 
-        // First element is the command name (bulk string)
-        if pos >= buf.len() || buf[pos] != b'$' {
-            break;
+    while pos < buf.len() {
+        match skip_reply(&buf[pos..], version, u64::MAX) {
+            Ok(Some(len)) => {
+                parsed.replies.push(Reply {
+                    kind: buf[pos],
+                    span: pos..pos + len,
+                });
+                pos += len;
+            }
+            Ok(None) => break, // Incomplete
+            Err(_) => {
+                parsed.malformed = true;
+                break;
+            }
         }
+    }
+
+    parsed.consumed = pos;
+    parsed
+}
 
-        let Some((str_len, consumed)) = parse_integer(&buf[pos + 1..]) else {
-            break;
+/// Returns the length of the single complete reply at the start of `buf`.
+///
+/// Nested arrays are walked iteratively, counting the elements still owed, so deeply
+/// nested replies cannot exhaust the stack.
+fn skip_reply(buf: &[u8], version: RespVersion, max_bulk_len: u64) -> Step<usize> {
+    let mut pos = 0;
+    let mut remaining: u64 = 1;
+
+    while remaining > 0 {
+        let Some(&marker) = buf.get(pos) else {
+            return Ok(None);
         };
-        pos += 1 + consumed;
+        remaining -= 1;
 
-        if str_len < 0 {
-            continue; // Null bulk string
+        // Pushes, doubles, booleans and nulls are tolerated in RESP2 too
+        if version == RespVersion::Resp2
+            && matches!(marker, b'(' | b'=' | b'!' | b'~' | b'%' | b'|')
+        {
+            return Err(Malformed::Invalid);
         }
 
-        let str_len = str_len as usize;
-        if pos + str_len + 2 > buf.len() {
-            break; // Incomplete
+        match marker {
+            // Simple strings, errors, integers, and RESP3 doubles and big numbers
+            b'+' | b'-' | b':' | b',' | b'(' => {
+                let Some(end) = find_crlf(&buf[pos + 1..]) else {
+                    return Ok(None);
+                };
+                pos += 1 + end + 2;
+            }
+            // RESP3 booleans (`#t`, `#f`) and null (`_`) have fixed contents
+            b'#' | b'_' => {
+                let Some(end) = find_crlf(&buf[pos + 1..]) else {
+                    return Ok(None);
+                };
+                let valid = matches!(
+                    (marker, &buf[pos + 1..pos + 1 + end]),
+                    (b'#', b"t" | b"f") | (b'_', b"")
+                );
+                if !valid {
+                    return Err(Malformed::Invalid);
+                }
+                pos += 1 + end + 2;
+            }
+            // Bulk strings, and RESP3 verbatim strings and blob errors framed the same way
+            b'$' | b'=' | b'!' => {
+                let Some((_, consumed)) = parse_bulk_string(&buf[pos..], max_bulk_len)? else {
+                    return Ok(None);
+                };
+                pos += consumed;
+            }
+            // Arrays, pushes and RESP3 sets, maps (two elements per entry) and attributes
+            // (which precede the reply they describe)
+            b'*' | b'>' | b'~' | b'%' | b'|' => {
+                let Some((count, consumed)) = parse_integer(&buf[pos + 1..])? else {
+                    return Ok(None);
+                };
+                pos += 1 + consumed;
+                let count = count.max(0) as u64;
+                remaining += match marker {
+                    b'%' => count.saturating_mul(2),
+                    b'|' => count.saturating_mul(2).saturating_add(1),
+                    _ => count,
+                };
+            }
+            _ => return Err(Malformed::Invalid),
         }
+    }
 
-        let command = String::from_utf8_lossy(&buf[pos..pos + str_len]).to_string();
-        commands.push(command);
-        pos += str_len + 2; // +2 for \r\n
+    Ok(Some(pos))
+}
 
-        // Skip remaining array elements
-        for _ in 1..array_len {
-            if pos >= buf.len() {
-                break;
-            }
+/// Returns the type of a complete RESP3 push frame (its first element, e.g.
+/// `invalidate` or `message`), or `None` if the frame is not a push.
+pub fn push_type(frame: &[u8]) -> Option<String> {
+    if frame.first() != Some(&b'>') {
+        return None;
+    }
+    let (count, consumed) = parse_integer(&frame[1..]).ok()??;
+    if count <= 0 {
+        return None;
+    }
+    let (kind, _) = parse_element(&frame[1 + consumed..], u64::MAX).ok()??;
+    Some(kind)
+}
 
-            match buf[pos] {
-                b'$' => {
-                    // Bulk string
-                    let Some((len, consumed)) = parse_integer(&buf[pos + 1..]) else {
-                        return (commands, 0); // Incomplete, but we got the command
-                    };
-                    pos += 1 + consumed;
-
-                    if len >= 0 {
-                        let len = len as usize;
-                        if pos + len + 2 > buf.len() {
-                            return (commands, 0);
-                        }
-                        pos += len + 2;
-                    }
-                }
-                b'+' | b'-' | b':' => {
-                    // Simple string, error, or integer - find \r\n
-                    if let Some(end) = find_crlf(&buf[pos + 1..]) {
-                        pos += 1 + end + 2;
-                    } else {
-                        return (commands, 0);
-                    }
-                }
-                _ => break,
+/// Returns the elements of a complete array or push frame whose elements are all
+/// scalars (strings, integers and the like), or `None` for any other frame (including
+/// nested arrays).
+pub fn flat_array(frame: &[u8]) -> Option<Vec<String>> {
+    if !matches!(frame.first(), Some(b'*' | b'>')) {
+        return None;
+    }
+    let (count, consumed) = parse_integer(&frame[1..]).ok()??;
+    let mut pos = 1 + consumed;
+    let mut elements = Vec::with_capacity(count.clamp(0, 16) as usize);
+    for _ in 0..count.max(0) {
+        let (element, consumed) = parse_element(&frame[pos..], u64::MAX).ok()??;
+        elements.push(element);
+        pos += consumed;
+    }
+    Some(elements)
+}
+
+/// Returns the class of a complete error reply: its leading uppercase code, such as
+/// `WRONGTYPE` or `OOM`, or `ERR` for errors without one. `None` if the frame is not
+/// an error.
+pub fn error_class(frame: &[u8]) -> Option<String> {
+    let message = frame.strip_prefix(b"-")?;
+    let end = message
+        .iter()
+        .position(|&b| b == b' ' || b == b'\r')
+        .unwrap_or(message.len());
+    let code = &message[..end];
+    let is_code = code.first().is_some_and(u8::is_ascii_uppercase)
+        && code
+            .iter()
+            .all(|&b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+    Some(if is_code {
+        String::from_utf8_lossy(code).into_owned()
+    } else {
+        "ERR".to_string()
+    })
+}
+
+/// A cluster redirect reply, sent when a key's slot is served by another node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// `ASK` (the slot is migrating) rather than `MOVED`
+    pub ask: bool,
+    pub slot: u16,
+    /// Address of the node serving the slot, as `host:port`
+    pub node: String,
+}
+
+/// Parse a complete `-MOVED <slot> <node>` or `-ASK <slot> <node>` error reply.
+pub fn redirect(frame: &[u8]) -> Option<Redirect> {
+    let line = frame.strip_prefix(b"-")?.strip_suffix(b"\r\n")?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split(' ');
+    let ask = match words.next()? {
+        "MOVED" => false,
+        "ASK" => true,
+        _ => return None,
+    };
+    let slot = words.next()?.parse().ok().filter(|&slot: &u16| slot < 16384)?;
+    let node = words.next().filter(|node| !node.is_empty())?.to_string();
+    if words.next().is_some() {
+        return None;
+    }
+    Some(Redirect { ask, slot, node })
+}
+
+/// Parse an array command: `*<count>\r\n` followed by bulk string elements.
+fn parse_array_command(buf: &[u8], max_bulk_len: u64) -> Step<Frame> {
+    let Some((count, consumed)) = parse_integer(&buf[1..])? else {
+        return Ok(None);
+    };
+    let mut pos = 1 + consumed;
+
+    if count <= 0 {
+        return Ok(Some(Frame {
+            name: None,
+            args: Vec::new(),
+            len: pos,
+        }));
+    }
+
+    // First element is the command name (bulk string)
+    if pos >= buf.len() {
+        return Ok(None);
+    }
+    if buf[pos] != b'$' {
+        return Err(Malformed::Invalid);
+    }
+    let Some((name, consumed)) = parse_bulk_string(&buf[pos..], max_bulk_len)? else {
+        return Ok(None);
+    };
+    let name = decode_name(name.ok_or(Malformed::Invalid)?);
+    pos += consumed;
+
+    // Collect remaining array elements as arguments
+    // Cap the preallocation: the count comes straight off the wire
+    let mut args = Vec::with_capacity((count as usize - 1).min(64));
+    for _ in 1..count {
+        let Some((arg, consumed)) = parse_argument(&buf[pos..], max_bulk_len)? else {
+            return Ok(None);
+        };
+        args.push(arg);
+        pos += consumed;
+    }
+
+    Ok(Some(Frame {
+        name: Some(name),
+        args,
+        len: pos,
+    }))
+}
+
+/// Parse a single command argument, returning its text and the bytes it occupies.
+///
+/// Besides the scalar types of [`parse_element`], RESP3 clients may send aggregates
+/// (arrays, maps, sets, pushes and attributes). Redis rejects those, but they are consumed
+/// whole so the commands after them are still counted; their text is the raw frame.
+fn parse_argument(buf: &[u8], max_bulk_len: u64) -> Step<(String, usize)> {
+    match buf.first() {
+        Some(b'*' | b'%' | b'~' | b'>' | b'|') => {
+            Ok(skip_reply(buf, RespVersion::Resp3, max_bulk_len)?.map(|len| {
+                (String::from_utf8_lossy(&buf[..len]).to_string(), len)
+            }))
+        }
+        _ => parse_element(buf, max_bulk_len),
+    }
+}
+
+/// Parse a single scalar RESP element, returning its text and the bytes it occupies.
+fn parse_element(buf: &[u8], max_bulk_len: u64) -> Step<(String, usize)> {
+    let Some(&marker) = buf.first() else {
+        return Ok(None);
+    };
+
+    match marker {
+        // Bulk strings, and RESP3 blob errors framed the same way
+        b'$' | b'!' => Ok(parse_bulk_string(buf, max_bulk_len)?.map(|(data, consumed)| {
+            let text = data.map(String::from_utf8_lossy).unwrap_or_default();
+            (text.to_string(), consumed)
+        })),
+        // RESP3 verbatim strings: the text follows a three letter format such as `txt:`
+        b'=' => Ok(parse_bulk_string(buf, max_bulk_len)?.map(|(data, consumed)| {
+            let data = data.unwrap_or_default();
+            let text = data.get(4..).filter(|_| data.get(3) == Some(&b':')).unwrap_or(data);
+            (String::from_utf8_lossy(text).to_string(), consumed)
+        })),
+        // Simple string, error, integer, and RESP3 double, big number, boolean or null -
+        // find \r\n
+        b'+' | b'-' | b':' | b',' | b'(' | b'#' | b'_' => {
+            let Some(end) = find_crlf(&buf[1..]) else {
+                return Ok(None);
+            };
+            let line = &buf[1..1 + end];
+            let valid = match marker {
+                b'#' => matches!(line, b"t" | b"f"),
+                b'_' => line.is_empty(),
+                _ => true,
+            };
+            if !valid {
+                return Err(Malformed::Invalid);
             }
+            Ok(Some((String::from_utf8_lossy(line).to_string(), 1 + end + 2)))
         }
+        _ => Err(Malformed::Invalid),
+    }
+}
+
+/// Parse a bulk string (`$<len>\r\n<data>\r\n`) starting at `buf[0] == b'$'`.
+/// Returns the payload (`None` for a null bulk string) and bytes consumed. Lengths over
+/// `max_bulk_len`, or too large to address, are malformed.
+fn parse_bulk_string(buf: &[u8], max_bulk_len: u64) -> Step<(Option<&[u8]>, usize)> {
+    let Some((len, consumed)) = parse_integer(&buf[1..])? else {
+        return Ok(None);
+    };
+    let pos = 1 + consumed;
+
+    if len < 0 {
+        return Ok(Some((None, pos)));
+    }
+    if len as u64 > max_bulk_len {
+        return Err(Malformed::Invalid);
+    }
+
+    // The length comes straight off the wire, so it must not be able to wrap the end
+    let len = usize::try_from(len).map_err(|_| Malformed::Invalid)?;
+    let end = pos.checked_add(len).ok_or(Malformed::Invalid)?;
+    let Some(terminator) = buf.get(end..end.checked_add(2).ok_or(Malformed::Invalid)?) else {
+        return Ok(None); // Incomplete
+    };
+    if terminator != b"\r\n" {
+        return Err(Malformed::Invalid);
     }
 
-    (commands, pos)
+    Ok(Some((Some(&buf[pos..end]), end + 2)))
 }
 
 /// Parse an inline command (space-separated, ending with \r\n).
-fn parse_inline_command(buf: &[u8]) -> Option<(String, usize)> {
-    let crlf_pos = find_crlf(buf)?;
+/// Blank lines are consumed without producing a command.
+fn parse_inline_command(buf: &[u8]) -> Step<Frame> {
+    let Some(crlf_pos) = find_crlf(buf) else {
+        return Ok(None);
+    };
     let line = &buf[..crlf_pos];
 
-    // First word is the command
-    let cmd_end = line
-        .iter()
-        .position(|&b| b == b' ')
-        .unwrap_or(line.len());
+    // First word is the command, the rest are arguments
+    let mut words = line.split(|&b| b == b' ').filter(|word| !word.is_empty());
+    let name = words.next().map(decode_name);
 
-    if cmd_end == 0 {
-        return None;
-    }
+    Ok(Some(Frame {
+        name,
+        args: words.map(|word| String::from_utf8_lossy(word).to_string()).collect(),
+        len: crlf_pos + 2,
+    }))
+}
 
-    let command = String::from_utf8_lossy(&line[..cmd_end]).to_string();
-    Some((command, crlf_pos + 2))
+/// Decode a command name, writing bytes that are not valid UTF-8 as `\xNN` escapes.
+/// Unlike lossy decoding, which turns them all into U+FFFD, this keeps distinct garbage
+/// names apart in the stats and leaves them safe to log.
+fn decode_name(bytes: &[u8]) -> String {
+    let mut name = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        name.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            let _ = write!(name, "\\x{:02x}", byte);
+        }
+    }
+    name
 }
 
 /// Parse a RESP integer (until \r\n), returns value and bytes consumed including \r\n.
-fn parse_integer(buf: &[u8]) -> Option<(i64, usize)> {
-    let crlf_pos = find_crlf(buf)?;
-    let num_str = std::str::from_utf8(&buf[..crlf_pos]).ok()?;
-    let num = num_str.parse().ok()?;
-    Some((num, crlf_pos + 2))
+/// Returns `Ok(None)` if the line is not yet complete. Only the first [`MAX_HEADER_LEN`]
+/// bytes are searched, so a line that never ends is not rescanned as it grows.
+fn parse_integer(buf: &[u8]) -> Step<(i64, usize)> {
+    let Some(crlf_pos) = find_crlf(&buf[..buf.len().min(MAX_HEADER_LEN)]) else {
+        if buf.len() >= MAX_HEADER_LEN {
+            return Err(Malformed::HeaderTooLong);
+        }
+        return Ok(None);
+    };
+    let num = std::str::from_utf8(&buf[..crlf_pos])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Malformed::Invalid)?;
+    Ok(Some((num, crlf_pos + 2)))
 }
 
 /// Find position of \r\n in buffer.
@@ -124,6 +532,53 @@ fn find_crlf(buf: &[u8]) -> Option<usize> {
     buf.windows(2).position(|w| w == b"\r\n")
 }
 
+/// Rewrite the command name of a single complete command frame, preserving its
+/// arguments byte for byte. Array frames get the name's bulk-string length adjusted.
+pub fn rename_command(frame: &[u8], new_name: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() + new_name.len());
+
+    if frame.first() == Some(&b'*') {
+        // *<count>\r\n$<len>\r\n<name>\r\n<rest>
+        let Some(header_end) = find_crlf(frame).map(|p| p + 2) else {
+            return frame.to_vec();
+        };
+        let Ok(Some((Some(_), consumed))) = parse_bulk_string(&frame[header_end..], u64::MAX) else {
+            return frame.to_vec();
+        };
+        let name_end = header_end + consumed;
+
+        out.extend_from_slice(&frame[..header_end]);
+        out.extend_from_slice(format!("${}\r\n", new_name.len()).as_bytes());
+        out.extend_from_slice(new_name);
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&frame[name_end..]);
+    } else {
+        // Inline: replace the first word
+        let start = frame.iter().position(|&b| b != b' ').unwrap_or(0);
+        let end = frame[start..]
+            .iter()
+            .position(|&b| b == b' ' || b == b'\r')
+            .map_or(frame.len(), |p| start + p);
+
+        out.extend_from_slice(&frame[..start]);
+        out.extend_from_slice(new_name);
+        out.extend_from_slice(&frame[end..]);
+    }
+
+    out
+}
+
+/// Encode a command as a RESP array of bulk strings.
+pub fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -155,4 +610,505 @@ mod tests {
         let (commands, _) = parse_commands(buf);
         assert_eq!(commands, vec!["PING", "PING"]);
     }
+
+    #[test]
+    fn test_parse_mixed_inline_and_array_commands() {
+        let buf = b"PING\r\n*1\r\n$4\r\nPING\r\n";
+        let parsed = parse_command_frames(buf);
+        let spans: Vec<_> = parsed.commands.iter().map(|c| c.span.clone()).collect();
+        assert_eq!(spans, vec![0..6, 6..20]);
+        assert_eq!(parsed.consumed, buf.len());
+
+        let buf = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nSET k v\r\n*1\r\n$4\r\nPING\r\nPING\r\n";
+        let (commands, consumed) = parse_commands(buf);
+        assert_eq!(commands, vec!["GET", "SET", "PING", "PING"]);
+        assert_eq!(consumed, buf.len());
+    }
+
+    #[test]
+    fn test_parse_mixed_commands_split_across_reads() {
+        let stream = b"PING\r\n*1\r\n$4\r\nPING\r\nECHO hi\r\n*2\r\n$4\r\nECHO\r\n$2\r\nho\r\n";
+        for split in 0..=stream.len() {
+            // Retain the unconsumed tail of the first read, as the proxy does
+            let mut buf = stream[..split].to_vec();
+            let (mut commands, consumed) = parse_commands(&buf);
+            buf.drain(..consumed);
+            buf.extend_from_slice(&stream[split..]);
+            let (rest, consumed) = parse_commands(&buf);
+            commands.extend(rest);
+            assert_eq!(commands, vec!["PING", "PING", "ECHO", "ECHO"], "split at {split}");
+            assert_eq!(consumed, buf.len(), "split at {split}");
+        }
+    }
+
+    #[test]
+    fn test_parse_incomplete_command_is_not_consumed() {
+        let buf = b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$3\r\nk";
+        let parsed = parse_command_frames(buf);
+        assert_eq!(parsed.commands.len(), 1);
+        assert_eq!(parsed.commands[0].span, 0..14);
+        assert_eq!(parsed.consumed, 14);
+        assert!(!parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_commands_full() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\n\xffv\r\nGET key\r\n*1\r\n$4\r\nPING\r\n";
+        let (commands, consumed) = parse_commands_full(buf);
+        assert_eq!(
+            commands,
+            vec![vec!["SET", "key", "\u{fffd}v"], vec!["GET", "key"], vec!["PING"]]
+        );
+        assert_eq!(consumed, buf.len());
+    }
+
+    #[test]
+    fn test_non_utf8_command_names_escaped() {
+        let buf = b"*1\r\n$3\r\nG\xffT\r\n*1\r\n$3\r\nG\xfeT\r\n\xc3\xa9\xc3 arg\r\n";
+        let (commands, consumed) = parse_commands(buf);
+        assert_eq!(commands, vec!["G\\xffT", "G\\xfeT", "\u{e9}\\xc3"]);
+        assert_eq!(consumed, buf.len());
+    }
+
+    #[test]
+    fn test_parse_commands_full_leaves_incomplete_command() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nval";
+        let (commands, consumed) = parse_commands_full(buf);
+        assert_eq!(commands, vec![vec!["GET", "k"]]);
+        assert_eq!(consumed, 20);
+        assert_eq!(parse_commands(buf), (vec!["GET".to_string()], 20));
+    }
+
+    #[test]
+    fn test_parse_resp3_arguments() {
+        let buf = b"*8\r\n$4\r\nECHO\r\n,1.5\r\n#t\r\n_\r\n(12345678901234567890\r\n\
+                    =8\r\ntxt:text\r\n!3\r\nERR\r\n:7\r\nPING\r\n";
+        let parsed = parse_command_frames(buf);
+        assert_eq!(parsed.commands.len(), 2);
+        assert_eq!(
+            parsed.commands[0].args,
+            vec!["1.5", "t", "", "12345678901234567890", "text", "ERR", "7"]
+        );
+        assert_eq!(parsed.commands[1].name, "PING");
+        assert_eq!(parsed.consumed, buf.len());
+    }
+
+    #[test]
+    fn test_parse_resp3_aggregate_arguments() {
+        let map = "%2\r\n+a\r\n:1\r\n+b\r\n~2\r\n#f\r\n,2\r\n";
+        let buf = format!("*4\r\n$3\r\nCMD\r\n{map}|1\r\n+k\r\n+v\r\n*0\r\n$1\r\nx\r\nPING\r\n");
+        let (commands, consumed) = parse_commands_full(buf.as_bytes());
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0][1], map);
+        assert_eq!(commands[0][3], "x");
+        assert_eq!(commands[1], vec!["PING"]);
+        assert_eq!(consumed, buf.len());
+
+        // A nested aggregate still missing elements waits for more data
+        let buf = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nCMD\r\n%1\r\n+a\r\n";
+        let parsed = parse_command_frames(buf);
+        assert_eq!(parsed.commands.len(), 1);
+        assert_eq!(parsed.consumed, 14);
+        assert!(!parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_invalid_resp3_arguments() {
+        assert!(parse_command_frames(b"*2\r\n$4\r\nECHO\r\n#x\r\n").malformed);
+        assert!(parse_command_frames(b"*2\r\n$4\r\nECHO\r\n_0\r\n").malformed);
+        assert!(parse_command_frames(b"*2\r\n$4\r\nECHO\r\n*1\r\n?\r\n").malformed);
+    }
+
+    #[test]
+    fn test_parse_malformed_array() {
+        let parsed = parse_command_frames(b"*abc\r\n");
+        assert!(parsed.commands.is_empty());
+        assert!(parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_oversized_bulk_length() {
+        let buf = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$9999999999999999\r\nkey\r\n";
+        // The commands before it are still returned, and parsing stops at the bad length
+        assert_eq!(parse_commands(buf), (vec!["PING".to_string()], 14));
+        assert!(parse_command_frames(buf).malformed);
+
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2048\r\n";
+        assert!(parse_command_frames_limited(set, 1024).malformed);
+        let parsed = parse_command_frames_limited(set, 4096);
+        assert!(!parsed.malformed, "within the limit, it is only incomplete");
+        assert_eq!((parsed.commands.len(), parsed.consumed), (0, 0));
+
+        // The limit covers bulk strings nested in aggregate arguments too
+        let nested = b"*2\r\n$4\r\nECHO\r\n*1\r\n$2048\r\n";
+        assert!(parse_command_frames_limited(nested, 1024).malformed);
+    }
+
+    #[test]
+    fn test_parse_adversarial_length_headers() {
+        let lengths = [
+            "9999999999999999",
+            "9223372036854775807",
+            "9223372036854775808",
+            "18446744073709551615",
+            "-9223372036854775808",
+            "-1",
+            "-2",
+            "+3",
+            "",
+            " 3",
+            "0x10",
+            "3\r",
+        ];
+        let ping = b"*1\r\n$4\r\nPING\r\n";
+        for len in lengths {
+            let frames = [
+                format!("*1\r\n${len}\r\nGET\r\n"),
+                format!("*{len}\r\n$3\r\nGET\r\n"),
+                format!("*2\r\n$3\r\nGET\r\n${len}\r\nkey\r\n"),
+                format!("*2\r\n$4\r\nECHO\r\n*1\r\n${len}\r\nkey\r\n"),
+                format!("*2\r\n$4\r\nECHO\r\n%{len}\r\n"),
+                format!("*2\r\n$4\r\nECHO\r\n!{len}\r\nkey\r\n"),
+                format!("*2\r\n$4\r\nECHO\r\n={len}\r\ntxt:k\r\n"),
+            ];
+            for frame in frames {
+                let buf = [&ping[..], frame.as_bytes()].concat();
+                // Every truncation too, as the frame might arrive split across reads
+                for end in 0..=buf.len() {
+                    let (names, consumed) = parse_commands(&buf[..end]);
+                    assert!(consumed <= end, "{:?}", &buf[..end]);
+                    if end >= ping.len() {
+                        assert_eq!(names.first().map(String::as_str), Some("PING"));
+                    }
+                    parse_command_frames_limited(&buf[..end], 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated_integers() {
+        // Every prefix of a valid command is waited on, never malformed
+        let buf = b"*12\r\n$3\r\nDEL\r\n$10\r\n0123456789\r\n:-42\r\n";
+        for end in 0..buf.len() {
+            let parsed = parse_command_frames(&buf[..end]);
+            assert!(parsed.commands.is_empty(), "{:?}", &buf[..end]);
+            assert_eq!((parsed.consumed, parsed.malformed), (0, false), "{:?}", &buf[..end]);
+        }
+        for buf in [&b"*"[..], b"*-", b"*1\r", b"*1\r\n$", b"*1\r\n$-", b"*1\r\n$4\r"] {
+            assert_eq!(parse_commands_full(buf), (vec![], 0));
+        }
+        // A line ending too early is not a number at all
+        for buf in [&b"*\r\n"[..], b"*-\r\n", b"*1\r\n$\r\n", b"*1\r\n$1\r\r\n"] {
+            assert!(parse_command_frames(buf).malformed, "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_parse_header_without_line_ending() {
+        // Waited on while it could still be a number...
+        let count = [&b"PING\r\n*"[..], &[b'1'; MAX_HEADER_LEN - 1]].concat();
+        let parsed = parse_command_frames(&count);
+        assert_eq!((parsed.consumed, parsed.malformed), (6, false));
+
+        // ...but not once the line runs on longer than any count could be
+        let count = [&count[..], b"1"].concat();
+        let parsed = parse_command_frames(&count);
+        assert_eq!((parsed.commands.len(), parsed.consumed), (1, 6));
+        assert!(parsed.malformed && parsed.header_too_long);
+
+        let length = [&b"*1\r\n$"[..], &[b'9'; 1 << 20]].concat();
+        assert!(parse_command_frames(&length).header_too_long);
+        assert!(!parse_command_frames(b"*abc\r\n").header_too_long);
+    }
+
+    #[test]
+    fn test_parse_non_utf8_bytes() {
+        // Names are escaped, arguments decoded lossily, and the frames still line up
+        let buf = b"*2\r\n$2\r\n\xff\xfe\r\n$2\r\n\xc3\x28\r\n\xe2\x82 \xff\r\nPING\r\n";
+        let (commands, consumed) = parse_commands_full(buf);
+        let lossy = String::from_utf8_lossy(b"\xc3\x28").to_string();
+        assert_eq!(commands[0], vec!["\\xff\\xfe".to_string(), lossy]);
+        assert_eq!(commands[1], vec!["\\xe2\\x82", "\u{fffd}"]);
+        assert_eq!((commands.len(), consumed), (3, buf.len()));
+
+        // Non-UTF-8 where a length is expected is malformed
+        assert!(parse_command_frames(b"*\xff\r\n").malformed);
+        assert!(parse_command_frames(b"*1\r\n$\xc3\xa9\r\n").malformed);
+    }
+
+    #[test]
+    fn test_parse_negative_array_counts() {
+        // Null and negative arrays hold no command but are consumed like empty ones
+        let buf = b"*-1\r\n*-5\r\n*-9223372036854775808\r\n*0\r\nPING\r\n";
+        assert_eq!(parse_commands(buf), (vec!["PING".to_string()], buf.len()));
+
+        // As arguments, they are consumed whole
+        let buf = b"*3\r\n$4\r\nECHO\r\n*-1\r\n%-3\r\n*1\r\n$4\r\nPING\r\n";
+        let (commands, consumed) = parse_commands_full(buf);
+        assert_eq!(commands, vec![vec!["ECHO", "*-1\r\n", "%-3\r\n"], vec!["PING"]]);
+        assert_eq!(consumed, buf.len());
+
+        // A null command name cannot be a command
+        assert!(parse_command_frames(b"*1\r\n$-1\r\n").malformed);
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\nGET  k\r\n";
+        let parsed = parse_command_frames(buf);
+        assert_eq!(parsed.commands[0].args, vec!["k", "v"]);
+        assert_eq!(parsed.commands[1].name, "GET");
+        assert_eq!(parsed.commands[1].args, vec!["k"]);
+    }
+
+    #[test]
+    fn test_parse_bulk_string_with_embedded_crlf() {
+        // Bulk payloads are length-prefixed, so CRLF inside them is data, not a delimiter
+        let buf = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n*1\r\n$4\r\nPING\r\n";
+        let parsed = parse_command_frames(buf);
+        assert_eq!(parsed.commands.len(), 2);
+        assert_eq!(parsed.commands[0].name, "SET");
+        assert_eq!(parsed.commands[0].args, vec!["k", "a\r\nb"]);
+        assert_eq!(parsed.commands[0].span, 0..30);
+        assert_eq!(parsed.commands[1].name, "PING");
+        assert_eq!(parsed.consumed, buf.len());
+        assert!(!parsed.malformed);
+
+        // Cut right after the embedded CRLF: still incomplete, nothing consumed
+        let parsed = parse_command_frames(&buf[..25]);
+        assert!(parsed.commands.is_empty());
+        assert_eq!(parsed.consumed, 0);
+        assert!(!parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_reply_frames() {
+        let buf = b"+OK\r\n$5\r\nhello\r\n$-1\r\n:42\r\n-ERR bad\r\n*2\r\n*1\r\n:1\r\n$1\r\nx\r\n*-1\r\n";
+        let parsed = parse_reply_frames(buf, RespVersion::Resp2);
+        let kinds: Vec<u8> = parsed.replies.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, b"+$$:-**");
+        assert_eq!(parsed.replies[1].span, 5..16);
+        assert_eq!(parsed.replies[5].span, 36..55);
+        assert_eq!(parsed.consumed, buf.len());
+        assert!(!parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_resp3_double_replies() {
+        let buf = b",3.14\r\n,-inf\r\n,1.5e10\r\n:1\r\n";
+        let parsed = parse_reply_frames(buf, RespVersion::Resp3);
+        let spans: Vec<_> = parsed.replies.iter().map(|r| (r.kind, r.span.clone())).collect();
+        assert_eq!(spans, [(b',', 0..7), (b',', 7..14), (b',', 14..23), (b':', 23..27)]);
+        assert_eq!(parsed.consumed, buf.len());
+
+        let parsed = parse_reply_frames(b",3.1", RespVersion::Resp3);
+        assert!(parsed.replies.is_empty() && !parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_resp3_boolean_replies() {
+        let buf = b"#t\r\n#f\r\n*2\r\n#t\r\n#f\r\n";
+        let parsed = parse_reply_frames(buf, RespVersion::Resp3);
+        let spans: Vec<_> = parsed.replies.iter().map(|r| (r.kind, r.span.clone())).collect();
+        assert_eq!(spans, [(b'#', 0..4), (b'#', 4..8), (b'*', 8..20)]);
+        assert_eq!(parsed.consumed, buf.len());
+
+        // A boolean split across reads waits for the rest
+        let parsed = parse_reply_frames(b"+OK\r\n#t\r", RespVersion::Resp3);
+        assert_eq!((parsed.replies.len(), parsed.consumed), (1, 5));
+        assert!(!parsed.malformed);
+
+        assert!(parse_reply_frames(b"#x\r\n", RespVersion::Resp3).malformed);
+    }
+
+    #[test]
+    fn test_parse_resp3_null_replies() {
+        let buf = b"_\r\n*3\r\n_\r\n:1\r\n_\r\n_\r\n";
+        let parsed = parse_reply_frames(buf, RespVersion::Resp3);
+        let spans: Vec<_> = parsed.replies.iter().map(|r| (r.kind, r.span.clone())).collect();
+        assert_eq!(spans, [(b'_', 0..3), (b'*', 3..17), (b'_', 17..20)]);
+        assert_eq!(parsed.consumed, buf.len());
+
+        let parsed = parse_reply_frames(b"_", RespVersion::Resp3);
+        assert!(parsed.replies.is_empty() && !parsed.malformed);
+        assert!(parse_reply_frames(b"_x\r\n", RespVersion::Resp3).malformed);
+    }
+
+    #[test]
+    fn test_parse_resp3_aggregate_replies() {
+        let map = b"%2\r\n+server\r\n+redis\r\n+proto\r\n:3\r\n";
+        let set = b"~2\r\n$1\r\na\r\n(12345678901234567890\r\n";
+        let verbatim = b"=8\r\ntxt:info\r\n";
+        let blob_error = b"!8\r\nSYNTAX x\r\n";
+        let attribute = b"|1\r\n+ttl\r\n:10\r\n$1\r\nv\r\n";
+        let buf = [&map[..], set, verbatim, blob_error, attribute].concat();
+
+        let parsed = parse_reply_frames(&buf, RespVersion::Resp3);
+        let kinds: Vec<u8> = parsed.replies.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, b"%~=!|");
+        assert_eq!(parsed.replies[0].span, 0..map.len());
+        // The attribute and the reply it annotates form one frame
+        assert_eq!(parsed.replies[4].span, buf.len() - attribute.len()..buf.len());
+        assert_eq!(parsed.consumed, buf.len());
+
+        // Until HELLO 3, these types can only be garbage
+        for frame in [&map[..], set, verbatim, blob_error, attribute] {
+            assert!(parse_reply_frames(frame, RespVersion::Resp2).malformed);
+        }
+    }
+
+    #[test]
+    fn test_hello_version() {
+        let command = |args: &[&str]| Command {
+            name: "hello".to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            span: 0..0,
+        };
+        assert_eq!(hello_version(&command(&["3"])), Some(RespVersion::Resp3));
+        assert_eq!(
+            hello_version(&command(&["2", "AUTH", "u", "p"])),
+            Some(RespVersion::Resp2)
+        );
+        assert_eq!(hello_version(&command(&[])), None);
+        assert_eq!(hello_version(&command(&["4"])), None);
+    }
+
+    #[test]
+    fn test_parse_incomplete_reply_is_not_consumed() {
+        let buf = b"+OK\r\n*2\r\n$1\r\na\r\n";
+        let parsed = parse_reply_frames(buf, RespVersion::Resp2);
+        assert_eq!(parsed.replies.len(), 1);
+        assert_eq!(parsed.consumed, 5);
+        assert!(!parsed.malformed);
+
+        let parsed = parse_reply_frames(b"?what\r\n", RespVersion::Resp2);
+        assert!(parsed.replies.is_empty());
+        assert!(parsed.malformed);
+    }
+
+    #[test]
+    fn test_parse_push_frames() {
+        // Client-side caching invalidation: keys, then a flush (null key list)
+        let invalidate = b">2\r\n$10\r\ninvalidate\r\n*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let flush = b">2\r\n$10\r\ninvalidate\r\n*-1\r\n";
+        let mut buf = invalidate.to_vec();
+        buf.extend_from_slice(b"+OK\r\n");
+        buf.extend_from_slice(flush);
+
+        let parsed = parse_reply_frames(&buf, RespVersion::Resp2);
+        assert_eq!(parsed.replies.len(), 3);
+        assert_eq!(parsed.replies[0].kind, b'>');
+        assert_eq!(parsed.replies[0].span, 0..invalidate.len());
+        assert_eq!(parsed.replies[1].kind, b'+');
+        assert_eq!(parsed.replies[2].kind, b'>');
+        assert_eq!(parsed.consumed, buf.len());
+
+        assert_eq!(push_type(invalidate).as_deref(), Some("invalidate"));
+        assert_eq!(push_type(flush).as_deref(), Some("invalidate"));
+        assert_eq!(push_type(b"*1\r\n$10\r\ninvalidate\r\n"), None);
+    }
+
+    #[test]
+    fn test_flat_array() {
+        assert_eq!(
+            flat_array(b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n"),
+            Some(vec!["subscribe".to_string(), "a".to_string(), "1".to_string()])
+        );
+        assert_eq!(
+            flat_array(b">3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n"),
+            Some(vec!["unsubscribe".to_string(), String::new(), "0".to_string()])
+        );
+        assert_eq!(flat_array(b"*0\r\n"), Some(vec![]));
+        assert_eq!(flat_array(b"*2\r\n*1\r\n:1\r\n:2\r\n"), None);
+        assert_eq!(flat_array(b"+OK\r\n"), None);
+    }
+
+    #[test]
+    fn test_redirect() {
+        assert_eq!(
+            redirect(b"-MOVED 3999 127.0.0.1:7001\r\n"),
+            Some(Redirect {
+                ask: false,
+                slot: 3999,
+                node: "127.0.0.1:7001".to_string(),
+            })
+        );
+        let ask = redirect(b"-ASK 16383 redis-2.internal:6379\r\n").unwrap();
+        assert!(ask.ask);
+        assert_eq!((ask.slot, ask.node.as_str()), (16383, "redis-2.internal:6379"));
+
+        assert_eq!(redirect(b"-MOVED 16384 127.0.0.1:7001\r\n"), None);
+        assert_eq!(redirect(b"-MOVED 3999\r\n"), None);
+        assert_eq!(redirect(b"-ERR MOVED 3999 127.0.0.1:7001\r\n"), None);
+        assert_eq!(redirect(b"+MOVED 3999 127.0.0.1:7001\r\n"), None);
+    }
+
+    #[test]
+    fn test_error_class() {
+        let class = |frame: &[u8]| error_class(frame);
+        assert_eq!(
+            class(b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n")
+                .as_deref(),
+            Some("WRONGTYPE")
+        );
+        assert_eq!(
+            class(b"-OOM command not allowed when used memory > 'maxmemory'.\r\n").as_deref(),
+            Some("OOM")
+        );
+        assert_eq!(
+            class(b"-LOADING Redis is loading the dataset in memory\r\n").as_deref(),
+            Some("LOADING")
+        );
+        assert_eq!(
+            class(b"-MASTERDOWN Link with MASTER is down\r\n").as_deref(),
+            Some("MASTERDOWN")
+        );
+        assert_eq!(
+            class(b"-READONLY You can't write against a read only replica.\r\n").as_deref(),
+            Some("READONLY")
+        );
+        assert_eq!(
+            class(b"-CLUSTERDOWN The cluster is down\r\n").as_deref(),
+            Some("CLUSTERDOWN")
+        );
+        assert_eq!(class(b"-NOAUTH\r\n").as_deref(), Some("NOAUTH"));
+        assert_eq!(class(b"-ERR unknown command 'FOO'\r\n").as_deref(), Some("ERR"));
+        // No uppercase code counts as a generic error
+        assert_eq!(class(b"-user script failed\r\n").as_deref(), Some("ERR"));
+        assert_eq!(class(b"-\r\n").as_deref(), Some("ERR"));
+        assert_eq!(class(b"+OK\r\n"), None);
+        assert_eq!(class(b"$5\r\n-OOM \r\n"), None);
+    }
+
+    #[test]
+    fn test_rename_command_adjusts_length() {
+        let frame = b"*2\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n";
+        assert_eq!(
+            rename_command(frame, b"abcdef0123456789"),
+            b"*2\r\n$16\r\nabcdef0123456789\r\n$3\r\nGET\r\n"
+        );
+        assert_eq!(rename_command(frame, b"C"), b"*2\r\n$1\r\nC\r\n$3\r\nGET\r\n");
+
+        // The renamed frame parses as the new command with the same arguments
+        let renamed = rename_command(frame, b"CFG");
+        let parsed = parse_command_frames(&renamed);
+        assert_eq!(parsed.commands[0].name, "CFG");
+        assert_eq!(parsed.commands[0].args, vec!["GET"]);
+        assert_eq!(parsed.consumed, renamed.len());
+    }
+
+    #[test]
+    fn test_rename_inline_command() {
+        assert_eq!(rename_command(b"CONFIG GET *\r\n", b"X"), b"X GET *\r\n");
+        assert_eq!(rename_command(b"PING\r\n", b"PONGO"), b"PONGO\r\n");
+    }
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(&[b"SELECT", b"5"]),
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n5\r\n"
+        );
+    }
 }
@@ -0,0 +1,172 @@
+//! Recently sampled commands, kept in full for debugging.
+//!
+//! With `--sample-rate` a fraction of forwarded commands is captured with its arguments
+//! and, when replies are parsed, the reply, so traffic can be inspected at `/samples`
+//! without turning on the verbose log. Captures follow the same redaction policy as the
+//! log, each is cut to [`MAX_SAMPLE_TEXT`] bytes and only the newest are kept, so memory
+//! stays bounded whatever the traffic.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+/// Number of samples retained by default.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Longest command or reply text kept in a sample.
+pub const MAX_SAMPLE_TEXT: usize = 4096;
+
+/// One sampled command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sample {
+    /// The command and its arguments, as redaction allows
+    pub command: String,
+    /// The reply with control bytes escaped; none if replies are not parsed
+    pub reply: Option<String>,
+    /// How long upstream took to reply; none if replies are not parsed
+    #[serde(rename = "duration_us", serialize_with = "serialize_micros")]
+    pub duration: Option<Duration>,
+    /// When the sample was taken, in milliseconds since the Unix epoch
+    pub timestamp_ms: u128,
+    pub peer: SocketAddr,
+}
+
+/// Serialize a duration, if any, as whole microseconds.
+fn serialize_micros<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.map(|d| d.as_micros() as u64).serialize(serializer)
+}
+
+/// Ring buffer of the most recent samples.
+#[derive(Debug)]
+pub struct CommandSamples {
+    capacity: usize,
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Default for CommandSamples {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl CommandSamples {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Keep a sample, evicting the oldest once full.
+    pub fn record(&self, mut sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        clip(&mut sample.command);
+        if let Some(reply) = &mut sample.reply {
+            clip(reply);
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Retained samples, oldest first.
+    pub fn snapshot(&self) -> Vec<Sample> {
+        self.samples.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, for [`Sample::timestamp_ms`].
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Cut `text` to [`MAX_SAMPLE_TEXT`] bytes, marking how much was left out.
+fn clip(text: &mut String) {
+    if text.len() <= MAX_SAMPLE_TEXT {
+        return;
+    }
+    let mut end = MAX_SAMPLE_TEXT;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let dropped = text.len() - end;
+    text.truncate(end);
+    text.push_str(&format!("...(+{} bytes)", dropped));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(command: &str) -> Sample {
+        Sample {
+            command: command.to_string(),
+            reply: Some("+OK\\r\\n".to_string()),
+            duration: Some(Duration::from_micros(250)),
+            timestamp_ms: 1700000000000,
+            peer: "10.0.0.1:50000".parse().unwrap(),
+        }
+    }
+
+    fn commands(samples: &CommandSamples) -> Vec<String> {
+        samples.snapshot().into_iter().map(|s| s.command).collect()
+    }
+
+    #[test]
+    fn test_oldest_evicted_when_full() {
+        let samples = CommandSamples::new(3);
+        for command in ["SET a 1", "SET b 2", "GET a"] {
+            samples.record(sample(command));
+        }
+        assert_eq!(commands(&samples), ["SET a 1", "SET b 2", "GET a"]);
+
+        samples.record(sample("GET b"));
+        samples.record(sample("DEL a"));
+        assert_eq!(commands(&samples), ["GET a", "GET b", "DEL a"]);
+
+        let disabled = CommandSamples::new(0);
+        disabled.record(sample("GET a"));
+        assert!(disabled.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_long_text_clipped() {
+        let samples = CommandSamples::new(1);
+        let mut long = sample(&format!("SET k {}", "v".repeat(MAX_SAMPLE_TEXT)));
+        long.reply = Some("é".repeat(MAX_SAMPLE_TEXT));
+        samples.record(long);
+
+        let kept = &samples.snapshot()[0];
+        assert!(kept.command.ends_with("...(+6 bytes)"), "{}", kept.command);
+        let reply = kept.reply.as_deref().unwrap();
+        assert!(reply.ends_with(&format!("...(+{} bytes)", MAX_SAMPLE_TEXT)));
+    }
+
+    #[test]
+    fn test_sample_json() {
+        assert_eq!(
+            serde_json::to_string(&sample("GET k")).unwrap(),
+            r#"{"command":"GET k","reply":"+OK\\r\\n","duration_us":250,"timestamp_ms":1700000000000,"peer":"10.0.0.1:50000"}"#
+        );
+        let unanswered = Sample {
+            reply: None,
+            duration: None,
+            ..sample("GET k")
+        };
+        let json = serde_json::to_string(&unanswered).unwrap();
+        assert!(json.contains(r#""reply":null,"duration_us":null"#));
+    }
+}
@@ -1,125 +1,760 @@
-/// TCP/TLS server implementation for accepting client connections.
+//! TCP/TLS server implementation for accepting client connections.
 
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
+use crate::accept::{Acceptor, Listener, UNIX_PEER};
+use crate::bandwidth::BandwidthLimiter;
+use crate::chain::{ChainStream, Role};
 use crate::config::Config;
+use crate::drain::Drain;
 use crate::error::Result;
-use crate::proxy::proxy_connection;
+use crate::events::{ConnectionEvent, EventSink};
+use crate::limit::{ConnectionLimiter, Entry, IpLimiter, IpSlot};
+use crate::memory::MemoryGuard;
+use crate::pool::BufferPool;
+use crate::proxy::{proxy_connection, CloseCode, ProxyContext, Reconnect};
+use crate::ratelimit::GlobalRate;
 use crate::stats::Stats;
-use crate::tls::build_server_config;
-use crate::upstream::UpstreamConnection;
+use crate::samples::CommandSamples;
+use crate::timing::TimingLog;
+use crate::tls::{build_server_config, HandshakeLimited};
+use crate::upstream::{UpstreamConfig, UpstreamConnection, UpstreamPool};
+
+/// Process-wide handles shared by the accept loops and connection tasks.
+#[derive(Clone)]
+pub struct ServerState {
+    pub stats: Arc<Stats>,
+    pub memory: Option<Arc<MemoryGuard>>,
+    pub drain: Arc<Drain>,
+    pub timing: Option<Arc<TimingLog>>,
+    /// Set with `--sample-rate`
+    pub samples: Option<Arc<CommandSamples>>,
+    pub events: Option<Arc<EventSink>>,
+    pub limiter: Arc<ConnectionLimiter>,
+    pub ip_limiter: Arc<IpLimiter>,
+    /// Set with `--max-total-bps`
+    pub bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// Set with `--global-max-commands-per-sec`
+    pub global_rate: Option<Arc<GlobalRate>>,
+    pub buffers: Arc<BufferPool>,
+}
+
+/// Sent to plain TCP clients turned away because the connection queue is full.
+const QUEUE_FULL_REPLY: &[u8] = b"-ERR max number of clients reached\r\n";
+
+/// Sent to plain TCP clients shed while the proxy is out of file descriptors.
+const NO_DESCRIPTORS_REPLY: &[u8] = b"-ERR proxy out of file descriptors\r\n";
+
+/// Sent to plain TCP clients turned away by `--max-connections-per-ip`.
+const IP_LIMIT_REPLY: &[u8] = b"-ERR max number of clients from this address reached\r\n";
+
+impl ServerState {
+    /// Returns false (and logs) if a new connection should be rejected.
+    fn admit_connection(&self, peer_addr: SocketAddr) -> bool {
+        if self.drain.is_draining() {
+            info!("Rejecting connection from {}: draining", peer_addr);
+            return false;
+        }
+        if let Some(guard) = &self.memory
+            && !guard.accepts_connections()
+        {
+            warn!(
+                "Rejecting connection from {}: memory pressure (RSS {} bytes)",
+                peer_addr,
+                guard.last_rss()
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Count the connection against its client IP, or `None` (logged) if that IP already
+    /// has `--max-connections-per-ip` connections open. Unix socket clients have no IP and
+    /// are not limited.
+    fn enter_ip_limit(&self, peer_addr: SocketAddr) -> Option<IpSlot> {
+        if peer_addr == UNIX_PEER {
+            return Some(self.ip_limiter.unlimited());
+        }
+        let slot = self.ip_limiter.try_enter(peer_addr.ip());
+        if slot.is_none() {
+            warn!(
+                "Rejecting connection from {}: per-IP connection limit reached ({} open)",
+                peer_addr,
+                self.ip_limiter.connections_from(peer_addr.ip())
+            );
+        }
+        slot
+    }
+
+    /// Claim a connection slot, or `None` (logged) if too many connections are already
+    /// waiting for one.
+    fn enter_limiter(&self, peer_addr: SocketAddr) -> Option<Entry> {
+        let entry = self.limiter.try_enter();
+        match &entry {
+            Some(Entry::Queued(_)) => debug!("Queueing connection from {}", peer_addr),
+            Some(Entry::Admitted(_)) => {}
+            None => warn!(
+                "Rejecting connection from {}: connection queue full ({} waiting)",
+                peer_addr,
+                self.limiter.queued()
+            ),
+        }
+        entry
+    }
+}
 
 /// Run the proxy server (TLS or plain TCP based on config).
-pub async fn run_server(config: Config, stats: Arc<Stats>) -> Result<()> {
-    let listener = TcpListener::bind(&config.listen).await?;
+pub async fn run_server(config: Config, state: ServerState) -> Result<()> {
+    let listener = Listener::bind(&config.listen).await?;
 
     if config.no_tls {
-        info!("Listening on {} (plain TCP)", config.listen);
+        info!("Listening on {} (plain)", config.listen);
     } else {
         info!("Listening on {} (TLS)", config.listen);
     }
-    info!(
-        "Forwarding to {} ({})",
-        config.upstream,
-        if config.upstream_tls { "TLS" } else { "plain TCP" }
-    );
+    let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+    for upstream in upstreams.upstreams() {
+        info!("Forwarding to {}", upstream);
+        if upstream.use_tls() && upstream.insecure_skip_verify {
+            warn!(
+                "TLS certificate verification is DISABLED for upstream {} \
+                 (--upstream-insecure-skip-verify); do not use this in production",
+                upstream.addr
+            );
+        }
+    }
+
+    // TLS clients could not read a plain reply, so shed connections are just closed
+    let reply = config.no_tls.then_some(NO_DESCRIPTORS_REPLY);
+    let mut incoming = Acceptor::new(listener, reply);
+    if config.rebind_on_listen_error {
+        incoming = incoming.with_rebind(config.listen.clone());
+    }
 
+    let config = Arc::new(config);
     if config.no_tls {
-        run_plain_server(listener, config, stats).await
+        run_plain_server(incoming, config, upstreams, state).await
     } else {
-        run_tls_server(listener, config, stats).await
+        run_tls_server(incoming, config, upstreams, state).await
     }
 }
 
 /// Run the server accepting plain TCP connections.
 async fn run_plain_server(
-    listener: TcpListener,
-    config: Config,
-    stats: Arc<Stats>,
+    mut incoming: Acceptor,
+    config: Arc<Config>,
+    upstreams: Arc<UpstreamPool>,
+    state: ServerState,
 ) -> Result<()> {
     loop {
-        let (tcp_stream, peer_addr) = listener.accept().await?;
-        let upstream_addr = config.upstream.clone();
-        let upstream_tls = config.upstream_tls;
-        let upstream_hostname = config.upstream_hostname();
-        let stats = stats.clone();
+        let (tcp_stream, peer_addr) = incoming.accept().await?;
+        if !state.admit_connection(peer_addr) {
+            continue;
+        }
+        let Some(ip_slot) = state.enter_ip_limit(peer_addr) else {
+            let mut tcp_stream = tcp_stream;
+            tokio::spawn(async move {
+                let _ = tcp_stream.write_all(IP_LIMIT_REPLY).await;
+            });
+            continue;
+        };
+        let Some(entry) = state.enter_limiter(peer_addr) else {
+            // Plain clients can be told why before the socket closes
+            let mut tcp_stream = tcp_stream;
+            tokio::spawn(async move {
+                let _ = tcp_stream.write_all(QUEUE_FULL_REPLY).await;
+            });
+            continue;
+        };
+        let id = state.stats.record_connection();
+        let active = state.stats.track_connection();
+        let config = config.clone();
+        let upstreams = upstreams.clone();
+        let conn_state = state.clone();
 
         tokio::spawn(async move {
-            info!("New connection from {}", peer_addr);
-
-            // Connect to upstream
-            let upstream = match UpstreamConnection::connect(
-                &upstream_addr,
-                upstream_tls,
-                &upstream_hostname,
-            )
-            .await
-            {
-                Ok(conn) => conn,
-                Err(e) => {
-                    error!("Failed to connect to upstream {}: {}", upstream_addr, e);
-                    return;
-                }
-            };
-
-            // Proxy the connection
-            proxy_connection(tcp_stream, upstream, stats).await;
-            info!("Connection from {} closed", peer_addr);
+            let _active = active;
+            let _ip_slot = ip_slot;
+            let _permit = entry.permit().await;
+            info!(id, "New connection from {}", peer_addr);
+            serve_client(tcp_stream, id, peer_addr, config, &upstreams, conn_state).await;
         });
     }
 }
 
 /// Run the server accepting TLS connections.
 async fn run_tls_server(
-    listener: TcpListener,
-    config: Config,
-    stats: Arc<Stats>,
+    mut incoming: Acceptor,
+    config: Arc<Config>,
+    upstreams: Arc<UpstreamPool>,
+    state: ServerState,
 ) -> Result<()> {
     let tls_config = build_server_config(&config)?;
-    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let acceptor = Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(tls_config))));
+    #[cfg(unix)]
+    tokio::spawn(reload_tls_on_sighup(config.clone(), acceptor.clone()));
 
     loop {
-        let (tcp_stream, peer_addr) = listener.accept().await?;
-        let acceptor = acceptor.clone();
-        let upstream_addr = config.upstream.clone();
-        let upstream_tls = config.upstream_tls;
-        let upstream_hostname = config.upstream_hostname();
-        let stats = stats.clone();
+        let (tcp_stream, peer_addr) = incoming.accept().await?;
+        if !state.admit_connection(peer_addr) {
+            continue;
+        }
+        let Some(ip_slot) = state.enter_ip_limit(peer_addr) else {
+            continue;
+        };
+        let Some(entry) = state.enter_limiter(peer_addr) else {
+            continue;
+        };
+        let id = state.stats.record_connection();
+        let active = state.stats.track_connection();
+        let acceptor = acceptor.read().unwrap().clone();
+        let handshake_limit = config.max_handshake_bytes;
+        let config = config.clone();
+        let upstreams = upstreams.clone();
+        let conn_state = state.clone();
 
         tokio::spawn(async move {
-            info!("New connection from {}", peer_addr);
+            let _active = active;
+            let _ip_slot = ip_slot;
+            let _permit = entry.permit().await;
+            info!(id, "New connection from {}", peer_addr);
 
-            // Accept TLS connection from client
-            let tls_stream = match acceptor.accept(tcp_stream).await {
+            // Accept TLS connection from client, bounding what it can send before the
+            // handshake completes
+            let limited = HandshakeLimited::new(tcp_stream, handshake_limit);
+            let mut tls_stream = match acceptor.accept(limited).await {
                 Ok(stream) => stream,
                 Err(e) => {
+                    // No connect event was sent yet, so none is owed for the disconnect
                     error!("TLS handshake failed for {}: {}", peer_addr, e);
+                    conn_state.stats.record_closes(CloseCode::Error, 1);
                     return;
                 }
             };
+            tls_stream.get_mut().0.complete();
+
+            serve_client(tls_stream, id, peer_addr, config, &upstreams, conn_state).await;
+        });
+    }
+}
+
+/// Rebuild the listener's TLS configuration on SIGHUP, so renewed certificates and
+/// updated CRLs apply to new connections. If the files fail to load, the error is logged
+/// and the current configuration stays in use.
+#[cfg(unix)]
+async fn reload_tls_on_sighup(config: Arc<Config>, acceptor: Arc<RwLock<TlsAcceptor>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, TLS reload disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        match build_server_config(&config) {
+            Ok(tls_config) => {
+                *acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(tls_config));
+                info!("Reloaded TLS configuration");
+            }
+            Err(e) => error!("Failed to reload TLS configuration, keeping the current one: {}", e),
+        }
+    }
+}
+
+/// Proxy an accepted client connection, unwrapping chain framing with `--chain-listen`.
+async fn serve_client<S>(
+    client: S,
+    id: u64,
+    peer_addr: SocketAddr,
+    config: Arc<Config>,
+    upstreams: &UpstreamPool,
+    state: ServerState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match config.chain_listen_key() {
+        Some(key) => match ChainStream::open(client, &key, Role::Accepting).await {
+            Ok(client) => handle_connection(client, id, peer_addr, config, upstreams, state).await,
+            Err(e) => {
+                error!("Chain link from {} failed to open: {}", peer_addr, e);
+                state.stats.record_closes(CloseCode::Error, 1);
+            }
+        },
+        None => handle_connection(client, id, peer_addr, config, upstreams, state).await,
+    }
+}
 
-            // Connect to upstream
-            let upstream = match UpstreamConnection::connect(
-                &upstream_addr,
-                upstream_tls,
-                &upstream_hostname,
-            )
+/// Connect to upstream and proxy an accepted client connection until either side closes.
+async fn handle_connection<S>(
+    client: S,
+    id: u64,
+    peer_addr: SocketAddr,
+    config: Arc<Config>,
+    upstreams: &UpstreamPool,
+    state: ServerState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let clock = state.stats.clock().clone();
+    let started = clock.now();
+    let upstream_config = upstreams.pick();
+    let emit_disconnect = |commands, outcome| {
+        if let Some(events) = &state.events {
+            events.emit(ConnectionEvent::Disconnect {
+                peer: peer_addr,
+                upstream: upstream_config.addr.clone(),
+                duration: clock.now().saturating_duration_since(started),
+                commands,
+                outcome,
+            });
+        }
+    };
+    if let Some(events) = &state.events {
+        events.emit(ConnectionEvent::Connect {
+            peer: peer_addr,
+            upstream: upstream_config.addr.clone(),
+        });
+    }
+
+    // Connect to upstream
+    let connected = UpstreamConnection::connect_with_retry(
+        upstream_config,
+        &state.stats,
+        config.upstream_connect_retries,
+        Duration::from_millis(config.upstream_connect_backoff_ms),
+    )
+    .await;
+    let mut upstream = match connected {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to connect to upstream {}: {}", upstream_config.addr, e);
+            state.stats.record_closes(CloseCode::Error, 1);
+            emit_disconnect(0, "upstream_connect_failed");
+            return;
+        }
+    };
+
+    if let Some(password) = &config.upstream_auth_password
+        && let Err(e) = upstream
+            .authenticate(config.upstream_auth_user.as_deref(), password)
             .await
-            {
-                Ok(conn) => conn,
-                Err(e) => {
-                    error!("Failed to connect to upstream {}: {}", upstream_addr, e);
-                    return;
-                }
-            };
+    {
+        error!("Failed to authenticate to upstream {}: {}", upstream_config.addr, e);
+        state.stats.record_closes(CloseCode::Error, 1);
+        emit_disconnect(0, "upstream_setup_failed");
+        return;
+    }
+
+    if let Some(db) = config.force_db
+        && let Err(e) = upstream.select_db(db).await
+    {
+        error!(
+            "Failed to select database {} on upstream {}: {}",
+            db, upstream_config.addr, e
+        );
+        state.stats.record_closes(CloseCode::Error, 1);
+        emit_disconnect(0, "upstream_setup_failed");
+        return;
+    }
+
+    // Proxy the connection
+    let mut ctx = ProxyContext::new(peer_addr, state.stats.clone(), config.clone());
+    ctx.timing = state.timing.clone();
+    ctx.samples = state.samples.clone();
+    ctx.buffers = state.buffers.clone();
+    ctx.bandwidth = state.bandwidth.clone();
+    ctx.global_rate = state.global_rate.clone();
+    if config.upstream_unavailable_queue_time.is_some() {
+        ctx.reconnect = Some(reconnector(upstream_config.clone(), config.clone(), &state));
+    }
+    let summary = proxy_connection(client, upstream, ctx).await;
+    let code = summary.reason.code();
+    state.stats.record_closes(code, 1);
+    emit_disconnect(summary.commands, summary.reason.as_str());
+    info!(
+        id,
+        peer = %peer_addr,
+        upstream = %upstream_config.addr,
+        duration_ms = clock.now().saturating_duration_since(started).as_millis() as u64,
+        commands = summary.commands,
+        bytes_in = summary.bytes_in,
+        bytes_out = summary.bytes_out,
+        max_pipeline_depth = summary.max_pipeline_depth,
+        reason = code.as_str(),
+        detail = summary.reason.as_str(),
+        top_commands = %format_top_commands(&summary.top_commands),
+        "Connection closed"
+    );
+}
+
+/// Format per-connection command counts as `GET=12,SET=3`, one token in the log line.
+fn format_top_commands(top: &[(String, u64)]) -> String {
+    top.iter()
+        .map(|(command, count)| format!("{}={}", command, count))
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-            // Proxy the connection
-            proxy_connection(tls_stream, upstream, stats).await;
-            info!("Connection from {} closed", peer_addr);
+/// Reconnects to `upstream` with the same setup as the original connection.
+fn reconnector(
+    upstream: UpstreamConfig,
+    config: Arc<Config>,
+    state: &ServerState,
+) -> Reconnect<UpstreamConnection> {
+    let stats = state.stats.clone();
+    Box::new(move || {
+        let upstream = upstream.clone();
+        let config = config.clone();
+        let stats = stats.clone();
+        Box::pin(async move {
+            let mut conn = UpstreamConnection::connect_recorded(&upstream, &stats).await?;
+            if let Some(password) = &config.upstream_auth_password {
+                conn.authenticate(config.upstream_auth_user.as_deref(), password).await?;
+            }
+            if let Some(db) = config.force_db {
+                conn.select_db(db).await?;
+            }
+            Ok(conn)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use clap::Parser;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::admin::{serve_admin, AdminState};
+    use crate::testing::{reply_ok, MockRss, MockUpstream};
+    use tokio::time::timeout;
+
+    fn state(limiter: Arc<ConnectionLimiter>) -> ServerState {
+        ServerState {
+            stats: Stats::new(),
+            memory: None,
+            drain: Drain::new(),
+            timing: None,
+            samples: None,
+            events: None,
+            limiter,
+            ip_limiter: IpLimiter::new(usize::MAX),
+            bandwidth: None,
+            global_rate: None,
+            buffers: BufferPool::new(),
+        }
+    }
+
+    /// Run a plain TCP proxy with `args` and return its listening address.
+    async fn spawn_proxy(args: &[&str]) -> (SocketAddr, ServerState) {
+        let config = Config::parse_from([&["redis-tls-proxy", "--no-tls"], args].concat());
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let state = state(ConnectionLimiter::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, Some(NO_DESCRIPTORS_REPLY));
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+        (addr, state)
+    }
+
+    #[tokio::test]
+    async fn test_chained_proxies() {
+        let (redis, _) = MockUpstream::bind(reply_ok()).await;
+        let (back, back_state) =
+            spawn_proxy(&["--chain-secret", "k", "--chain-listen", "-u", &redis]).await;
+        let back = back.to_string();
+        let (front, _) =
+            spawn_proxy(&["--chain-secret", "k", "--chain-upstream", "-u", &back]).await;
+
+        let mut client = TcpStream::connect(front).await.unwrap();
+        client.write_all(b"SET k v\r\nGET k\r\n").await.unwrap();
+        let mut reply = [0u8; 10];
+        timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("no reply through chained proxies")
+            .unwrap();
+        assert_eq!(&reply, b"+OK\r\n+OK\r\n");
+        assert_eq!(back_state.stats.total(), 2);
+
+        // Unframed traffic to the chained listener is dropped without reaching Redis
+        let mut direct = TcpStream::connect(&back).await.unwrap();
+        direct.write_all(&b"*1\r\n$4\r\nPING\r\n".repeat(2)).await.unwrap();
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(5), direct.read_to_end(&mut buf))
+            .await
+            .expect("unframed connection left open")
+            .unwrap();
+        // Nothing but the listener's nonce and its empty end-of-stream frame
+        assert_eq!(buf.len(), 16 + 4 + 32);
+        assert_eq!(back_state.stats.total(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_transparent_plain_to_plain_counts_commands() {
+        let (upstream_addr, _) = MockUpstream::bind(reply_ok()).await;
+        let config = Config::parse_from(["redis-tls-proxy", "--transparent", "-u", &upstream_addr]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let state = state(ConnectionLimiter::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, Some(NO_DESCRIPTORS_REPLY));
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // A pipelined array and inline command, then an array split across writes
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        client.write_all(&[&set[..], b"GET k\r\n"].concat()).await.unwrap();
+        client.write_all(b"*2\r\n$3\r\nGET\r\n").await.unwrap();
+        client.write_all(b"$1\r\nk\r\n").await.unwrap();
+
+        let mut reply = [0u8; 15];
+        timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("no reply through proxy")
+            .unwrap();
+        assert_eq!(&reply, b"+OK\r\n+OK\r\n+OK\r\n");
+        assert_eq!(state.stats.total(), 3);
+        assert_eq!(state.stats.command_counts().get("GET"), Some(&2));
+        assert_eq!(state.stats.command_counts().get("SET"), Some(&1));
+
+        drop(client);
+        timeout(Duration::from_secs(5), async {
+            while state.stats.close_counts()[0] != (CloseCode::ClientClosed, 1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client close not counted");
+    }
+
+    #[tokio::test]
+    async fn test_failed_tls_handshake_counted_as_error_close() {
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+        let (cert, key) = (format!("{testdata}/server.pem"), format!("{testdata}/server.key"));
+        let config = Config::parse_from(["redis-tls-proxy", "--cert", &cert, "--key", &key]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let state = state(ConnectionLimiter::from_config(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, None);
+        tokio::spawn(run_tls_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        // A plaintext client never completes the handshake
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"PING\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await;
+
+        timeout(Duration::from_secs(5), async {
+            while state.stats.close_counts()[5] != (CloseCode::Error, 1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("failed handshake not counted");
+        assert_eq!(state.stats.total_connections(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        use tokio::net::UnixStream;
+
+        let (upstream_addr, _) = MockUpstream::bind(reply_ok()).await;
+        let path = std::env::temp_dir().join(format!("server-unix-{}.sock", std::process::id()));
+        let listen = format!("unix:{}", path.display());
+        let config = Config::parse_from([
+            "redis-tls-proxy",
+            "--no-tls",
+            "--listen",
+            &listen,
+            "--max-connections-per-ip",
+            "1",
+            "-u",
+            &upstream_addr,
+        ]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let state = ServerState {
+            ip_limiter: IpLimiter::from_config(&config),
+            ..state(ConnectionLimiter::from_config(&config))
+        };
+        let incoming = Acceptor::new(Listener::bind(&listen).await.unwrap(), None);
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        // Clients without an IP are not held to the per-IP limit
+        let mut first = UnixStream::connect(&path).await.unwrap();
+        let mut second = UnixStream::connect(&path).await.unwrap();
+        for client in [&mut first, &mut second] {
+            client.write_all(b"SET k v\r\n").await.unwrap();
+            let mut reply = [0u8; 5];
+            timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+                .await
+                .expect("no reply through proxy")
+                .unwrap();
+            assert_eq!(&reply, b"+OK\r\n");
+        }
+        assert_eq!(state.stats.total(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Status line of the admin endpoint's answer to `GET /health`.
+    async fn health(admin: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(admin).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_connections_refused_over_max_rss() {
+        let (upstream_addr, _) = MockUpstream::bind(reply_ok()).await;
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "-u", &upstream_addr]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let guard = MemoryGuard::new(1000);
+        let rss = MockRss::default();
+        let mut state = state(ConnectionLimiter::from_config(&config));
+        state.memory = Some(guard.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, Some(NO_DESCRIPTORS_REPLY));
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin.local_addr().unwrap();
+        let admin_state = Arc::new(AdminState {
+            stats: state.stats.clone(),
+            memory: Some(guard.clone()),
+            drain: state.drain.clone(),
+            config_json: "{}".to_string(),
+            samples: None,
+            percentiles: Vec::new(),
+        });
+        tokio::spawn(serve_admin(admin, admin_state));
+
+        // Over the limit, new connections are closed unanswered and health checks fail
+        rss.set(5000);
+        guard.sample(&rss);
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut reply = Vec::new();
+        timeout(Duration::from_secs(5), refused.read_to_end(&mut reply))
+            .await
+            .expect("refused connection was not closed")
+            .unwrap();
+        assert!(reply.is_empty());
+        assert_eq!(state.stats.total_connections(), 0);
+        assert_eq!(health(admin_addr).await, "HTTP/1.1 503 Service Unavailable");
+
+        // Back under it, connections are proxied again
+        rss.set(500);
+        guard.sample(&rss);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"PING\r\n").await.unwrap();
+        let mut reply = [0u8; 5];
+        timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("no reply through proxy")
+            .unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+        assert_eq!(state.stats.total_connections(), 1);
+        assert_eq!(health(admin_addr).await, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_queue_are_rejected() {
+        // Upstream that accepts connections and holds them open
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = upstream.accept().await {
+                held.push(stream);
+            }
         });
+
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "-u", &upstream_addr]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let state = state(ConnectionLimiter::new(1, Some(1)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, Some(NO_DESCRIPTORS_REPLY));
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        // Accepted in order: the first is proxied, the second waits, the third is turned away
+        let _active = TcpStream::connect(addr).await.unwrap();
+        let _queued = TcpStream::connect(addr).await.unwrap();
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+
+        let mut reply = Vec::new();
+        timeout(Duration::from_secs(5), rejected.read_to_end(&mut reply))
+            .await
+            .expect("rejected connection was not closed")
+            .unwrap();
+        assert_eq!(reply, QUEUE_FULL_REPLY);
+        assert_eq!(state.limiter.queued(), 1);
+        assert_eq!(state.stats.total_connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_per_ip_limit_are_rejected() {
+        let (upstream_addr, _) = MockUpstream::bind(reply_ok()).await;
+        let config = Config::parse_from(["redis-tls-proxy", "--no-tls", "-u", &upstream_addr]);
+        let upstreams = Arc::new(UpstreamPool::new(config.upstreams()));
+        let mut state = state(ConnectionLimiter::from_config(&config));
+        state.ip_limiter = IpLimiter::new(2);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Acceptor::new(listener, Some(NO_DESCRIPTORS_REPLY));
+        tokio::spawn(run_plain_server(incoming, Arc::new(config), upstreams, state.clone()));
+
+        let first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+
+        let mut reply = Vec::new();
+        timeout(Duration::from_secs(5), rejected.read_to_end(&mut reply))
+            .await
+            .expect("rejected connection was not closed")
+            .unwrap();
+        assert_eq!(reply, IP_LIMIT_REPLY);
+        assert_eq!(state.stats.total_connections(), 2);
+        let localhost = addr.ip();
+        assert_eq!(state.ip_limiter.connections_from(localhost), 2);
+
+        // Once one closes, the address may connect again
+        drop(first);
+        timeout(Duration::from_secs(5), async {
+            while state.ip_limiter.connections_from(localhost) > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("closed connection still counted");
+        let mut again = TcpStream::connect(addr).await.unwrap();
+        again.write_all(b"PING\r\n").await.unwrap();
+        let mut reply = [0u8; 5];
+        timeout(Duration::from_secs(5), again.read_exact(&mut reply))
+            .await
+            .expect("no reply after a slot freed up")
+            .unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+    }
+
+    #[test]
+    fn test_format_top_commands() {
+        let top = [("GET".to_string(), 12), ("SET".to_string(), 3)];
+        assert_eq!(format_top_commands(&top), "GET=12,SET=3");
+        assert_eq!(format_top_commands(&[]), "");
     }
 }
@@ -0,0 +1,180 @@
+//! All-time slowest commands.
+//!
+//! Keeps the N slowest command observations since startup in a min-heap, so a new
+//! observation only has to beat the fastest of the retained ones to get in.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicU64};
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+/// Number of observations retained by default.
+pub const DEFAULT_CAPACITY: usize = 10;
+
+/// One command and how long upstream took to answer it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Observation {
+    pub command: String,
+    #[serde(rename = "duration_us", serialize_with = "serialize_micros")]
+    pub duration: Duration,
+    /// When the reply arrived, in milliseconds since the Unix epoch
+    pub timestamp_ms: u128,
+    pub peer: SocketAddr,
+}
+
+/// Serialize a duration as whole microseconds.
+pub fn serialize_micros<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_micros() as u64)
+}
+
+/// Heap entry ordered by duration alone.
+#[derive(Debug)]
+struct ByDuration(Observation);
+
+impl PartialEq for ByDuration {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.duration == other.0.duration
+    }
+}
+
+impl Eq for ByDuration {}
+
+impl PartialOrd for ByDuration {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByDuration {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.duration.cmp(&other.0.duration)
+    }
+}
+
+/// Bounded set of the slowest observations seen.
+#[derive(Debug)]
+pub struct SlowestCommands {
+    capacity: usize,
+    heap: Mutex<BinaryHeap<Reverse<ByDuration>>>,
+    /// Duration in microseconds an observation must exceed once the heap is full,
+    /// so the common fast command skips the lock
+    threshold_us: AtomicU64,
+}
+
+impl Default for SlowestCommands {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl SlowestCommands {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            heap: Mutex::new(BinaryHeap::with_capacity(capacity + 1)),
+            threshold_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Consider an observation for the all-time slowest list.
+    pub fn observe(&self, observation: Observation) {
+        if self.capacity == 0 {
+            return;
+        }
+        let micros = observation.duration.as_micros().min(u64::MAX as u128) as u64;
+        let threshold = self.threshold_us.load(atomic::Ordering::Relaxed);
+        if threshold > 0 && micros <= threshold {
+            return;
+        }
+
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(Reverse(ByDuration(observation)));
+        if heap.len() > self.capacity {
+            heap.pop();
+        }
+        if heap.len() == self.capacity
+            && let Some(Reverse(fastest)) = heap.peek()
+        {
+            let micros = fastest.0.duration.as_micros().min(u64::MAX as u128) as u64;
+            self.threshold_us.store(micros, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Forget all observations.
+    pub fn clear(&self) {
+        let mut heap = self.heap.lock().unwrap();
+        heap.clear();
+        self.threshold_us.store(0, atomic::Ordering::Relaxed);
+    }
+
+    /// Retained observations, slowest first.
+    pub fn snapshot(&self) -> Vec<Observation> {
+        let heap = self.heap.lock().unwrap();
+        let mut observations: Vec<Observation> =
+            heap.iter().map(|Reverse(entry)| entry.0.clone()).collect();
+        observations.sort_by_key(|o| Reverse(o.duration));
+        observations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(command: &str, millis: u64) -> Observation {
+        Observation {
+            command: command.to_string(),
+            duration: Duration::from_millis(millis),
+            timestamp_ms: 1700000000000,
+            peer: "10.0.0.1:50000".parse().unwrap(),
+        }
+    }
+
+    fn durations(slowest: &SlowestCommands) -> Vec<u64> {
+        slowest
+            .snapshot()
+            .iter()
+            .map(|o| o.duration.as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn test_retains_worst_n() {
+        let slowest = SlowestCommands::new(3);
+        for millis in [5, 1, 9, 3, 7, 2, 8, 9, 1] {
+            slowest.observe(observation("GET", millis));
+        }
+        assert_eq!(durations(&slowest), vec![9, 9, 8]);
+
+        slowest.observe(observation("KEYS", 20));
+        assert_eq!(durations(&slowest), vec![20, 9, 9]);
+        assert_eq!(slowest.snapshot()[0].command, "KEYS");
+    }
+
+    #[test]
+    fn test_fewer_observations_than_capacity() {
+        let slowest = SlowestCommands::new(5);
+        slowest.observe(observation("GET", 2));
+        slowest.observe(observation("SET", 4));
+        assert_eq!(durations(&slowest), vec![4, 2]);
+
+        let empty = SlowestCommands::new(0);
+        empty.observe(observation("GET", 2));
+        assert!(empty.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_observation_json() {
+        assert_eq!(
+            serde_json::to_string(&observation("GET", 3)).unwrap(),
+            r#"{"command":"GET","duration_us":3000,"timestamp_ms":1700000000000,"peer":"10.0.0.1:50000"}"#
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Recent commands slower than `--slowlog-threshold-ms`, like Redis's `SLOWLOG`.
+//!
+//! Unlike the all-time slowest list, this keeps the newest slow commands in arrival order,
+//! so a latency spike shows up even when slower commands were seen before it. Commands
+//! under the threshold never take the lock, and only the newest `--slowlog-max-len`
+//! entries are kept.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::slowest::serialize_micros;
+
+/// Entries retained by default, as in Redis.
+pub const DEFAULT_MAX_LEN: usize = 128;
+
+/// One command slower than the threshold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SlowlogEntry {
+    /// Increases by one per entry and is never reused, so readers can tell what they
+    /// have already seen
+    pub id: u64,
+    /// When the reply arrived, in milliseconds since the Unix epoch
+    pub timestamp_ms: u128,
+    pub command: String,
+    #[serde(rename = "duration_us", serialize_with = "serialize_micros")]
+    pub duration: Duration,
+    pub peer: SocketAddr,
+}
+
+/// Ring buffer of the newest slow commands.
+#[derive(Debug)]
+pub struct Slowlog {
+    /// None when the slowlog is off
+    threshold: Option<Duration>,
+    max_len: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    next_id: u64,
+    newest_last: VecDeque<SlowlogEntry>,
+}
+
+impl Default for Slowlog {
+    fn default() -> Self {
+        Self::new(None, DEFAULT_MAX_LEN)
+    }
+}
+
+impl Slowlog {
+    pub fn new(threshold: Option<Duration>, max_len: usize) -> Self {
+        Self {
+            threshold,
+            max_len,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold.is_some()
+    }
+
+    /// Keep `command` if it took longer than the threshold, evicting the oldest entry
+    /// once full.
+    pub fn observe(&self, command: &str, duration: Duration, peer: SocketAddr) {
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        if duration <= threshold || self.max_len == 0 {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.next_id;
+        entries.next_id += 1;
+        if entries.newest_last.len() == self.max_len {
+            entries.newest_last.pop_front();
+        }
+        entries.newest_last.push_back(SlowlogEntry {
+            id,
+            timestamp_ms,
+            command: command.to_string(),
+            duration,
+            peer,
+        });
+    }
+
+    /// Retained entries, newest first.
+    pub fn snapshot(&self) -> Vec<SlowlogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.newest_last.iter().rev().cloned().collect()
+    }
+
+    /// Forget all entries. IDs keep counting up, as with `SLOWLOG RESET`.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().newest_last.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "10.0.0.1:50000".parse().unwrap()
+    }
+
+    fn logged(slowlog: &Slowlog) -> Vec<(u64, String)> {
+        slowlog
+            .snapshot()
+            .into_iter()
+            .map(|entry| (entry.id, entry.command))
+            .collect()
+    }
+
+    #[test]
+    fn test_keeps_newest_over_threshold() {
+        let slowlog = Slowlog::new(Some(Duration::from_millis(10)), 2);
+        slowlog.observe("GET", Duration::from_millis(10), peer());
+        assert!(slowlog.snapshot().is_empty(), "at the threshold is not slow");
+
+        slowlog.observe("KEYS", Duration::from_millis(50), peer());
+        slowlog.observe("GET", Duration::from_millis(1), peer());
+        slowlog.observe("SMEMBERS", Duration::from_millis(11), peer());
+        slowlog.observe("HGETALL", Duration::from_millis(12), peer());
+        // Newest first, and the slowest one was evicted by age regardless of its latency
+        assert_eq!(
+            logged(&slowlog),
+            [(2, "HGETALL".to_string()), (1, "SMEMBERS".to_string())]
+        );
+        let entry = &slowlog.snapshot()[0];
+        assert_eq!((entry.duration, entry.peer), (Duration::from_millis(12), peer()));
+        let json = serde_json::to_string(entry).unwrap();
+        assert!(json.starts_with(r#"{"id":2,"timestamp_ms":"#));
+        let fields = r#""command":"HGETALL","duration_us":12000,"peer":"10.0.0.1:50000"}"#;
+        assert!(json.ends_with(fields));
+    }
+
+    #[test]
+    fn test_ids_continue_after_clear() {
+        let slowlog = Slowlog::new(Some(Duration::ZERO), 10);
+        slowlog.observe("GET", Duration::from_micros(1), peer());
+        slowlog.clear();
+        assert!(slowlog.snapshot().is_empty());
+        slowlog.observe("SET", Duration::from_micros(1), peer());
+        assert_eq!(logged(&slowlog), [(1, "SET".to_string())]);
+    }
+
+    #[test]
+    fn test_off_without_threshold() {
+        let slowlog = Slowlog::default();
+        assert!(!slowlog.is_enabled());
+        slowlog.observe("KEYS", Duration::from_secs(5), peer());
+        assert!(slowlog.snapshot().is_empty());
+    }
+}
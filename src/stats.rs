@@ -1,36 +1,328 @@
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::fmt::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
 use tracing::info;
 
+use crate::clock::{self, Clock};
+use crate::config::{Config, Protocol};
+use crate::histogram::Histogram;
+use crate::proxy::CloseCode;
+use crate::resp::Redirect;
+use crate::slowest::{Observation, SlowestCommands};
+use crate::slowlog::{Slowlog, SlowlogEntry};
+use crate::ttl::Expiry;
+
 /// Global statistics for command counting.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Stats {
+    /// Time source for uptime, dump intervals and everything timed against these stats
+    clock: Arc<dyn Clock>,
+    /// When the proxy started
+    started_at: Instant,
+    /// Total client connections accepted
+    total_connections: AtomicU64,
+    /// Client connections currently open
+    active_connections: AtomicU64,
     /// Total commands processed
     total_commands: AtomicU64,
     /// Per-command counts
-    command_counts: RwLock<HashMap<String, u64>>,
+    command_counts: CommandCounts,
+    /// Commands left out of all command statistics, as normalized by `command_key`
+    excluded_commands: HashSet<String>,
+    /// Whether command names are Redis commands (case-insensitive) or opaque tokens
+    protocol: Protocol,
+    /// Most commands read from a client at once, across all connections
+    max_pipeline_depth: AtomicU64,
+    /// Closed client connections by reason code, indexed like `CloseCode::ALL`
+    closes: [AtomicU64; CloseCode::ALL.len()],
+    /// Error replies from upstream by class (e.g. `WRONGTYPE`, `OOM`)
+    error_classes: Mutex<HashMap<String, u64>>,
+    /// `MULTI` ... `EXEC` transactions run by clients
+    transactions: AtomicU64,
+    /// Commands refused by `--deny-command` / `--allow-command`
+    blocked_commands: AtomicU64,
+    /// Commands refused by `--max-commands-per-sec` or `--global-max-commands-per-sec`
+    rate_limited_commands: AtomicU64,
+    /// What the client command parser got through
+    parser: ParserCounters,
+    /// Cluster redirects from upstream by slot and target node, as `[MOVED, ASK]` counts
+    redirects: Mutex<HashMap<(u16, String), [u64; 2]>>,
+    /// Client-side caching invalidation messages pushed by upstream
+    invalidations: AtomicU64,
+    /// Lifecycle events dropped because the webhook queue was full
+    dropped_events: AtomicU64,
+    /// TTLs set by client commands, in milliseconds
+    key_ttls: Histogram,
+    /// SET commands that left their key without a TTL
+    sets_without_ttl: AtomicU64,
+    /// Keys touched by each multi-key command
+    keys_per_command: RwLock<HashMap<String, Arc<Histogram>>>,
+    /// Reply latency of each command in microseconds
+    latency_per_command: RwLock<HashMap<String, Arc<Histogram>>>,
+    /// All-time slowest commands by reply latency
+    slowest: SlowestCommands,
+    /// Newest commands over `--slowlog-threshold-ms`
+    slowlog: Slowlog,
+    /// Counters at the last `delta_since_last_dump`
+    last_dump: Mutex<Option<DumpSnapshot>>,
+    /// Plain TCP upstream connection setup time in microseconds
+    upstream_connect_plain: Histogram,
+    /// TLS upstream connection setup time (TCP connect + handshake) in microseconds
+    upstream_connect_tls: Histogram,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let clock = clock::system();
+        Self {
+            started_at: clock.now(),
+            clock,
+            total_connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+            command_counts: CommandCounts::default(),
+            excluded_commands: HashSet::new(),
+            protocol: Protocol::Redis,
+            max_pipeline_depth: AtomicU64::new(0),
+            closes: Default::default(),
+            error_classes: Mutex::new(HashMap::new()),
+            transactions: AtomicU64::new(0),
+            blocked_commands: AtomicU64::new(0),
+            rate_limited_commands: AtomicU64::new(0),
+            parser: ParserCounters::default(),
+            redirects: Mutex::new(HashMap::new()),
+            invalidations: AtomicU64::new(0),
+            dropped_events: AtomicU64::new(0),
+            key_ttls: Histogram::new(),
+            sets_without_ttl: AtomicU64::new(0),
+            keys_per_command: RwLock::new(HashMap::new()),
+            latency_per_command: RwLock::new(HashMap::new()),
+            slowest: SlowestCommands::default(),
+            slowlog: Slowlog::default(),
+            last_dump: Mutex::new(None),
+            upstream_connect_plain: Histogram::new(),
+            upstream_connect_tls: Histogram::new(),
+        }
+    }
 }
 
 impl Stats {
+    #[cfg_attr(not(test), allow(dead_code))]
     pub fn new() -> Arc<Self> {
         Arc::new(Self::default())
     }
 
-    /// Increment the count for a specific command.
+    /// Stats measuring time on `clock`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Arc<Self> {
+        Arc::new(Self {
+            started_at: clock.now(),
+            clock,
+            ..Self::default()
+        })
+    }
+
+    /// Stats that ignore the given commands entirely (matched case-insensitively).
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_excluded_commands<S: AsRef<str>>(commands: &[S]) -> Arc<Self> {
+        Self::for_protocol(Protocol::Redis, commands)
+    }
+
+    /// Stats for traffic of `protocol` that ignore the given commands entirely. Redis
+    /// command names are matched and reported case-insensitively, generic ones verbatim.
+    pub fn for_protocol<S: AsRef<str>>(protocol: Protocol, excluded: &[S]) -> Arc<Self> {
+        Arc::new(Self::unshared(protocol, excluded))
+    }
+
+    /// Stats for the configured protocol, `--no-count` and slowlog.
+    pub fn from_config(config: &Config) -> Arc<Self> {
+        let threshold = config.slowlog_threshold_ms.map(Duration::from_millis);
+        Arc::new(Self {
+            slowlog: Slowlog::new(threshold, config.slowlog_max_len),
+            ..Self::unshared(config.protocol, &config.no_count)
+        })
+    }
+
+    fn unshared<S: AsRef<str>>(protocol: Protocol, excluded: &[S]) -> Self {
+        let mut stats = Self {
+            protocol,
+            ..Self::default()
+        };
+        stats.excluded_commands = excluded.iter().map(|c| stats.command_key(c.as_ref())).collect();
+        stats
+    }
+
+    /// The name a command is counted and reported under.
+    pub fn command_key(&self, command: &str) -> String {
+        match self.protocol {
+            Protocol::Redis => command.to_uppercase(),
+            Protocol::Generic => command.to_string(),
+        }
+    }
+
+    /// Record an accepted client connection, returning its ID: the number of connections
+    /// accepted so far, including this one.
+    pub fn record_connection(&self) -> u64 {
+        self.total_connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Mark a connection as active until the returned guard is dropped.
+    pub fn track_connection(self: &Arc<Self>) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            stats: self.clone(),
+        }
+    }
+
+    /// Get the number of currently open client connections.
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Get total accepted connection count.
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Record `count` client connections closed for `code`.
+    pub fn record_closes(&self, code: CloseCode, count: u64) {
+        let index = CloseCode::ALL.iter().position(|&c| c == code).unwrap();
+        self.closes[index].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Closed client connections per reason code, in `CloseCode::ALL` order.
+    pub fn close_counts(&self) -> Vec<(CloseCode, u64)> {
+        CloseCode::ALL
+            .iter()
+            .zip(&self.closes)
+            .map(|(&code, count)| (code, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Record how long establishing an upstream connection took.
+    pub fn record_upstream_connect(&self, tls: bool, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.upstream_connect_latency(tls).record(micros);
+    }
+
+    /// Upstream connection setup latency in microseconds, for plain or TLS upstreams.
+    pub fn upstream_connect_latency(&self, tls: bool) -> &Histogram {
+        if tls {
+            &self.upstream_connect_tls
+        } else {
+            &self.upstream_connect_plain
+        }
+    }
+
+    /// Time since the proxy started.
+    pub fn uptime(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started_at)
+    }
+
+    /// The clock these stats measure time on.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Increment the count for a specific command, unless it is excluded from stats.
     pub fn record_command(&self, command: &str) {
-        let new_total = self.total_commands.fetch_add(1, Ordering::Relaxed) + 1;
+        let command = self.command_key(command);
+        if self.excluded_commands.contains(&command) {
+            return;
+        }
 
-        let command_upper = command.to_uppercase();
-        let mut counts = self.command_counts.write().unwrap();
-        *counts.entry(command_upper).or_insert(0) += 1;
+        let new_total = self.total_commands.fetch_add(1, Ordering::Relaxed) + 1;
+        self.command_counts.increment(command);
 
         // Log every 100 commands
-        if new_total % 100 == 0 {
+        if new_total.is_multiple_of(100) {
             info!("Commands processed: {}", new_total);
         }
     }
 
+    /// Record the expiry a command set on its key, unless the command is excluded.
+    pub fn record_expiry(&self, command: &str, expiry: Expiry) {
+        if self.excluded_commands.contains(&self.command_key(command)) {
+            return;
+        }
+        match expiry {
+            Expiry::After(ttl) => self
+                .key_ttls
+                .record(ttl.as_millis().min(u64::MAX as u128) as u64),
+            Expiry::Never => {
+                self.sets_without_ttl.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// TTLs set by client commands, in milliseconds.
+    pub fn key_ttls(&self) -> &Histogram {
+        &self.key_ttls
+    }
+
+    /// Number of SET commands that left their key without a TTL.
+    pub fn sets_without_ttl(&self) -> u64 {
+        self.sets_without_ttl.load(Ordering::Relaxed)
+    }
+
+    /// Record the number of keys a multi-key command touched, unless it is excluded.
+    pub fn record_key_count(&self, command: &str, keys: usize) {
+        let command = self.command_key(command);
+        if self.excluded_commands.contains(&command) {
+            return;
+        }
+        histogram_for(&self.keys_per_command, command).record(keys as u64);
+    }
+
+    /// Distribution of keys touched by each multi-key command, by command name.
+    pub fn keys_per_command(&self) -> Vec<(String, Arc<Histogram>)> {
+        sorted_histograms(&self.keys_per_command)
+    }
+
+    /// Reply latency of each command in microseconds, by command name.
+    pub fn latency_per_command(&self) -> Vec<(String, Arc<Histogram>)> {
+        sorted_histograms(&self.latency_per_command)
+    }
+
+    /// Record how long upstream took to answer a command from `peer`.
+    pub fn record_command_latency(&self, command: &str, duration: Duration, peer: SocketAddr) {
+        let command = self.command_key(command);
+        if self.excluded_commands.contains(&command) {
+            return;
+        }
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        histogram_for(&self.latency_per_command, command.clone()).record(micros);
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.slowlog.observe(&command, duration, peer);
+        self.slowest.observe(Observation {
+            command,
+            duration,
+            timestamp_ms,
+            peer,
+        });
+    }
+
+    /// The slowest commands seen since startup, slowest first.
+    pub fn slowest_commands(&self) -> Vec<Observation> {
+        self.slowest.snapshot()
+    }
+
+    /// The newest commands over `--slowlog-threshold-ms`, newest first, or none if the
+    /// slowlog is off.
+    pub fn slowlog(&self) -> Option<Vec<SlowlogEntry>> {
+        self.slowlog.is_enabled().then(|| self.slowlog.snapshot())
+    }
+
     /// Get total command count.
     pub fn total(&self) -> u64 {
         self.total_commands.load(Ordering::Relaxed)
@@ -38,26 +330,1197 @@ impl Stats {
 
     /// Get a snapshot of per-command counts.
     pub fn command_counts(&self) -> HashMap<String, u64> {
-        self.command_counts.read().unwrap().clone()
+        self.command_counts.snapshot()
+    }
+
+    /// Record how many commands a client sent in one batch, keeping the maximum.
+    pub fn record_pipeline_depth(&self, depth: u64) {
+        // Only writes when the maximum grows, so the common case stays a plain load
+        let mut current = self.max_pipeline_depth.load(Ordering::Relaxed);
+        while depth > current {
+            match self.max_pipeline_depth.compare_exchange_weak(
+                current,
+                depth,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Most commands read from a client at once since startup.
+    pub fn max_pipeline_depth(&self) -> u64 {
+        self.max_pipeline_depth.load(Ordering::Relaxed)
+    }
+
+    /// Count an error reply from upstream of the given class.
+    pub fn record_error_reply(&self, class: &str) {
+        let mut classes = self.error_classes.lock().unwrap();
+        match classes.get_mut(class) {
+            Some(count) => *count += 1,
+            None => {
+                classes.insert(class.to_string(), 1);
+            }
+        }
+    }
+
+    /// Error replies by class, most frequent first.
+    pub fn error_classes(&self) -> Vec<(String, u64)> {
+        let mut classes: Vec<_> = self
+            .error_classes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(class, &count)| (class.clone(), count))
+            .collect();
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        classes
+    }
+
+    /// Count a transaction completed with `EXEC`.
+    pub fn record_transaction(&self) {
+        self.transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn transactions(&self) -> u64 {
+        self.transactions.load(Ordering::Relaxed)
+    }
+
+    /// Count a command refused by the proxy's command filter.
+    pub fn record_blocked_command(&self) {
+        self.blocked_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn blocked_commands(&self) -> u64 {
+        self.blocked_commands.load(Ordering::Relaxed)
+    }
+
+    /// Count a command refused for going over a rate limit.
+    pub fn record_rate_limited_command(&self) {
+        self.rate_limited_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limited_commands(&self) -> u64 {
+        self.rate_limited_commands.load(Ordering::Relaxed)
+    }
+
+    /// Account for one pass of the command parser over client data: `bytes` of complete
+    /// commands read, `commands` of them, and whether a partial command was left buffered
+    /// for the next read.
+    pub fn record_parse(&self, bytes: usize, commands: usize, incomplete: bool) {
+        self.parser.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.parser.commands.fetch_add(commands as u64, Ordering::Relaxed);
+        if incomplete {
+            self.parser.incomplete.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count client data the parser gave up on, passed through unparsed.
+    pub fn record_malformed_parse(&self) {
+        self.parser.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parser(&self) -> ParserStats {
+        ParserStats {
+            bytes: self.parser.bytes.load(Ordering::Relaxed),
+            commands: self.parser.commands.load(Ordering::Relaxed),
+            incomplete: self.parser.incomplete.load(Ordering::Relaxed),
+            malformed: self.parser.malformed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a `MOVED` or `ASK` redirect from upstream.
+    pub fn record_redirect(&self, redirect: &Redirect) {
+        let mut redirects = self.redirects.lock().unwrap();
+        let counts = redirects
+            .entry((redirect.slot, redirect.node.clone()))
+            .or_default();
+        counts[usize::from(redirect.ask)] += 1;
+    }
+
+    /// Redirects by slot and target node, most frequent first.
+    pub fn redirects(&self) -> Vec<RedirectCount> {
+        let mut redirects: Vec<_> = self
+            .redirects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((slot, node), &[moved, ask])| RedirectCount {
+                slot: *slot,
+                node: node.clone(),
+                moved,
+                ask,
+            })
+            .collect();
+        redirects.sort_by(|a, b| {
+            (b.moved + b.ask)
+                .cmp(&(a.moved + a.ask))
+                .then_with(|| (a.slot, &a.node).cmp(&(b.slot, &b.node)))
+        });
+        redirects
+    }
+
+    /// Record a client-side caching invalidation message from upstream.
+    pub fn record_invalidation(&self) {
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of invalidation messages seen.
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+
+    /// Record a lifecycle event dropped because the webhook queue was full.
+    pub fn record_dropped_event(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of dropped lifecycle events.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// What changed since the previous call, or since startup on the first, and remember
+    /// the current counters for the next.
+    pub fn delta_since_last_dump(&self) -> StatsDelta {
+        let now = DumpSnapshot {
+            at: self.clock.now(),
+            total: self.total(),
+            counts: self.command_counts(),
+        };
+        let previous = self.last_dump.lock().unwrap().replace(now.clone());
+        let previous = previous.unwrap_or_else(|| DumpSnapshot {
+            at: self.started_at,
+            total: 0,
+            counts: HashMap::new(),
+        });
+        now.delta_since(&previous)
+    }
+
+    /// Zero the command statistics, as if no command had been seen since now: counts,
+    /// histograms, error and redirect tallies, closes, the slowest list and the slowlog.
+    /// Connection totals, the active gauge and uptime are kept, and the next delta dump
+    /// starts here.
+    /// Connections may keep recording meanwhile; a command recorded during the reset can
+    /// end up counted in some statistics and not in others.
+    pub fn reset(&self) {
+        self.total_commands.store(0, Ordering::Relaxed);
+        self.command_counts.clear();
+        self.max_pipeline_depth.store(0, Ordering::Relaxed);
+        for count in &self.closes {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.error_classes.lock().unwrap().clear();
+        self.transactions.store(0, Ordering::Relaxed);
+        self.blocked_commands.store(0, Ordering::Relaxed);
+        self.rate_limited_commands.store(0, Ordering::Relaxed);
+        for count in [
+            &self.parser.bytes,
+            &self.parser.commands,
+            &self.parser.incomplete,
+            &self.parser.malformed,
+        ] {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.redirects.lock().unwrap().clear();
+        self.invalidations.store(0, Ordering::Relaxed);
+        self.dropped_events.store(0, Ordering::Relaxed);
+        self.key_ttls.reset();
+        self.sets_without_ttl.store(0, Ordering::Relaxed);
+        self.keys_per_command.write().unwrap().clear();
+        self.latency_per_command.write().unwrap().clear();
+        self.slowest.clear();
+        self.slowlog.clear();
+        self.upstream_connect_plain.reset();
+        self.upstream_connect_tls.reset();
+        *self.last_dump.lock().unwrap() = Some(DumpSnapshot {
+            at: self.clock.now(),
+            total: 0,
+            counts: HashMap::new(),
+        });
     }
 
     /// Print a summary of stats to stderr (ensures visibility on shutdown).
-    pub fn print_summary(&self) {
+    pub fn print_summary(&self, percentiles: &[f64]) {
+        eprint!("{}", self.summary(percentiles));
+    }
+
+    /// Human-readable summary of all statistics, reporting latencies at `percentiles`.
+    pub fn summary(&self, percentiles: &[f64]) -> String {
         let total = self.total();
         let counts = self.command_counts();
+        let mut out = String::new();
 
-        eprintln!("\n=== Command Statistics ===");
-        eprintln!("Total commands: {}", total);
+        let _ = writeln!(out, "\n=== Command Statistics ===");
+        let _ = writeln!(out, "Total commands: {}", total);
 
         if !counts.is_empty() {
-            eprintln!("\nPer-command breakdown:");
+            let _ = writeln!(out, "\nPer-command breakdown:");
             let mut sorted: Vec<_> = counts.into_iter().collect();
-            sorted.sort_by(|a, b| b.1.cmp(&a.1)); // Sort by count descending
+            sorted.sort_by_key(|(_, count)| std::cmp::Reverse(*count)); // Sort by count descending
+
+            for (cmd, count) in sorted {
+                let _ = writeln!(out, "  {}: {}", cmd, count);
+            }
+        }
+
+        let closes = self.close_counts();
+        if closes.iter().any(|&(_, count)| count > 0) {
+            let _ = writeln!(out, "\nConnections closed: {}", format_close_counts(&closes, " "));
+        }
+
+        let depth = self.max_pipeline_depth();
+        if depth > 0 {
+            let _ = writeln!(out, "\nMax pipeline depth: {}", depth);
+        }
+
+        let transactions = self.transactions();
+        if transactions > 0 {
+            let _ = writeln!(out, "\nTransactions: {}", transactions);
+        }
+
+        let blocked = self.blocked_commands();
+        if blocked > 0 {
+            let _ = writeln!(out, "\nBlocked commands: {}", blocked);
+        }
+
+        let rate_limited = self.rate_limited_commands();
+        if rate_limited > 0 {
+            let _ = writeln!(out, "\nRate-limited commands: {}", rate_limited);
+        }
+
+        let parser = self.parser();
+        if parser.bytes > 0 || parser.malformed > 0 {
+            let _ = writeln!(
+                out,
+                "\nParser: {} bytes in {} commands (avg {:.1} bytes), {} incomplete reads, \
+                 {} malformed",
+                parser.bytes,
+                parser.commands,
+                parser.average_command_size(),
+                parser.incomplete,
+                parser.malformed
+            );
+        }
+
+        let errors = self.error_classes();
+        if !errors.is_empty() {
+            let errors: Vec<_> = errors
+                .iter()
+                .map(|(class, count)| format!("{}={}", class, count))
+                .collect();
+            let _ = writeln!(out, "\nUpstream errors: {}", errors.join(" "));
+        }
+
+        let redirects = self.redirects();
+        if !redirects.is_empty() {
+            let _ = writeln!(out, "\nCluster redirects (top slots):");
+            for redirect in redirects.iter().take(REDIRECT_HOTSPOTS) {
+                let _ = writeln!(
+                    out,
+                    "  slot {} -> {}: MOVED={} ASK={}",
+                    redirect.slot, redirect.node, redirect.moved, redirect.ask
+                );
+            }
+        }
+
+        let invalidations = self.invalidations();
+        if invalidations > 0 {
+            let _ = writeln!(out, "\nInvalidation messages: {}", invalidations);
+        }
+
+        let dropped_events = self.dropped_events();
+        if dropped_events > 0 {
+            let _ = writeln!(out, "\nDropped webhook events: {}", dropped_events);
+        }
+
+        if self.key_ttls.count() > 0 || self.sets_without_ttl() > 0 {
+            let _ = writeln!(
+                out,
+                "\nKey TTLs set: {}",
+                format_ttl(&self.key_ttls, percentiles)
+            );
+            let _ = writeln!(out, "Keys set without TTL: {}", self.sets_without_ttl());
+        }
+
+        let keys = self.keys_per_command();
+        if !keys.is_empty() {
+            let _ = writeln!(out, "\nKeys per command:");
+            for (command, hist) in keys {
+                let _ = writeln!(out, "  {}: {}", command, format_key_counts(&hist, percentiles));
+            }
+        }
+
+        let latencies = self.latency_per_command();
+        if !latencies.is_empty() {
+            let _ = writeln!(out, "\nCommand latency:");
+            for (command, hist) in latencies {
+                let _ = writeln!(out, "  {}: {}", command, format_latency(&hist, percentiles));
+            }
+        }
+
+        let slowest = self.slowest_commands();
+        if !slowest.is_empty() {
+            let _ = writeln!(out, "\nSlowest commands:");
+            for observation in slowest {
+                let _ = writeln!(
+                    out,
+                    "  {}: {}us from {}",
+                    observation.command,
+                    observation.duration.as_micros(),
+                    observation.peer
+                );
+            }
+        }
+
+        for (label, tls) in [("plain", false), ("TLS", true)] {
+            let hist = self.upstream_connect_latency(tls);
+            if hist.count() > 0 {
+                let _ = writeln!(
+                    out,
+                    "\nUpstream connect latency ({}): {}",
+                    label,
+                    format_latency(hist, percentiles)
+                );
+            }
+        }
+        let _ = writeln!(out, "==========================\n");
+        out
+    }
+}
 
+impl Stats {
+    /// A point-in-time copy of the counters for `/stats.json`, with command latencies at
+    /// `percentiles`.
+    pub fn snapshot(&self, percentiles: &[f64]) -> StatsSnapshot {
+        let mut commands: Vec<_> = self
+            .command_counts()
+            .into_iter()
+            .map(|(name, count)| NamedCount { name, count })
+            .collect();
+        commands.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        let upstream_errors = self
+            .error_classes()
+            .into_iter()
+            .map(|(name, count)| NamedCount { name, count })
+            .collect();
+        let latency = self
+            .latency_per_command()
+            .into_iter()
+            .map(|(command, hist)| LatencySummary {
+                command,
+                count: hist.count(),
+                mean_us: hist.mean(),
+                max_us: hist.max(),
+                percentiles: percentiles.iter().map(|&p| (p, hist.percentile(p))).collect(),
+            })
+            .collect();
+        StatsSnapshot {
+            uptime_secs: self.uptime().as_secs(),
+            total_connections: self.total_connections(),
+            active_connections: self.active_connections(),
+            total_commands: self.total(),
+            commands,
+            upstream_errors,
+            latency,
+        }
+    }
+
+    /// [`Stats::snapshot`] as a JSON object.
+    pub fn to_json(&self, percentiles: &[f64]) -> serde_json::Value {
+        serde_json::to_value(self.snapshot(percentiles)).expect("snapshot has string keys")
+    }
+}
+
+/// The histogram for `command` in `map`, added if it has none yet.
+fn histogram_for(
+    map: &RwLock<HashMap<String, Arc<Histogram>>>,
+    command: String,
+) -> Arc<Histogram> {
+    let existing = map.read().unwrap().get(&command).cloned();
+    match existing {
+        Some(hist) => hist,
+        None => map.write().unwrap().entry(command).or_default().clone(),
+    }
+}
+
+/// The histograms in `map`, sorted by command name.
+fn sorted_histograms(
+    map: &RwLock<HashMap<String, Arc<Histogram>>>,
+) -> Vec<(String, Arc<Histogram>)> {
+    let mut commands: Vec<_> = map
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(command, hist)| (command.clone(), hist.clone()))
+        .collect();
+    commands.sort_by(|a, b| a.0.cmp(&b.0));
+    commands
+}
+
+/// Format a microsecond histogram as a one-line latency summary.
+pub fn format_latency(hist: &Histogram, percentiles: &[f64]) -> String {
+    let ms = |us: u64| us as f64 / 1000.0;
+    let mut out = format!("count={} mean={:.3}ms", hist.count(), ms(hist.mean()));
+    for &p in percentiles {
+        let _ = write!(out, " p{}={:.3}ms", p, ms(hist.percentile(p)));
+    }
+    let _ = write!(out, " max={:.3}ms", ms(hist.max()));
+    out
+}
+
+/// Most redirected slots listed in the summary.
+const REDIRECT_HOTSPOTS: usize = 10;
+
+/// Cluster redirects to one node for one slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectCount {
+    pub slot: u16,
+    pub node: String,
+    pub moved: u64,
+    pub ask: u64,
+}
+
+/// Counters served at `/stats.json`, copied out of [`Stats`] at one point in time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub total_connections: u64,
+    pub active_connections: u64,
+    pub total_commands: u64,
+    /// Most used first
+    pub commands: Vec<NamedCount>,
+    /// Error replies by class, most frequent first
+    pub upstream_errors: Vec<NamedCount>,
+    /// By command name, only with `--parse-replies on`
+    pub latency: Vec<LatencySummary>,
+}
+
+/// How often something named was seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NamedCount {
+    pub name: String,
+    pub count: u64,
+}
+
+/// One command's reply latency in microseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySummary {
+    pub command: String,
+    pub count: u64,
+    pub mean_us: u64,
+    pub max_us: u64,
+    /// `(percentile, latency)` for each of `--percentiles`
+    pub percentiles: Vec<(f64, u64)>,
+}
+
+/// Percentiles become fields of their own, named like `p99.9_us`.
+impl Serialize for LatencySummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(4 + self.percentiles.len()))?;
+        map.serialize_entry("command", &self.command)?;
+        map.serialize_entry("count", &self.count)?;
+        map.serialize_entry("mean_us", &self.mean_us)?;
+        for (p, us) in &self.percentiles {
+            map.serialize_entry(&format!("p{}_us", p), us)?;
+        }
+        map.serialize_entry("max_us", &self.max_us)?;
+        map.end()
+    }
+}
+
+/// Live counters behind [`ParserStats`].
+#[derive(Debug, Default)]
+struct ParserCounters {
+    bytes: AtomicU64,
+    commands: AtomicU64,
+    incomplete: AtomicU64,
+    malformed: AtomicU64,
+}
+
+/// What the client command parser got through since startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParserStats {
+    /// Bytes of complete commands parsed
+    pub bytes: u64,
+    pub commands: u64,
+    /// Reads that ended in a partial command, kept buffered until the rest arrived
+    pub incomplete: u64,
+    /// Times parsing stopped at bytes that can never form a command
+    pub malformed: u64,
+}
+
+impl ParserStats {
+    /// Mean size of a parsed command in bytes, 0 before any.
+    pub fn average_command_size(&self) -> f64 {
+        if self.commands == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.commands as f64
+    }
+}
+
+/// Command counters captured at a stats dump.
+#[derive(Debug, Clone)]
+struct DumpSnapshot {
+    at: Instant,
+    total: u64,
+    counts: HashMap<String, u64>,
+}
+
+impl DumpSnapshot {
+    fn delta_since(&self, earlier: &DumpSnapshot) -> StatsDelta {
+        let counts = self
+            .counts
+            .iter()
+            .filter_map(|(cmd, &count)| {
+                let before = earlier.counts.get(cmd).copied().unwrap_or(0);
+                let delta = count.saturating_sub(before);
+                (delta > 0).then(|| (cmd.clone(), delta))
+            })
+            .collect();
+        StatsDelta {
+            elapsed: self.at.saturating_duration_since(earlier.at),
+            total: self.total.saturating_sub(earlier.total),
+            counts,
+        }
+    }
+}
+
+/// Commands seen between two stats dumps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsDelta {
+    pub elapsed: Duration,
+    pub total: u64,
+    /// Per-command counts, leaving out commands not seen in the interval
+    pub counts: HashMap<String, u64>,
+}
+
+impl StatsDelta {
+    /// Average command rate over the interval.
+    pub fn commands_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.total as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Human-readable summary of the interval.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "\n=== Command Statistics (last {:.1}s) ===",
+            self.elapsed.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "Commands: {} ({:.1}/s)",
+            self.total,
+            self.commands_per_sec()
+        );
+        if !self.counts.is_empty() {
+            let _ = writeln!(out, "\nPer-command breakdown:");
+            let mut sorted: Vec<_> = self.counts.iter().collect();
+            sorted.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
             for (cmd, count) in sorted {
-                eprintln!("  {}: {}", cmd, count);
+                let _ = writeln!(out, "  {}: {}", cmd, count);
+            }
+        }
+        let _ = writeln!(out, "==========================");
+        out
+    }
+}
+
+/// Format close counts as `code=count` pairs joined by `separator`.
+pub fn format_close_counts(closes: &[(CloseCode, u64)], separator: &str) -> String {
+    closes
+        .iter()
+        .map(|(code, count)| format!("{}={}", code.as_str(), count))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Format a distribution of keys per command as a one-line summary.
+pub fn format_key_counts(hist: &Histogram, percentiles: &[f64]) -> String {
+    let mut out = format!("count={} mean={}", hist.count(), hist.mean());
+    for &p in percentiles {
+        let _ = write!(out, " p{}={}", p, hist.percentile(p));
+    }
+    let _ = write!(out, " max={}", hist.max());
+    out
+}
+
+/// Format a millisecond TTL histogram as a one-line summary in seconds.
+pub fn format_ttl(hist: &Histogram, percentiles: &[f64]) -> String {
+    let secs = |ms: u64| ms as f64 / 1000.0;
+    let mut out = format!("count={} mean={:.3}s", hist.count(), secs(hist.mean()));
+    for &p in percentiles {
+        let _ = write!(out, " p{}={:.3}s", p, secs(hist.percentile(p)));
+    }
+    let _ = write!(out, " max={:.3}s", secs(hist.max()));
+    out
+}
+
+/// Number of independently locked shards in [`CommandCounts`].
+const COUNT_SHARDS: usize = 16;
+
+/// Per-command counters split across shards.
+///
+/// Counters are atomics, so recording a command already seen only takes a shard's
+/// read lock and never waits for a snapshot, which also only reads. The write lock is
+/// needed just to add a new command name, and then only blocks one shard.
+#[derive(Debug)]
+struct CommandCounts {
+    hasher: RandomState,
+    shards: [RwLock<HashMap<String, AtomicU64>>; COUNT_SHARDS],
+}
+
+impl Default for CommandCounts {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: std::array::from_fn(|_| RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl CommandCounts {
+    fn shard(&self, command: &str) -> &RwLock<HashMap<String, AtomicU64>> {
+        &self.shards[self.hasher.hash_one(command) as usize % COUNT_SHARDS]
+    }
+
+    fn increment(&self, command: String) {
+        let shard = self.shard(&command);
+        if let Some(count) = shard.read().unwrap().get(&command) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        shard
+            .write()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// Copy the counts, one shard at a time.
+    fn snapshot(&self) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for shard in &self.shards {
+            for (command, count) in shard.read().unwrap().iter() {
+                counts.insert(command.clone(), count.load(Ordering::Relaxed));
+            }
+        }
+        counts
+    }
+}
+
+/// Keeps a connection counted in the active gauge until dropped.
+#[derive(Debug)]
+pub struct ActiveConnection {
+    stats: Arc<Stats>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_excluded_commands_not_recorded() {
+        let stats = Stats::with_excluded_commands(&["ping"]);
+        stats.record_command("PING");
+        stats.record_command("ping");
+        stats.record_command("GET");
+
+        assert_eq!(stats.total(), 1);
+        assert_eq!(stats.command_counts().get("PING"), None);
+        let summary = stats.summary(&[50.0]);
+        assert!(summary.contains("  GET: 1"), "{}", summary);
+        assert!(!summary.contains("PING"), "{}", summary);
+    }
+
+    #[test]
+    fn test_generic_commands_counted_verbatim() {
+        let stats = Stats::for_protocol(Protocol::Generic, &["noop"]);
+        stats.record_command("get");
+        stats.record_command("GET");
+        stats.record_command("MyService.Query");
+        stats.record_command("noop");
+        stats.record_command("NOOP");
+
+        assert_eq!(stats.total(), 4);
+        let counts = stats.command_counts();
+        assert_eq!(counts.get("get"), Some(&1));
+        assert_eq!(counts.get("GET"), Some(&1));
+        assert_eq!(counts.get("MyService.Query"), Some(&1));
+        assert_eq!(counts.get("noop"), None);
+        assert_eq!(counts.get("NOOP"), Some(&1));
+    }
+
+    #[test]
+    fn test_max_pipeline_depth_across_threads() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("pipeline"));
+
+        let handles: Vec<_> = (1..=8)
+            .map(|t| {
+                let stats = stats.clone();
+                std::thread::spawn(move || {
+                    for depth in (0..1000).map(|i| i * t % 997) {
+                        stats.record_pipeline_depth(depth);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(stats.max_pipeline_depth(), 996);
+
+        stats.record_pipeline_depth(3);
+        assert_eq!(stats.max_pipeline_depth(), 996);
+        assert!(stats.summary(&[]).contains("Max pipeline depth: 996"));
+    }
+
+    #[test]
+    fn test_delta_between_dumps() {
+        let start = Instant::now();
+        let earlier = DumpSnapshot {
+            at: start,
+            total: 10,
+            counts: HashMap::from([("GET".to_string(), 7), ("SET".to_string(), 3)]),
+        };
+        let later = DumpSnapshot {
+            at: start + Duration::from_secs(4),
+            total: 30,
+            counts: HashMap::from([
+                ("GET".to_string(), 22),
+                ("SET".to_string(), 3),
+                ("DEL".to_string(), 5),
+            ]),
+        };
+
+        let delta = later.delta_since(&earlier);
+        assert_eq!(delta.elapsed, Duration::from_secs(4));
+        assert_eq!(delta.total, 20);
+        assert_eq!(
+            delta.counts,
+            HashMap::from([("GET".to_string(), 15), ("DEL".to_string(), 5)])
+        );
+        assert_eq!(delta.commands_per_sec(), 5.0);
+        let summary = delta.summary();
+        assert!(summary.contains("(last 4.0s)"), "{}", summary);
+        assert!(summary.contains("Commands: 20 (5.0/s)"), "{}", summary);
+        assert!(summary.contains("  GET: 15\n  DEL: 5\n"), "{}", summary);
+    }
+
+    #[test]
+    fn test_delta_since_last_dump_resets() {
+        let stats = Stats::new();
+        stats.record_command("GET");
+        stats.record_command("GET");
+        let first = stats.delta_since_last_dump();
+        assert_eq!(first.total, 2);
+        assert_eq!(first.counts.get("GET"), Some(&2));
+
+        stats.record_command("SET");
+        let second = stats.delta_since_last_dump();
+        assert_eq!(second.total, 1);
+        assert_eq!(second.counts, HashMap::from([("SET".to_string(), 1)]));
+        assert_eq!(stats.delta_since_last_dump().total, 0);
+        // Cumulative totals are untouched
+        assert_eq!(stats.total(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_uptime_and_dump_rate_on_mock_clock() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        clock.advance(Duration::from_secs(90)).await;
+        assert_eq!(stats.uptime().as_secs(), 90);
+        stats.delta_since_last_dump();
+
+        for _ in 0..25 {
+            stats.record_command("GET");
+        }
+        clock.advance(Duration::from_secs(10)).await;
+        let delta = stats.delta_since_last_dump();
+        assert_eq!(delta.elapsed.as_secs(), 10);
+        assert_eq!(format!("{:.2}", delta.commands_per_sec()), "2.50");
+    }
+
+    #[test]
+    fn test_close_reasons_aggregated() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Connections closed"));
+
+        stats.record_closes(CloseCode::ClientClosed, 1);
+        stats.record_closes(CloseCode::ClientClosed, 1);
+        stats.record_closes(CloseCode::Error, 1);
+        stats.record_closes(CloseCode::Reset, 3);
+        stats.record_closes(CloseCode::Shutdown, 4);
+        assert_eq!(
+            stats.close_counts(),
+            [
+                (CloseCode::ClientClosed, 2),
+                (CloseCode::UpstreamClosed, 0),
+                (CloseCode::Reset, 3),
+                (CloseCode::IdleTimeout, 0),
+                (CloseCode::Shutdown, 4),
+                (CloseCode::Error, 1),
+            ]
+        );
+        assert!(stats.summary(&[]).contains(
+            "Connections closed: client_closed=2 upstream_closed=0 reset=3 idle_timeout=0 \
+             shutdown=4 error=1"
+        ));
+    }
+
+    #[test]
+    fn test_transactions_in_summary() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Transactions"));
+        stats.record_transaction();
+        stats.record_transaction();
+        assert_eq!(stats.transactions(), 2);
+        assert!(stats.summary(&[]).contains("\nTransactions: 2\n"));
+    }
+
+    #[test]
+    fn test_parser_stats_in_summary() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Parser"));
+        stats.record_parse(30, 2, false);
+        stats.record_parse(10, 1, true);
+        stats.record_malformed_parse();
+        let parser = stats.parser();
+        assert_eq!(
+            parser,
+            ParserStats {
+                bytes: 40,
+                commands: 3,
+                incomplete: 1,
+                malformed: 1
+            }
+        );
+        assert!((parser.average_command_size() - 13.333).abs() < 0.001);
+        assert!(stats.summary(&[]).contains(
+            "\nParser: 40 bytes in 3 commands (avg 13.3 bytes), 1 incomplete reads, 1 malformed\n"
+        ));
+        assert_eq!(ParserStats::default().average_command_size(), 0.0);
+    }
+
+    #[test]
+    fn test_blocked_commands_in_summary() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Blocked"));
+        stats.record_blocked_command();
+        assert_eq!(stats.blocked_commands(), 1);
+        assert!(stats.summary(&[]).contains("\nBlocked commands: 1\n"));
+    }
+
+    #[test]
+    fn test_redirects_counted_by_slot_and_node() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Cluster redirects"));
+
+        let redirect = |ask, slot, node: &str| Redirect {
+            ask,
+            slot,
+            node: node.to_string(),
+        };
+        stats.record_redirect(&redirect(false, 3999, "127.0.0.1:7001"));
+        stats.record_redirect(&redirect(false, 3999, "127.0.0.1:7001"));
+        stats.record_redirect(&redirect(true, 3999, "127.0.0.1:7001"));
+        stats.record_redirect(&redirect(true, 12, "127.0.0.1:7002"));
+        assert_eq!(
+            stats.redirects(),
+            [
+                RedirectCount {
+                    slot: 3999,
+                    node: "127.0.0.1:7001".to_string(),
+                    moved: 2,
+                    ask: 1,
+                },
+                RedirectCount {
+                    slot: 12,
+                    node: "127.0.0.1:7002".to_string(),
+                    moved: 0,
+                    ask: 1,
+                },
+            ]
+        );
+        assert!(stats.summary(&[]).contains(
+            "Cluster redirects (top slots):\n  slot 3999 -> 127.0.0.1:7001: MOVED=2 ASK=1\n  \
+             slot 12 -> 127.0.0.1:7002: MOVED=0 ASK=1\n"
+        ));
+    }
+
+    #[test]
+    fn test_error_classes_counted() {
+        let stats = Stats::new();
+        assert!(!stats.summary(&[]).contains("Upstream errors"));
+
+        stats.record_error_reply("WRONGTYPE");
+        stats.record_error_reply("OOM");
+        stats.record_error_reply("OOM");
+        stats.record_error_reply("ERR");
+        assert_eq!(
+            stats.error_classes(),
+            [
+                ("OOM".to_string(), 2),
+                ("ERR".to_string(), 1),
+                ("WRONGTYPE".to_string(), 1),
+            ]
+        );
+        assert!(stats.summary(&[]).contains("Upstream errors: OOM=2 ERR=1 WRONGTYPE=1"));
+    }
+
+    #[test]
+    fn test_keys_per_command_recorded() {
+        let stats = Stats::with_excluded_commands(&["DEL"]);
+        stats.record_key_count("mget", 3);
+        stats.record_key_count("MGET", 100);
+        stats.record_key_count("SUNION", 2);
+        stats.record_key_count("DEL", 1000);
+
+        let keys = stats.keys_per_command();
+        let names: Vec<_> = keys.iter().map(|(command, _)| command.as_str()).collect();
+        assert_eq!(names, ["MGET", "SUNION"]);
+        assert_eq!(keys[0].1.count(), 2);
+        assert_eq!(keys[0].1.max(), 100);
+        assert!(stats
+            .summary(&[50.0])
+            .contains("Keys per command:\n  MGET: count=2 mean=51 p50=3 max=100\n  SUNION:"));
+    }
+
+    #[test]
+    fn test_key_ttls_recorded() {
+        let stats = Stats::new();
+        stats.record_expiry("SET", Expiry::After(Duration::from_secs(3600)));
+        stats.record_expiry("set", Expiry::Never);
+
+        assert_eq!(stats.key_ttls().count(), 1);
+        assert_eq!(stats.key_ttls().max(), 3_600_000);
+        assert_eq!(stats.sets_without_ttl(), 1);
+        let summary = stats.summary(&[]);
+        assert!(
+            summary.contains("Key TTLs set: count=1 mean=3600.000s max=3600.000s\nKeys set without TTL: 1"),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn test_latency_per_command_in_summary() {
+        let stats = Stats::with_excluded_commands(&["blpop"]);
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        for micros in [100, 200, 300] {
+            stats.record_command_latency("get", Duration::from_micros(micros), peer);
+        }
+        stats.record_command_latency("SET", Duration::from_millis(2), peer);
+        stats.record_command_latency("BLPOP", Duration::from_secs(5), peer);
+
+        let latencies = stats.latency_per_command();
+        let names: Vec<_> = latencies.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["GET", "SET"]);
+        assert_eq!((latencies[0].1.count(), latencies[0].1.max()), (3, 300));
+
+        let summary = stats.summary(&[50.0, 99.0]);
+        let get = format_latency(&latencies[0].1, &[50.0, 99.0]);
+        assert!(get.contains(" p50=") && get.contains(" p99="), "{get}");
+        assert!(summary.contains(&format!("\nCommand latency:\n  GET: {get}\n  SET: ")), "{summary}");
+    }
+
+    #[tokio::test]
+    async fn test_reset_zeroes_command_stats() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        stats.record_connection();
+        let _connection = stats.track_connection();
+        stats.record_command("GET");
+        stats.record_command_latency("GET", Duration::from_millis(3), peer);
+        stats.record_key_count("MGET", 2);
+        stats.record_expiry("SET", Expiry::Never);
+        stats.record_error_reply("OOM");
+        stats.record_closes(CloseCode::Reset, 1);
+        stats.record_pipeline_depth(8);
+        stats.record_parse(20, 1, true);
+        stats.record_upstream_connect(true, Duration::from_millis(4));
+        clock.advance(Duration::from_secs(30)).await;
+
+        stats.reset();
+        assert_eq!(stats.summary(&[50.0]), Stats::new().summary(&[50.0]));
+        assert!(stats.slowest_commands().is_empty());
+        assert_eq!(stats.upstream_connect_latency(true).count(), 0);
+        // Connections and uptime are not command statistics
+        assert_eq!((stats.total_connections(), stats.active_connections()), (1, 1));
+        assert_eq!(stats.uptime().as_secs(), 30);
+
+        // The next delta covers only what came after the reset
+        stats.record_command("SET");
+        clock.advance(Duration::from_secs(10)).await;
+        let delta = stats.delta_since_last_dump();
+        assert_eq!((delta.total, delta.elapsed.as_secs()), (1, 10));
+    }
+
+    #[test]
+    fn test_reset_while_recording() {
+        let stats = Stats::new();
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..2000 {
+                        let command = if i % 2 == 0 { "GET" } else { "SET" };
+                        stats.record_command(command);
+                        stats.record_command_latency(command, Duration::from_micros(i), peer);
+                    }
+                });
+            }
+            for _ in 0..50 {
+                stats.reset();
+                std::thread::yield_now();
             }
+        });
+
+        // Whatever survived the last reset is still counted consistently afterwards
+        let before = stats.total();
+        stats.record_command("GET");
+        assert_eq!(stats.total(), before + 1);
+        assert!(stats.command_counts().values().sum::<u64>() <= 8000);
+        stats.reset();
+        assert_eq!(stats.total(), 0);
+        assert!(stats.command_counts().is_empty() && stats.latency_per_command().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_as_json() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let empty = r#"{"uptime_secs":0,"total_connections":0,"active_connections":0,"#;
+        assert!(stats.to_json(&[]).to_string().starts_with(empty));
+
+        stats.record_connection();
+        let _connection = stats.track_connection();
+        for command in ["get", "SET", "GET"] {
+            stats.record_command(command);
+        }
+        stats.record_error_reply("OOM");
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        stats.record_command_latency("GET", Duration::from_micros(300), peer);
+        clock.advance(Duration::from_secs(5)).await;
+
+        let snapshot = stats.snapshot(&[50.0, 99.9]);
+        assert_eq!(snapshot.latency[0].percentiles, [(50.0, 300), (99.9, 300)]);
+        assert_eq!(stats.to_json(&[50.0])["total_commands"], 3);
+        assert_eq!(
+            serde_json::to_string(&snapshot).unwrap(),
+            concat!(
+                r#"{"uptime_secs":5,"total_connections":1,"active_connections":1,"#,
+                r#""total_commands":3,"#,
+                r#""commands":[{"name":"GET","count":2},{"name":"SET","count":1}],"#,
+                r#""upstream_errors":[{"name":"OOM","count":1}],"#,
+                r#""latency":[{"command":"GET","count":1,"mean_us":300,"p50_us":300,"#,
+                r#""p99.9_us":300,"max_us":300}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_slowest_commands_in_summary() {
+        let stats = Stats::with_excluded_commands(&["blpop"]);
+        let peer = "10.0.0.1:50000".parse().unwrap();
+        stats.record_command_latency("get", Duration::from_micros(150), peer);
+        stats.record_command_latency("BLPOP", Duration::from_secs(5), peer);
+        stats.record_command_latency("KEYS", Duration::from_micros(900), peer);
+
+        let slowest = stats.slowest_commands();
+        assert_eq!(slowest.len(), 2);
+        assert_eq!(slowest[0].command, "KEYS");
+        assert_eq!(slowest[1].command, "GET");
+        let summary = stats.summary(&[50.0]);
+        assert!(
+            summary.contains("Slowest commands:\n  KEYS: 900us from 10.0.0.1:50000\n  GET: 150us"),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn test_snapshot_during_concurrent_recording() {
+        const THREADS: usize = 4;
+        const PER_THREAD: u64 = 20_000;
+        let stats = Stats::new();
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let stats = stats.clone();
+                std::thread::spawn(move || {
+                    for i in 0..PER_THREAD {
+                        stats.record_command(if i % 2 == 0 { "GET" } else { "SET" });
+                        stats.record_command(&format!("CMD{}", t));
+                    }
+                })
+            })
+            .collect();
+
+        // Counts only ever grow between snapshots taken under load
+        let mut previous = HashMap::new();
+        while !writers.iter().all(|w| w.is_finished()) {
+            let snapshot = stats.command_counts();
+            for (command, count) in &previous {
+                assert!(snapshot[command] >= *count, "{} went backwards", command);
+            }
+            previous = snapshot;
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let counts = stats.command_counts();
+        let half = THREADS as u64 * PER_THREAD / 2;
+        assert_eq!(counts["GET"], half);
+        assert_eq!(counts["SET"], half);
+        for t in 0..THREADS {
+            assert_eq!(counts[&format!("CMD{}", t)], PER_THREAD);
+        }
+        assert_eq!(counts.values().sum::<u64>(), stats.total());
+    }
+
+    #[test]
+    fn test_format_latency_at_custom_percentiles() {
+        let hist = Histogram::new();
+        for us in 1..=10_000 {
+            hist.record(us);
         }
-        eprintln!("==========================\n");
+        assert_eq!(
+            format_latency(&hist, &[90.0, 99.9]),
+            format!(
+                "count=10000 mean=5.000ms p90={:.3}ms p99.9={:.3}ms max=10.000ms",
+                hist.percentile(90.0) as f64 / 1000.0,
+                hist.percentile(99.9) as f64 / 1000.0
+            )
+        );
+        assert_eq!(format_latency(&hist, &[]), "count=10000 mean=5.000ms max=10.000ms");
     }
 }
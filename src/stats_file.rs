@@ -0,0 +1,131 @@
+//! Periodic statistics dumps to a file with `--stats-file`.
+//!
+//! Each dump replaces the whole file: it is written next to it under a temporary name and
+//! renamed into place, so readers never see a partial dump.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::stats::Stats;
+
+/// Where and how statistics are dumped.
+#[derive(Debug, Clone)]
+pub struct StatsFile {
+    path: PathBuf,
+    /// JSON like `/stats.json` for a `.json` path, the shutdown summary otherwise
+    json: bool,
+    percentiles: Vec<f64>,
+}
+
+impl StatsFile {
+    pub fn new(path: PathBuf, percentiles: Vec<f64>) -> Self {
+        let json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        Self {
+            path,
+            json,
+            percentiles,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replace the file with the current statistics.
+    pub fn write(&self, stats: &Stats) -> io::Result<()> {
+        let contents = if self.json {
+            format!("{}\n", stats.to_json(&self.percentiles))
+        } else {
+            stats.summary(&self.percentiles)
+        };
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temp = self.path.with_file_name(name);
+        std::fs::write(&temp, contents)?;
+        std::fs::rename(&temp, &self.path)
+    }
+}
+
+/// Dump statistics to `file` every `interval` until the task is dropped. Failed writes are
+/// logged and retried at the next interval.
+pub async fn write_periodically(file: StatsFile, stats: Arc<Stats>, interval: Duration) {
+    loop {
+        stats.clock().sleep(interval).await;
+        if let Err(e) = file.write(&stats) {
+            warn!("Cannot write stats to {}: {}", file.path().display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_format_follows_extension() {
+        let stats = Stats::new();
+        stats.record_command("GET");
+
+        let json = StatsFile::new(temp_path("stats.json"), vec![50.0]);
+        json.write(&stats).unwrap();
+        let written = std::fs::read_to_string(json.path()).unwrap();
+        assert!(written.starts_with(r#"{"uptime_secs":"#), "{written}");
+        assert!(written.ends_with("}\n"));
+
+        let text = StatsFile::new(temp_path("stats.txt"), vec![50.0]);
+        text.write(&stats).unwrap();
+        let written = std::fs::read_to_string(text.path()).unwrap();
+        assert!(written.contains("Total commands: 1"), "{written}");
+
+        // Nothing is left behind under the temporary name
+        assert!(!temp_path("stats.json.tmp").exists());
+        for file in [json, text] {
+            std::fs::remove_file(file.path()).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_every_interval() {
+        let clock = MockClock::start();
+        let stats = Stats::with_clock(clock.clone());
+        let file = StatsFile::new(temp_path("periodic.json"), vec![]);
+        let path = file.path().to_path_buf();
+        let writer = tokio::spawn(write_periodically(
+            file,
+            stats.clone(),
+            Duration::from_secs(60),
+        ));
+        let total = || {
+            let written = std::fs::read_to_string(&path).ok()?;
+            let (_, rest) = written.split_once(r#""total_commands":"#)?;
+            rest.split(',').next()?.parse::<u64>().ok()
+        };
+
+        stats.record_command("GET");
+        clock.advance(Duration::from_secs(59)).await;
+        assert_eq!(total(), None);
+        clock.advance(Duration::from_secs(1)).await;
+        assert_eq!(total(), Some(1));
+
+        stats.record_command("SET");
+        clock.advance(Duration::from_secs(60)).await;
+        assert_eq!(total(), Some(2));
+
+        // Stopping the task stops the dumps
+        writer.abort();
+        assert!(writer.await.unwrap_err().is_cancelled());
+        stats.record_command("SET");
+        clock.advance(Duration::from_secs(60)).await;
+        assert_eq!(total(), Some(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! Test harness for running whole connections through the proxy.
+//!
+//! [`MockUpstream`] plays Redis: it parses the commands it receives, answers each with
+//! a scripted reply and records everything in order. [`ProxyHarness`] wires a client
+//! and a mock upstream to [`proxy_connection`] over in-memory streams, so tests can
+//! assert exactly which commands were forwarded, which were answered by the proxy,
+//! and in what order replies came back.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use clap::Parser;
+use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::config::Config;
+use crate::memory::RssSource;
+use crate::proxy::{proxy_connection, ConnectionSummary, ProxyContext};
+use crate::resp::{parse_command_frames, parse_reply_frames, Command, RespVersion};
+use crate::stats::Stats;
+
+/// How long harness reads wait before failing the test.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something the mock upstream saw or did, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A command arrived, as its name followed by its arguments
+    Command(Vec<String>),
+    /// The mock sent this reply
+    Reply(Vec<u8>),
+}
+
+/// Builds the mock's reply to a command.
+pub type Responder = Arc<dyn Fn(&Command) -> Vec<u8> + Send + Sync>;
+
+/// Answer every command with `+OK`.
+pub fn reply_ok() -> Responder {
+    Arc::new(|_| b"+OK\r\n".to_vec())
+}
+
+/// A scripted Redis stand-in that records what it receives.
+#[derive(Clone)]
+pub struct MockUpstream {
+    received: Arc<Mutex<Vec<u8>>>,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl MockUpstream {
+    /// Serve one connection on `stream`.
+    pub fn spawn<S>(stream: S, respond: Responder) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mock = Self {
+            received: Arc::default(),
+            events: Arc::default(),
+        };
+        tokio::spawn(mock.clone().serve(stream, respond));
+        mock
+    }
+
+    /// Listen on a local TCP port, serving every connection and recording them all
+    /// into the same log. Returns the address to point the proxy at.
+    pub async fn bind(respond: Responder) -> (String, Self) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mock = Self {
+            received: Arc::default(),
+            events: Arc::default(),
+        };
+        let acceptor = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(acceptor.clone().serve(stream, respond.clone()));
+            }
+        });
+        (addr, mock)
+    }
+
+    async fn serve<S>(self, mut stream: S, respond: Responder)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            self.received.lock().unwrap().extend_from_slice(&chunk[..n]);
+            buf.extend_from_slice(&chunk[..n]);
+
+            let parsed = parse_command_frames(&buf);
+            buf.advance(parsed.consumed);
+            for cmd in parsed.commands {
+                let reply = respond(&cmd);
+                {
+                    let mut events = self.events.lock().unwrap();
+                    let mut words = vec![cmd.name.clone()];
+                    words.extend(cmd.args.iter().cloned());
+                    events.push(Event::Command(words));
+                    events.push(Event::Reply(reply.clone()));
+                }
+                if stream.write_all(&reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Raw bytes received so far.
+    pub fn received(&self) -> Vec<u8> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Commands received so far, each as its name followed by its arguments.
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Command(words) => Some(words),
+                Event::Reply(_) => None,
+            })
+            .collect()
+    }
+
+    /// Everything seen and sent so far, in order.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+/// One client connection proxied to a [`MockUpstream`] over in-memory streams.
+pub struct ProxyHarness {
+    pub client: DuplexStream,
+    pub upstream: MockUpstream,
+    pub stats: Arc<Stats>,
+    /// Reply bytes read from the proxy but not yet returned as whole replies
+    pending: BytesMut,
+    proxy: JoinHandle<ConnectionSummary>,
+}
+
+impl ProxyHarness {
+    /// Start a connection through a proxy configured with `args`.
+    pub fn start(args: &[&str], respond: Responder) -> Self {
+        let config = Config::parse_from([&["redis-tls-proxy", "--no-tls"], args].concat());
+        let (client, client_remote) = duplex(1 << 16);
+        let (upstream, upstream_remote) = duplex(1 << 16);
+        let stats = Stats::new();
+        let peer: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let ctx = ProxyContext::new(peer, stats.clone(), Arc::new(config));
+        let proxy = tokio::spawn(proxy_connection(client_remote, upstream, ctx));
+        Self {
+            client,
+            upstream: MockUpstream::spawn(upstream_remote, respond),
+            stats,
+            pending: BytesMut::new(),
+            proxy,
+        }
+    }
+
+    /// Send raw bytes as the client.
+    pub async fn send(&mut self, data: &[u8]) {
+        self.client.write_all(data).await.unwrap();
+    }
+
+    /// Read the next `n` complete replies the client receives, in order.
+    pub async fn replies(&mut self, n: usize) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        loop {
+            let parsed = parse_reply_frames(&self.pending, RespVersion::Resp3);
+            assert!(!parsed.malformed, "malformed reply: {:?}", self.pending);
+            let mut used = 0;
+            for reply in parsed.replies.iter().take(n - replies.len()) {
+                replies.push(self.pending[reply.span.clone()].to_vec());
+                used = reply.span.end;
+            }
+            self.pending.advance(used);
+            if replies.len() == n {
+                return replies;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let read = timeout(READ_TIMEOUT, self.client.read(&mut chunk))
+                .await
+                .expect("timed out waiting for replies");
+            let len = read.unwrap();
+            assert!(len > 0, "proxy closed the connection after {:?}", replies);
+            self.pending.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    /// Close the client side and wait for the proxy to finish.
+    pub async fn close(self) -> ConnectionSummary {
+        drop(self.client);
+        timeout(READ_TIMEOUT, self.proxy)
+            .await
+            .expect("proxy did not finish")
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::CloseReason;
+
+    fn words(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    /// Echo the command name back as a simple string.
+    fn reply_name() -> Responder {
+        Arc::new(|cmd| format!("+{}\r\n", cmd.name).into_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_forwarded_and_answered_in_order() {
+        let mut harness = ProxyHarness::start(&["--rename", "CONFIG=cfg2"], reply_name());
+        harness
+            .send(b"SET a 1\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\nCONFIG GET maxmemory\r\nINCR b\r\n")
+            .await;
+
+        let replies = harness.replies(4).await;
+        assert_eq!(replies, [&b"+SET\r\n"[..], b"+GET\r\n", b"+cfg2\r\n", b"+INCR\r\n"]);
+        assert_eq!(
+            harness.upstream.commands(),
+            [words("SET a 1"), words("GET a"), words("cfg2 GET maxmemory"), words("INCR b")]
+        );
+
+        let summary = harness.close().await;
+        assert_eq!(summary.commands, 4);
+        assert_eq!(summary.reason, CloseReason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_command_never_reaches_upstream() {
+        let mut harness = ProxyHarness::start(&["--max-select-db", "3"], reply_ok());
+        harness.send(b"SELECT 9\r\n").await;
+        assert_eq!(harness.replies(1).await, [b"-ERR DB index is out of range\r\n"]);
+
+        harness.send(b"SELECT 2\r\nPING\r\n").await;
+        assert_eq!(harness.replies(2).await, [b"+OK\r\n", b"+OK\r\n"]);
+        assert_eq!(
+            harness.upstream.events(),
+            [
+                Event::Command(words("SELECT 2")),
+                Event::Reply(b"+OK\r\n".to_vec()),
+                Event::Command(words("PING")),
+                Event::Reply(b"+OK\r\n".to_vec()),
+            ]
+        );
+        assert_eq!(harness.upstream.received(), b"SELECT 2\r\nPING\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replies_reassembled_when_parsing_replies() {
+        // A bulk reply large enough to arrive in pieces
+        let value = "v".repeat(100_000);
+        let reply = format!("${}\r\n{}\r\n", value.len(), value).into_bytes();
+        let expected = reply.clone();
+        let mut harness =
+            ProxyHarness::start(&["--parse-replies", "on"], Arc::new(move |_| reply.clone()));
+
+        harness.send(b"GET big\r\nGET big\r\n").await;
+        assert_eq!(harness.replies(2).await, [expected.clone(), expected]);
+        assert_eq!(harness.stats.total(), 2);
+        assert_eq!(harness.stats.slowest_commands().len(), 2);
+    }
+}
+
+/// Resident set size reading that a test sets by hand.
+#[derive(Debug, Default)]
+pub struct MockRss(AtomicU64);
+
+impl MockRss {
+    pub fn set(&self, bytes: u64) {
+        self.0.store(bytes, Ordering::Relaxed);
+    }
+}
+
+impl RssSource for MockRss {
+    fn rss_bytes(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,127 @@
+//! Per-command timing log for offline performance analysis.
+//!
+//! Every command that gets a reply becomes one complete ("X") event in the Chrome trace
+//! event format, with one track per client connection. The file is a JSON array that is
+//! never closed, which the format explicitly allows, so it stays loadable even if the
+//! proxy is killed. Open it in Perfetto or `chrome://tracing`, or fold it into stacks
+//! for `inferno`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::json;
+use tracing::warn;
+
+
+/// Shared writer for command timing events.
+pub struct TimingLog {
+    /// Event timestamps are microseconds since this instant
+    epoch: Instant,
+    next_track: AtomicU64,
+    out: Mutex<BufWriter<Box<dyn Write + Send>>>,
+    failed: AtomicBool,
+}
+
+impl TimingLog {
+    /// Create (or truncate) the log file at `path`.
+    pub fn create(path: &Path) -> io::Result<Arc<Self>> {
+        Self::from_writer(Box::new(File::create(path)?))
+    }
+
+    fn from_writer(writer: Box<dyn Write + Send>) -> io::Result<Arc<Self>> {
+        let mut out = BufWriter::new(writer);
+        out.write_all(b"[\n")?;
+        Ok(Arc::new(Self {
+            epoch: Instant::now(),
+            next_track: AtomicU64::new(1),
+            out: Mutex::new(out),
+            failed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Allocate a track id for a new connection.
+    pub fn register_connection(&self) -> u64 {
+        self.next_track.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Record one command on `track` that ran from `start` to `end`.
+    pub fn record(&self, track: u64, name: &str, start: Instant, end: Instant) {
+        let ts = start.saturating_duration_since(self.epoch).as_micros();
+        let dur = end.saturating_duration_since(start).as_micros();
+        let event = format_event(name, track, ts, dur);
+
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(event.as_bytes()) {
+            // Don't flood the log when the disk is full
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                warn!("Failed to write timing log: {}", e);
+            }
+        }
+    }
+
+    /// Flush buffered events to disk.
+    pub fn flush(&self) {
+        if let Err(e) = self.out.lock().unwrap().flush() {
+            warn!("Failed to flush timing log: {}", e);
+        }
+    }
+}
+
+/// Format a single trace event, including the separator that follows it.
+fn format_event(name: &str, track: u64, ts: u128, dur: u128) -> String {
+    let event = json!({
+        "name": name,
+        "cat": "command",
+        "ph": "X",
+        "ts": ts as u64,
+        "dur": dur as u64,
+        "pid": 1,
+        "tid": track,
+    });
+    format!("{},\n", event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Writer whose contents can be inspected after the log takes ownership of it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_in_trace_format() {
+        let buf = SharedBuf::default();
+        let log = TimingLog::from_writer(Box::new(buf.clone())).unwrap();
+        let track = log.register_connection();
+        let start = log.epoch + Duration::from_micros(1500);
+        log.record(track, "GET", start, start + Duration::from_micros(250));
+        log.record(track, "SET", start, start);
+        log.flush();
+
+        let text = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "[\n\
+             {\"name\":\"GET\",\"cat\":\"command\",\"ph\":\"X\",\"ts\":1500,\"dur\":250,\"pid\":1,\"tid\":1},\n\
+             {\"name\":\"SET\",\"cat\":\"command\",\"ph\":\"X\",\"ts\":1500,\"dur\":0,\"pid\":1,\"tid\":1},\n"
+        );
+        assert_eq!(log.register_connection(), 2);
+    }
+}
@@ -1,13 +1,23 @@
-/// TLS certificate and key loading utilities.
+//! TLS certificate and key loading utilities.
 
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::ServerConfig;
+use rustls::crypto::{aws_lc_rs, CryptoProvider};
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    Error as TlsError, InconsistentKeys, RootCertStore, ServerConfig, SupportedProtocolVersion,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::config::Config;
+use crate::config::{Config, TlsVersion};
 use crate::error::{ProxyError, Result};
 
 /// Load TLS certificates from a PEM file.
@@ -64,6 +74,56 @@ pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
     }
 }
 
+/// Load certificate revocation lists from a PEM file, or a single CRL from a DER file.
+pub fn load_crls(path: &Path) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    let data = std::fs::read(path).map_err(|e| {
+        ProxyError::CrlLoad(format!("Failed to open {}: {}", path.display(), e))
+    })?;
+    if !data.starts_with(b"-----") {
+        return Ok(vec![CertificateRevocationListDer::from(data)]);
+    }
+
+    let crls = rustls_pemfile::crls(&mut &data[..])
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| ProxyError::CrlLoad(format!("Failed to parse {}: {}", path.display(), e)))?;
+    if crls.is_empty() {
+        return Err(ProxyError::CrlLoad(format!(
+            "No certificate revocation lists found in {}",
+            path.display()
+        )));
+    }
+    Ok(crls)
+}
+
+/// Verifier requiring client certificates issued by a CA in `ca` and not revoked by
+/// `crl`. With `optional`, clients that present no certificate are let through too.
+fn client_verifier(
+    ca: &Path,
+    crl: Option<&Path>,
+    optional: bool,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert).map_err(|e| {
+            ProxyError::CertificateLoad(format!("Invalid client CA {}: {}", ca.display(), e))
+        })?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder(Arc::new(roots));
+    if let Some(crl) = crl {
+        builder = builder.with_crls(load_crls(crl)?);
+    }
+    if optional {
+        builder = builder.allow_unauthenticated();
+    }
+    builder.build().map_err(|e| match (e, crl) {
+        (VerifierBuilderError::InvalidCrl(e), Some(crl)) => {
+            ProxyError::CrlLoad(format!("Invalid CRL in {}: {:?}", crl.display(), e))
+        }
+        (e, _) => ProxyError::CertificateLoad(format!("Invalid client CA {}: {}", ca.display(), e)),
+    })
+}
+
 /// Build TLS server configuration from certificate and key files.
 pub fn build_server_config(config: &Config) -> Result<ServerConfig> {
     let cert_path = config.cert.as_ref().expect("cert required for TLS");
@@ -71,11 +131,448 @@ pub fn build_server_config(config: &Config) -> Result<ServerConfig> {
 
     let certs = load_certs(cert_path)?;
     let key = load_private_key(key_path)?;
+    verify_key_matches(&certs, &key).map_err(|e| match e {
+        ProxyError::CertificateLoad(reason) => ProxyError::CertificateLoad(format!(
+            "{} (certificate {}, key {})",
+            reason,
+            cert_path.display(),
+            key_path.display()
+        )),
+        e => e,
+    })?;
 
-    let tls_config = ServerConfig::builder()
-        .with_no_client_auth()
+    let versions: &[&SupportedProtocolVersion] = match config.tls_min_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let builder = ServerConfig::builder_with_provider(Arc::new(crypto_provider(config)?))
+        .with_protocol_versions(versions)
+        .map_err(|e| ProxyError::Config(format!("Unusable TLS settings: {}", e)))?;
+    let builder = match &config.client_ca {
+        Some(ca) => builder.with_client_cert_verifier(client_verifier(
+            ca,
+            config.client_crl.as_deref(),
+            config.client_ca_optional,
+        )?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder
         .with_single_cert(certs, key)
         .map_err(ProxyError::Tls)?;
+    tls_config.alpn_protocols = config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
 
     Ok(tls_config)
 }
+
+/// The aws-lc-rs provider, offering only the `--tls-cipher-suites` if any are given.
+fn crypto_provider(config: &Config) -> Result<CryptoProvider> {
+    let mut provider = aws_lc_rs::default_provider();
+    if !config.tls_cipher_suites.is_empty() {
+        provider.cipher_suites = config
+            .tls_cipher_suites
+            .iter()
+            .map(|name| {
+                aws_lc_rs::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                    .copied()
+                    .ok_or_else(|| ProxyError::Config(format!("Unknown cipher suite {}", name)))
+            })
+            .collect::<Result<_>>()?;
+    }
+    Ok(provider)
+}
+
+/// Check that `key` is the private key of the leaf certificate in `certs`, so a
+/// mismatched pair fails at startup with a clear message.
+fn verify_key_matches(
+    certs: &[CertificateDer<'static>],
+    key: &PrivateKeyDer<'static>,
+) -> Result<()> {
+    let signing_key = aws_lc_rs::default_provider()
+        .key_provider
+        .load_private_key(key.clone_key())
+        .map_err(|e| ProxyError::PrivateKeyLoad(format!("Unsupported private key: {}", e)))?;
+
+    match CertifiedKey::new(certs.to_vec(), signing_key).keys_match() {
+        // Unknown: the key type can't report its public key; leave it to rustls
+        Ok(()) | Err(TlsError::InconsistentKeys(InconsistentKeys::Unknown)) => Ok(()),
+        Err(TlsError::InconsistentKeys(InconsistentKeys::KeyMismatch)) => Err(
+            ProxyError::CertificateLoad("private key does not match certificate".to_string()),
+        ),
+        Err(e) => Err(ProxyError::CertificateLoad(format!("Invalid certificate: {}", e))),
+    }
+}
+
+/// Stream wrapper that fails reads once more than `limit` bytes have been received,
+/// bounding how much a client can make us buffer before the TLS handshake completes.
+/// Call [`HandshakeLimited::complete`] after the handshake to lift the limit.
+#[derive(Debug)]
+pub struct HandshakeLimited<S> {
+    inner: S,
+    read: u64,
+    limit: Option<u64>,
+}
+
+impl<S> HandshakeLimited<S> {
+    pub fn new(inner: S, limit: u64) -> Self {
+        Self {
+            inner,
+            read: 0,
+            limit: Some(limit),
+        }
+    }
+
+    /// Lift the limit once the handshake is done.
+    pub fn complete(&mut self) {
+        self.limit = None;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HandshakeLimited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Some(limit) = this.limit {
+            this.read += (buf.filled().len() - before) as u64;
+            if this.read > limit {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("TLS handshake exceeded {} bytes", limit),
+                )));
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HandshakeLimited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::path::PathBuf;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    fn acceptor() -> TlsAcceptor {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certs(&testdata("server.pem")).unwrap(),
+                load_private_key(&testdata("server.key")).unwrap(),
+            )
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    fn connector() -> TlsConnector {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[test]
+    fn test_mismatched_key_is_rejected() {
+        let config = |cert: &str, key: &str| {
+            Config::parse_from([
+                "redis-tls-proxy",
+                "--cert",
+                testdata(cert).to_str().unwrap(),
+                "--key",
+                testdata(key).to_str().unwrap(),
+            ])
+        };
+        assert!(build_server_config(&config("server.pem", "server.key")).is_ok());
+
+        let err = build_server_config(&config("server.pem", "client.key")).unwrap_err();
+        assert!(matches!(err, ProxyError::CertificateLoad(_)));
+        let message = err.to_string();
+        assert!(message.contains("private key does not match certificate"), "{}", message);
+        assert!(message.contains("client.key"), "{}", message);
+    }
+
+    fn mtls_config(crl: &str, optional: bool) -> ServerConfig {
+        let mut args = vec![
+            "redis-tls-proxy".to_string(),
+            "--cert".to_string(),
+            testdata("server.pem").display().to_string(),
+            "--key".to_string(),
+            testdata("server.key").display().to_string(),
+            "--client-ca".to_string(),
+            testdata("ca.pem").display().to_string(),
+        ];
+        if !crl.is_empty() {
+            args.push("--client-crl".to_string());
+            args.push(testdata(crl).display().to_string());
+        }
+        if optional {
+            args.push("--client-ca-optional".to_string());
+        }
+        build_server_config(&Config::parse_from(args)).unwrap()
+    }
+
+    /// Handshake as a client presenting `cert`, or no certificate if it is empty,
+    /// returning the server's outcome.
+    async fn client_auth(server: ServerConfig, cert: &str, key: &str) -> io::Result<()> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+        let client = if cert.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            builder
+                .with_client_auth_cert(
+                    load_certs(&testdata(cert)).unwrap(),
+                    load_private_key(&testdata(key)).unwrap(),
+                )
+                .unwrap()
+        };
+
+        let (server_side, client_side) = duplex(1 << 16);
+        let accept = tokio::spawn(async move {
+            TlsAcceptor::from(Arc::new(server)).accept(server_side).await.map(|_| ())
+        });
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let _client = TlsConnector::from(Arc::new(client)).connect(name, client_side).await;
+        accept.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_revoked_client_certificate_rejected() {
+        let valid = client_auth(mtls_config("crl.pem", false), "client.pem", "client.key");
+        assert!(valid.await.is_ok());
+        let err = client_auth(mtls_config("crl.pem", false), "revoked.pem", "revoked.key")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Revoked"), "{}", err);
+
+        // Without the CRL the revoked certificate is otherwise valid
+        assert!(client_auth(mtls_config("", false), "revoked.pem", "revoked.key").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_certificate() {
+        assert!(client_auth(mtls_config("", false), "", "").await.is_err());
+        assert!(client_auth(mtls_config("", true), "", "").await.is_ok());
+
+        // A certificate that is presented is still checked
+        let valid = client_auth(mtls_config("crl.pem", true), "client.pem", "client.key");
+        assert!(valid.await.is_ok());
+        let err = client_auth(mtls_config("crl.pem", true), "revoked.pem", "revoked.key")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Revoked"), "{}", err);
+    }
+
+    fn restricted_config(args: &[&str]) -> ServerConfig {
+        let (cert, key) = (testdata("server.pem"), testdata("server.key"));
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        let base = ["redis-tls-proxy", "--cert", cert, "--key", key];
+        build_server_config(&Config::parse_from(base.iter().chain(args))).unwrap()
+    }
+
+    /// Handshake as a client offering only `versions`, returning the negotiated version
+    /// and cipher suite as the server sees them.
+    async fn negotiate(
+        server: ServerConfig,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> io::Result<(rustls::ProtocolVersion, rustls::CipherSuite)> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let client = rustls::ClientConfig::builder_with_protocol_versions(versions)
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (server_side, client_side) = duplex(1 << 16);
+        let accept = tokio::spawn(async move {
+            let stream = TlsAcceptor::from(Arc::new(server)).accept(server_side).await?;
+            let session = stream.get_ref().1;
+            Ok((
+                session.protocol_version().unwrap(),
+                session.negotiated_cipher_suite().unwrap().suite(),
+            ))
+        });
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let _client = TlsConnector::from(Arc::new(client)).connect(name, client_side).await;
+        accept.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_min_version_and_cipher_suites_applied() {
+        let tls12 = &[&rustls::version::TLS12][..];
+        let (version, _) = negotiate(restricted_config(&[]), tls12).await.unwrap();
+        assert_eq!(version, rustls::ProtocolVersion::TLSv1_2);
+
+        let tls13_only = restricted_config(&["--tls-min-version", "1.3"]);
+        assert!(negotiate(tls13_only.clone(), tls12).await.is_err());
+        let (version, _) = negotiate(tls13_only, rustls::ALL_VERSIONS).await.unwrap();
+        assert_eq!(version, rustls::ProtocolVersion::TLSv1_3);
+
+        let suites = "TLS13_CHACHA20_POLY1305_SHA256,TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384";
+        let restricted = restricted_config(&["--tls-cipher-suites", suites]);
+        let (_, suite) = negotiate(restricted.clone(), rustls::ALL_VERSIONS).await.unwrap();
+        assert_eq!(suite, rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+        let (_, suite) = negotiate(restricted, tls12).await.unwrap();
+        assert_eq!(suite, rustls::CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384);
+
+        let rsa_only = restricted_config(&[
+            "--tls-cipher-suites",
+            "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        ]);
+        assert!(negotiate(rsa_only, tls12).await.is_err());
+    }
+
+    /// Handshake as a client offering `protocols` through ALPN, returning the protocol
+    /// the server chose.
+    async fn alpn_handshake(server: ServerConfig, protocols: &[&str]) -> io::Result<Vec<u8>> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let mut client = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        let (server_side, client_side) = duplex(1 << 16);
+        let accept = tokio::spawn(async move {
+            let stream = TlsAcceptor::from(Arc::new(server)).accept(server_side).await?;
+            Ok(stream.get_ref().1.alpn_protocol().unwrap_or_default().to_vec())
+        });
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let _client = TlsConnector::from(Arc::new(client)).connect(name, client_side).await;
+        accept.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_alpn_negotiated() {
+        let server = restricted_config(&["--alpn", "redis", "--alpn", "resp3"]);
+        let chosen = alpn_handshake(server.clone(), &["resp3", "redis"]).await.unwrap();
+        assert_eq!(chosen, b"redis");
+        // Clients that don't use ALPN still connect; those offering only others are refused
+        assert_eq!(alpn_handshake(server.clone(), &[]).await.unwrap(), b"");
+        assert!(alpn_handshake(server, &["h2"]).await.is_err());
+
+        let without = restricted_config(&[]);
+        assert_eq!(alpn_handshake(without, &["h2"]).await.unwrap(), b"");
+    }
+
+    #[test]
+    fn test_crl_errors_reported() {
+        let dir = std::env::temp_dir().join(format!("crl-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let garbage = dir.join("garbage.der");
+        std::fs::write(&garbage, b"not a crl").unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, std::fs::read(testdata("ca.pem")).unwrap()).unwrap();
+
+        let verifier_err =
+            |crl: &Path| match client_verifier(&testdata("ca.pem"), Some(crl), false) {
+                Err(e) => e.to_string(),
+                Ok(_) => panic!("{} accepted", crl.display()),
+            };
+        let message = verifier_err(&garbage);
+        assert!(message.starts_with("Failed to load certificate revocation list: Invalid CRL"));
+        assert!(message.contains("garbage.der"), "{}", message);
+        assert!(verifier_err(&empty).contains("No certificate revocation lists found"));
+        assert!(verifier_err(&dir.join("missing.pem")).contains("Failed to open"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // DER-encoded CRLs load as well
+        let pem = load_crls(&testdata("crl.pem")).unwrap();
+        let der = dir.with_extension("der");
+        std::fs::write(&der, &pem[0]).unwrap();
+        assert_eq!(load_crls(&der).unwrap(), pem);
+        std::fs::remove_file(&der).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limit_rejects_large_pre_handshake_stream() {
+        let (server_side, mut client_side) = duplex(1 << 20);
+        let accept = tokio::spawn(async move {
+            acceptor()
+                .accept(HandshakeLimited::new(server_side, 4096))
+                .await
+        });
+
+        // A ClientHello announced as 64 KiB and fragmented across many records,
+        // which the TLS layer would buffer until the message is complete
+        let mut flood = Vec::new();
+        for i in 0..16 {
+            flood.extend_from_slice(&[22, 3, 1, 0x04, 0x00]);
+            let mut fragment = [0u8; 1024];
+            if i == 0 {
+                fragment[..4].copy_from_slice(&[1, 0x00, 0xff, 0xff]);
+            }
+            flood.extend_from_slice(&fragment);
+        }
+        let _ = client_side.write_all(&flood).await;
+
+        let err = accept.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("exceeded 4096 bytes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_within_limit_succeeds_and_lifts_limit() {
+        let (server_side, client_side) = duplex(1 << 20);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor()
+                .accept(HandshakeLimited::new(server_side, 16 * 1024))
+                .await
+                .unwrap();
+            stream.get_mut().0.complete();
+            stream.write_all(b"ready").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut data = vec![0u8; 64 * 1024];
+            stream.read_exact(&mut data).await.unwrap();
+            data
+        });
+
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let mut client = connector().connect(name, client_side).await.unwrap();
+        let mut ready = [0u8; 5];
+        client.read_exact(&mut ready).await.unwrap();
+        // More application data than the handshake limit must flow once it is lifted
+        client.write_all(&vec![7u8; 64 * 1024]).await.unwrap();
+        client.flush().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), vec![7u8; 64 * 1024]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Expiry times set by client commands.
+//!
+//! Recognises the TTL arguments of `EXPIRE`, `PEXPIRE`, `EXPIREAT`, `PEXPIREAT`,
+//! `SETEX`, `PSETEX` and `SET`, so the distribution of TTLs being set, and how many
+//! keys are written with none at all, can be reported.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::resp::Command;
+
+/// The expiry a command gives its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// The key expires after this long
+    After(Duration),
+    /// A plain `SET` that leaves the key without a TTL
+    Never,
+}
+
+/// The expiry set by `cmd`, if it sets one. `now` converts absolute expiry times.
+///
+/// Returns `None` for unrelated commands, `SET ... KEEPTTL`, and arguments Redis would
+/// reject.
+pub fn expiry(cmd: &Command, now: SystemTime) -> Option<Expiry> {
+    let name = cmd.name.to_ascii_uppercase();
+    let arg = |i: usize| cmd.args.get(i).map(String::as_str);
+    match name.as_str() {
+        "EXPIRE" => relative(arg(1)?, Duration::from_secs),
+        "PEXPIRE" => relative(arg(1)?, Duration::from_millis),
+        "EXPIREAT" => absolute(arg(1)?, Duration::from_secs, now),
+        "PEXPIREAT" => absolute(arg(1)?, Duration::from_millis, now),
+        "SETEX" => relative(arg(1)?, Duration::from_secs),
+        "PSETEX" => relative(arg(1)?, Duration::from_millis),
+        "SET" if cmd.args.len() >= 2 => set_expiry(&cmd.args[2..], now),
+        _ => None,
+    }
+}
+
+/// Expiry from the options following `SET key value`.
+fn set_expiry(options: &[String], now: SystemTime) -> Option<Expiry> {
+    let mut i = 0;
+    while i < options.len() {
+        let value = options.get(i + 1).map(String::as_str);
+        match options[i].to_ascii_uppercase().as_str() {
+            "EX" => return relative(value?, Duration::from_secs),
+            "PX" => return relative(value?, Duration::from_millis),
+            "EXAT" => return absolute(value?, Duration::from_secs, now),
+            "PXAT" => return absolute(value?, Duration::from_millis, now),
+            "KEEPTTL" => return None,
+            _ => {}
+        }
+        i += 1;
+    }
+    Some(Expiry::Never)
+}
+
+fn relative(value: &str, unit: fn(u64) -> Duration) -> Option<Expiry> {
+    value.parse().ok().map(|n| Expiry::After(unit(n)))
+}
+
+fn absolute(value: &str, unit: fn(u64) -> Duration, now: SystemTime) -> Option<Expiry> {
+    let at = UNIX_EPOCH + unit(value.parse().ok()?);
+    // Times in the past expire the key immediately
+    Some(Expiry::After(at.duration_since(now).unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        let mut words = line.split(' ').map(str::to_string);
+        Command {
+            name: words.next().unwrap(),
+            args: words.collect(),
+            span: 0..0,
+        }
+    }
+
+    fn at(line: &str) -> Option<Expiry> {
+        expiry(&command(line), UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+    }
+
+    fn secs(n: u64) -> Option<Expiry> {
+        Some(Expiry::After(Duration::from_secs(n)))
+    }
+
+    #[test]
+    fn test_set_options() {
+        assert_eq!(at("SET key val EX 3600"), secs(3600));
+        assert_eq!(at("set key val nx px 1500"), Some(Expiry::After(Duration::from_millis(1500))));
+        assert_eq!(at("SET key val EXAT 1700000060"), secs(60));
+        assert_eq!(at("SET key val PXAT 1600000000000"), secs(0));
+        assert_eq!(at("SET key val"), Some(Expiry::Never));
+        assert_eq!(at("SET key val GET"), Some(Expiry::Never));
+        assert_eq!(at("SET key val KEEPTTL"), None);
+        assert_eq!(at("SET key val EX soon"), None);
+        assert_eq!(at("SET key val EX"), None);
+        assert_eq!(at("SET key"), None);
+    }
+
+    #[test]
+    fn test_expire_commands() {
+        assert_eq!(at("EXPIRE key 30"), secs(30));
+        assert_eq!(at("EXPIRE key 30 NX"), secs(30));
+        assert_eq!(at("PEXPIRE key 250"), Some(Expiry::After(Duration::from_millis(250))));
+        assert_eq!(at("EXPIREAT key 1700000100"), secs(100));
+        assert_eq!(at("PEXPIREAT key 1700000001000"), secs(1));
+        assert_eq!(at("SETEX key 10 val"), secs(10));
+        assert_eq!(at("PSETEX key 2000 val"), secs(2));
+        assert_eq!(at("EXPIRE key -1"), None);
+        assert_eq!(at("GET key"), None);
+    }
+}
@@ -1,61 +1,585 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasher;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use rustls::pki_types::ServerName;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::TcpStream;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
 
+use crate::accept::UNIX_PREFIX;
+use crate::chain::{ChainKey, ChainStream, Role};
+use crate::clock::Clock;
 use crate::error::{ProxyError, Result};
+use crate::resp::encode_command;
+use crate::stats::Stats;
+use crate::tls::{load_certs, load_private_key};
+
+/// Maximum length of a reply line read while setting up an upstream connection.
+const MAX_SETUP_REPLY_LEN: usize = 64 * 1024;
+
+/// Maximum number of lines read while waiting for a setup command's reply, including
+/// anything the upstream sent unprompted.
+const MAX_SETUP_REPLY_LINES: usize = 64;
+
+/// Longest wait between two attempts to connect to an upstream, or to bind the listening
+/// address again.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Echoed back by a PING sent after each setup command, marking the end of its reply.
+const SETUP_MARKER: &str = "redis-tls-proxy-setup";
+
+/// Connection settings for a single upstream server.
+///
+/// Parsed from `ADDR[,OPTION...]` where options are `tls`, `plain`, `hostname=NAME`,
+/// `ca=PATH`, `client-cert=PATH`, `client-key=PATH` and `bind=IP`. Unset options fall
+/// back to the global upstream flags. `ADDR` is `host:port`, or `unix:PATH` for a Unix
+/// domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    /// Address to connect to (host:port or unix:PATH)
+    pub addr: String,
+    /// Use TLS; `None` means use the global `--upstream-tls` setting
+    pub tls: Option<bool>,
+    /// Hostname for TLS SNI and verification (defaults to the host part of `addr`)
+    pub hostname: Option<String>,
+    /// CA bundle for verifying the server (defaults to the webpki roots)
+    pub ca: Option<PathBuf>,
+    /// Trust the webpki roots alongside `ca`; set from the global `--upstream-ca-add`
+    pub webpki_roots: bool,
+    /// Accept any server certificate; set from the global `--upstream-insecure-skip-verify`
+    pub insecure_skip_verify: bool,
+    /// Client certificate presented to the server
+    pub client_cert: Option<PathBuf>,
+    /// Private key for `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Local address connections originate from (defaults to the OS choice)
+    pub bind: Option<IpAddr>,
+    /// Frame and authenticate traffic for an upstream that is another instance of this
+    /// proxy; set from the global `--chain-upstream`
+    pub chain: Option<ChainKey>,
+    /// ALPN protocols requested over TLS; set from the global `--upstream-alpn`
+    pub alpn: Vec<String>,
+}
+
+/// Shown with defaults resolved, and the chain key only as whether one is set.
+impl Serialize for UpstreamConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut upstream = serializer.serialize_struct("UpstreamConfig", 11)?;
+        upstream.serialize_field("addr", &self.addr)?;
+        upstream.serialize_field("tls", &self.use_tls())?;
+        upstream.serialize_field("hostname", &self.hostname())?;
+        upstream.serialize_field("ca", &self.ca)?;
+        upstream.serialize_field("webpki_roots", &(self.ca.is_none() || self.webpki_roots))?;
+        upstream.serialize_field("insecure_skip_verify", &self.insecure_skip_verify)?;
+        upstream.serialize_field("client_cert", &self.client_cert)?;
+        upstream.serialize_field("client_key", &self.client_key)?;
+        upstream.serialize_field("bind", &self.bind)?;
+        upstream.serialize_field("chain", &self.chain.is_some())?;
+        upstream.serialize_field("alpn", &self.alpn)?;
+        upstream.end()
+    }
+}
+
+impl UpstreamConfig {
+    /// Parse an upstream entry from the command line.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default().trim();
+        if addr.is_empty() {
+            return Err("upstream address is empty".to_string());
+        }
+
+        let mut upstream = UpstreamConfig {
+            addr: addr.to_string(),
+            tls: None,
+            hostname: None,
+            ca: None,
+            webpki_roots: false,
+            insecure_skip_verify: false,
+            client_cert: None,
+            client_key: None,
+            bind: None,
+            chain: None,
+            alpn: Vec::new(),
+        };
+
+        for option in parts {
+            match option.trim().split_once('=') {
+                None if option.trim() == "tls" => upstream.tls = Some(true),
+                None if option.trim() == "plain" => upstream.tls = Some(false),
+                Some(("hostname", v)) => upstream.hostname = Some(v.to_string()),
+                Some(("ca", v)) => upstream.ca = Some(PathBuf::from(v)),
+                Some(("client-cert", v)) => upstream.client_cert = Some(PathBuf::from(v)),
+                Some(("client-key", v)) => upstream.client_key = Some(PathBuf::from(v)),
+                Some(("bind", v)) => {
+                    let ip = v.parse().map_err(|_| format!("invalid bind address '{}'", v))?;
+                    upstream.bind = Some(ip);
+                }
+                _ => return Err(format!("unknown upstream option '{}' in '{}'", option, s)),
+            }
+        }
+
+        if upstream.unix_path().is_some() && upstream.bind.is_some() {
+            return Err(format!("bind does not apply to a Unix socket upstream in '{}'", s));
+        }
+        if upstream.client_cert.is_some() != upstream.client_key.is_some() {
+            return Err(format!(
+                "client-cert and client-key must be given together in '{}'",
+                s
+            ));
+        }
+
+        Ok(upstream)
+    }
+
+    /// Whether this upstream is reached over TLS.
+    pub fn use_tls(&self) -> bool {
+        self.tls.unwrap_or(false)
+    }
+
+    /// Socket path of a `unix:PATH` upstream.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.addr.strip_prefix(UNIX_PREFIX).map(Path::new)
+    }
+
+    /// Hostname used for TLS verification; `localhost` for Unix socket upstreams.
+    pub fn hostname(&self) -> String {
+        if self.unix_path().is_some() {
+            return self.hostname.clone().unwrap_or_else(|| "localhost".to_string());
+        }
+        self.hostname.clone().unwrap_or_else(|| {
+            self.addr
+                .split(':')
+                .next()
+                .unwrap_or("localhost")
+                .to_string()
+        })
+    }
+
+    /// Certificates trusted to sign the upstream's certificate.
+    fn root_store(&self) -> Result<rustls::RootCertStore> {
+        let mut root_store = rustls::RootCertStore::empty();
+        if let Some(ca) = &self.ca {
+            let certs = load_certs(ca).map_err(|e| match e {
+                ProxyError::CertificateLoad(reason) => {
+                    ProxyError::CertificateLoad(format!("{} (CA {})", reason, ca.display()))
+                }
+                e => e,
+            })?;
+            for cert in certs {
+                root_store.add(cert).map_err(|e| {
+                    ProxyError::CertificateLoad(format!(
+                        "Invalid CA certificate in {}: {}",
+                        ca.display(),
+                        e
+                    ))
+                })?;
+            }
+        }
+        if self.ca.is_none() || self.webpki_roots {
+            // Use the system root certificates
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        Ok(root_store)
+    }
+
+    /// Build the rustls client configuration for this upstream.
+    fn client_config(&self) -> Result<rustls::ClientConfig> {
+        let builder = if self.insecure_skip_verify {
+            rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipServerVerification::new()))
+        } else {
+            rustls::ClientConfig::builder().with_root_certificates(self.root_store()?)
+        };
+        let mut config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)
+                .map_err(ProxyError::Tls)?,
+            _ => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok(config)
+    }
+}
+
+/// Accepts any server certificate, for `--upstream-insecure-skip-verify`. Handshake
+/// signatures are still checked, so the server must hold the key of the certificate it
+/// sends, but nothing ties that certificate to the upstream.
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl SkipServerVerification {
+    fn new() -> Self {
+        Self(Arc::new(aws_lc_rs::default_provider()))
+    }
+}
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl fmt::Display for UpstreamConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}{})",
+            self.addr,
+            match (self.use_tls(), self.unix_path().is_some()) {
+                (true, false) => "TLS",
+                (false, false) => "plain TCP",
+                (true, true) => "TLS over Unix socket",
+                (false, true) => "Unix socket",
+            },
+            if self.chain.is_some() { ", chained" } else { "" }
+        )
+    }
+}
+
+/// Round-robin selection over the configured upstreams.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<UpstreamConfig>,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<UpstreamConfig>) -> Self {
+        assert!(!upstreams.is_empty(), "at least one upstream is required");
+        Self {
+            upstreams,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick the upstream for the next connection.
+    pub fn pick(&self) -> &UpstreamConfig {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+        &self.upstreams[index]
+    }
+
+    pub fn upstreams(&self) -> &[UpstreamConfig] {
+        &self.upstreams
+    }
+}
 
 /// Represents a connection to the upstream Redis server.
-/// Can be either plain TCP or TLS-encrypted.
+/// Can be either plain TCP or TLS-encrypted, optionally framed for a chained proxy.
 pub enum UpstreamConnection {
     Plain(TcpStream),
-    Tls(TlsStream<TcpStream>),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// TLS over a Unix socket, with `--allow-upstream-unix-tls`
+    #[cfg(unix)]
+    UnixTls(Box<TlsStream<UnixStream>>),
+    Chained(Box<ChainStream<UpstreamConnection>>),
 }
 
 impl UpstreamConnection {
-    /// Connect to upstream Redis server over plain TCP.
-    pub async fn connect_plain(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
+    /// Connect to upstream Redis server over plain TCP, or its Unix socket.
+    pub async fn connect_plain(upstream: &UpstreamConfig) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = upstream.unix_path() {
+            return Ok(UpstreamConnection::Unix(UnixStream::connect(path).await?));
+        }
+        let stream = connect_tcp(upstream).await?;
         Ok(UpstreamConnection::Plain(stream))
     }
 
     /// Connect to upstream Redis server over TLS.
-    pub async fn connect_tls(addr: &str, hostname: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
+    pub async fn connect_tls(upstream: &UpstreamConfig) -> Result<Self> {
+        let config = upstream.client_config()?;
+        #[cfg(unix)]
+        if let Some(path) = upstream.unix_path() {
+            let stream = UnixStream::connect(path).await?;
+            let tls_stream = tls_handshake(upstream, config, stream).await?;
+            return Ok(UpstreamConnection::UnixTls(Box::new(tls_stream)));
+        }
+        let stream = connect_tcp(upstream).await?;
+        let tls_stream = tls_handshake(upstream, config, stream).await?;
+        Ok(UpstreamConnection::Tls(Box::new(tls_stream)))
+    }
 
-        // Use the system root certificates
-        let root_store = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    /// Connect to upstream based on its configuration.
+    pub async fn connect(upstream: &UpstreamConfig) -> Result<Self> {
+        let conn = if upstream.use_tls() {
+            Self::connect_tls(upstream).await?
+        } else {
+            Self::connect_plain(upstream).await?
         };
+        Ok(match &upstream.chain {
+            Some(key) => UpstreamConnection::Chained(Box::new(
+                ChainStream::open(conn, key, Role::Connecting).await?,
+            )),
+            None => conn,
+        })
+    }
 
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+    /// Connect to upstream and record the setup time (TCP connect plus any TLS handshake).
+    pub async fn connect_recorded(upstream: &UpstreamConfig, stats: &Stats) -> Result<Self> {
+        let started = stats.clock().now();
+        let conn = Self::connect(upstream).await?;
+        let elapsed = stats.clock().now().saturating_duration_since(started);
+        stats.record_upstream_connect(upstream.use_tls(), elapsed);
+        Ok(conn)
+    }
 
-        let connector = TlsConnector::from(Arc::new(config));
+    /// Connect like [`Self::connect_recorded`], trying up to `retries` more times if the
+    /// upstream cannot be reached, waiting `base_delay` before the first retry and twice
+    /// as long before each next one. Returns the last error once retries run out.
+    pub async fn connect_with_retry(
+        upstream: &UpstreamConfig,
+        stats: &Stats,
+        retries: u32,
+        base_delay: Duration,
+    ) -> Result<Self> {
+        with_retries(retries, base_delay, stats.clock().as_ref(), || {
+            Self::connect_recorded(upstream, stats)
+        })
+        .await
+    }
 
-        let server_name = ServerName::try_from(hostname.to_string())
-            .map_err(|_| ProxyError::Connection(format!("Invalid server name: {}", hostname)))?;
+    /// Authenticate the upstream connection before any client data is proxied, as `user`
+    /// (Redis 6 ACLs) or with the password alone.
+    pub async fn authenticate(&mut self, user: Option<&str>, password: &str) -> Result<()> {
+        match user {
+            Some(user) => {
+                send_setup_command(self, &[b"AUTH", user.as_bytes(), password.as_bytes()]).await
+            }
+            None => send_setup_command(self, &[b"AUTH", password.as_bytes()]).await,
+        }
+    }
 
-        let tls_stream = connector.connect(server_name, stream).await?;
+    /// Switch the upstream connection to database `db` before any client data is proxied.
+    pub async fn select_db(&mut self, db: u32) -> Result<()> {
+        send_setup_command(self, &[b"SELECT", db.to_string().as_bytes()]).await
+    }
+}
 
-        Ok(UpstreamConnection::Tls(tls_stream))
+/// Run `attempt` until it succeeds, fails with an error retrying cannot fix, or has
+/// failed `retries` more times, backing off exponentially in between. Only I/O and
+/// connection errors are retried; configuration errors such as a bad certificate or
+/// server name would fail the same way every time.
+async fn with_retries<T, F, Fut>(
+    retries: u32,
+    base_delay: Duration,
+    clock: &dyn Clock,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut failures = 0;
+    loop {
+        match attempt().await {
+            Err(e @ (ProxyError::Io(_) | ProxyError::Connection(_))) if failures < retries => {
+                let delay = backoff(base_delay, failures);
+                warn!(
+                    "Upstream connection attempt {} failed, retrying in {:?}: {}",
+                    failures + 1,
+                    delay,
+                    e
+                );
+                clock.sleep(delay).await;
+                failures += 1;
+            }
+            result => return result,
+        }
     }
+}
+
+/// Delay before the retry following `failures` failed attempts: half of the exponential
+/// delay is kept and the other half randomized, so connections that failed together don't
+/// retry in lockstep.
+pub fn backoff(base_delay: Duration, failures: u32) -> Duration {
+    let delay = base_delay
+        .saturating_mul(1 << failures.min(16))
+        .min(MAX_RETRY_DELAY);
+    let jitter = RandomState::new().hash_one(failures) as f64 / u64::MAX as f64;
+    delay / 2 + delay.mul_f64(jitter / 2.0)
+}
+
+/// Run the TLS handshake with `upstream` over `stream`.
+async fn tls_handshake<S>(
+    upstream: &UpstreamConfig,
+    config: rustls::ClientConfig,
+    stream: S,
+) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let hostname = upstream.hostname();
+    let server_name = ServerName::try_from(hostname.clone())
+        .map_err(|_| ProxyError::Config(format!("Invalid server name: {}", hostname)))?;
 
-    /// Connect to upstream based on configuration.
-    pub async fn connect(addr: &str, use_tls: bool, hostname: &str) -> Result<Self> {
-        if use_tls {
-            Self::connect_tls(addr, hostname).await
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+/// Open the TCP connection to an upstream, from its bind address if one is set.
+async fn connect_tcp(upstream: &UpstreamConfig) -> Result<TcpStream> {
+    let Some(bind) = upstream.bind else {
+        return Ok(TcpStream::connect(&upstream.addr).await?);
+    };
+
+    // Only addresses of the bind address's family are reachable from it
+    let mut last_err = None;
+    for addr in lookup_host(&upstream.addr).await? {
+        if addr.is_ipv4() != bind.is_ipv4() {
+            continue;
+        }
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
         } else {
-            Self::connect_plain(addr).await
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(bind, 0))?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(match last_err {
+        Some(e) => e.into(),
+        None => ProxyError::Config(format!(
+            "{} has no address reachable from bind address {}",
+            upstream.addr, bind
+        )),
+    })
+}
+
+/// Send a command on a freshly opened upstream connection and wait for its reply.
+/// Fails if upstream answers with an error reply.
+///
+/// Some upstreams greet new connections with a banner before any command is sent, so
+/// the command is followed by `PING <marker>`: the command's reply is the one right
+/// before the echoed marker, and anything earlier is skipped.
+pub async fn send_setup_command<S>(stream: &mut S, args: &[&[u8]]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = encode_command(args);
+    request.extend_from_slice(&encode_command(&[b"PING", SETUP_MARKER.as_bytes()]));
+    stream.write_all(&request).await?;
+
+    let marker_header = format!("${}", SETUP_MARKER.len());
+    let mut lines: Vec<String> = Vec::new();
+    let reply = loop {
+        let line = read_reply_line(stream).await?;
+        if line == SETUP_MARKER && lines.last() == Some(&marker_header) {
+            lines.pop();
+            break lines.pop();
+        }
+        // An upstream that rejects the command outright (e.g. NOAUTH) rejects the PING
+        // too, so two errors in a row are the command's reply followed by the PING's
+        if line.starts_with('-') && lines.last().is_some_and(|prev| prev.starts_with('-')) {
+            break lines.pop();
         }
+        if lines.len() >= MAX_SETUP_REPLY_LINES {
+            return Err(ProxyError::Connection(
+                "too much unsolicited data from upstream during setup".to_string(),
+            ));
+        }
+        lines.push(line);
+    };
+    let reply = reply.ok_or_else(|| {
+        ProxyError::Connection("upstream did not reply to setup command".to_string())
+    })?;
+    for line in &lines {
+        debug!("Skipped unsolicited data from upstream: {}", line);
+    }
+
+    if let Some(err) = reply.strip_prefix('-') {
+        let name = String::from_utf8_lossy(args.first().copied().unwrap_or_default());
+        return Err(ProxyError::Connection(format!(
+            "upstream rejected {}: {}",
+            name, err
+        )));
     }
+    Ok(())
+}
+
+/// The bytes an upstream sends in response to a setup command answered with `reply`.
+#[cfg(test)]
+pub fn setup_reply(reply: &str) -> Vec<u8> {
+    format!(
+        "{}\r\n${}\r\n{}\r\n",
+        reply,
+        SETUP_MARKER.len(),
+        SETUP_MARKER
+    )
+    .into_bytes()
+}
+
+/// Read a single CRLF-terminated reply line without reading past it.
+async fn read_reply_line<S>(stream: &mut S) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_SETUP_REPLY_LEN {
+            return Err(ProxyError::Connection("upstream reply too long".to_string()));
+        }
+        let byte = stream.read_u8().await?;
+        line.push(byte);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).to_string())
 }
 
 impl AsyncRead for UpstreamConnection {
@@ -67,6 +591,11 @@ impl AsyncRead for UpstreamConnection {
         match self.get_mut() {
             UpstreamConnection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
             UpstreamConnection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamConnection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamConnection::UnixTls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamConnection::Chained(stream) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
@@ -80,6 +609,11 @@ impl AsyncWrite for UpstreamConnection {
         match self.get_mut() {
             UpstreamConnection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
             UpstreamConnection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamConnection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamConnection::UnixTls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamConnection::Chained(stream) => Pin::new(stream).poll_write(cx, buf),
         }
     }
 
@@ -87,6 +621,11 @@ impl AsyncWrite for UpstreamConnection {
         match self.get_mut() {
             UpstreamConnection::Plain(stream) => Pin::new(stream).poll_flush(cx),
             UpstreamConnection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamConnection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamConnection::UnixTls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamConnection::Chained(stream) => Pin::new(stream).poll_flush(cx),
         }
     }
 
@@ -94,6 +633,583 @@ impl AsyncWrite for UpstreamConnection {
         match self.get_mut() {
             UpstreamConnection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
             UpstreamConnection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamConnection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamConnection::UnixTls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamConnection::Chained(stream) => Pin::new(stream).poll_shutdown(cx),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    use crate::clock::MockClock;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    /// Accept one connection and answer a single PING with +PONG.
+    async fn serve_ping<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"*1\r\n$4\r\nPING\r\n");
+        stream.write_all(b"+PONG\r\n").await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    /// TLS acceptor presenting the `localhost` test certificate.
+    fn test_acceptor() -> tokio_rustls::TlsAcceptor {
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certs(&testdata("server.pem")).unwrap(),
+                load_private_key(&testdata("server.key")).unwrap(),
+            )
+            .unwrap();
+        tokio_rustls::TlsAcceptor::from(Arc::new(server_config))
+    }
+
+    async fn ping(upstream: &UpstreamConfig) -> String {
+        let mut conn = UpstreamConnection::connect(upstream).await.unwrap();
+        conn.write_all(&encode_command(&[b"PING"])).await.unwrap();
+        read_reply_line(&mut conn).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_upstream_entry() {
+        let upstream = UpstreamConfig::parse(
+            "redis.example.com:6380,tls,hostname=redis,ca=/etc/ca.pem,client-cert=c.pem,client-key=c.key",
+        )
+        .unwrap();
+        assert_eq!(upstream.addr, "redis.example.com:6380");
+        assert_eq!(upstream.tls, Some(true));
+        assert_eq!(upstream.hostname(), "redis");
+        assert_eq!(upstream.ca, Some(PathBuf::from("/etc/ca.pem")));
+        assert_eq!(upstream.client_cert, Some(PathBuf::from("c.pem")));
+        assert_eq!(upstream.client_key, Some(PathBuf::from("c.key")));
+
+        let plain = UpstreamConfig::parse("10.0.0.1:6379,bind=10.0.0.5").unwrap();
+        assert_eq!(plain.tls, None);
+        assert_eq!(plain.hostname(), "10.0.0.1");
+        assert_eq!(plain.bind, Some("10.0.0.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_upstream_entry_errors() {
+        assert!(UpstreamConfig::parse("").is_err());
+        assert!(UpstreamConfig::parse("unix:/run/redis.sock,bind=10.0.0.5").is_err());
+        assert!(UpstreamConfig::parse("host:1,bogus").is_err());
+        assert!(UpstreamConfig::parse("host:1,client-cert=c.pem").is_err());
+        assert!(UpstreamConfig::parse("host:1,bind=eth0").is_err());
+    }
+
+    #[test]
+    fn test_pool_round_robin() {
+        let pool = UpstreamPool::new(vec![
+            UpstreamConfig::parse("a:1").unwrap(),
+            UpstreamConfig::parse("b:1").unwrap(),
+        ]);
+        let picks: Vec<_> = (0..4).map(|_| pool.pick().addr.clone()).collect();
+        assert_eq!(picks, vec!["a:1", "b:1", "a:1", "b:1"]);
+    }
+
+    #[test]
+    fn test_backoff_doubles_with_jitter_up_to_cap() {
+        let base = Duration::from_millis(100);
+        for failures in 0..4 {
+            let full = base * 2u32.pow(failures);
+            let delay = backoff(base, failures);
+            assert!(delay >= full / 2 && delay <= full, "{failures}: {delay:?}");
+        }
+        let delay = backoff(base, 30);
+        assert!(delay >= MAX_RETRY_DELAY / 2 && delay <= MAX_RETRY_DELAY, "{delay:?}");
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_connected() {
+        let clock = MockClock::start();
+        let start = clock.now();
+        let mut attempts = 0;
+        let result = with_retries(3, Duration::from_millis(100), clock.as_ref(), || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err(ProxyError::Io(io::ErrorKind::ConnectionRefused.into()))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+        // Waited 50-100ms, then 100-200ms
+        let waited = clock.now() - start;
+        assert!(waited >= Duration::from_millis(150), "{waited:?}");
+        assert!(waited <= Duration::from_millis(302), "{waited:?}");
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted_return_last_error() {
+        let clock = MockClock::start();
+        let mut attempts = 0;
+        let result: Result<()> = with_retries(2, Duration::from_millis(10), clock.as_ref(), || {
+            attempts += 1;
+            let message = format!("attempt {attempts}");
+            async move { Err(ProxyError::Connection(message)) }
+        })
+        .await;
+        assert_eq!(attempts, 3);
+        assert!(matches!(result, Err(ProxyError::Connection(m)) if m == "attempt 3"));
+    }
+
+    #[tokio::test]
+    async fn test_config_errors_not_retried() {
+        let clock = MockClock::start();
+        let mut attempts = 0;
+        let result: Result<()> = with_retries(5, Duration::from_millis(10), clock.as_ref(), || {
+            attempts += 1;
+            async { Err(ProxyError::CertificateLoad("missing".to_string())) }
+        })
+        .await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(result, Err(ProxyError::CertificateLoad(_))));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_server_name_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = UpstreamConfig::parse(&format!("{},tls,hostname=not a name", addr)).unwrap();
+        let stats = Stats::with_clock(MockClock::start());
+        let result =
+            UpstreamConnection::connect_with_retry(&upstream, &stats, 3, Duration::from_secs(1))
+                .await;
+        assert!(matches!(result, Err(ProxyError::Config(m)) if m.contains("not a name")));
+
+        // Only the first attempt reached the listener
+        listener.accept().await.unwrap();
+        let again = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(again.is_err(), "retried a configuration error");
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_retry_fails_on_closed_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let upstream = UpstreamConfig::parse(&addr.to_string()).unwrap();
+        let stats = Stats::with_clock(MockClock::start());
+        let result =
+            UpstreamConnection::connect_with_retry(&upstream, &stats, 2, Duration::from_secs(1))
+                .await;
+        assert!(matches!(result, Err(ProxyError::Io(_))));
+        assert_eq!(stats.upstream_connect_latency(false).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connect_mixed_tls_and_plain_upstreams() {
+        let plain_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain_addr = plain_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = plain_listener.accept().await.unwrap();
+            serve_ping(stream).await;
+        });
+
+        let acceptor = test_acceptor();
+        let tls_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_addr = tls_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = tls_listener.accept().await.unwrap();
+            serve_ping(acceptor.accept(stream).await.unwrap()).await;
+        });
+
+        let plain = UpstreamConfig::parse(&format!("{},plain", plain_addr)).unwrap();
+        let tls = UpstreamConfig::parse(&format!(
+            "{},tls,hostname=localhost,ca={}",
+            tls_addr,
+            testdata("ca.pem").display()
+        ))
+        .unwrap();
+
+        assert_eq!(ping(&plain).await, "+PONG");
+        assert_eq!(ping(&tls).await, "+PONG");
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_bind_address() {
+        // Any 127.0.0.0/8 address is local on Linux, so 127.0.0.2 is a distinct source
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            serve_ping(stream).await;
+            peer
+        });
+
+        let upstream = UpstreamConfig::parse(&format!("{},bind=127.0.0.2", addr)).unwrap();
+        assert_eq!(ping(&upstream).await, "+PONG");
+        assert_eq!(accepted.await.unwrap().ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        // An IPv6 source can't reach an IPv4-only upstream
+        let upstream = UpstreamConfig::parse(&format!("{},bind=::1", addr)).unwrap();
+        assert!(UpstreamConnection::connect(&upstream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_latency_is_recorded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_ping(stream).await;
+        });
+        let tls_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_addr = tls_listener.local_addr().unwrap();
+        let acceptor = test_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = tls_listener.accept().await.unwrap();
+            serve_ping(acceptor.accept(stream).await.unwrap()).await;
+        });
+
+        let stats = Stats::new();
+        let plain = UpstreamConfig::parse(&addr.to_string()).unwrap();
+        UpstreamConnection::connect_recorded(&plain, &stats)
+            .await
+            .unwrap();
+        assert_eq!(stats.upstream_connect_latency(false).count(), 1);
+        assert_eq!(stats.upstream_connect_latency(true).count(), 0);
+
+        let tls = UpstreamConfig::parse(&format!(
+            "{},tls,hostname=localhost,ca={}",
+            tls_addr,
+            testdata("ca.pem").display()
+        ))
+        .unwrap();
+        UpstreamConnection::connect_recorded(&tls, &stats)
+            .await
+            .unwrap();
+        assert_eq!(stats.upstream_connect_latency(true).count(), 1);
+        assert!(stats.upstream_connect_latency(true).max() > 0);
+
+        // Failed connects are not recorded
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let down = UpstreamConfig::parse(&closed_addr.to_string()).unwrap();
+        assert!(UpstreamConnection::connect_recorded(&down, &stats).await.is_err());
+        assert_eq!(stats.upstream_connect_latency(false).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_requests_alpn() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server_config = rustls::ServerConfig::clone(test_acceptor().config());
+        server_config.alpn_protocols = vec![b"redis".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let mut upstream = UpstreamConfig::parse(&format!(
+            "{},tls,hostname=localhost,ca={}",
+            addr,
+            testdata("ca.pem").display()
+        ))
+        .unwrap();
+        upstream.alpn = vec!["resp3".to_string(), "redis".to_string()];
+        match UpstreamConnection::connect(&upstream).await.unwrap() {
+            UpstreamConnection::Tls(stream) => {
+                assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"redis"[..]));
+            }
+            _ => panic!("expected a TLS connection"),
+        }
+
+        // A server that supports none of the requested protocols refuses the handshake
+        upstream.alpn = vec!["resp3".to_string()];
+        assert!(UpstreamConnection::connect(&upstream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_ca_combined_with_webpki_roots() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = test_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_ping(acceptor.accept(stream).await.unwrap()).await;
+        });
+
+        let mut upstream = UpstreamConfig::parse(&format!(
+            "{},tls,hostname=localhost,ca={}",
+            addr,
+            testdata("ca.pem").display()
+        ))
+        .unwrap();
+        let webpki = webpki_roots::TLS_SERVER_ROOTS.len();
+        assert_eq!(upstream.root_store().unwrap().len(), 1);
+
+        upstream.webpki_roots = true;
+        assert_eq!(upstream.root_store().unwrap().len(), 1 + webpki);
+        assert_eq!(ping(&upstream).await, "+PONG");
+
+        let default = UpstreamConfig::parse(&format!("{},tls", addr)).unwrap();
+        assert_eq!(default.root_store().unwrap().len(), webpki);
+    }
+
+    #[test]
+    fn test_invalid_ca_names_the_file() {
+        let garbage = std::env::temp_dir().join(format!("upstream-ca-{}.pem", std::process::id()));
+        std::fs::write(&garbage, b"not a certificate").unwrap();
+        let upstream =
+            UpstreamConfig::parse(&format!("localhost:6380,tls,ca={}", garbage.display())).unwrap();
+        let err = upstream.client_config().unwrap_err();
+        std::fs::remove_file(&garbage).unwrap();
+        assert!(matches!(err, ProxyError::CertificateLoad(_)));
+        assert!(err.to_string().contains(&garbage.display().to_string()), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_insecure_skip_verify_accepts_untrusted_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = test_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_ping(acceptor.accept(stream).await.unwrap()).await;
+        });
+
+        // Neither trusted by the webpki roots nor issued for this hostname
+        let mut upstream =
+            UpstreamConfig::parse(&format!("{},tls,hostname=redis.example.com", addr)).unwrap();
+        upstream.insecure_skip_verify = true;
+        assert_eq!(ping(&upstream).await, "+PONG");
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_presents_client_certificate() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(load_certs(&testdata("ca.pem")).unwrap().remove(0)).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs(&testdata("server.pem")).unwrap(),
+                load_private_key(&testdata("server.key")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    serve_ping(stream).await;
+                }
+            }
+        });
+
+        let with_key = |key: &str| {
+            UpstreamConfig::parse(&format!(
+                "{},tls,hostname=localhost,ca={},client-cert={},client-key={}",
+                addr,
+                testdata("ca.pem").display(),
+                testdata("client.pem").display(),
+                testdata(key).display()
+            ))
+            .unwrap()
+        };
+        // PKCS#8 and SEC1 encodings of the same key both work
+        assert_eq!(ping(&with_key("client.key")).await, "+PONG");
+        assert_eq!(ping(&with_key("client-sec1.key")).await, "+PONG");
+
+        let anonymous = UpstreamConfig {
+            client_cert: None,
+            client_key: None,
+            ..with_key("client.key")
+        };
+        let mut conn = UpstreamConnection::connect(&anonymous).await.unwrap();
+        // TLS 1.3 clients learn of the rejection on their first read
+        let _ = conn.write_all(&encode_command(&[b"PING"])).await;
+        assert!(read_reply_line(&mut conn).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_unix_socket_upstreams() {
+        let dir = std::env::temp_dir().join(format!("upstream-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plain_path = dir.join("plain.sock");
+        let plain_listener = tokio::net::UnixListener::bind(&plain_path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = plain_listener.accept().await.unwrap();
+            serve_ping(stream).await;
+        });
+        let tls_path = dir.join("tls.sock");
+        let tls_listener = tokio::net::UnixListener::bind(&tls_path).unwrap();
+        let acceptor = test_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = tls_listener.accept().await.unwrap();
+            serve_ping(acceptor.accept(stream).await.unwrap()).await;
+        });
+
+        let plain = UpstreamConfig::parse(&format!("unix:{}", plain_path.display())).unwrap();
+        assert_eq!(plain.to_string(), format!("unix:{} (Unix socket)", plain_path.display()));
+        assert_eq!(ping(&plain).await, "+PONG");
+
+        // The test certificate is issued for localhost, the default name for Unix sockets
+        let tls = UpstreamConfig::parse(&format!(
+            "unix:{},tls,ca={}",
+            tls_path.display(),
+            testdata("ca.pem").display()
+        ))
+        .unwrap();
+        assert_eq!(ping(&tls).await, "+PONG");
+
+        let missing = UpstreamConfig::parse(&format!("unix:{}", dir.join("gone").display()));
+        assert!(UpstreamConnection::connect(&missing.unwrap()).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_tls_rejects_untrusted_server() {
+        // Verifying against the public webpki roots must fail for the test CA
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = test_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+
+        let upstream = UpstreamConfig::parse(&format!("{},tls,hostname=localhost", addr)).unwrap();
+        assert!(UpstreamConnection::connect(&upstream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_setup_command_success() {
+        let (mut proxy_side, mut redis_side) = duplex(1024);
+        let redis = tokio::spawn(async move {
+            let mut buf = vec![0u8; 128];
+            let n = redis_side.read(&mut buf).await.unwrap();
+            redis_side.write_all(&setup_reply("+OK")).await.unwrap();
+            buf.truncate(n);
+            buf
+        });
+
+        send_setup_command(&mut proxy_side, &[b"SELECT", b"3"]).await.unwrap();
+        let mut expected = encode_command(&[b"SELECT", b"3"]);
+        expected.extend_from_slice(&encode_command(&[b"PING", SETUP_MARKER.as_bytes()]));
+        assert_eq!(redis.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_setup_command_skips_banner() {
+        let (mut proxy_side, mut redis_side) = duplex(1024);
+        tokio::spawn(async move {
+            // Greeting sent on connect, before any command arrives
+            redis_side
+                .write_all(b"+Welcome to the cache\r\n-ERR not a reply\r\n")
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; 128];
+            let _ = redis_side.read(&mut buf).await.unwrap();
+            redis_side.write_all(&setup_reply("+OK")).await.unwrap();
+            // Client traffic follows the setup
+            redis_side.write_all(b"+PONG\r\n").await.unwrap();
+        });
+
+        send_setup_command(&mut proxy_side, &[b"SELECT", b"3"]).await.unwrap();
+        assert_eq!(read_reply_line(&mut proxy_side).await.unwrap(), "+PONG");
+    }
+
+    #[tokio::test]
+    async fn test_setup_command_rejected_with_ping() {
+        let (mut proxy_side, mut redis_side) = duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 128];
+            let _ = redis_side.read(&mut buf).await.unwrap();
+            redis_side
+                .write_all(b"-NOAUTH Authentication required.\r\n-NOAUTH Authentication required.\r\n")
+                .await
+                .unwrap();
+        });
+
+        let err = send_setup_command(&mut proxy_side, &[b"SELECT", b"3"])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("NOAUTH"), "{}", err);
+    }
+
+    /// Connect to an upstream that answers the first request with `reply`, returning the
+    /// connection attempt's result and what the upstream received.
+    async fn authenticate_against(user: Option<&str>, reply: Vec<u8>) -> (Result<()>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UpstreamConfig::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let redis = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 256];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&reply).await.unwrap();
+            buf.truncate(n);
+            buf
+        });
+        let mut conn = UpstreamConnection::connect(&upstream).await.unwrap();
+        let result = conn.authenticate(user, "s3cret").await;
+        (result, redis.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_sends_auth_first() {
+        let (result, received) = authenticate_against(Some("proxy"), setup_reply("+OK")).await;
+        result.unwrap();
+        let expected = encode_command(&[b"AUTH", b"proxy", b"s3cret"]);
+        assert!(received.starts_with(&expected), "{received:?}");
+
+        let (result, received) = authenticate_against(None, setup_reply("+OK")).await;
+        result.unwrap();
+        assert!(received.starts_with(&encode_command(&[b"AUTH", b"s3cret"])));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_fails_on_error_reply() {
+        let reply = "-WRONGPASS invalid username-password pair\r\n\
+                     -NOAUTH Authentication required.\r\n";
+        let (result, _) = authenticate_against(None, reply.into()).await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("upstream rejected AUTH: WRONGPASS"), "{err}");
+        assert!(!err.contains("s3cret"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_setup_command_error_reply() {
+        let (mut proxy_side, mut redis_side) = duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 128];
+            let _ = redis_side.read(&mut buf).await.unwrap();
+            redis_side
+                .write_all(&setup_reply("-ERR DB index is out of range"))
+                .await
+                .unwrap();
+        });
+
+        let err = send_setup_command(&mut proxy_side, &[b"SELECT", b"99"])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("DB index is out of range"));
+    }
+}